@@ -6,6 +6,8 @@ module SExpr = struct
     | List of s list
     | Symbol of string
     | Num of int
+    | Float of float
+    | Str of string
 
   exception Syntax
 
@@ -13,24 +15,38 @@ module SExpr = struct
     let s = s ^ ")\x00" in
     let isdigit c = '0' <= c && c <= '9' in
     let isident c = 'a' <= c && c <= 'z' || 'A' <= c && c <= 'Z'
-      || isdigit c || String.contains "+-*><=!" c in
+      || isdigit c || String.contains "+-*/><=!?" c in
     let rec ws i =
       if s.[i] = ' ' || s.[i] = '\n' || s.[i] = '\t' then ws (i+1) else i in
     let rec end_of_ident i = if isident s.[i] then end_of_ident (i+1) else i in
+    let rec end_of_str b i = match s.[i] with
+      | '"' -> i+1
+      | '\\' ->
+          Buffer.add_char b (match s.[i+1] with
+            | 'n' -> '\n' | 't' -> '\t' | '"' -> '"' | '\\' -> '\\'
+            | _ -> raise Syntax);
+          end_of_str b (i+2)
+      | '\x00' -> raise Syntax
+      | c -> Buffer.add_char b c; end_of_str b (i+1) in
     let rec end_of_num i =
-      if isdigit s.[i] then end_of_num (i+1)
+      if isdigit s.[i] || s.[i] = '.' then end_of_num (i+1)
       else if isident s.[i] then true, end_of_ident (i+1)
       else false, i in
     let rec item i =
       if s.[i] = '(' then
         let xs, i = rest_of_list @@ ws (i+1) in List(xs), i
+      else if s.[i] = '"' then
+        let b = Buffer.create 16 in
+        let i' = end_of_str b (i+1) in Str (Buffer.contents b), ws i'
       else if isdigit s.[i] || s.[i] = '-' then
         let ident, i' = end_of_num (i+1) in
-        if ident || i' = i+1 && s.[i] = '-' then
-          Symbol (String.sub s i (i' - i)), ws i'
-        else match int_of_string_opt @@ String.sub s i (i' - i) with
-        | Some x -> Num x, ws i'
-        | None -> raise Syntax
+        let tok = String.sub s i (i' - i) in
+        if tok = "-" then Symbol tok, ws i'
+        else match (if ident then None else int_of_string_opt tok),
+                   float_of_string_opt tok with
+        | Some x, _ -> Num x, ws i'
+        | None, Some x -> Float x, ws i'
+        | None, None -> if ident then Symbol tok, ws i' else raise Syntax
       else if isident s.[i] then
         let i' = end_of_ident (i+1) in Symbol (String.sub s i (i' - i)), ws i'
       else
@@ -53,8 +69,12 @@ module AST = struct
 
   type t =
     | Num of int
+    | Float of float
+    | Str of string
     | True | False
     | Input
+    | InputAt of t
+    | InputCount
     | Var of string
     | Let of (string * t) list * t
     | Op1 of op1 * t
@@ -62,18 +82,35 @@ module AST = struct
     | Set of string * t
     | If of t * t * t
     | Block of t list * t
-    | Loop of t
+    | And of t list
+    | Or of t list
+    | Loop of string option * t
+    | Break of string option * t
+    | Continue of string option
     | Call of string * t list
-  and op1 = Add1 | Sub1 | Isnum | Isbool | Print | Break
+    | Substring of t * t * t
+    (* functions defined in a block, around its other expressions *)
+    | LocalFuns of defn list * t
+  and op1 = Add1 | Sub1 | Isnum | Isbool | Print | StringLength
+    | ToFloat | Truncate | Not
   and op2 = Add | Sub | Mul | Lt | Gt | Lte | Gte | Eq
-
-  type defn = { name: string; args: string list; body: t }
+    | StringAppend | StringEq
+    | Div | Quotient | Remainder | Modulo | Bitand | Bitor | Bitxor | Shl | Shr
+  and defn = { name: string; args: string list; body: t }
 
   let kws =
-    [ "true"; "false"; "input"
+    [ "true"; "false"; "input"; "input-count"
     ; "let"; "set!"; "if"; "block"; "loop"; "break"; "fun"
     ; "add1"; "sub1"; "isnum"; "isbool"; "print"
-    ; "+"; "-"; "*"; "<"; ">"; "<="; ">="; "="]
+    ; "+"; "-"; "*"; "<"; ">"; "<="; ">="; "="
+    ; "string-length"; "string-append"; "substring"; "string=?"
+    ; "/"; "quotient"; "remainder"; "modulo"
+    ; "bitand"; "bitor"; "bitxor"; "shl"; "shr"
+    ; "float"; "truncate"; "and"; "or"; "not"
+    ; "cond"; "when"; "unless"; "case"; "else"
+    ; "while"; "for"; "continue"; "import"; "provide"; "define"]
+
+  let is_label s = String.length s > 1 && s.[0] = ':'
 
   let sexp_to_ast s =
     let rec init_last = function
@@ -82,15 +119,26 @@ module AST = struct
       | x::xs -> let a, b = init_last xs in (x::a), b in
     let op1 = function
       | "add1" -> Add1 | "sub1" -> Sub1 | "isnum" -> Isnum | "isbool" -> Isbool
-      | "print" -> Print | "break" -> Break | _ -> raise Syntax in
+      | "print" -> Print
+      | "string-length" -> StringLength
+      | "float" -> ToFloat | "truncate" -> Truncate | "not" -> Not
+      | _ -> raise Syntax in
     let op2 = function
       | "+" -> Add | "-" -> Sub | "*" -> Mul | "<" -> Lt | ">" -> Gt
-      | "<=" -> Lte | ">=" -> Gte | "=" -> Eq | _ -> raise Syntax in
+      | "<=" -> Lte | ">=" -> Gte | "=" -> Eq
+      | "string-append" -> StringAppend | "string=?" -> StringEq
+      | "/" -> Div | "quotient" -> Quotient | "remainder" -> Remainder
+      | "modulo" -> Modulo | "bitand" -> Bitand | "bitor" -> Bitor
+      | "bitxor" -> Bitxor | "shl" -> Shl | "shr" -> Shr
+      | _ -> raise Syntax in
     let rec exp = function
       | Symbol "true" -> True | Symbol "false" -> False
       | Symbol "input" -> Input
+      | Symbol "input-count" -> InputCount
       | Symbol x -> if List.mem x kws then raise Syntax else Var x
       | Num x -> Num x
+      | SExpr.Float x -> Float x
+      | SExpr.Str x -> Str x
       | List[Symbol "let"; List binds; body] ->
           let bind = function
             | List [Symbol s; x] when not (List.mem s kws)-> s, exp x
@@ -102,70 +150,190 @@ module AST = struct
       | List(Symbol "set!" :: _) -> raise Syntax
       | List[Symbol "if"; a; b; c] -> If(exp a, exp b, exp c)
       | List(Symbol "if" :: _) -> raise Syntax
-      | List(Symbol "block" :: contents) ->
-          let ss, e = init_last @@ List.map exp contents in
-          Block(ss, e)
-      | List[Symbol "loop"; body] -> Loop(exp body)
+      | List(Symbol "block" :: contents) -> block contents
+      | List(Symbol "cond" :: clauses) -> cond clauses
+      | List(Symbol "when" :: c :: body) -> If(exp c, body_of body, False)
+      | List(Symbol "unless" :: c :: body) -> If(exp c, False, body_of body)
+      | List(Symbol "case" :: key :: clauses) ->
+          Let(["case key", exp key], case clauses)
+      | List(Symbol "and" :: xs) -> And(List.map exp xs)
+      | List(Symbol "or" :: xs) -> Or(List.map exp xs)
+      | List[Symbol "input"; i] -> InputAt(exp i)
+      | List[Symbol "substring"; s; a; b] -> Substring(exp s, exp a, exp b)
+      | List[Symbol "loop"; Symbol l; body] when is_label l -> Loop(Some l, exp body)
+      | List[Symbol "loop"; body] -> Loop(None, exp body)
       | List(Symbol "loop" :: _) -> raise Syntax
+      | List[Symbol "break"; Symbol l; v] when is_label l -> Break(Some l, exp v)
+      | List[Symbol "break"; v] -> Break(None, exp v)
+      | List[Symbol "continue"] -> Continue None
+      | List[Symbol "continue"; Symbol l] when is_label l -> Continue (Some l)
+      | List(Symbol "while" :: Symbol l :: c :: body) when is_label l ->
+          while_ (Some l) c body
+      | List(Symbol "while" :: c :: body) -> while_ None c body
+      | List(Symbol "for" :: Symbol l :: range :: body) when is_label l ->
+          for_ (Some l) range body
+      | List(Symbol "for" :: range :: body) -> for_ None range body
       | List[Symbol op; body] when List.mem op kws -> Op1(op1 op, exp body)
       | List[Symbol op; x; y] when List.mem op kws -> Op2(op2 op, exp x, exp y)
       | List(Symbol fn :: args) ->
           if List.mem fn kws then raise Syntax else Call(fn, List.map exp args)
-      | List _ -> raise Syntax in
-    let fn = function
+      | List _ -> raise Syntax
+    and body_of = function
+      | [] -> raise Syntax
+      | [x] -> exp x
+      | xs -> block xs
+    (* the functions defined among a block's expressions see all of it *)
+    and block contents =
+      let is_fun = function List(Symbol "fun" :: _) -> true | _ -> false in
+      if contents <> [] && is_fun (List.nth contents (List.length contents - 1))
+      then raise Syntax;
+      let funs, rest = List.partition is_fun contents in
+      let ss, e = init_last @@ List.map exp rest in
+      if funs = [] then Block(ss, e) else LocalFuns(List.map fn funs, Block(ss, e))
+    and while_ label c body =
+      Loop(label, If(exp c, body_of body, Break(None, False)))
+    (* the step comes first, skipped on the first pass, so `continue` steps *)
+    and for_ label range body = match range with
+      | List [Symbol v; a; b] when not (List.mem v kws) ->
+          let step =
+            If(Var "for first", Set("for first", False), Set(v, Op1(Add1, Var v))) in
+          let test =
+            If(Op2(Lt, Var v, Var "for end"), body_of body, Break(None, False)) in
+          Let([v, exp a; "for end", exp b; "for first", True],
+              Loop(label, Block([step], test)))
+      | _ -> raise Syntax
+    and cond = function
+      | [] -> False
+      | [List(Symbol "else" :: body)] -> body_of body
+      | List(Symbol "else" :: _) :: _ -> raise Syntax
+      | List(test :: body) :: rest -> If(exp test, body_of body, cond rest)
+      | _ -> raise Syntax
+    and case = function
+      | [] -> False
+      | [List(Symbol "else" :: body)] -> body_of body
+      | List(Symbol "else" :: _) :: _ -> raise Syntax
+      | List(List datums :: body) :: rest ->
+          If(Or(List.map datum datums), body_of body, case rest)
+      | _ -> raise Syntax
+    (* literals of another kind than the key's just don't match *)
+    and datum d =
+      let key = Var "case key" in
+      match d with
+      | Num _ | SExpr.Float _ -> And [Op1(Isnum, key); Op2(Eq, key, exp d)]
+      | Symbol ("true" | "false") -> And [Op1(Isbool, key); Op2(Eq, key, exp d)]
+      | _ -> raise Syntax
+    and fn = function
       | List [Symbol "fun"; List(Symbol name :: args); body] ->
           let arg = function
             | Symbol s when not (List.mem s kws) -> s
             | _ -> raise Syntax in
           { name; args = List.map arg args; body = exp body }
       | _ -> raise Syntax in
-    let fns, body = init_last s in
-    List.map fn fns, exp body
+    (* top-level `define`s, kept in order among the functions *)
+    let is_global = function List(Symbol "define" :: _) -> true | _ -> false in
+    let global = function
+      | List [Symbol "define"; Symbol name; x] when not (List.mem name kws) ->
+          name, exp x
+      | _ -> raise Syntax in
+    let tops, body = init_last s in
+    let globals, fns = List.partition is_global tops in
+    List.map fn fns, List.map global globals, exp body
 
 
   exception BadProgram
 
-  let check_program fns main =
+  (* a global used before its `define` through a call is only caught when
+     it is evaluated *)
+  let check_program fns globals main =
     let no_dups xs =
       if List.(length (sort_uniq Stdlib.compare xs) <> length xs) then
         raise BadProgram in
     let fn_arities =
       List.map (fun { name; args; _ } -> name, List.length args) fns in
     no_dups (List.map fst fn_arities);
+    let names = List.map fst globals in
+    no_dups names;
+    (* what a local function sees of the scope it is defined in: not the
+       input, nor the loops around it *)
+    let fn_ctx =
+      List.filter (fun c ->
+        c.[0] <> '[' || String.starts_with ~prefix:"[fun " c) in
     let rec exp ctx = function
-      | Num _ | True | False -> ()
+      | Num _ | Float _ | Str _ | True | False -> ()
       | Var v -> if not (List.mem v ctx) then raise BadProgram
-      | Input -> if not (List.mem "[input]" ctx) then raise BadProgram
+      | Input | InputCount ->
+          if not (List.mem "[input]" ctx) then raise BadProgram
+      | InputAt i ->
+          if not (List.mem "[input]" ctx) then raise BadProgram; exp ctx i
       | Let(binds, body) ->
           no_dups (List.map fst binds);
           let ctx =
             List.fold_left (fun ctx (a,v) -> exp ctx v; a::ctx) ctx binds in
           exp ctx body
-      | Op1(Break, _) when not (List.mem "[loop]" ctx) -> raise BadProgram
       | Op1(_, x) -> exp ctx x
       | Op2(_, x, y) -> exp ctx x; exp ctx y
       | Set(name, x) ->
           if not (List.mem name ctx) then raise BadProgram; exp ctx x
-      | If(cond, a, b) -> exp ctx cond; exp ctx a; exp ctx b
+      | If(cond, a, b) | Substring(cond, a, b) ->
+          exp ctx cond; exp ctx a; exp ctx b
+      | Substring(s, a, b) -> begin
+          let s = go env fenv s in
+          let a = go env fenv a in
+          let b = go env fenv b in
+          match s, a, b with
+          | VStr s, VNum a, VNum b ->
+              if a < 0 || a > b || b > String.length s then raise Range;
+              VStr (String.sub s a (b - a))
+          | _ -> raise Type
+          end
       | Block(ss, e) -> List.iter (exp ctx) ss; exp ctx e
-      | Loop l -> exp ("[loop]" :: ctx) l
-      | Call(f, args) -> match List.assoc_opt f fn_arities with
+      | And xs | Or xs -> List.iter (exp ctx) xs
+      | Loop(None, l) -> exp ("[loop]" :: ctx) l
+      | Loop(Some label, l) -> exp ("[loop]" :: ("[loop " ^ label ^ "]") :: ctx) l
+      | Break(label, x) -> target ctx label; exp ctx x
+      | Continue label -> target ctx label
+      | LocalFuns(fns, body) ->
+          no_dups (List.map (fun f -> f.name) fns);
+          let ctx = List.map (fun f ->
+            Printf.sprintf "[fun %s %d]" f.name (List.length f.args)) fns @ ctx in
+          List.iter (fun f -> no_dups f.args; exp (f.args @ fn_ctx ctx) f.body) fns;
+          exp ctx body
+      | Call(f, args) ->
+          (* the innermost local function of that name, else the program's *)
+          let prefix = "[fun " ^ f ^ " " in
+          let arity = match List.find_opt (String.starts_with ~prefix) ctx with
+            | Some c ->
+                let n = String.length prefix in
+                Some (int_of_string (String.sub c n (String.length c - n - 1)))
+            | None -> List.assoc_opt f fn_arities in
+          match arity with
           | Some a -> if a <> List.length args then raise BadProgram;
               List.iter (exp ctx) args
-          | None -> raise BadProgram in
-    let fn { name; args; body } = no_dups args; exp args body in
-    List.iter fn fns; exp ["[input]"] main
+          | None -> raise BadProgram
+    and target ctx = function
+      | None -> if not (List.mem "[loop]" ctx) then raise BadProgram
+      | Some l -> if not (List.mem ("[loop " ^ l ^ "]") ctx) then raise BadProgram in
+    let fn { name; args; body } = no_dups args; exp (args @ names) body in
+    List.iter fn fns;
+    let ctx = List.fold_left
+      (fun ctx (name, x) -> exp ctx x; name :: ctx) ["[input]"] globals in
+    exp ctx main
 end
 
 module Eval = struct
   open AST
 
-  type v = VBool of bool | VNum of int
+  type v = VBool of bool | VNum of int | VFloat of float | VStr of string
 
   exception Type
+  exception Range
+  exception DivideByZero
   exception Overflow
+  exception InputIndex of int * int (* index, number of inputs *)
 
-  exception BreakExn of v (* for control flow *)
+  (* for control flow, to the innermost loop or the innermost with the label *)
+  exception BreakExn of string option * v
+  exception ContinueExn of string option
 
   let (+) a b =
     let sum = a + b in
@@ -183,26 +351,117 @@ module Eval = struct
     (* TODO: does this properly check for overflow? *)
     if x = 0 || result / x = y then result else raise Overflow
 
+  (* truncating, like the hardware *)
+  let quotient x y =
+    if y = 0 then raise DivideByZero
+    else if x = Int.min_int && y = -1 then raise Overflow
+    else x / y
+
+  let remainder x y =
+    if y = 0 then raise DivideByZero else x mod y
+
+  (* rounding towards negative infinity instead *)
+  let floor_div x y =
+    let q = quotient x y in
+    let r = x mod y in
+    if r <> 0 && (r < 0) <> (y < 0) then q - 1 else q
+
+  let modulo x y =
+    let r = remainder x y in
+    if r <> 0 && (r < 0) <> (y < 0) then r + y else r
+
+  (* a negative count shifts the other way *)
+  let rec shl x k =
+    if k < 0 then shr x (negate k)
+    else if x = 0 then 0
+    else if k >= 63 || (x lsl k) asr k <> x then raise Overflow
+    else x lsl k
+  and shr x k =
+    if k < 0 then shl x (negate k) else x asr (min k 62)
+
+  (* the shortest digits that read back as the same float, laid out like
+     Rust's {:?}: plain between 1e-4 and 1e16, scientific outside *)
+  let show_float x =
+    if Float.is_nan x then "NaN"
+    else if x = Float.infinity then "inf"
+    else if x = Float.neg_infinity then "-inf"
+    else if x = 0. then (if 1. /. x < 0. then "-0.0" else "0.0")
+    else
+      let rec shortest p =
+        let s = Printf.sprintf "%.*e" p x in
+        if p >= 16 || float_of_string s = x then p, s else shortest (p + 1) in
+      let p, s = shortest 0 in
+      let i = String.index s 'e' in
+      let e = String.sub s (i+1) (String.length s - i - 1) in
+      let e = int_of_string (if e.[0] = '+' then String.sub e 1 (String.length e - 1) else e) in
+      let ax = Float.abs x in
+      if ax >= 1e-4 && ax < 1e16 then Printf.sprintf "%.*f" (max 1 (p - e)) x
+      else String.sub s 0 i ^ "e" ^ string_of_int e
+
   let print = function
     | VBool b -> print_endline (if b then "true" else "false")
     | VNum x -> print_endline @@ string_of_int x
+    | VFloat x -> print_endline @@ show_float x
+    | VStr s -> print_endline s
+
+  (* truncating, and only for floats that fit in 63 bits *)
+  let truncate x =
+    if Float.is_nan x || Float.abs x >= 4611686018427387904. then
+      (if Float.is_integer x then raise Overflow else raise Type)
+    else Float.to_int x
+
+  (* mixing in a float makes the whole operation a float one *)
+  let float_op2 o x y = match o with
+    | Add -> VFloat (x +. y) | Sub -> VFloat (x -. y)
+    | Mul -> VFloat (x *. y) | Div -> VFloat (x /. y)
+    | Lt -> VBool (x < y) | Gt -> VBool (x > y)
+    | Lte -> VBool (x <= y) | Gte -> VBool (x >= y)
+    | Eq -> VBool (x = y)
+    | _ -> raise Type
 
   let op1 o x = match o, x with
     | Add1, VNum x -> VNum (x+1)
-    | Add1, VBool _ -> raise Type
     | Sub1, VNum x -> VNum (x-1)
-    | Sub1, VBool _ -> raise Type
-    | Isnum, VNum _ -> VBool true
-    | Isnum, VBool _ -> VBool false
-    | Isbool, VNum _ -> VBool false
+    | Add1, VFloat x -> VFloat (x +. 1.)
+    | Sub1, VFloat x -> VFloat (x -. 1.)
+    | (Add1 | Sub1), _ -> raise Type
+    | Isnum, (VNum _ | VFloat _) -> VBool true
+    | Isnum, _ -> VBool false
     | Isbool, VBool _ -> VBool true
+    | Isbool, _ -> VBool false
+    | StringLength, VStr s -> VNum (String.length s)
+    | StringLength, _ -> raise Type
+    | ToFloat, VNum x -> VFloat (float_of_int x)
+    | ToFloat, VFloat x -> VFloat x
+    | Truncate, VNum x -> VNum x
+    | Truncate, VFloat x -> VNum (truncate x)
+    | (ToFloat | Truncate), _ -> raise Type
+    | Not, VBool b -> VBool (not b)
+    | Not, _ -> raise Type
     | Print, v -> print v; v
-    | Break, x -> raise (BreakExn x)
 
   let op2 o x y = match o, x, y with
     | Eq, VBool x, VBool y -> VBool (x = y)
     | Eq, VNum x, VNum y -> VBool (x = y)
+    | Eq, VStr x, VStr y -> VBool (x = y)
+    | Div, VFloat _, VNum 0 -> raise DivideByZero
+    | (Add | Sub | Mul | Div | Lt | Gt | Lte | Gte | Eq), VFloat x, VNum y ->
+        float_op2 o x (float_of_int y)
+    | (Add | Sub | Mul | Div | Lt | Gt | Lte | Gte | Eq), VNum x, VFloat y ->
+        float_op2 o (float_of_int x) y
+    | _, VFloat x, VFloat y -> float_op2 o x y
     | Eq, _, _ -> raise Type
+    | StringAppend, VStr x, VStr y -> VStr (x ^ y)
+    | StringEq, VStr x, VStr y -> VBool (x = y)
+    | Div, VNum x, VNum y -> VNum (floor_div x y)
+    | Quotient, VNum x, VNum y -> VNum (quotient x y)
+    | Remainder, VNum x, VNum y -> VNum (remainder x y)
+    | Modulo, VNum x, VNum y -> VNum (modulo x y)
+    | Bitand, VNum x, VNum y -> VNum (x land y)
+    | Bitor, VNum x, VNum y -> VNum (x lor y)
+    | Bitxor, VNum x, VNum y -> VNum (x lxor y)
+    | Shl, VNum x, VNum y -> VNum (shl x y)
+    | Shr, VNum x, VNum y -> VNum (shr x y)
     | Add, VNum x, VNum y -> VNum (x + y)
     | Sub, VNum x, VNum y -> VNum (x - y)
     | Mul, VNum x, VNum y -> VNum (x * y)
@@ -212,40 +471,94 @@ module Eval = struct
     | Gte, VNum x, VNum y -> VBool (x >= y)
     | _ -> raise Type
 
-  let eval input defs =
-    let rec go env = function
+  (* a local function, with the variables and local functions it sees; the
+     functions defined together share theirs *)
+  type closure = Closure of defn * (string * v ref) list * (string * closure) list ref
+
+  let eval inputs defs globals =
+    (* empty until the global's `define` has run *)
+    let cells = List.map (fun (name, _) -> name, ref None) globals in
+    let rec go env fenv fenv = function
       | Num n -> VNum n
+      | Float x -> VFloat x
+      | Str s -> VStr s
       | True -> VBool true
       | False -> VBool false
-      | Input -> input
-      | Var x -> !(List.assoc x env)
+      | Input -> if Array.length inputs = 0 then VBool false else inputs.(0)
+      | InputCount -> VNum (Array.length inputs)
+      | InputAt i -> begin
+          match go env fenv i with
+          | VNum i when i >= 0 && i < Array.length inputs -> inputs.(i)
+          | VNum i -> raise (InputIndex (i, Array.length inputs))
+          | _ -> raise Type
+          end
+      | Var x -> begin
+          match List.assoc_opt x env with
+          | Some r -> !r
+          | None -> match !(List.assoc x cells) with
+            | Some v -> v
+            | None -> raise BadProgram
+          end
       | Let(binds, body) ->
           let env = List.fold_left
-          (fun env (x,v) -> (x, ref (go env v))::env) env binds in
-          go env body
-      | Op1(op, x) -> op1 op (go env x)
+          (fun env (x,v) -> (x, ref (go env fenv v))::env) env binds in
+          go env fenv body
+      | Op1(op, x) -> op1 op (go env fenv x)
       | Op2(op, x, y) -> (* OCaml eval order is fun *)
-          let a = go env x in
-          let b = go env y in
+          let a = go env fenv x in
+          let b = go env fenv y in
           op2 op a b
       | Set(var, v) ->
-          let x = go env v in
-          List.assoc var env := x; x
+          let x = go env fenv v in
+          begin match List.assoc_opt var env with
+          | Some r -> r := x
+          | None ->
+              let cell = List.assoc var cells in
+              if !cell = None then raise BadProgram;
+              cell := Some x
+          end;
+          x
       | If(cond, t, e) -> begin
-          match go env cond with
-          | VBool false -> go env e
-          | _ -> go env t (* Scheme is great, love how all numbers are truthy *)
+          match go env fenv cond with
+          | VBool false -> go env fenv e
+          | _ -> go env fenv t (* Scheme is great, love how all numbers are truthy *)
           end
-      | Block(ss, e) -> List.iter (fun b -> ignore @@ go env b) ss; go env e
-      | Loop l -> begin
-          try
-            let rec loop () = ignore (go env l); loop () in loop ()
-          with BreakExn v -> v end
-      | Call(f, args) ->
-          let f = List.find (fun x -> x.name = f) defs in
-          let env = List.map2 (fun v a -> v, ref (go env a)) f.args args in
-          go env f.body in
-    go []
+      | Block(ss, e) -> List.iter (fun b -> ignore @@ go env fenv b) ss; go env fenv e
+      (* stop at the first value that decides the answer *)
+      | And xs ->
+          List.fold_left
+            (fun v x -> if v = VBool false then v else go env fenv x) (VBool true) xs
+      | Or xs ->
+          List.fold_left
+            (fun v x -> if v = VBool false then go env fenv x else v) (VBool false) xs
+      | Loop(label, l) ->
+          let mine l' = l' = None || l' = label in
+          let rec loop () =
+            match go env fenv l with
+            | _ -> loop ()
+            | exception BreakExn(l', v) when mine l' -> v
+            | exception ContinueExn l' when mine l' -> loop () in
+          loop ()
+      | Break(label, x) -> raise (BreakExn(label, go env fenv x))
+      | Continue label -> raise (ContinueExn label)
+      | LocalFuns(fns, body) ->
+          let group = ref [] in
+          let fenv = List.map (fun d -> d.name, Closure(d, env, group)) fns @ fenv in
+          group := fenv;
+          go env fenv body
+      | Call(f, args) -> begin
+          match List.assoc_opt f fenv with
+          | Some (Closure(d, denv, dfenv)) ->
+              let args = List.map2 (fun v a -> v, ref (go env fenv a)) d.args args in
+              go (args @ denv) !dfenv d.body
+          | None ->
+              let f = List.find (fun x -> x.name = f) defs in
+              let env = List.map2 (fun v a -> v, ref (go env fenv a)) f.args args in
+              go env [] f.body
+          end in
+    fun main ->
+      List.iter2 (fun (_, x) (_, cell) -> cell := Some (go [] [] x)) globals cells;
+      go [] [] main
 end
 
 (* From stackoverflow *)
@@ -257,16 +570,16 @@ let read_file filename =
 let main () =
   let filename = Sys.argv.(1) in
   let contents = read_file filename in
-  let input =
+  let inputs =
     let open Eval in
-    if Array.length Sys.argv < 2 then VBool false else
-    match Sys.argv.(2) with
-    | "true" -> VBool true
-    | "false" -> VBool false
-    | x -> VNum (int_of_string x) in
+    Array.sub Sys.argv 2 (Array.length Sys.argv - 2)
+    |> Array.map (function
+      | "true" -> VBool true
+      | "false" -> VBool false
+      | x -> VNum (int_of_string x)) in
   let s = SExpr.parse contents in
-  let defs, main = AST.sexp_to_ast s in
-  AST.check_program defs main;
-  Eval.(print @@ eval input defs main)
+  let defs, globals, main = AST.sexp_to_ast s in
+  AST.check_program defs globals main;
+  Eval.(print @@ eval inputs defs globals main)
 
 let () = main ()
@@ -0,0 +1,109 @@
+# gdb support for programs compiled with `cobra --debug`.
+#
+#   make tests/foo.debug.run
+#   gdb -x runtime/snek-gdb.py tests/foo.debug.run
+#   (gdb) break foo.snek:12
+#   (gdb) run
+#   (gdb) print x
+#
+# Variables of the compiler's `snek_value` type hold tagged words; this
+# prints them the way the runtime's `print_value` does.
+
+import math
+import re
+import struct
+
+import gdb
+
+STRING_TAG = 5
+BIG_TAG = 7
+FLOAT_TAG = 3
+DATA_TAG = 1
+
+
+def read_bytes(addr, count):
+    return bytes(gdb.selected_inferior().read_memory(addr, count))
+
+
+def read_words(addr, count):
+    return struct.unpack("<%dq" % count, read_bytes(addr, 8 * count))
+
+
+# A string points at its length in bytes, followed by its UTF-8 bytes.
+def string_text(word):
+    addr = word - STRING_TAG
+    (length,) = read_words(addr, 1)
+    return read_bytes(addr + 8, length).decode("utf-8", "replace")
+
+
+# A bignum points at its signed limb count, followed by its 64-bit limbs,
+# least significant first.
+def big_value(word):
+    addr = word - BIG_TAG
+    (count,) = read_words(addr, 1)
+    limbs = struct.unpack("<%dQ" % abs(count), read_bytes(addr + 8, 8 * abs(count)))
+    n = sum(limb << (64 * i) for i, limb in enumerate(limbs))
+    return -n if count < 0 else n
+
+
+# A float points at its double. Rust's `{:?}` writes exponents without a
+# `+` or leading zeros, and NaN as `NaN`.
+def float_text(word):
+    (x,) = struct.unpack("<d", read_bytes(word - FLOAT_TAG, 8))
+    if math.isnan(x):
+        return "NaN"
+    return re.sub(r"e\+?(-?)0*(\d)", r"e\1\2", repr(x))
+
+
+def display(word):
+    if word == 3:
+        return "true"
+    if word == 1:
+        return "false"
+    if word % 2 == 0:
+        return str(word >> 1)
+    if word & 7 == BIG_TAG:
+        return str(big_value(word))
+    if word & 7 == FLOAT_TAG:
+        return float_text(word)
+    if word & 7 == STRING_TAG:
+        return string_text(word)
+    if word & 7 == DATA_TAG:
+        return data_text(word)
+    return "NaN, with value %d" % word
+
+
+# How error messages and data fields show a value: strings are quoted, the
+# way Rust's `{:?}` quotes them.
+def show(word):
+    if word & 7 == STRING_TAG:
+        escapes = {'"': '\\"', "\\": "\\\\", "\n": "\\n", "\t": "\\t", "\r": "\\r", "\0": "\\0"}
+        text = string_text(word)
+        return '"%s"' % "".join(escapes.get(c, c) for c in text)
+    return display(word)
+
+
+# A data value points at its constructor's name, as a string value, the
+# number of fields and the fields.
+def data_text(word):
+    addr = word - DATA_TAG
+    name, count = read_words(addr, 2)
+    fields = read_words(addr + 16, count) if count else ()
+    return "(%s)" % " ".join([string_text(name)] + [show(field) for field in fields])
+
+
+class SnekValuePrinter:
+    def __init__(self, val):
+        self.val = val
+
+    def to_string(self):
+        return display(int(self.val))
+
+
+def lookup(val):
+    if val.type.strip_typedefs().name == "snek_value":
+        return SnekValuePrinter(val)
+    return None
+
+
+gdb.pretty_printers.append(lookup)
@@ -1,4 +1,34 @@
 use std::env;
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[allow(dead_code)]
+#[path = "../src/bignum.rs"]
+mod bignum;
+
+use bignum::BigInt;
+
+// Number of inputs passed on the command line, for error messages.
+static INPUT_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+// Exit codes: 1 for errors raised by the program itself (snek_error), and
+// EXIT_INVALID_INPUT when a command-line input is not a valid snek value.
+const EXIT_INVALID_INPUT: i32 = 2;
+
+// One entry of a site table: the operator that can fail, its `file:line:col`
+// and enclosing function (all NUL-terminated), that function's frame size in
+// bytes (0 for main), and for call sites the return address and the bytes of
+// arguments pushed. Compiled code passes sites as the address of their entry.
+#[repr(C)]
+struct ErrorSite {
+    op: *const c_char,
+    loc: *const c_char,
+    func: *const c_char,
+    frame: u64,
+    ret: u64,
+    args: u64,
+}
 
 #[link(name = "our_code")]
 extern "C" {
@@ -6,55 +36,471 @@ extern "C" {
     // it does not add an underscore in front of the name.
     // Courtesy of Max New (https://maxsnew.com/teaching/eecs-483-fa22/hw_adder_assignment.html)
     #[link_name = "\x01our_code_starts_here"]
-    fn our_code_starts_here(input: u64) -> u64;
+    fn our_code_starts_here(inputs: *const u64) -> u64;
+    // the number of site tables, then the address of each: one per object,
+    // each a count of entries followed by the entries
+    #[link_name = "\x01snek_site_tables"]
+    static SNEK_SITE_TABLES: [u64; 0];
+    // 1 when compiled with --trap-overflow
+    #[link_name = "\x01snek_trap_overflow"]
+    static SNEK_TRAP_OVERFLOW: u64;
+    // 1 when compiled with --typecheck, which only takes numbers as input
+    #[link_name = "\x01snek_typed"]
+    static SNEK_TYPED: u64;
 }
 
 #[no_mangle]
 #[export_name = "\x01snek_error"]
-pub extern "C" fn snek_error(errcode: i64) {
-    // TODO: print error message according to writeup
-    if errcode == 1 { eprintln!("Runtime: invalid argument error"); }
-    else if errcode == 2 { eprintln!("Runtime: overflow error"); }
+pub extern "C" fn snek_error(errcode: i64, value: i64, site: i64, other: i64, rsp: *const u64, len: i64) {
+    let (op, loc) = match site_entry(site) {
+        Some(entry) => (c_str(entry.op), c_str(entry.loc)),
+        None => ("?".to_string(), "unknown location".to_string()),
+    };
+    if errcode == 1 && is_big(value) {
+        eprintln!(
+            "Runtime: invalid argument: `{}` expected a number that fits in 63 bits, got {} at {}",
+            op, show(value), loc
+        );
+    }
+    else if errcode == 1 && is_float(value) {
+        eprintln!("Runtime: invalid argument: `{}` expected an integer, got {} at {}", op, show(value), loc);
+    }
+    else if errcode == 1 {
+        eprintln!("Runtime: invalid argument: `{}` expected a number, got {} at {}", op, show(value), loc);
+    }
+    else if errcode == 4 {
+        eprintln!(
+            "Runtime: invalid argument: `{}` expected operands of the same type, got {} and {} at {}",
+            op, show(value), show(other), loc
+        );
+    }
+    else if errcode == 5 {
+        eprintln!("Runtime: invalid argument: `{}` expected a string, got {} at {}", op, show(value), loc);
+    }
+    else if errcode == 6 {
+        eprintln!(
+            "Runtime: invalid argument: `{}` range {}..{} is out of bounds for a string of length {} at {}",
+            op, value >> 1, other >> 1, len >> 1, loc
+        );
+    }
+    else if errcode == 7 { eprintln!("Runtime: division by zero: `{}` at {}", op, loc); }
+    else if errcode == 8 {
+        eprintln!("Runtime: invalid argument: `{}` expected a finite number, got {} at {}", op, show(value), loc);
+    }
+    else if errcode == 9 {
+        eprintln!("Runtime: invalid argument: `{}` expected a boolean, got {} at {}", op, show(value), loc);
+    }
+    else if errcode == 10 {
+        eprintln!("Runtime: annotation violation: {}, got {} at {}", op, show(value), loc);
+    }
+    else if errcode == 11 {
+        eprintln!("Runtime: match failure: no pattern matches {} at {}", show(value), loc);
+    }
+    else if errcode == 2 { eprintln!("Runtime: overflow: `{}` result does not fit in 63 bits at {}", op, loc); }
+    else if errcode == 3 {
+        eprintln!(
+            "Runtime: input index {} out of range, the program was given {} input(s) at {}",
+            value >> 1,
+            INPUT_COUNT.load(Ordering::Relaxed),
+            loc
+        );
+    }
     else { eprintln!("Runtime: unkown error with code {}", errcode); }
+    if let Some(entry) = site_entry(site) {
+        print_stack_trace(entry, rsp);
+    }
     std::process::exit(1);
 }
 
+fn site_entry(site: i64) -> Option<&'static ErrorSite> {
+    unsafe { (site as *const ErrorSite).as_ref() }
+}
+
+// Every site of every object.
+fn all_sites() -> impl Iterator<Item = &'static ErrorSite> {
+    unsafe {
+        let tables = SNEK_SITE_TABLES.as_ptr();
+        (1..=*tables as usize).flat_map(move |i| {
+            let table = *tables.add(i) as *const u64;
+            let entries = table.add(1) as *const ErrorSite;
+            (0..*table as usize).map(move |j| &*entries.add(j))
+        })
+    }
+}
+
+fn c_str(s: *const c_char) -> String {
+    unsafe { CStr::from_ptr(s).to_string_lossy().into_owned() }
+}
+
+// Walks from the frame that raised the error (whose stack pointer is `rsp`)
+// out to main. Each frame's return address is found right above it, and the
+// call site with that return address names the caller and its frame size.
+// Errors raised in main itself get no trace.
+fn print_stack_trace(site: &ErrorSite, rsp: *const u64) {
+    if site.frame == 0 {
+        return;
+    }
+    let mut entry = site;
+    let mut loc = c_str(site.loc);
+    let mut rsp = rsp;
+    while entry.frame != 0 {
+        let slots = (entry.frame / 8) as usize;
+        eprintln!("  at {} ({})", c_str(entry.func), loc);
+        // the frame's top slot counts the frames its tail calls replaced
+        let elided = unsafe { *rsp.add(slots - 1) };
+        if elided > 0 {
+            eprintln!("  ... {} frame(s) elided by tail calls", elided);
+        }
+        let ret = unsafe { *rsp.add(slots) };
+        match all_sites().find(|s| s.ret == ret) {
+            Some(call) => {
+                loc = c_str(call.loc);
+                rsp = unsafe { rsp.add(slots + 1 + (call.args / 8) as usize) };
+                entry = call;
+            }
+            None => return,
+        }
+    }
+    eprintln!("  at main ({})", loc);
+}
+
+// Strings are tagged with 5 and point at their length in bytes, followed by
+// the bytes themselves.
+const STRING_TAG: i64 = 5;
+
+fn is_string(val: i64) -> bool {
+    val & 7 == STRING_TAG
+}
+
+fn string_bytes(val: i64) -> &'static [u8] {
+    unsafe {
+        let ptr = (val - STRING_TAG) as *const u64;
+        std::slice::from_raw_parts(ptr.add(1) as *const u8, *ptr as usize)
+    }
+}
+
+// Strings only ever hold UTF-8: literals, and appends and substrings of
+// them.
+fn string_text(val: i64) -> &'static str {
+    std::str::from_utf8(string_bytes(val)).expect("strings are UTF-8")
+}
+
+// Strings are never freed.
+fn alloc_string(bytes: &[u8]) -> i64 {
+    let mut words = vec![0u64; 1 + (bytes.len() + 7) / 8];
+    words[0] = bytes.len() as u64;
+    unsafe {
+        std::ptr::copy_nonoverlapping(bytes.as_ptr(), words.as_mut_ptr().add(1) as *mut u8, bytes.len());
+    }
+    Box::leak(words.into_boxed_slice()).as_ptr() as i64 + STRING_TAG
+}
+
+// Bignums are tagged with 7 and point at their limb count, negated for
+// negative numbers, followed by the limbs, least significant first. Only
+// numbers outside the fixnum range are bignums.
+const BIG_TAG: i64 = 7;
+
+fn is_big(val: i64) -> bool {
+    val & 7 == BIG_TAG
+}
+
+// A fixnum or bignum as a BigInt.
+fn big_value(val: i64) -> BigInt {
+    if !is_big(val) {
+        return BigInt::from_i64(val >> 1);
+    }
+    unsafe {
+        let ptr = (val - BIG_TAG) as *const i64;
+        let limbs = std::slice::from_raw_parts(ptr.add(1) as *const u64, (*ptr).unsigned_abs() as usize);
+        BigInt::from_parts(*ptr < 0, limbs)
+    }
+}
+
+fn fits_fixnum(n: &BigInt) -> Option<i64> {
+    n.to_i64().filter(|n| *n >= -(1 << 62) && *n < 1 << 62)
+}
+
+// Bignums, like strings, are never freed.
+fn alloc_big(n: &BigInt) -> i64 {
+    let (neg, limbs) = n.parts();
+    let len = limbs.len() as i64;
+    let mut words = vec![if neg { -len } else { len } as u64];
+    words.extend_from_slice(limbs);
+    Box::leak(words.into_boxed_slice()).as_ptr() as i64 + BIG_TAG
+}
+
+// Floats are tagged with 3 and point at the double. Their address is never
+// 0, so no float is the word for true.
+const FLOAT_TAG: i64 = 3;
+
+fn is_float(val: i64) -> bool {
+    val & 7 == FLOAT_TAG && val != 3
+}
+
+fn float_value(val: i64) -> f64 {
+    unsafe { *((val - FLOAT_TAG) as *const f64) }
+}
+
+// Floats, like strings, are never freed.
+fn alloc_float(x: f64) -> i64 {
+    Box::leak(Box::new(x)) as *const f64 as i64 + FLOAT_TAG
+}
+
+// Data values are tagged with 1 and point at their constructor's name, as a
+// string value, the number of fields and the fields. Their address is never
+// 0, so no data value is the word for false.
+const DATA_TAG: i64 = 1;
+
+fn is_data(val: i64) -> bool {
+    val & 7 == DATA_TAG && val != 1
+}
+
+fn data_parts(val: i64) -> (i64, &'static [i64]) {
+    unsafe {
+        let ptr = (val - DATA_TAG) as *const i64;
+        (*ptr, std::slice::from_raw_parts(ptr.add(2), *ptr.add(1) as usize))
+    }
+}
+
+fn show_data(val: i64) -> String {
+    let (name, fields) = data_parts(val);
+    let mut out = format!("({}", String::from_utf8_lossy(string_bytes(name)));
+    for field in fields {
+        out.push(' ');
+        out.push_str(&show(*field));
+    }
+    out.push(')');
+    out
+}
+
+// Data values, like strings, are never freed.
+#[no_mangle]
+#[export_name = "\x01snek_construct"]
+pub extern "C" fn snek_construct(name: i64, count: i64, fields: *const i64) -> i64 {
+    let mut words = vec![name, count];
+    words.extend_from_slice(unsafe { std::slice::from_raw_parts(fields, count as usize) });
+    Box::leak(words.into_boxed_slice()).as_ptr() as i64 + DATA_TAG
+}
+
+// `=` on two data values: the same constructor and equal fields, where
+// fields of different types are just unequal.
+#[no_mangle]
+#[export_name = "\x01snek_equal"]
+pub extern "C" fn snek_equal(a: i64, b: i64) -> i64 {
+    if equal(a, b) { 3 } else { 1 }
+}
+
+fn equal(a: i64, b: i64) -> bool {
+    let is_num = |v: i64| v & 1 == 0 || is_big(v) || is_float(v);
+    if is_data(a) && is_data(b) {
+        let ((x, xs), (y, ys)) = (data_parts(a), data_parts(b));
+        string_bytes(x) == string_bytes(y) && xs.iter().zip(ys).all(|(x, y)| equal(*x, *y))
+    } else if is_string(a) && is_string(b) {
+        string_bytes(a) == string_bytes(b)
+    } else if is_float(a) || is_float(b) {
+        is_num(a) && is_num(b) && to_float(a) == to_float(b)
+    } else if is_num(a) && is_num(b) {
+        big_value(a) == big_value(b)
+    } else {
+        a == b
+    }
+}
+
+// Any number as a double.
+fn to_float(val: i64) -> f64 {
+    if is_float(val) { float_value(val) } else { big_value(val).to_f64() }
+}
+
+// An integer result as a fixnum, or a bignum when it doesn't fit.
+fn integer(n: &BigInt, site: i64, rsp: *const u64) -> i64 {
+    match fits_fixnum(n) {
+        Some(n) => n << 1,
+        None if unsafe { SNEK_TRAP_OVERFLOW } != 0 => {
+            snek_error(2, 0, site, 0, rsp, 0);
+            unreachable!()
+        }
+        None => alloc_big(n),
+    }
+}
+
+// Formats a tagged value the way `print` does, with strings unquoted.
+// Floats are printed so that reading them back gives the same double.
+fn display(val: i64) -> String {
+    if val == 3 { "true".to_string() }
+    else if val == 1 { "false".to_string() }
+    else if val % 2 == 0 { (val >> 1).to_string() }
+    else if is_big(val) { big_value(val).to_string() }
+    else if is_float(val) { format!("{:?}", float_value(val)) }
+    else if is_string(val) { String::from_utf8_lossy(string_bytes(val)).into_owned() }
+    else if is_data(val) { show_data(val) }
+    else { format!("NaN, with value {}", val) }
+}
+
+// Formats a tagged value the way error messages show it.
+fn show(val: i64) -> String {
+    if val == 3 { "true".to_string() }
+    else if val == 1 { "false".to_string() }
+    else if is_string(val) { format!("{:?}", String::from_utf8_lossy(string_bytes(val))) }
+    else if is_big(val) { big_value(val).to_string() }
+    else if is_float(val) { format!("{:?}", float_value(val)) }
+    else if is_data(val) { show_data(val) }
+    else { (val >> 1).to_string() }
+}
+
 #[no_mangle]
 #[export_name = "\x01snek_print"]
 pub extern "C" fn snek_print(val: i64) {
-    if val == 3 { println!("true"); }
-    else if val == 1 { println!("false"); }
-    else if val % 2 == 0 { println!("{}", val >> 1); }
-    else { println!("NaN, with value {}", val); }
+    println!("{}", display(val));
 }
 
 fn print_value(val: i64) {
-    if val == 3 { println!("true"); }
-    else if val == 1 { println!("false"); }
-    else if val % 2 == 0 { println!("{}", val >> 1); }
-    else { println!("NaN, with value {}", val); }
+    println!("{}", display(val));
 }
 
-fn parse_input(input: &str) -> u64 {
-    // TODO: parse the input string into internal value representation
-    if input == "true" { 3 }
+#[no_mangle]
+#[export_name = "\x01snek_string_append"]
+pub extern "C" fn snek_string_append(a: i64, b: i64) -> i64 {
+    alloc_string(&[string_bytes(a), string_bytes(b)].concat())
+}
+
+// Strings are indexed by character, not byte.
+#[no_mangle]
+#[export_name = "\x01snek_string_length"]
+pub extern "C" fn snek_string_length(s: i64) -> i64 {
+    (string_text(s).chars().count() as i64) << 1
+}
+
+// The range, in characters, has been checked by the caller.
+#[no_mangle]
+#[export_name = "\x01snek_substring"]
+pub extern "C" fn snek_substring(s: i64, start: i64, end: i64) -> i64 {
+    let text = string_text(s);
+    let byte = |i: i64| text.char_indices().map(|(b, _)| b).chain([text.len()]).nth((i >> 1) as usize).unwrap();
+    alloc_string(text[byte(start)..byte(end)].as_bytes())
+}
+
+#[no_mangle]
+#[export_name = "\x01snek_string_equal"]
+pub extern "C" fn snek_string_equal(a: i64, b: i64) -> i64 {
+    if string_bytes(a) == string_bytes(b) { 3 } else { 1 }
+}
+
+// The slow path of arithmetic, for bignum and float operands and fixnum
+// results that overflowed: `op` is the operator's code from `arith_code` in
+// the compiler, and both operands are numbers. The caller has ruled out an
+// exact zero divisor.
+#[no_mangle]
+#[export_name = "\x01snek_arith"]
+pub extern "C" fn snek_arith(op: i64, a: i64, b: i64, site: i64, rsp: *const u64) -> i64 {
+    let bool_val = |b: bool| if b { 3 } else { 1 };
+    if is_float(a) || is_float(b) {
+        // quotient, remainder and modulo only take integers
+        if op >= 9 {
+            let val = if is_float(a) { a } else { b };
+            snek_error(1, val, site, 0, rsp, 0);
+        }
+        let (x, y) = (to_float(a), to_float(b));
+        return match op {
+            0 => alloc_float(x + y),
+            1 => alloc_float(x - y),
+            2 => alloc_float(x * y),
+            3 => bool_val(x < y),
+            4 => bool_val(x <= y),
+            5 => bool_val(x > y),
+            6 => bool_val(x >= y),
+            7 => bool_val(x == y),
+            _ => alloc_float(x / y),
+        };
+    }
+    let (x, y) = (big_value(a), big_value(b));
+    let result = match op {
+        0 => x.add(&y),
+        1 => x.sub(&y),
+        2 => x.mul(&y),
+        3 => return bool_val(x < y),
+        4 => return bool_val(x <= y),
+        5 => return bool_val(x > y),
+        6 => return bool_val(x >= y),
+        7 => return bool_val(x == y),
+        _ => {
+            let (q, r) = x.div_rem(&y);
+            // `/` and `modulo` round down
+            let round_down = !r.is_zero() && r.is_negative() != y.is_negative();
+            match op {
+                8 if round_down => q.sub(&BigInt::from_i64(1)),
+                8 | 9 => q,
+                11 if round_down => r.add(&y),
+                _ => r,
+            }
+        }
+    };
+    integer(&result, site, rsp)
+}
+
+#[no_mangle]
+#[export_name = "\x01snek_float"]
+pub extern "C" fn snek_float(val: i64) -> i64 {
+    if is_float(val) { val } else { alloc_float(to_float(val)) }
+}
+
+// Rounds toward zero; integers are returned as they are.
+#[no_mangle]
+#[export_name = "\x01snek_truncate"]
+pub extern "C" fn snek_truncate(val: i64, site: i64, rsp: *const u64) -> i64 {
+    if !is_float(val) {
+        return val;
+    }
+    let x = float_value(val);
+    if !x.is_finite() {
+        snek_error(8, val, site, 0, rsp, 0);
+    }
+    integer(&BigInt::from_f64(x), site, rsp)
+}
+
+fn parse_input(input: &str, typed: bool) -> u64 {
+    if typed && (input == "true" || input == "false") {
+        eprintln!(
+            "Runtime: invalid input `{}`: expected an integer in -2^62..2^62-1, as the program was type checked",
+            input
+        );
+        std::process::exit(EXIT_INVALID_INPUT);
+    }
+    else if input == "true" { 3 }
     else if input == "false" { 1 }
     else if input.parse::<i64>().is_ok() {
         let n = input.parse::<i64>().unwrap();
         if n < 2i64.pow(62) && n >= -2i64.pow(62) {
             (n as u64) << 1
         } else {
-            panic!("Invalid")
+            invalid_input(input)
         }
     }
-    else { panic!("Invalid") }
+    else { invalid_input(input) }
+}
+
+fn invalid_input(input: &str) -> ! {
+    eprintln!(
+        "Runtime: invalid input `{}`: expected true, false or an integer in -2^62..2^62-1",
+        input
+    );
+    std::process::exit(EXIT_INVALID_INPUT);
 }
 
 fn main() {
     let args: Vec<String> = env::args().collect();
-    let input = if args.len() == 2 { &args[1] } else { "false" };
-    let input = parse_input(&input);
+    let count = args.len() - 1;
+    INPUT_COUNT.store(count, Ordering::Relaxed);
+
+    // laid out as the tagged input count followed by the tagged inputs;
+    // without any input, plain `input` still reads false, or 0 in a type
+    // checked program
+    let typed = unsafe { SNEK_TYPED } != 0;
+    let mut inputs: Vec<u64> = vec![(count as u64) << 1];
+    inputs.extend(args[1..].iter().map(|arg| parse_input(arg, typed)));
+    if count == 0 {
+        inputs.push(if typed { 0 } else { 1 });
+    }
 
-    let i: i64 = unsafe { our_code_starts_here(input) } as i64;
+    let i: i64 = unsafe { our_code_starts_here(inputs.as_ptr()) } as i64;
     print_value(i);
 }
\ No newline at end of file
@@ -0,0 +1,233 @@
+use std::cmp::Ordering;
+use std::fmt;
+
+// Arbitrary-precision integers, for numbers that no longer fit in a fixnum.
+// Shared with `runtime/start.rs`, which includes this file by path, so it
+// only depends on std.
+
+/// A sign and a magnitude of little-endian 64-bit limbs, without leading
+/// zero limbs. Zero has no limbs and is never negative.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BigInt {
+    neg: bool,
+    mag: Vec<u64>,
+}
+
+impl BigInt {
+    pub fn from_i64(n: i64) -> BigInt {
+        BigInt::new(n < 0, vec![n.unsigned_abs()])
+    }
+
+    fn new(neg: bool, mut mag: Vec<u64>) -> BigInt {
+        while mag.last() == Some(&0) {
+            mag.pop();
+        }
+        BigInt { neg: neg && !mag.is_empty(), mag }
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.mag.is_empty()
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.neg
+    }
+
+    pub fn to_i64(&self) -> Option<i64> {
+        match self.mag.as_slice() {
+            [] => Some(0),
+            [m] if self.neg && *m <= i64::MIN.unsigned_abs() => Some((*m as i64).wrapping_neg()),
+            [m] if !self.neg && *m <= i64::MAX as u64 => Some(*m as i64),
+            _ => None,
+        }
+    }
+
+    /// The nearest double, or an infinity past the largest one.
+    pub fn to_f64(&self) -> f64 {
+        let m = self.mag.iter().rev().fold(0.0, |acc, &limb| acc * 18446744073709551616.0 + limb as f64);
+        if self.neg {
+            -m
+        } else {
+            m
+        }
+    }
+
+    /// `x` rounded towards zero. `x` must be finite.
+    pub fn from_f64(x: f64) -> BigInt {
+        let t = x.trunc();
+        if t.abs() < 9.2e18 {
+            return BigInt::from_i64(t as i64);
+        }
+        // 53 significant bits, shifted left past the first limb
+        let bits = t.abs().to_bits();
+        let exp = ((bits >> 52) & 0x7ff) as usize - 1075;
+        let mant = (bits & ((1 << 52) - 1)) | (1 << 52);
+        let mut mag = vec![0u64; exp / 64];
+        let shift = exp % 64;
+        mag.push(mant << shift);
+        if shift > 0 {
+            mag.push(mant >> (64 - shift));
+        }
+        BigInt::new(t < 0.0, mag)
+    }
+
+    /// The sign and limbs, for laying the number out in memory.
+    #[allow(dead_code)]
+    pub fn parts(&self) -> (bool, &[u64]) {
+        (self.neg, &self.mag)
+    }
+
+    #[allow(dead_code)]
+    pub fn from_parts(neg: bool, mag: &[u64]) -> BigInt {
+        BigInt::new(neg, mag.to_vec())
+    }
+
+    pub fn neg(&self) -> BigInt {
+        BigInt::new(!self.neg, self.mag.clone())
+    }
+
+    pub fn add(&self, other: &BigInt) -> BigInt {
+        if self.neg == other.neg {
+            return BigInt::new(self.neg, add_mag(&self.mag, &other.mag));
+        }
+        match cmp_mag(&self.mag, &other.mag) {
+            Ordering::Less => BigInt::new(other.neg, sub_mag(&other.mag, &self.mag)),
+            _ => BigInt::new(self.neg, sub_mag(&self.mag, &other.mag)),
+        }
+    }
+
+    pub fn sub(&self, other: &BigInt) -> BigInt {
+        self.add(&other.neg())
+    }
+
+    pub fn mul(&self, other: &BigInt) -> BigInt {
+        let mut out = vec![0u64; self.mag.len() + other.mag.len()];
+        for (i, &a) in self.mag.iter().enumerate() {
+            let mut carry = 0u128;
+            for (j, &b) in other.mag.iter().enumerate() {
+                let t = a as u128 * b as u128 + out[i + j] as u128 + carry;
+                out[i + j] = t as u64;
+                carry = t >> 64;
+            }
+            out[i + other.mag.len()] = carry as u64;
+        }
+        BigInt::new(self.neg != other.neg, out)
+    }
+
+    /// Division rounding towards zero, with the remainder taking the
+    /// dividend's sign. `other` must not be zero.
+    pub fn div_rem(&self, other: &BigInt) -> (BigInt, BigInt) {
+        let bits = self.mag.len() * 64;
+        let mut q = vec![0u64; self.mag.len()];
+        let mut r: Vec<u64> = vec![];
+        for i in (0..bits).rev() {
+            r = shl1_mag(&r, (self.mag[i / 64] >> (i % 64)) & 1);
+            if cmp_mag(&r, &other.mag) != Ordering::Less {
+                r = sub_mag(&r, &other.mag);
+                q[i / 64] |= 1 << (i % 64);
+            }
+        }
+        (BigInt::new(self.neg != other.neg, q), BigInt::new(self.neg, r))
+    }
+
+    // Divides the magnitude by a small divisor in place, returning the
+    // remainder.
+    fn div_small(&mut self, d: u64) -> u64 {
+        let mut rem = 0u128;
+        for limb in self.mag.iter_mut().rev() {
+            let cur = (rem << 64) | *limb as u128;
+            *limb = (cur / d as u128) as u64;
+            rem = cur % d as u128;
+        }
+        while self.mag.last() == Some(&0) {
+            self.mag.pop();
+        }
+        rem as u64
+    }
+}
+
+impl Ord for BigInt {
+    fn cmp(&self, other: &BigInt) -> Ordering {
+        match (self.neg, other.neg) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => cmp_mag(&self.mag, &other.mag),
+            (true, true) => cmp_mag(&other.mag, &self.mag),
+        }
+    }
+}
+
+impl PartialOrd for BigInt {
+    fn partial_cmp(&self, other: &BigInt) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for BigInt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_zero() {
+            return write!(f, "0");
+        }
+        // peel off 19 decimal digits at a time
+        const CHUNK: u64 = 10_000_000_000_000_000_000;
+        let mut n = BigInt::new(false, self.mag.clone());
+        let mut chunks = vec![];
+        while !n.is_zero() {
+            chunks.push(n.div_small(CHUNK));
+        }
+        if self.neg {
+            write!(f, "-")?;
+        }
+        write!(f, "{}", chunks.pop().unwrap())?;
+        for chunk in chunks.iter().rev() {
+            write!(f, "{:019}", chunk)?;
+        }
+        Ok(())
+    }
+}
+
+fn cmp_mag(a: &[u64], b: &[u64]) -> Ordering {
+    a.len().cmp(&b.len()).then_with(|| a.iter().rev().cmp(b.iter().rev()))
+}
+
+fn add_mag(a: &[u64], b: &[u64]) -> Vec<u64> {
+    let mut out = Vec::with_capacity(a.len().max(b.len()) + 1);
+    let mut carry = 0u128;
+    for i in 0..a.len().max(b.len()) {
+        let t = *a.get(i).unwrap_or(&0) as u128 + *b.get(i).unwrap_or(&0) as u128 + carry;
+        out.push(t as u64);
+        carry = t >> 64;
+    }
+    out.push(carry as u64);
+    out
+}
+
+// `a` must be at least `b`.
+fn sub_mag(a: &[u64], b: &[u64]) -> Vec<u64> {
+    let mut out = Vec::with_capacity(a.len());
+    let mut borrow = false;
+    for (i, &x) in a.iter().enumerate() {
+        let (d, b1) = x.overflowing_sub(*b.get(i).unwrap_or(&0));
+        let (d, b2) = d.overflowing_sub(borrow as u64);
+        out.push(d);
+        borrow = b1 || b2;
+    }
+    while out.last() == Some(&0) {
+        out.pop();
+    }
+    out
+}
+
+// Shifts a magnitude left by one bit, bringing in `bit`.
+fn shl1_mag(a: &[u64], bit: u64) -> Vec<u64> {
+    let mut out = Vec::with_capacity(a.len() + 1);
+    let mut carry = bit;
+    for &x in a {
+        out.push((x << 1) | carry);
+        carry = x >> 63;
+    }
+    if carry != 0 {
+        out.push(carry);
+    }
+    out
+}
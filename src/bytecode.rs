@@ -0,0 +1,817 @@
+use std::collections::HashSet;
+
+use im::HashMap;
+
+use crate::interp::Site;
+use crate::reader::Pos;
+use crate::{annotation_to_str, op1_to_str, op2_to_str, find_loop, register_definition, Definition, Expr, Op1, Op2, Program, Ty, FALSE_CONST, TRUE_CONST};
+
+const MAGIC: &[u8; 4] = b"SNEK";
+const VERSION: u8 = 13;
+
+/// A stack machine instruction. Values on the operand stack and in locals
+/// are tagged words, laid out exactly like the native backend's. Operations
+/// that can fail carry the index of their entry in `Chunk::sites`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Const(i64),
+    Input,
+    InputAt(u32),
+    InputCount,
+    Load(u32),
+    Store(u32),
+    // the global with this index, shared by every function
+    LoadGlobal(u32),
+    StoreGlobal(u32),
+    // the local with this index in the frame `hops` static links out, the
+    // first operand, from a local function
+    LoadOuter(u32, u32),
+    StoreOuter(u32, u32),
+    // pushes the static link for a function defined `hops` functions out:
+    // the base of that function's frame
+    Link(u32),
+    Pop,
+    // drop `n` values beneath the top of the stack
+    Slide(u32),
+    Add1(u32),
+    Sub1(u32),
+    IsNum,
+    IsBool,
+    Add(u32),
+    Sub(u32),
+    Mul(u32),
+    Eq(u32),
+    Lt(u32),
+    Le(u32),
+    Gt(u32),
+    Ge(u32),
+    Jmp(u32),
+    JmpFalse(u32),
+    // jump keeping the value when it is false (or when it isn't, for the
+    // second), otherwise drop it; for `and` and `or`
+    JmpFalseOrPop(u32),
+    JmpTrueOrPop(u32),
+    // the callee and the call's site, for stack traces
+    Call(u32, u32),
+    TailCall(u32),
+    Ret,
+    Print,
+    Halt,
+    // pushes the string literal with this index in `Chunk::strings`
+    Str(u32),
+    StrLen(u32),
+    StrAppend(u32),
+    StrEq(u32),
+    Substring(u32),
+    Div(u32),
+    Quotient(u32),
+    Remainder(u32),
+    Modulo(u32),
+    BitAnd(u32),
+    BitOr(u32),
+    BitXor(u32),
+    Shl(u32),
+    Shr(u32),
+    // pushes the float literal with this index in `Chunk::floats`
+    Float(u32),
+    ToFloat(u32),
+    Truncate(u32),
+    Not(u32),
+    // leaves the value on top of the stack if it has the annotated type
+    Check(Ty, u32),
+    // builds a data value from the top `n` values, with the constructor
+    // named by the string with this index in `Chunk::strings`
+    Construct(u32, u32),
+    // whether the value on top of the stack is built by that constructor
+    IsVariant(u32),
+    Field(u32),
+    MatchFail(u32),
+    IsStr,
+}
+
+#[derive(Debug)]
+pub struct Func {
+    pub name: String,
+    pub arity: u32,
+    pub locals: u32,
+    pub entry: u32,
+}
+
+#[derive(Debug)]
+pub struct Chunk {
+    pub funcs: Vec<Func>,
+    pub sites: Vec<Site>,
+    pub strings: Vec<String>,
+    pub floats: Vec<f64>,
+    pub globals: u32,
+    // arithmetic that leaves the fixnum range is an error instead of
+    // producing a bignum
+    pub trap_overflow: bool,
+    // the program was type checked, so it takes only numbers as inputs
+    pub typed: bool,
+    pub main_locals: u32,
+    pub main_entry: u32,
+    pub code: Vec<Op>,
+}
+
+struct Compiler<'a> {
+    code: Vec<Op>,
+    sites: Vec<Site>,
+    strings: Vec<String>,
+    floats: Vec<f64>,
+    file: &'a str,
+    func_dic: &'a im::HashMap<String, i32>,
+    globals: &'a [String],
+    func_ids: &'a HashMap<String, u32>,
+    // the program's functions, then the local functions as they are found
+    funcs: Vec<Func>,
+    // operand stack height at the current instruction, known statically
+    height: u32,
+    max_slot: u32,
+    loop_stack: Vec<LoopEntry>,
+    is_main: bool,
+    // the program was type checked, which proves its annotations
+    typed: bool,
+}
+
+// What code sees around it: the local each variable is, `hops` static
+// links out, and the local functions in scope by (hops, id). A local
+// function takes its static link as local 0, before its arguments.
+#[derive(Clone, Default)]
+struct Env {
+    vars: HashMap<String, (u32, u32)>,
+    funs: HashMap<String, (u32, u32)>,
+}
+
+impl Env {
+    // What a function defined here sees of it, before its parameters.
+    fn nested(&self) -> Env {
+        let out = |m: &HashMap<String, (u32, u32)>| m.iter().map(|(name, &(hops, i))| (name.clone(), (hops + 1, i))).collect();
+        Env { vars: out(&self.vars), funs: out(&self.funs) }
+    }
+}
+
+// An enclosing loop: its label, where `continue` jumps back to, the `break`
+// jumps still to be patched, and the operand stack height on entry.
+struct LoopEntry {
+    label: Option<String>,
+    start: u32,
+    breaks: Vec<usize>,
+    height: u32,
+}
+
+pub fn compile_program(p: &Program, file: &str, trap_overflow: bool, typed: bool) -> Chunk {
+    let mut func_dic: im::HashMap<String, i32> = im::HashMap::new();
+    for def in &p.defs {
+        register_definition(def, &mut func_dic);
+    }
+    let mut func_ids = HashMap::new();
+    let mut funcs = vec![];
+    for (i, def) in p.defs.iter().enumerate() {
+        let Definition::Func(_, args, _) = def;
+        func_ids.insert(args[0].clone(), i as u32);
+        funcs.push(Func { name: args[0].clone(), arity: args.len() as u32 - 1, locals: 0, entry: 0 });
+    }
+
+    let globals: Vec<String> = p.globals.iter().map(|(name, _)| name.clone()).collect();
+    let mut c = Compiler::new(file, &func_dic, &globals, &func_ids, funcs, typed);
+    for (i, def) in p.defs.iter().enumerate() {
+        let Definition::Func(_, args, body) = def;
+        c.function(i as u32, &args[1..], body, Env::default());
+    }
+
+    c.is_main = true;
+    let main_entry = c.code.len() as u32;
+    // main starts by initializing the globals
+    for (i, (_, init)) in p.globals.iter().enumerate() {
+        c.compile(init, 0, &Env::default(), false);
+        c.emit(Op::StoreGlobal(i as u32));
+    }
+    c.compile(&p.main, 0, &Env::default(), false);
+    c.emit(Op::Halt);
+    Chunk { funcs: c.funcs, sites: c.sites, strings: c.strings, floats: c.floats, globals: globals.len() as u32, trap_overflow, typed, main_locals: c.max_slot, main_entry, code: c.code }
+}
+
+impl<'a> Compiler<'a> {
+    fn new(file: &'a str, func_dic: &'a im::HashMap<String, i32>, globals: &'a [String], func_ids: &'a HashMap<String, u32>, funcs: Vec<Func>, typed: bool) -> Self {
+        Compiler { code: vec![], sites: vec![], strings: vec![], floats: vec![], file, func_dic, globals, func_ids, funcs, height: 0, max_slot: 0, loop_stack: vec![], is_main: false, typed }
+    }
+
+    // Compiles the body of function `f`, with `env` holding what it sees
+    // around it, then goes back to the code it is nested in.
+    fn function(&mut self, f: u32, params: &[String], body: &Expr, mut env: Env) {
+        let outer = (self.height, self.max_slot, std::mem::take(&mut self.loop_stack), self.is_main);
+        let first = self.funcs[f as usize].arity - params.len() as u32;
+        let mut arg_names = vec![];
+        for (i, arg) in params.iter().enumerate() {
+            if arg_names.contains(arg) {
+                panic!("parse error: Duplicate argument name {}", arg);
+            }
+            arg_names.push(arg.clone());
+            env.vars.insert(arg.clone(), (0, first + i as u32));
+        }
+        let arity = self.funcs[f as usize].arity;
+        self.funcs[f as usize].entry = self.code.len() as u32;
+        self.height = 0;
+        self.max_slot = arity;
+        self.is_main = false;
+        self.compile(body, arity, &env, true);
+        self.emit(Op::Ret);
+        self.funcs[f as usize].locals = self.max_slot;
+        (self.height, self.max_slot, self.loop_stack, self.is_main) = outer;
+    }
+
+    fn string(&mut self, text: &str) -> u32 {
+        let i = match self.strings.iter().position(|s| s == text) {
+            Some(i) => i,
+            None => {
+                self.strings.push(text.to_string());
+                self.strings.len() - 1
+            }
+        };
+        i as u32
+    }
+
+    fn new_site(&mut self, op: &str, pos: &Pos) -> u32 {
+        self.sites.push(Site { op: op.to_string(), loc: format!("{}:{}", self.file, pos) });
+        (self.sites.len() - 1) as u32
+    }
+
+    fn emit(&mut self, op: Op) -> usize {
+        self.height = match op {
+            Op::Const(_) | Op::Str(_) | Op::Float(_) | Op::Input | Op::InputCount | Op::Load(_) | Op::LoadGlobal(_) => self.height + 1,
+            Op::LoadOuter(..) | Op::Link(_) => self.height + 1,
+            Op::Store(_) | Op::StoreGlobal(_) | Op::StoreOuter(..) | Op::Pop | Op::JmpFalse(_) | Op::JmpFalseOrPop(_) | Op::JmpTrueOrPop(_) => self.height - 1,
+            Op::Slide(n) => self.height - n,
+            Op::Add(_) | Op::Sub(_) | Op::Mul(_) | Op::Eq(_) | Op::Lt(_) | Op::Le(_) | Op::Gt(_) | Op::Ge(_) => self.height - 1,
+            Op::StrAppend(_) | Op::StrEq(_) => self.height - 1,
+            Op::Div(_) | Op::Quotient(_) | Op::Remainder(_) | Op::Modulo(_) | Op::BitAnd(_) | Op::BitOr(_) | Op::BitXor(_) | Op::Shl(_) | Op::Shr(_) => self.height - 1,
+            Op::Substring(_) => self.height - 2,
+            Op::Construct(_, n) => self.height - n + 1,
+            Op::Call(f, _) | Op::TailCall(f) => self.height - self.funcs[f as usize].arity + 1,
+            _ => self.height,
+        };
+        self.code.push(op);
+        self.code.len() - 1
+    }
+
+    // The instructions that load and store a variable: its local, in this
+    // frame or an enclosing function's, or else the global.
+    fn variable(&self, name: &str, env: &Env) -> (Op, Op) {
+        match (env.vars.get(name), self.globals.iter().position(|g| g == name)) {
+            (Some(&(0, slot)), _) => (Op::Load(slot), Op::Store(slot)),
+            (Some(&(hops, slot)), _) => (Op::LoadOuter(hops, slot), Op::StoreOuter(hops, slot)),
+            (None, Some(i)) => (Op::LoadGlobal(i as u32), Op::StoreGlobal(i as u32)),
+            (None, None) => panic!("Unbound variable identifier {name}"),
+        }
+    }
+
+    fn patch(&mut self, at: usize) {
+        let target = self.code.len() as u32;
+        match &mut self.code[at] {
+            Op::Jmp(t) | Op::JmpFalse(t) | Op::JmpFalseOrPop(t) | Op::JmpTrueOrPop(t) => *t = target,
+            op => panic!("cannot patch {:?}", op),
+        }
+    }
+
+    fn compile(&mut self, e: &Expr, si: u32, env: &Env, is_tail: bool) {
+        match e {
+            Expr::Number(n) => {
+                let max_bound = 4611686018427387903_i64;
+                let min_bound = -4611686018427387904_i64;
+                if *n > max_bound || *n < min_bound {
+                    panic!("Invalid immutable, integer overflow");
+                }
+                self.emit(Op::Const(n << 1));
+            }
+            Expr::Boolean(b) => {
+                self.emit(Op::Const(if *b { TRUE_CONST } else { FALSE_CONST }));
+            }
+            Expr::Str(text) => {
+                let i = self.string(text);
+                self.emit(Op::Str(i));
+            }
+            Expr::Float(x) => {
+                // by bits, so that 0.0 and -0.0 stay apart
+                let i = match self.floats.iter().position(|f| f.to_bits() == x.to_bits()) {
+                    Some(i) => i,
+                    None => {
+                        self.floats.push(*x);
+                        self.floats.len() - 1
+                    }
+                };
+                self.emit(Op::Float(i as u32));
+            }
+            Expr::Input() => {
+                if !self.is_main {
+                    panic!("parse error: Not expected to use input in non-main function")
+                }
+                self.emit(Op::Input);
+            }
+            Expr::InputCount() => {
+                if !self.is_main {
+                    panic!("parse error: Not expected to use input in non-main function")
+                }
+                self.emit(Op::InputCount);
+            }
+            Expr::InputAt(expr, pos) => {
+                if !self.is_main {
+                    panic!("parse error: Not expected to use input in non-main function")
+                }
+                self.compile(expr, si, env, false);
+                let site = self.new_site("input", pos);
+                self.emit(Op::InputAt(site));
+            }
+            Expr::Id(s) => {
+                let (load, _) = self.variable(s, env);
+                self.emit(load);
+            }
+            Expr::Let(bindings, body, _) => {
+                if bindings.is_empty() {
+                    panic!("parse error: Invalid let without bindings");
+                }
+                let mut env_new = env.clone();
+                let mut curr_names = HashSet::<String>::new();
+                for (i, (name, expr)) in bindings.iter().enumerate() {
+                    if curr_names.contains(name) {
+                        panic!("parse error: Duplicate binding {name} Invalid");
+                    }
+                    let slot = si + i as u32;
+                    self.compile(expr, slot, &env_new, false);
+                    self.emit(Op::Store(slot));
+                    self.max_slot = self.max_slot.max(slot + 1);
+                    curr_names.insert(name.clone());
+                    env_new.vars.insert(name.clone(), (0, slot));
+                }
+                self.compile(body, si + bindings.len() as u32, &env_new, is_tail);
+            }
+            Expr::UnOp(op, expr, pos) => {
+                self.compile(expr, si, env, false);
+                let site = self.new_site(op1_to_str(op), pos);
+                self.emit(match op {
+                    Op1::Add1 => Op::Add1(site),
+                    Op1::Sub1 => Op::Sub1(site),
+                    Op1::IsNum => Op::IsNum,
+                    Op1::IsBool => Op::IsBool,
+                    Op1::IsStr => Op::IsStr,
+                    Op1::StringLength => Op::StrLen(site),
+                    Op1::Float => Op::ToFloat(site),
+                    Op1::Truncate => Op::Truncate(site),
+                    Op1::Not => Op::Not(site),
+                });
+            }
+            Expr::BinOp(op, lhs, rhs, pos) => {
+                // same order as the native backend: right operand first
+                self.compile(rhs, si, env, false);
+                self.compile(lhs, si, env, false);
+                let site = self.new_site(op2_to_str(op), pos);
+                self.emit(match op {
+                    Op2::Plus => Op::Add(site),
+                    Op2::Minus => Op::Sub(site),
+                    Op2::Times => Op::Mul(site),
+                    Op2::Equal => Op::Eq(site),
+                    Op2::Less => Op::Lt(site),
+                    Op2::LessEqual => Op::Le(site),
+                    Op2::Greater => Op::Gt(site),
+                    Op2::GreaterEqual => Op::Ge(site),
+                    Op2::StringAppend => Op::StrAppend(site),
+                    Op2::StringEqual => Op::StrEq(site),
+                    Op2::Divide => Op::Div(site),
+                    Op2::Quotient => Op::Quotient(site),
+                    Op2::Remainder => Op::Remainder(site),
+                    Op2::Modulo => Op::Modulo(site),
+                    Op2::BitAnd => Op::BitAnd(site),
+                    Op2::BitOr => Op::BitOr(site),
+                    Op2::BitXor => Op::BitXor(site),
+                    Op2::ShiftLeft => Op::Shl(site),
+                    Op2::ShiftRight => Op::Shr(site),
+                });
+            }
+            Expr::Substring(s, start, end, pos) => {
+                // left to right, unlike the binary operators
+                self.compile(s, si, env, false);
+                self.compile(start, si, env, false);
+                self.compile(end, si, env, false);
+                let site = self.new_site("substring", pos);
+                self.emit(Op::Substring(site));
+            }
+            Expr::Print(expr, _) => {
+                self.compile(expr, si, env, false);
+                self.emit(Op::Print);
+            }
+            Expr::Set(s, expr, _) => {
+                self.compile(expr, si, env, false);
+                let (load, store) = self.variable(s, env);
+                self.emit(store);
+                self.emit(load);
+            }
+            Expr::If(cond, thn, els, _) => {
+                self.compile(cond, si, env, false);
+                let to_els = self.emit(Op::JmpFalse(0));
+                self.compile(thn, si, env, is_tail);
+                let to_end = self.emit(Op::Jmp(0));
+                self.height -= 1;
+                self.patch(to_els);
+                self.compile(els, si, env, is_tail);
+                self.patch(to_end);
+            }
+            Expr::Block(exprs) => {
+                if exprs.is_empty() {
+                    panic!("parse error: Invalid: No instructions in block which is invalid");
+                }
+                for (i, expr) in exprs.iter().enumerate() {
+                    if i == exprs.len() - 1 {
+                        self.compile(expr, si, env, is_tail);
+                    } else {
+                        self.compile(expr, si, env, false);
+                        self.emit(Op::Pop);
+                    }
+                }
+            }
+            Expr::And(exprs) | Expr::Or(exprs) => {
+                let is_and = matches!(e, Expr::And(_));
+                if exprs.is_empty() {
+                    self.emit(Op::Const(if is_and { TRUE_CONST } else { FALSE_CONST }));
+                }
+                let mut to_end = vec![];
+                for (i, expr) in exprs.iter().enumerate() {
+                    if i == exprs.len() - 1 {
+                        self.compile(expr, si, env, is_tail);
+                    } else {
+                        self.compile(expr, si, env, false);
+                        to_end.push(self.emit(if is_and { Op::JmpFalseOrPop(0) } else { Op::JmpTrueOrPop(0) }));
+                    }
+                }
+                for at in to_end {
+                    self.patch(at);
+                }
+            }
+            Expr::Loop(label, expr) => {
+                let start = self.code.len() as u32;
+                self.loop_stack.push(LoopEntry { label: label.clone(), start, breaks: vec![], height: self.height });
+                self.compile(expr, si, env, false);
+                self.emit(Op::Pop);
+                self.emit(Op::Jmp(start));
+                let entry = self.loop_stack.pop().unwrap();
+                for at in entry.breaks {
+                    self.patch(at);
+                }
+                // control only leaves through a break, which carries a value
+                self.height += 1;
+            }
+            Expr::Break(label, expr, _) => {
+                let target = find_loop(self.loop_stack.iter().map(|l| &l.label), label, "break");
+                self.compile(expr, si, env, false);
+                let loop_height = self.loop_stack[target].height;
+                let height = self.height;
+                if height - 1 > loop_height {
+                    self.emit(Op::Slide(height - 1 - loop_height));
+                }
+                let at = self.emit(Op::Jmp(0));
+                self.loop_stack[target].breaks.push(at);
+                self.height = height;
+            }
+            Expr::Continue(label, _) => {
+                let target = find_loop(self.loop_stack.iter().map(|l| &l.label), label, "continue");
+                let height = self.height;
+                while self.height > self.loop_stack[target].height {
+                    self.emit(Op::Pop);
+                }
+                self.emit(Op::Jmp(self.loop_stack[target].start));
+                // control never falls through; the value is only nominal
+                self.height = height + 1;
+            }
+            Expr::LocalFuns(defs, body) => {
+                let mut inner = env.clone();
+                for Definition::Func(_, args, _) in defs {
+                    let f = self.funcs.len() as u32;
+                    self.funcs.push(Func { name: args[0].clone(), arity: args.len() as u32, locals: 0, entry: 0 });
+                    inner.funs.insert(args[0].clone(), (0, f));
+                }
+                // the functions' code sits in line, jumped over
+                let skip = self.emit(Op::Jmp(0));
+                for Definition::Func(_, args, e) in defs {
+                    let (_, f) = inner.funs[&args[0]];
+                    self.function(f, &args[1..], e, inner.nested());
+                }
+                self.patch(skip);
+                self.compile(body, si, &inner, is_tail);
+            }
+            Expr::Check(e, _) if self.typed => self.compile(e, si, env, is_tail),
+            Expr::Check(e, annotation) => {
+                let site = self.new_site(&annotation_to_str(annotation), &annotation.pos);
+                self.compile(e, si, env, false);
+                self.emit(Op::Check(annotation.ty, site));
+            }
+            Expr::Construct(variant, args) => {
+                for arg in args {
+                    self.compile(arg, si, env, false);
+                }
+                let name = self.string(&variant.ctor);
+                self.emit(Op::Construct(name, args.len() as u32));
+            }
+            Expr::IsVariant(e, variant) => {
+                self.compile(e, si, env, false);
+                let name = self.string(&variant.ctor);
+                self.emit(Op::IsVariant(name));
+            }
+            Expr::Field(e, _, i) => {
+                self.compile(e, si, env, false);
+                self.emit(Op::Field(*i as u32));
+            }
+            Expr::MatchFail(e, pos) => {
+                self.compile(e, si, env, false);
+                let site = self.new_site("match", pos);
+                self.emit(Op::MatchFail(site));
+            }
+            Expr::Match(..) => unreachable!("matches are lowered before compiling"),
+            Expr::Sugar(..) => unreachable!("derived forms are desugared before compiling"),
+            Expr::Call(fname, params, pos) => {
+                let local = env.funs.get(fname).copied();
+                let (f, arity) = match local {
+                    Some((_, f)) => (f, self.funcs[f as usize].arity as i32 - 1),
+                    None if self.func_dic.contains_key(fname) => (self.func_ids[fname], self.func_dic[fname]),
+                    None => panic!("parse error: Invalid Function {} is not defined", fname),
+                };
+                if arity != params.len() as i32 {
+                    panic!("parse error: Function {} expects {} arguments, but actually receive {} arguments", fname, arity, params.len());
+                }
+                if let Some((hops, _)) = local {
+                    self.emit(Op::Link(hops));
+                }
+                for expr in params {
+                    self.compile(expr, si, env, false);
+                }
+                // a function defined right here needs this frame to stay
+                let keeps_frame = local.map_or(false, |(hops, _)| hops == 0);
+                if is_tail && !keeps_frame {
+                    self.emit(Op::TailCall(f));
+                } else {
+                    let site = self.new_site(fname, pos);
+                    self.emit(Op::Call(f, site));
+                }
+            }
+        }
+    }
+}
+
+impl Chunk {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = vec![];
+        out.extend_from_slice(MAGIC);
+        out.push(VERSION);
+        put_u32(&mut out, self.funcs.len() as u32);
+        for f in &self.funcs {
+            put_str(&mut out, &f.name);
+            put_u32(&mut out, f.arity);
+            put_u32(&mut out, f.locals);
+            put_u32(&mut out, f.entry);
+        }
+        put_u32(&mut out, self.sites.len() as u32);
+        for site in &self.sites {
+            put_str(&mut out, &site.op);
+            put_str(&mut out, &site.loc);
+        }
+        put_u32(&mut out, self.strings.len() as u32);
+        for s in &self.strings {
+            put_str(&mut out, s);
+        }
+        put_u32(&mut out, self.floats.len() as u32);
+        for x in &self.floats {
+            out.extend_from_slice(&x.to_bits().to_le_bytes());
+        }
+        put_u32(&mut out, self.globals);
+        out.push(self.trap_overflow as u8);
+        out.push(self.typed as u8);
+        put_u32(&mut out, self.main_locals);
+        put_u32(&mut out, self.main_entry);
+        put_u32(&mut out, self.code.len() as u32);
+        for op in &self.code {
+            let (opcode, arg) = match op {
+                Op::Const(n) => {
+                    out.push(0);
+                    out.extend_from_slice(&n.to_le_bytes());
+                    continue;
+                }
+                Op::Input => (1, None),
+                Op::Load(s) => (2, Some(*s)),
+                Op::Store(s) => (3, Some(*s)),
+                Op::Pop => (4, None),
+                Op::Slide(n) => (5, Some(*n)),
+                Op::Add1(site) => (6, Some(*site)),
+                Op::Sub1(site) => (7, Some(*site)),
+                Op::IsNum => (8, None),
+                Op::IsBool => (9, None),
+                Op::Add(site) => (10, Some(*site)),
+                Op::Sub(site) => (11, Some(*site)),
+                Op::Mul(site) => (12, Some(*site)),
+                Op::Eq(site) => (13, Some(*site)),
+                Op::Lt(site) => (14, Some(*site)),
+                Op::Le(site) => (15, Some(*site)),
+                Op::Gt(site) => (16, Some(*site)),
+                Op::Ge(site) => (17, Some(*site)),
+                Op::Jmp(t) => (18, Some(*t)),
+                Op::JmpFalse(t) => (19, Some(*t)),
+                Op::Call(f, site) => {
+                    out.push(20);
+                    put_u32(&mut out, *f);
+                    put_u32(&mut out, *site);
+                    continue;
+                }
+                Op::TailCall(f) => (21, Some(*f)),
+                Op::LoadOuter(hops, i) | Op::StoreOuter(hops, i) => {
+                    out.push(if let Op::LoadOuter(..) = op { 49 } else { 50 });
+                    put_u32(&mut out, *hops);
+                    put_u32(&mut out, *i);
+                    continue;
+                }
+                Op::Check(ty, site) => {
+                    out.push(52);
+                    put_u32(&mut out, *ty as u32);
+                    put_u32(&mut out, *site);
+                    continue;
+                }
+                Op::Construct(name, n) => {
+                    out.push(53);
+                    put_u32(&mut out, *name);
+                    put_u32(&mut out, *n);
+                    continue;
+                }
+                Op::IsVariant(name) => (54, Some(*name)),
+                Op::Field(i) => (55, Some(*i)),
+                Op::MatchFail(site) => (56, Some(*site)),
+                Op::IsStr => (57, None),
+                Op::Ret => (22, None),
+                Op::Print => (23, None),
+                Op::Halt => (24, None),
+                Op::InputAt(site) => (25, Some(*site)),
+                Op::InputCount => (26, None),
+                Op::Str(i) => (27, Some(*i)),
+                Op::StrLen(site) => (28, Some(*site)),
+                Op::StrAppend(site) => (29, Some(*site)),
+                Op::StrEq(site) => (30, Some(*site)),
+                Op::Substring(site) => (31, Some(*site)),
+                Op::Div(site) => (32, Some(*site)),
+                Op::Quotient(site) => (33, Some(*site)),
+                Op::Remainder(site) => (34, Some(*site)),
+                Op::Modulo(site) => (35, Some(*site)),
+                Op::BitAnd(site) => (36, Some(*site)),
+                Op::BitOr(site) => (37, Some(*site)),
+                Op::BitXor(site) => (38, Some(*site)),
+                Op::Shl(site) => (39, Some(*site)),
+                Op::Shr(site) => (40, Some(*site)),
+                Op::Float(i) => (41, Some(*i)),
+                Op::ToFloat(site) => (42, Some(*site)),
+                Op::Truncate(site) => (43, Some(*site)),
+                Op::JmpFalseOrPop(t) => (44, Some(*t)),
+                Op::JmpTrueOrPop(t) => (45, Some(*t)),
+                Op::Not(site) => (46, Some(*site)),
+                Op::LoadGlobal(i) => (47, Some(*i)),
+                Op::StoreGlobal(i) => (48, Some(*i)),
+                Op::Link(hops) => (51, Some(*hops)),
+            };
+            out.push(opcode);
+            if let Some(arg) = arg {
+                put_u32(&mut out, arg);
+            }
+        }
+        out
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Chunk, String> {
+        let mut r = Reader { bytes, pos: 0 };
+        if r.take(4)? != MAGIC || r.take(1)?[0] != VERSION {
+            return Err("not a snek bytecode file".to_string());
+        }
+        let mut funcs = vec![];
+        for _ in 0..r.u32()? {
+            let name = r.string()?;
+            funcs.push(Func { name, arity: r.u32()?, locals: r.u32()?, entry: r.u32()? });
+        }
+        let mut sites = vec![];
+        for _ in 0..r.u32()? {
+            sites.push(Site { op: r.string()?, loc: r.string()? });
+        }
+        let mut strings = vec![];
+        for _ in 0..r.u32()? {
+            strings.push(r.string()?);
+        }
+        let mut floats = vec![];
+        for _ in 0..r.u32()? {
+            floats.push(f64::from_bits(u64::from_le_bytes(r.take(8)?.try_into().unwrap())));
+        }
+        let globals = r.u32()?;
+        let trap_overflow = r.take(1)?[0] != 0;
+        let typed = r.take(1)?[0] != 0;
+        let main_locals = r.u32()?;
+        let main_entry = r.u32()?;
+        let mut code = vec![];
+        for _ in 0..r.u32()? {
+            let opcode = r.take(1)?[0];
+            code.push(match opcode {
+                0 => Op::Const(i64::from_le_bytes(r.take(8)?.try_into().unwrap())),
+                1 => Op::Input,
+                2 => Op::Load(r.u32()?),
+                3 => Op::Store(r.u32()?),
+                4 => Op::Pop,
+                5 => Op::Slide(r.u32()?),
+                6 => Op::Add1(r.u32()?),
+                7 => Op::Sub1(r.u32()?),
+                8 => Op::IsNum,
+                9 => Op::IsBool,
+                10 => Op::Add(r.u32()?),
+                11 => Op::Sub(r.u32()?),
+                12 => Op::Mul(r.u32()?),
+                13 => Op::Eq(r.u32()?),
+                14 => Op::Lt(r.u32()?),
+                15 => Op::Le(r.u32()?),
+                16 => Op::Gt(r.u32()?),
+                17 => Op::Ge(r.u32()?),
+                18 => Op::Jmp(r.u32()?),
+                19 => Op::JmpFalse(r.u32()?),
+                20 => Op::Call(r.u32()?, r.u32()?),
+                21 => Op::TailCall(r.u32()?),
+                22 => Op::Ret,
+                23 => Op::Print,
+                24 => Op::Halt,
+                25 => Op::InputAt(r.u32()?),
+                26 => Op::InputCount,
+                27 => Op::Str(r.u32()?),
+                28 => Op::StrLen(r.u32()?),
+                29 => Op::StrAppend(r.u32()?),
+                30 => Op::StrEq(r.u32()?),
+                31 => Op::Substring(r.u32()?),
+                32 => Op::Div(r.u32()?),
+                33 => Op::Quotient(r.u32()?),
+                34 => Op::Remainder(r.u32()?),
+                35 => Op::Modulo(r.u32()?),
+                36 => Op::BitAnd(r.u32()?),
+                37 => Op::BitOr(r.u32()?),
+                38 => Op::BitXor(r.u32()?),
+                39 => Op::Shl(r.u32()?),
+                40 => Op::Shr(r.u32()?),
+                41 => Op::Float(r.u32()?),
+                42 => Op::ToFloat(r.u32()?),
+                43 => Op::Truncate(r.u32()?),
+                44 => Op::JmpFalseOrPop(r.u32()?),
+                45 => Op::JmpTrueOrPop(r.u32()?),
+                46 => Op::Not(r.u32()?),
+                47 => Op::LoadGlobal(r.u32()?),
+                48 => Op::StoreGlobal(r.u32()?),
+                49 => Op::LoadOuter(r.u32()?, r.u32()?),
+                50 => Op::StoreOuter(r.u32()?, r.u32()?),
+                51 => Op::Link(r.u32()?),
+                53 => Op::Construct(r.u32()?, r.u32()?),
+                54 => Op::IsVariant(r.u32()?),
+                55 => Op::Field(r.u32()?),
+                56 => Op::MatchFail(r.u32()?),
+                57 => Op::IsStr,
+                52 => {
+                    let ty = match r.u32()? {
+                        0 => Ty::Num,
+                        1 => Ty::Bool,
+                        2 => Ty::Str,
+                        ty => return Err(format!("unknown type {}", ty)),
+                    };
+                    Op::Check(ty, r.u32()?)
+                }
+                _ => return Err(format!("unknown opcode {}", opcode)),
+            });
+        }
+        Ok(Chunk { funcs, sites, strings, floats, globals, trap_overflow, typed, main_locals, main_entry, code })
+    }
+}
+
+fn put_u32(out: &mut Vec<u8>, n: u32) {
+    out.extend_from_slice(&n.to_le_bytes());
+}
+
+fn put_str(out: &mut Vec<u8>, s: &str) {
+    put_u32(out, s.len() as u32);
+    out.extend_from_slice(s.as_bytes());
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.pos + n > self.bytes.len() {
+            return Err("truncated bytecode file".to_string());
+        }
+        let s = &self.bytes[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(s)
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let len = self.u32()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|e| e.to_string())
+    }
+}
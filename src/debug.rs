@@ -0,0 +1,328 @@
+use crate::reader::Pos;
+use crate::Instr;
+
+// DWARF constants, version 3 (the newest one whose line table header and
+// `DW_AT_high_pc` encoding need no special casing).
+const DWARF_VERSION: u16 = 3;
+const DW_TAG_COMPILE_UNIT: u8 = 0x11;
+const DW_TAG_BASE_TYPE: u8 = 0x24;
+const DW_TAG_SUBPROGRAM: u8 = 0x2e;
+const DW_TAG_FORMAL_PARAMETER: u8 = 0x05;
+const DW_TAG_VARIABLE: u8 = 0x34;
+const DW_TAG_LEXICAL_BLOCK: u8 = 0x0b;
+const DW_AT_NAME: u8 = 0x03;
+const DW_AT_BYTE_SIZE: u8 = 0x0b;
+const DW_AT_STMT_LIST: u8 = 0x10;
+const DW_AT_LOW_PC: u8 = 0x11;
+const DW_AT_HIGH_PC: u8 = 0x12;
+const DW_AT_LANGUAGE: u8 = 0x13;
+const DW_AT_PRODUCER: u8 = 0x25;
+const DW_AT_LOCATION: u8 = 0x02;
+const DW_AT_ENCODING: u8 = 0x3e;
+const DW_AT_TYPE: u8 = 0x49;
+const DW_FORM_ADDR: u8 = 0x01;
+const DW_FORM_BLOCK1: u8 = 0x0a;
+const DW_FORM_DATA1: u8 = 0x0b;
+const DW_FORM_DATA2: u8 = 0x05;
+const DW_FORM_DATA4: u8 = 0x06;
+const DW_FORM_STRING: u8 = 0x08;
+const DW_FORM_REF4: u8 = 0x13;
+const DW_ATE_SIGNED: u8 = 0x05;
+// gdb only evaluates `print x` for languages it knows; C's expression
+// syntax covers plain snek identifiers
+const DW_LANG_C89: u16 = 0x0001;
+const DW_OP_BREG7: u8 = 0x77;
+const DW_LNS_COPY: u8 = 0x01;
+const DW_LNS_ADVANCE_LINE: u8 = 0x03;
+const DW_LNE_END_SEQUENCE: u8 = 0x01;
+const DW_LNE_SET_ADDRESS: u8 = 0x02;
+
+// abbreviation codes, in the order `abbrev_section` declares them
+const ABBREV_CU: u8 = 1;
+const ABBREV_TYPE: u8 = 2;
+const ABBREV_SUBPROGRAM: u8 = 3;
+const ABBREV_PARAM: u8 = 4;
+const ABBREV_VAR: u8 = 5;
+const ABBREV_BLOCK: u8 = 6;
+
+// (code, tag, has children, (attribute, form) pairs) of an abbreviation
+type Abbrev = (u8, u8, bool, &'static [(u8, u8)]);
+
+/// The label the generated text section ends with in debug mode.
+pub const TEXT_END: &str = "snek_text_end";
+
+// A variable living in stack slot `slot` of its frame, i.e. at [rsp+8*slot].
+struct Var {
+    name: String,
+    slot: i64,
+}
+
+// A function body or the range of code a `let` binding is visible in.
+struct Scope {
+    // the function name, for the outermost scope of a function
+    name: Option<String>,
+    low: String,
+    high: String,
+    params: Vec<Var>,
+    vars: Vec<Var>,
+    children: Vec<Scope>,
+}
+
+/// Collects the line table and variable locations of a program as it is
+/// compiled, for `--debug`. When disabled every method emits nothing, so the
+/// code generator can call them unconditionally.
+pub struct DebugInfo {
+    enabled: bool,
+    // (label, line) rows of the line table, in code order
+    lines: Vec<(String, u32)>,
+    open: Vec<Scope>,
+    funcs: Vec<Scope>,
+    next_label: usize,
+}
+
+impl DebugInfo {
+    pub fn new(enabled: bool) -> DebugInfo {
+        DebugInfo { enabled, lines: vec![], open: vec![], funcs: vec![], next_label: 0 }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    fn label(&mut self, kind: &str) -> String {
+        self.next_label += 1;
+        format!("snek_dbg_{}{}", kind, self.next_label)
+    }
+
+    /// Marks the code that follows as belonging to source line `pos.line`.
+    pub fn line(&mut self, pos: &Pos) -> Vec<Instr> {
+        if !self.enabled {
+            return vec![];
+        }
+        let label = self.label("line");
+        self.lines.push((label.clone(), pos.line));
+        vec![Instr::Label(label)]
+    }
+
+    /// Opens the scope of function `name`, whose arguments live in `params`.
+    pub fn begin_function(&mut self, name: &str, params: Vec<(String, i64)>) -> Vec<Instr> {
+        self.begin(Some(name.to_string()), params, vec![])
+    }
+
+    /// Opens the scope of a `let` binding, visible until `end_scope`.
+    pub fn begin_scope(&mut self, name: &str, slot: i64) -> Vec<Instr> {
+        self.begin(None, vec![], vec![(name.to_string(), slot)])
+    }
+
+    fn begin(&mut self, name: Option<String>, params: Vec<(String, i64)>, vars: Vec<(String, i64)>) -> Vec<Instr> {
+        if !self.enabled {
+            return vec![];
+        }
+        let low = self.label("low");
+        let to_vars = |vs: Vec<(String, i64)>| vs.into_iter().map(|(name, slot)| Var { name, slot }).collect();
+        self.open.push(Scope { name, low: low.clone(), high: String::new(), params: to_vars(params), vars: to_vars(vars), children: vec![] });
+        vec![Instr::Label(low)]
+    }
+
+    /// Closes the innermost scope opened by `begin_function` or `begin_scope`.
+    pub fn end_scope(&mut self) -> Vec<Instr> {
+        if !self.enabled {
+            return vec![];
+        }
+        let high = self.label("high");
+        let mut scope = self.open.pop().expect("no open debug scope");
+        scope.high = high.clone();
+        // local functions are described alongside the others, not nested
+        match self.open.last_mut() {
+            Some(parent) if scope.name.is_none() => parent.children.push(scope),
+            _ => self.funcs.push(scope),
+        }
+        vec![Instr::Label(high)]
+    }
+
+    /// Renders the `.debug_abbrev`, `.debug_info` and `.debug_line` sections
+    /// describing `file`. The text section must start at `throw_error` and
+    /// end at `TEXT_END`.
+    pub fn to_sections(&self, file: &str) -> String {
+        if !self.enabled {
+            return String::new();
+        }
+        let mut out = String::new();
+        out.push_str(&abbrev_section());
+        out.push_str(&self.info_section(file));
+        out.push_str(&self.line_section(file));
+        out
+    }
+
+    fn info_section(&self, file: &str) -> String {
+        let mut out = section("debug_info");
+        out.push_str("snek_dbg_cu:\n");
+        out.push_str("  dd snek_dbg_cu_end - snek_dbg_cu_version\n");
+        out.push_str("snek_dbg_cu_version:\n");
+        out.push_str(&format!("  dw {}\n  dd snek_dbg_abbrev\n  db 8\n", DWARF_VERSION));
+        out.push_str(&format!("  db {}\n", ABBREV_CU));
+        out.push_str(&format!("  db {}\n", bytes_str(&c_string("cobra"))));
+        out.push_str(&format!("  dw {}\n", DW_LANG_C89));
+        out.push_str(&format!("  db {}\n", bytes_str(&c_string(file))));
+        out.push_str(&format!("  dq throw_error, {}\n", TEXT_END));
+        out.push_str("  dd snek_dbg_line\n");
+        out.push_str("snek_dbg_type_value:\n");
+        out.push_str(&format!("  db {}\n", ABBREV_TYPE));
+        out.push_str(&format!("  db {}\n", bytes_str(&c_string("snek_value"))));
+        out.push_str(&format!("  db {}, 8\n", DW_ATE_SIGNED));
+        for func in &self.funcs {
+            scope_entries(func, &mut out);
+        }
+        // end of the compile unit's children
+        out.push_str("  db 0\n");
+        out.push_str("snek_dbg_cu_end:\n");
+        out
+    }
+
+    fn line_section(&self, file: &str) -> String {
+        let mut out = section("debug_line");
+        out.push_str("snek_dbg_line:\n");
+        out.push_str("  dd snek_dbg_line_end - snek_dbg_line_version\n");
+        out.push_str("snek_dbg_line_version:\n");
+        out.push_str(&format!("  dw {}\n", DWARF_VERSION));
+        out.push_str("  dd snek_dbg_line_program - snek_dbg_line_header\n");
+        out.push_str("snek_dbg_line_header:\n");
+        // minimum instruction length, default is_stmt, line base, line range,
+        // opcode base and the operand counts of the standard opcodes
+        out.push_str("  db 1, 1, -5, 14, 13\n");
+        out.push_str("  db 0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1\n");
+        // no include directories, one file
+        out.push_str("  db 0\n");
+        let mut entry = c_string(file);
+        entry.extend([0, 0, 0, 0]);
+        out.push_str(&format!("  db {}\n", bytes_str(&entry)));
+        out.push_str("snek_dbg_line_program:\n");
+        let mut line = 1i64;
+        for (label, row) in &self.lines {
+            out.push_str(&format!("  db 0, 9, {}\n  dq {}\n", DW_LNE_SET_ADDRESS, label));
+            let delta = *row as i64 - line;
+            if delta != 0 {
+                let mut op = vec![DW_LNS_ADVANCE_LINE];
+                op.extend(sleb128(delta));
+                out.push_str(&format!("  db {}\n", bytes_str(&op)));
+                line = *row as i64;
+            }
+            out.push_str(&format!("  db {}\n", DW_LNS_COPY));
+        }
+        out.push_str(&format!("  db 0, 9, {}\n  dq {}\n", DW_LNE_SET_ADDRESS, TEXT_END));
+        out.push_str(&format!("  db 0, 1, {}\n", DW_LNE_END_SEQUENCE));
+        out.push_str("snek_dbg_line_end:\n");
+        out
+    }
+}
+
+fn section(name: &str) -> String {
+    format!("section .{} noalloc noexec nowrite progbits align=1\n", name)
+}
+
+fn abbrev_section() -> String {
+    let abbrevs: [Abbrev; 6] = [
+        (
+            ABBREV_CU,
+            DW_TAG_COMPILE_UNIT,
+            true,
+            &[
+                (DW_AT_PRODUCER, DW_FORM_STRING),
+                (DW_AT_LANGUAGE, DW_FORM_DATA2),
+                (DW_AT_NAME, DW_FORM_STRING),
+                (DW_AT_LOW_PC, DW_FORM_ADDR),
+                (DW_AT_HIGH_PC, DW_FORM_ADDR),
+                (DW_AT_STMT_LIST, DW_FORM_DATA4),
+            ],
+        ),
+        (
+            ABBREV_TYPE,
+            DW_TAG_BASE_TYPE,
+            false,
+            &[(DW_AT_NAME, DW_FORM_STRING), (DW_AT_ENCODING, DW_FORM_DATA1), (DW_AT_BYTE_SIZE, DW_FORM_DATA1)],
+        ),
+        (
+            ABBREV_SUBPROGRAM,
+            DW_TAG_SUBPROGRAM,
+            true,
+            &[(DW_AT_NAME, DW_FORM_STRING), (DW_AT_LOW_PC, DW_FORM_ADDR), (DW_AT_HIGH_PC, DW_FORM_ADDR)],
+        ),
+        (
+            ABBREV_PARAM,
+            DW_TAG_FORMAL_PARAMETER,
+            false,
+            &[(DW_AT_NAME, DW_FORM_STRING), (DW_AT_TYPE, DW_FORM_REF4), (DW_AT_LOCATION, DW_FORM_BLOCK1)],
+        ),
+        (
+            ABBREV_VAR,
+            DW_TAG_VARIABLE,
+            false,
+            &[(DW_AT_NAME, DW_FORM_STRING), (DW_AT_TYPE, DW_FORM_REF4), (DW_AT_LOCATION, DW_FORM_BLOCK1)],
+        ),
+        (ABBREV_BLOCK, DW_TAG_LEXICAL_BLOCK, true, &[(DW_AT_LOW_PC, DW_FORM_ADDR), (DW_AT_HIGH_PC, DW_FORM_ADDR)]),
+    ];
+    let mut out = section("debug_abbrev");
+    out.push_str("snek_dbg_abbrev:\n");
+    for (code, tag, has_children, attrs) in abbrevs {
+        let mut bytes = vec![code, tag, has_children as u8];
+        for (attr, form) in attrs {
+            bytes.extend([*attr, *form]);
+        }
+        bytes.extend([0, 0]);
+        out.push_str(&format!("  db {}\n", bytes_str(&bytes)));
+    }
+    out.push_str("  db 0\n");
+    out
+}
+
+fn scope_entries(scope: &Scope, out: &mut String) {
+    match &scope.name {
+        Some(name) => {
+            out.push_str(&format!("  db {}\n", ABBREV_SUBPROGRAM));
+            out.push_str(&format!("  db {}\n", bytes_str(&c_string(name))));
+        }
+        None => out.push_str(&format!("  db {}\n", ABBREV_BLOCK)),
+    }
+    out.push_str(&format!("  dq {}, {}\n", scope.low, scope.high));
+    for (abbrev, vars) in [(ABBREV_PARAM, &scope.params), (ABBREV_VAR, &scope.vars)] {
+        for var in vars {
+            out.push_str(&format!("  db {}\n", abbrev));
+            out.push_str(&format!("  db {}\n", bytes_str(&c_string(&var.name))));
+            out.push_str("  dd snek_dbg_type_value - snek_dbg_cu\n");
+            // the frame never moves while its code runs, so [rsp+8*slot]
+            // holds the variable throughout its scope
+            let mut location = vec![DW_OP_BREG7];
+            location.extend(sleb128(var.slot * 8));
+            let mut block = vec![location.len() as u8];
+            block.extend(location);
+            out.push_str(&format!("  db {}\n", bytes_str(&block)));
+        }
+    }
+    for child in &scope.children {
+        scope_entries(child, out);
+    }
+    out.push_str("  db 0\n");
+}
+
+fn c_string(s: &str) -> Vec<u8> {
+    let mut bytes = s.as_bytes().to_vec();
+    bytes.push(0);
+    bytes
+}
+
+fn bytes_str(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| b.to_string()).collect::<Vec<_>>().join(", ")
+}
+
+fn sleb128(mut n: i64) -> Vec<u8> {
+    let mut out = vec![];
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        let done = (n == 0 && byte & 0x40 == 0) || (n == -1 && byte & 0x40 != 0);
+        out.push(if done { byte } else { byte | 0x80 });
+        if done {
+            return out;
+        }
+    }
+}
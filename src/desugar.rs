@@ -0,0 +1,107 @@
+// Expands the derived forms in `Sugar` into core `Expr`s, between parsing
+// and the backends, which never see a `Sugar`. Expansions take the position
+// of the form they came from, so errors and line info point back at it.
+
+use crate::reader::Pos;
+use crate::{Definition, Expr, Op1, Op2, Sugar};
+
+// Names no program can write, since the reader ends symbols at spaces.
+const CASE_KEY: &str = "case key";
+const FOR_END: &str = "for end";
+const FOR_FIRST: &str = "for first";
+
+pub fn expr(e: Expr) -> Expr {
+    let boxed = |e: Box<Expr>| Box::new(expr(*e));
+    match e {
+        Expr::Number(_) | Expr::Boolean(_) | Expr::Float(_) | Expr::Str(_) => e,
+        Expr::Input() | Expr::InputCount() | Expr::Id(_) => e,
+        Expr::InputAt(i, pos) => Expr::InputAt(boxed(i), pos),
+        Expr::Let(bindings, body, pos) => Expr::Let(
+            bindings.into_iter().map(|(name, e)| (name, expr(e))).collect(),
+            boxed(body),
+            pos,
+        ),
+        Expr::UnOp(op, e, pos) => Expr::UnOp(op, boxed(e), pos),
+        Expr::BinOp(op, lhs, rhs, pos) => Expr::BinOp(op, boxed(lhs), boxed(rhs), pos),
+        Expr::Substring(s, start, end, pos) => Expr::Substring(boxed(s), boxed(start), boxed(end), pos),
+        Expr::Set(name, e, pos) => Expr::Set(name, boxed(e), pos),
+        Expr::If(cond, thn, els, pos) => Expr::If(boxed(cond), boxed(thn), boxed(els), pos),
+        Expr::Block(exprs) => Expr::Block(exprs.into_iter().map(expr).collect()),
+        Expr::And(exprs) => Expr::And(exprs.into_iter().map(expr).collect()),
+        Expr::Or(exprs) => Expr::Or(exprs.into_iter().map(expr).collect()),
+        Expr::Loop(label, e) => Expr::Loop(label, boxed(e)),
+        Expr::Break(label, e, pos) => Expr::Break(label, boxed(e), pos),
+        Expr::Continue(..) => e,
+        Expr::Call(name, args, pos) => Expr::Call(name, args.into_iter().map(expr).collect(), pos),
+        Expr::Print(e, pos) => Expr::Print(boxed(e), pos),
+        Expr::LocalFuns(defs, body) => Expr::LocalFuns(
+            defs.into_iter().map(|Definition::Func(f, args, e)| Definition::Func(f, args, expr(e))).collect(),
+            boxed(body),
+        ),
+        Expr::Check(e, annotation) => Expr::Check(boxed(e), annotation),
+        Expr::Construct(variant, args) => Expr::Construct(variant, args.into_iter().map(expr).collect()),
+        Expr::Match(e, clauses, pos) => Expr::Match(
+            boxed(e),
+            clauses.into_iter().map(|(pattern, body)| (pattern, expr(body))).collect(),
+            pos,
+        ),
+        Expr::IsVariant(e, variant) => Expr::IsVariant(boxed(e), variant),
+        Expr::Field(e, variant, i) => Expr::Field(boxed(e), variant, i),
+        Expr::MatchFail(e, pos) => Expr::MatchFail(boxed(e), pos),
+        Expr::Sugar(sugar, pos) => expr(expand(*sugar, pos)),
+    }
+}
+
+// One step of expansion; the result may still contain sugar.
+fn expand(sugar: Sugar, pos: Pos) -> Expr {
+    let if_ = |cond: Expr, thn: Expr, els: Expr| Expr::If(Box::new(cond), Box::new(thn), Box::new(els), pos);
+    let or_false = |els: Option<Expr>| els.unwrap_or(Expr::Boolean(false));
+    match sugar {
+        Sugar::Cond(clauses, els) => clauses
+            .into_iter()
+            .rev()
+            .fold(or_false(els), |rest, (test, body)| if_(test, body, rest)),
+        Sugar::When(cond, body) => if_(cond, body, Expr::Boolean(false)),
+        Sugar::Unless(cond, body) => if_(cond, Expr::Boolean(false), body),
+        // the key is evaluated once; literals of another kind than the key's
+        // just don't match, rather than making `=` fail
+        Sugar::Case(key, clauses, els) => {
+            let matches = |datum: Expr| {
+                let kind = if let Expr::Boolean(_) = datum { Op1::IsBool } else { Op1::IsNum };
+                let key = || Box::new(Expr::Id(CASE_KEY.to_string()));
+                Expr::And(vec![
+                    Expr::UnOp(kind, key(), pos),
+                    Expr::BinOp(Op2::Equal, key(), Box::new(datum), pos),
+                ])
+            };
+            let body = clauses.into_iter().rev().fold(or_false(els), |rest, (datums, body)| {
+                if_(Expr::Or(datums.into_iter().map(matches).collect()), body, rest)
+            });
+            Expr::Let(vec![(CASE_KEY.to_string(), key)], Box::new(body), pos)
+        }
+        Sugar::While(label, cond, body) => {
+            let exit = Expr::Break(None, Box::new(Expr::Boolean(false)), pos);
+            Expr::Loop(label, Box::new(if_(cond, body, exit)))
+        }
+        // the step comes first in the loop, skipped on the first pass, so
+        // that `continue` steps too; the end is evaluated once
+        Sugar::For(label, var, start, end, body) => {
+            let id = |name: &str| Box::new(Expr::Id(name.to_string()));
+            let set = |name: &str, e: Expr| Expr::Set(name.to_string(), Box::new(e), pos);
+            let step = if_(
+                Expr::Id(FOR_FIRST.to_string()),
+                set(FOR_FIRST, Expr::Boolean(false)),
+                set(&var, Expr::UnOp(Op1::Add1, id(&var), pos)),
+            );
+            let exit = Expr::Break(None, Box::new(Expr::Boolean(false)), pos);
+            let test = Expr::BinOp(Op2::Less, id(&var), id(FOR_END), pos);
+            let bindings = vec![
+                (var.clone(), start),
+                (FOR_END.to_string(), end),
+                (FOR_FIRST.to_string(), Expr::Boolean(true)),
+            ];
+            let body = Expr::Loop(label, Box::new(Expr::Block(vec![step, if_(test, body, exit)])));
+            Expr::Let(bindings, Box::new(body), pos)
+        }
+    }
+}
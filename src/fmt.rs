@@ -0,0 +1,292 @@
+use std::fs;
+use std::io::{self, Read};
+
+use crate::reader::Atom::*;
+use crate::reader::{self, Comment, Layout, Pos, Sexp};
+
+const WIDTH: usize = 80;
+const INDENT: usize = 2;
+
+/// `cobra fmt [--check] [files...]` rewrites each file in the canonical
+/// layout, or with no files formats stdin to stdout. `--check` changes
+/// nothing and fails if any input is not already formatted.
+pub fn run(args: &[String]) -> io::Result<()> {
+    let check = args.iter().any(|a| a == "--check");
+    let files: Vec<&String> = args.iter().filter(|a| *a != "--check").collect();
+
+    let mut unformatted = 0;
+    if files.is_empty() {
+        let mut src = String::new();
+        io::stdin().read_to_string(&mut src)?;
+        let formatted = format_or_exit("<stdin>", &src);
+        if check {
+            unformatted += (formatted != src) as usize;
+        } else {
+            print!("{}", formatted);
+        }
+    }
+    for file in files {
+        let src = fs::read_to_string(file)?;
+        let formatted = format_or_exit(file, &src);
+        if formatted == src {
+            continue;
+        }
+        if check {
+            println!("{} is not formatted", file);
+            unformatted += 1;
+        } else {
+            fs::write(file, formatted)?;
+        }
+    }
+    if unformatted > 0 {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn format_or_exit(name: &str, src: &str) -> String {
+    match format(src) {
+        Ok(formatted) => formatted,
+        Err(e) => {
+            eprintln!("{}: Invalid sexp {}", name, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Lays out `src` canonically, keeping its comments.
+pub fn format(src: &str) -> Result<String, reader::ReadError> {
+    let (forms, layout) = reader::parse_with_layout(src)?;
+    let mut starts = vec![];
+    for form in &forms {
+        collect_starts(form, &mut starts);
+    }
+    let mut p = Printer { layout: &layout, starts, next_comment: 0, out: String::new(), col: 0, pending: vec![] };
+    // top-level forms and comments stay grouped or apart as they were, with
+    // at most one blank line between them
+    let mut last_line = None;
+    for form in &forms {
+        p.top_level_comments(form.pos(), &mut last_line);
+        p.blank_line_before(form.pos(), last_line);
+        p.item(form, Sep::Line, 0, Style::Auto, 0);
+        last_line = Some(layout.ends[&form.pos()].line);
+    }
+    p.top_level_comments(END, &mut last_line);
+    p.newline(0);
+
+    // the layout must only ever move whitespace and comments around
+    let (reformatted, new_layout) = reader::parse_with_layout(&p.out).expect("formatter produced an invalid sexp");
+    // (trailing comments that end up on one line are read back as one)
+    let texts = |l: &Layout| l.comments.iter().map(|c| c.text.as_str()).collect::<Vec<_>>().join(" ");
+    if reformatted.len() != forms.len()
+        || !reformatted.iter().zip(&forms).all(|(a, b)| same(a, b))
+        || texts(&new_layout) != texts(&layout)
+    {
+        panic!("formatter bug: the formatted program differs from the original");
+    }
+    Ok(p.out)
+}
+
+const END: Pos = Pos { line: u32::MAX, col: u32::MAX };
+
+fn collect_starts(s: &Sexp, starts: &mut Vec<Pos>) {
+    starts.push(s.pos());
+    if let Sexp::List(xs, _) = s {
+        for x in xs {
+            collect_starts(x, starts);
+        }
+    }
+}
+
+/// Structural equality, ignoring source positions.
+fn same(a: &Sexp, b: &Sexp) -> bool {
+    match (a, b) {
+        (Sexp::Atom(x, _), Sexp::Atom(y, _)) => x == y,
+        (Sexp::List(xs, _), Sexp::List(ys, _)) => xs.len() == ys.len() && xs.iter().zip(ys).all(|(x, y)| same(x, y)),
+        _ => false,
+    }
+}
+
+// A one-line `#| |#` or `#;` comment with code before it on its line, which
+// can stay where it is.
+fn inline(c: &Comment) -> bool {
+    c.trailing && !c.text.starts_with(';') && !c.text.contains('\n')
+}
+
+// How an item is separated from the one before it.
+#[derive(Clone, Copy, PartialEq)]
+enum Sep {
+    None,
+    Space,
+    Line,
+}
+
+// `Column` puts every element of a broken list on its own line, as for the
+// bindings of a `let`; `Auto` picks a layout from the head of the list.
+#[derive(Clone, Copy, PartialEq)]
+enum Style {
+    Auto,
+    Column,
+}
+
+struct Printer<'a> {
+    layout: &'a Layout,
+    // the start of every form, in source order
+    starts: Vec<Pos>,
+    next_comment: usize,
+    out: String,
+    col: usize,
+    // trailing comments waiting for the end of the current line
+    pending: Vec<String>,
+}
+
+impl<'a> Printer<'a> {
+    fn write(&mut self, text: &str) {
+        self.out.push_str(text);
+        self.col += text.chars().count();
+    }
+
+    fn newline(&mut self, indent: usize) {
+        if !self.pending.is_empty() {
+            let comments = self.pending.join(" ");
+            self.out.push(' ');
+            self.out.push_str(&comments);
+            self.pending.clear();
+        }
+        self.out.truncate(self.out.trim_end_matches(' ').len());
+        if self.out.is_empty() {
+            return;
+        }
+        self.out.push('\n');
+        self.out.push_str(&" ".repeat(indent));
+        self.col = indent;
+    }
+
+    fn end(&self, s: &Sexp) -> Pos {
+        self.layout.ends[&s.pos()]
+    }
+
+    // A trailing comment is printed at the end of the line, so it lands
+    // before whichever form came next in the source.
+    fn key(&self, c: &Comment) -> Pos {
+        let next = self.starts.partition_point(|&p| p <= c.pos);
+        self.starts.get(next).copied().unwrap_or(END)
+    }
+
+    // Whether a comment inside `s` stops it from being printed on one line.
+    fn has_comments(&self, s: &Sexp) -> bool {
+        let (start, end) = (s.pos(), self.end(s));
+        self.layout
+            .comments
+            .iter()
+            .any(|c| start < c.pos && c.pos < end && (!c.trailing || inline(c) || self.key(c) < end))
+    }
+
+    // Prints the comments before `limit`: inline ones in place, other
+    // trailing ones at the end of the line, and the rest on lines of their
+    // own. Returns whether any of the latter were printed.
+    fn comments_before(&mut self, limit: Pos, indent: usize) -> bool {
+        let mut own_line = false;
+        while let Some(c) = self.layout.comments.get(self.next_comment) {
+            if c.pos >= limit {
+                break;
+            }
+            self.next_comment += 1;
+            if inline(c) && self.pending.is_empty() {
+                if !self.out.ends_with('(') {
+                    self.write(" ");
+                }
+                self.write(&c.text);
+            } else if c.trailing {
+                self.pending.push(c.text.clone());
+            } else {
+                self.newline(indent);
+                self.write(&c.text);
+                own_line = true;
+            }
+        }
+        own_line
+    }
+
+    fn blank_line_before(&mut self, pos: Pos, last_line: Option<u32>) {
+        if last_line.map_or(false, |line| pos.line > line + 1) {
+            self.newline(0);
+        }
+    }
+
+    fn top_level_comments(&mut self, limit: Pos, last_line: &mut Option<u32>) {
+        while let Some(c) = self.layout.comments.get(self.next_comment) {
+            if c.pos >= limit {
+                break;
+            }
+            self.next_comment += 1;
+            if c.trailing {
+                self.pending.push(c.text.clone());
+            } else {
+                self.blank_line_before(c.pos, *last_line);
+                self.newline(0);
+                self.write(&c.text);
+                *last_line = Some(c.pos.line);
+            }
+        }
+    }
+
+    // `closing` counts the parens printed right after `s`, which must fit too.
+    fn item(&mut self, s: &Sexp, sep: Sep, indent: usize, style: Style, closing: usize) {
+        let own_line = self.comments_before(s.pos(), indent);
+        if own_line || sep == Sep::Line || !self.pending.is_empty() {
+            self.newline(indent);
+        } else if sep == Sep::Space || (sep == Sep::None && !self.out.ends_with('(')) {
+            // (the latter after an inline comment)
+            self.write(" ");
+        }
+        self.sexp(s, style, closing);
+    }
+
+    fn sexp(&mut self, s: &Sexp, style: Style, closing: usize) {
+        let xs = match s {
+            Sexp::Atom(a, _) => {
+                self.write(&a.to_string());
+                return;
+            }
+            Sexp::List(xs, _) => xs,
+        };
+        let end = self.end(s);
+        let flat = s.to_string();
+        if !self.has_comments(s) && self.col + flat.chars().count() + closing <= WIDTH {
+            self.write(&flat);
+            self.comments_before(end, self.col);
+            return;
+        }
+
+        let open = self.col;
+        self.write("(");
+        let head = match xs.first() {
+            Some(Sexp::Atom(S(head), _)) if style == Style::Auto => head.as_str(),
+            _ => "",
+        };
+        // how many items share the line with the `(`, and where the rest go
+        let (on_head_line, indent) = match head {
+            // a result annotation stays with the header
+            "fun" if matches!(xs.get(2), Some(Sexp::Atom(S(colon), _)) if colon == ":") => (4, open + INDENT),
+            "fun" | "let" | "when" | "unless" | "while" | "for" | "case" | "match" | "data" | "define" | "define-syntax" | "syntax-rules" => (2, open + INDENT),
+            "block" | "loop" | "cond" => (1, open + INDENT),
+            "" => (1, open + 1),
+            _ => (2, open + head.chars().count() + 2),
+        };
+        for (i, x) in xs.iter().enumerate() {
+            let sep = match i {
+                0 => Sep::None,
+                _ if i < on_head_line => Sep::Space,
+                _ => Sep::Line,
+            };
+            let style = if head == "let" && i == 1 { Style::Column } else { Style::Auto };
+            let closing = if i + 1 == xs.len() { closing + 1 } else { 0 };
+            self.item(x, sep, indent, style, closing);
+        }
+        if self.comments_before(end, indent) {
+            self.newline(indent);
+        }
+        self.write(")");
+    }
+}
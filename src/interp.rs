@@ -0,0 +1,877 @@
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::fmt;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use im::HashMap;
+
+use crate::bignum::BigInt;
+use crate::reader::Pos;
+use crate::{annotation_to_str, find_loop, op1_to_str, op2_to_str, Definition, Expr, Op1, Op2, Ty};
+
+const MAX_NUM: i64 = 4611686018427387903;
+const MIN_NUM: i64 = -4611686018427387904;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Num(i64),
+    Bool(bool),
+    // shared rather than reference counted per thread, since the REPL runs
+    // on a thread of its own
+    Str(Arc<[u8]>),
+    // only for numbers outside the fixnum range
+    Big(Arc<BigInt>),
+    Float(f64),
+    // a constructor's name and its fields
+    Data(Arc<str>, Arc<[Value]>),
+}
+
+// How `print` shows a value: strings unquoted.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Num(n) => write!(f, "{}", n),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Str(s) => write!(f, "{}", String::from_utf8_lossy(s)),
+            Value::Big(n) => write!(f, "{}", n),
+            // the shortest form that reads back as the same double
+            Value::Float(x) => write!(f, "{:?}", x),
+            Value::Data(ctor, fields) => {
+                write!(f, "({}", ctor)?;
+                for field in fields.iter() {
+                    write!(f, " {}", field.show())?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+impl Value {
+    /// How error messages show a value: strings quoted.
+    pub fn show(&self) -> String {
+        match self {
+            Value::Str(s) => format!("{:?}", String::from_utf8_lossy(s)),
+            _ => self.to_string(),
+        }
+    }
+
+    fn is_num(&self) -> bool {
+        matches!(self, Value::Num(_) | Value::Big(_) | Value::Float(_))
+    }
+}
+
+/// The operator that raised a runtime error and its `file:line:col`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Site {
+    pub op: String,
+    pub loc: String,
+}
+
+/// What went wrong, with offending values already formatted so that the
+/// interpreter and the VM's tagged words report them identically.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorKind {
+    InvalidArgument(String),
+    // a number outside the fixnum range where only a fixnum will do
+    TooBig(String),
+    // a float where only an integer will do
+    NotInteger(String),
+    NotFinite(String),
+    Mismatch(String, String),
+    Overflow,
+    InputIndex(i64, usize),
+    NotString(String),
+    NotBool(String),
+    // start, end and the string's length
+    Range(i64, i64, usize),
+    DivideByZero,
+    // a value breaking a parameter or result annotation, which the site's
+    // op describes
+    Annotation(String),
+    MatchFailure(String),
+}
+
+/// A call that was still active when an error was raised: the function
+/// running, how many frames its tail calls replaced, and where it was called.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceCall {
+    pub func: String,
+    pub elided: usize,
+    pub loc: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub kind: ErrorKind,
+    pub site: Site,
+    // innermost first; empty when the error was raised in main
+    pub trace: Vec<TraceCall>,
+}
+
+// Mirrors the messages and stack traces of `snek_error` in
+// `runtime/start.rs`.
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_message(f)?;
+        if self.trace.is_empty() {
+            return Ok(());
+        }
+        let mut loc = &self.site.loc;
+        for call in &self.trace {
+            write!(f, "\n  at {} ({})", call.func, loc)?;
+            if call.elided > 0 {
+                write!(f, "\n  ... {} frame(s) elided by tail calls", call.elided)?;
+            }
+            loc = &call.loc;
+        }
+        write!(f, "\n  at main ({})", loc)
+    }
+}
+
+impl RuntimeError {
+    pub fn new(kind: ErrorKind, site: Site) -> RuntimeError {
+        RuntimeError { kind, site, trace: vec![] }
+    }
+
+    fn fmt_message(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Site { op, loc } = &self.site;
+        match &self.kind {
+            ErrorKind::InvalidArgument(got) => {
+                write!(f, "Runtime: invalid argument: `{}` expected a number, got {} at {}", op, got, loc)
+            }
+            ErrorKind::TooBig(got) => write!(
+                f,
+                "Runtime: invalid argument: `{}` expected a number that fits in 63 bits, got {} at {}",
+                op, got, loc
+            ),
+            ErrorKind::NotInteger(got) => {
+                write!(f, "Runtime: invalid argument: `{}` expected an integer, got {} at {}", op, got, loc)
+            }
+            ErrorKind::NotFinite(got) => {
+                write!(f, "Runtime: invalid argument: `{}` expected a finite number, got {} at {}", op, got, loc)
+            }
+            ErrorKind::Mismatch(lhs, rhs) => write!(
+                f,
+                "Runtime: invalid argument: `{}` expected operands of the same type, got {} and {} at {}",
+                op, lhs, rhs, loc
+            ),
+            ErrorKind::Overflow => write!(f, "Runtime: overflow: `{}` result does not fit in 63 bits at {}", op, loc),
+            ErrorKind::InputIndex(i, count) => write!(
+                f,
+                "Runtime: input index {} out of range, the program was given {} input(s) at {}",
+                i, count, loc
+            ),
+            ErrorKind::NotString(got) => {
+                write!(f, "Runtime: invalid argument: `{}` expected a string, got {} at {}", op, got, loc)
+            }
+            ErrorKind::NotBool(got) => {
+                write!(f, "Runtime: invalid argument: `{}` expected a boolean, got {} at {}", op, got, loc)
+            }
+            ErrorKind::Range(start, end, len) => write!(
+                f,
+                "Runtime: invalid argument: `{}` range {}..{} is out of bounds for a string of length {} at {}",
+                op, start, end, len, loc
+            ),
+            ErrorKind::DivideByZero => write!(f, "Runtime: division by zero: `{}` at {}", op, loc),
+            ErrorKind::Annotation(got) => write!(f, "Runtime: annotation violation: {}, got {} at {}", op, got, loc),
+            ErrorKind::MatchFailure(got) => write!(f, "Runtime: match failure: no pattern matches {} at {}", got, loc),
+        }
+    }
+}
+
+// Non-local exits out of `eval`: a `break` or `continue` travelling to its
+// loop (by index into `Frame::loops`), a call in tail position handed back to
+// the caller's trampoline, or a runtime error. The callee is boxed to keep
+// `eval`'s results small.
+enum Control<'a> {
+    Break(usize, Value),
+    Continue(usize),
+    TailCall(Box<Callee<'a>>, Vec<Value>),
+    Error(RuntimeError),
+}
+
+impl From<RuntimeError> for Control<'_> {
+    fn from(e: RuntimeError) -> Self {
+        Control::Error(e)
+    }
+}
+
+struct Function {
+    params: Vec<String>,
+    body: Expr,
+}
+
+// The local functions defined together in a block, and what they see of
+// the scope around them.
+struct Group<'a> {
+    defs: &'a [Definition],
+    scope: Env<'a>,
+}
+
+// What code sees around it, like the code generator's: the slot each
+// variable is, in the frame `hops` functions out, and the local functions
+// in scope, by their group and index in it.
+#[derive(Clone, Default)]
+struct Env<'a> {
+    vars: HashMap<String, (usize, usize)>,
+    funs: HashMap<String, (usize, Rc<Group<'a>>, usize)>,
+}
+
+impl<'a> Env<'a> {
+    // What a function defined here sees of it, before its parameters.
+    fn nested(&self) -> Env<'a> {
+        Env {
+            vars: self.vars.iter().map(|(name, &(hops, i))| (name.clone(), (hops + 1, i))).collect(),
+            funs: self.funs.iter().map(|(name, (hops, group, i))| (name.clone(), (hops + 1, group.clone(), *i))).collect(),
+        }
+    }
+}
+
+// A function about to run: its parameters and body, what the body sees
+// around it, and the frames of the functions it is nested in.
+struct Callee<'a> {
+    name: String,
+    params: &'a [String],
+    body: &'a Expr,
+    env: Env<'a>,
+    links: Vec<Slots>,
+}
+
+// A frame's slots, shared with the local functions that see them.
+type Slots = Rc<RefCell<Vec<Value>>>;
+
+// Slots mirror the stack slots the code generator hands out: `env` maps a
+// name to its index in `slots`, and a `let` truncates back on exit.
+struct Frame {
+    slots: Slots,
+    // the slots of the functions this one is nested in, innermost first
+    links: Vec<Slots>,
+    is_main: bool,
+    // the labels of the enclosing loops, outermost first
+    loops: Vec<Option<String>>,
+}
+
+pub struct Interp {
+    funcs: HashMap<String, Rc<Function>>,
+    // the values of the top-level `define`s, which every function sees
+    globals: RefCell<HashMap<String, Value>>,
+    inputs: Vec<Value>,
+    // the name error locations are reported against
+    file: String,
+}
+
+impl Frame {
+    fn slots(&self, hops: usize) -> &Slots {
+        if hops == 0 {
+            &self.slots
+        } else {
+            &self.links[hops - 1]
+        }
+    }
+}
+
+impl Interp {
+    pub fn new(inputs: Vec<Value>, file: &str) -> Interp {
+        Interp { funcs: HashMap::new(), globals: RefCell::new(HashMap::new()), inputs, file: file.to_string() }
+    }
+
+    fn error<'a>(&self, kind: ErrorKind, op: &str, pos: &Pos) -> Control<'a> {
+        let site = Site { op: op.to_string(), loc: format!("{}:{}", self.file, pos) };
+        Control::Error(RuntimeError::new(kind, site))
+    }
+
+    pub fn define(&mut self, d: Definition) {
+        match d {
+            Definition::Func(_, args, body) => {
+                if args.is_empty() {
+                    panic!("parse error: Invalid function definition without function name");
+                }
+                let mut params = vec![];
+                for arg in args.iter().skip(1) {
+                    if params.contains(arg) {
+                        panic!("parse error: Duplicate argument name {}", arg);
+                    }
+                    params.push(arg.clone());
+                }
+                self.funcs.insert(args[0].clone(), Rc::new(Function { params, body }));
+            }
+        }
+    }
+
+    /// Evaluates the initializer of a top-level `(define name e)` as main
+    /// would, then binds `name`, replacing any earlier global of that name.
+    pub fn define_global(&self, name: &str, e: &Expr) -> Result<(), RuntimeError> {
+        let v = self.eval_main(e)?;
+        self.globals.borrow_mut().insert(name.to_string(), v);
+        Ok(())
+    }
+
+    /// Evaluates `e` as a main expression. Values written to globals with
+    /// `set!` stay written even if it then fails.
+    pub fn eval_main(&self, e: &Expr) -> Result<Value, RuntimeError> {
+        let mut frame = Frame { slots: Slots::default(), links: vec![], is_main: true, loops: vec![] };
+        match self.eval(e, &Env::default(), &mut frame, false) {
+            Ok(v) => Ok(v),
+            Err(Control::Error(err)) => Err(err),
+            // main never runs a call in tail position
+            Err(Control::TailCall(..)) => unreachable!(),
+            Err(Control::Break(..) | Control::Continue(_)) => unreachable!(),
+        }
+    }
+
+    fn call<'a>(&'a self, mut callee: Callee<'a>, mut args: Vec<Value>, loc: String) -> Result<Value, RuntimeError> {
+        let mut elided = 0;
+        // trampoline: calls in tail position come back here instead of
+        // growing the Rust stack, matching the compiled `jmp`
+        loop {
+            let mut env = callee.env.clone();
+            for (i, p) in callee.params.iter().enumerate() {
+                env.vars.insert(p.clone(), (0, i));
+            }
+            let mut frame = Frame { slots: Rc::new(RefCell::new(args)), links: callee.links, is_main: false, loops: vec![] };
+            match self.eval(callee.body, &env, &mut frame, true) {
+                Ok(v) => return Ok(v),
+                Err(Control::TailCall(next, a)) => {
+                    callee = *next;
+                    args = a;
+                    elided += 1;
+                }
+                Err(Control::Error(mut err)) => {
+                    err.trace.push(TraceCall { func: callee.name, elided, loc });
+                    return Err(err);
+                }
+                Err(Control::Break(..) | Control::Continue(_)) => unreachable!(),
+            }
+        }
+    }
+
+    // The local function `fname` defined `hops` functions out from `frame`,
+    // as `callee` for a call from there.
+    fn local<'a>(&self, fname: &str, hops: usize, group: &Rc<Group<'a>>, i: usize, frame: &Frame) -> Callee<'a> {
+        let Definition::Func(_, args, body) = &group.defs[i];
+        let mut scope = group.scope.clone();
+        for (j, Definition::Func(_, other, _)) in group.defs.iter().enumerate() {
+            scope.funs.insert(other[0].clone(), (0, group.clone(), j));
+        }
+        let mut links = vec![frame.slots(hops).clone()];
+        links.extend(frame.links[hops..].iter().cloned());
+        Callee { name: fname.to_string(), params: &args[1..], body, env: scope.nested(), links }
+    }
+
+    fn lookup(&self, fname: &str, nargs: usize) -> &Rc<Function> {
+        match self.funcs.get(fname) {
+            None => panic!("parse error: Invalid Function {} is not defined", fname),
+            Some(f) if f.params.len() != nargs => panic!(
+                "parse error: Function {} expects {} arguments, but actually receive {} arguments",
+                fname,
+                f.params.len(),
+                nargs
+            ),
+            Some(f) => f,
+        }
+    }
+
+    fn eval<'a>(&'a self, e: &'a Expr, env: &Env<'a>, frame: &mut Frame, is_tail: bool) -> Result<Value, Control<'a>> {
+        match e {
+            Expr::Number(n) => {
+                if !(MIN_NUM..=MAX_NUM).contains(n) {
+                    panic!("Invalid immutable, integer overflow");
+                }
+                Ok(Value::Num(*n))
+            }
+            Expr::Boolean(b) => Ok(Value::Bool(*b)),
+            Expr::Float(x) => Ok(Value::Float(*x)),
+            Expr::Str(s) => Ok(Value::Str(s.as_bytes().into())),
+            Expr::Input() => {
+                if !frame.is_main {
+                    panic!("parse error: Not expected to use input in non-main function")
+                }
+                Ok(self.inputs.first().cloned().unwrap_or(Value::Bool(false)))
+            }
+            Expr::InputCount() => {
+                if !frame.is_main {
+                    panic!("parse error: Not expected to use input in non-main function")
+                }
+                Ok(Value::Num(self.inputs.len() as i64))
+            }
+            Expr::InputAt(expr, pos) => {
+                if !frame.is_main {
+                    panic!("parse error: Not expected to use input in non-main function")
+                }
+                let v = self.eval(expr, env, frame, false)?;
+                let i = num(v).map_err(|k| self.error(k, "input", pos))?;
+                match usize::try_from(i).ok().and_then(|i| self.inputs.get(i)) {
+                    Some(v) => Ok(v.clone()),
+                    None => Err(self.error(ErrorKind::InputIndex(i, self.inputs.len()), "input", pos)),
+                }
+            }
+            Expr::Id(s) => match env.vars.get(s) {
+                Some(&(hops, i)) => Ok(frame.slots(hops).borrow()[i].clone()),
+                None => match self.globals.borrow().get(s) {
+                    Some(v) => Ok(v.clone()),
+                    None => panic!("Unbound variable identifier {s}"),
+                },
+            },
+            Expr::Let(bindings, body, _) => {
+                if bindings.is_empty() {
+                    panic!("parse error: Invalid let without bindings");
+                }
+                let base = frame.slots.borrow().len();
+                let mut env_new = env.clone();
+                let mut curr_names = HashSet::<String>::new();
+                for (name, expr) in bindings {
+                    if curr_names.contains(name) {
+                        panic!("parse error: Duplicate binding {name} Invalid");
+                    }
+                    let v = self.eval(expr, &env_new, frame, false)?;
+                    env_new.vars.insert(name.clone(), (0, frame.slots.borrow().len()));
+                    frame.slots.borrow_mut().push(v);
+                    curr_names.insert(name.clone());
+                }
+                let result = self.eval(body, &env_new, frame, is_tail);
+                frame.slots.borrow_mut().truncate(base);
+                result
+            }
+            Expr::UnOp(op, expr, pos) => {
+                let v = self.eval(expr, env, frame, false)?;
+                unop(op, v).map_err(|k| self.error(k, op1_to_str(op), pos))
+            }
+            Expr::BinOp(op, lhs, rhs, pos) => {
+                // the code generator evaluates the right operand first
+                let r = self.eval(rhs, env, frame, false)?;
+                let l = self.eval(lhs, env, frame, false)?;
+                binop(op, l, r).map_err(|k| self.error(k, op2_to_str(op), pos))
+            }
+            Expr::Substring(s, start, end, pos) => {
+                let s = self.eval(s, env, frame, false)?;
+                let start = self.eval(start, env, frame, false)?;
+                let end = self.eval(end, env, frame, false)?;
+                substring(s, start, end).map_err(|k| self.error(k, "substring", pos))
+            }
+            Expr::Print(expr, _) => {
+                let v = self.eval(expr, env, frame, false)?;
+                println!("{}", v);
+                Ok(v)
+            }
+            Expr::Set(s, expr, _) => {
+                let v = self.eval(expr, env, frame, false)?;
+                match (env.vars.get(s), self.globals.borrow_mut().get_mut(s)) {
+                    (Some(&(hops, i)), _) => frame.slots(hops).borrow_mut()[i] = v.clone(),
+                    (None, Some(global)) => *global = v.clone(),
+                    (None, None) => panic!("Unbound variable identifier {s}"),
+                }
+                Ok(v)
+            }
+            Expr::If(cond, thn, els, _) => {
+                if self.eval(cond, env, frame, false)? == Value::Bool(false) {
+                    self.eval(els, env, frame, is_tail)
+                } else {
+                    self.eval(thn, env, frame, is_tail)
+                }
+            }
+            Expr::Block(exprs) => {
+                if exprs.is_empty() {
+                    panic!("parse error: Invalid: No instructions in block which is invalid");
+                }
+                for expr in &exprs[..exprs.len() - 1] {
+                    self.eval(expr, env, frame, false)?;
+                }
+                self.eval(&exprs[exprs.len() - 1], env, frame, is_tail)
+            }
+            Expr::And(exprs) | Expr::Or(exprs) => {
+                let is_and = matches!(e, Expr::And(_));
+                let mut v = Value::Bool(is_and);
+                for (i, expr) in exprs.iter().enumerate() {
+                    v = self.eval(expr, env, frame, is_tail && i == exprs.len() - 1)?;
+                    if (v == Value::Bool(false)) == is_and {
+                        break;
+                    }
+                }
+                Ok(v)
+            }
+            Expr::Loop(label, expr) => {
+                let depth = frame.loops.len();
+                frame.loops.push(label.clone());
+                let result = loop {
+                    match self.eval(expr, env, frame, false) {
+                        Ok(_) => {}
+                        Err(Control::Break(target, v)) if target == depth => break Ok(v),
+                        Err(Control::Continue(target)) if target == depth => {}
+                        Err(c) => break Err(c),
+                    }
+                };
+                frame.loops.pop();
+                result
+            }
+            Expr::Break(label, expr, _) => {
+                let target = find_loop(frame.loops.iter(), label, "break");
+                let v = self.eval(expr, env, frame, false)?;
+                Err(Control::Break(target, v))
+            }
+            Expr::Continue(label, _) => Err(Control::Continue(find_loop(frame.loops.iter(), label, "continue"))),
+            Expr::Check(e, annotation) => {
+                let v = self.eval(e, env, frame, false)?;
+                let ok = match annotation.ty {
+                    Ty::Num => matches!(v, Value::Num(_) | Value::Big(_) | Value::Float(_)),
+                    Ty::Bool => matches!(v, Value::Bool(_)),
+                    Ty::Str => matches!(v, Value::Str(_)),
+                };
+                if !ok {
+                    return Err(self.error(ErrorKind::Annotation(v.show()), &annotation_to_str(annotation), &annotation.pos));
+                }
+                Ok(v)
+            }
+            Expr::Construct(variant, args) => {
+                let mut fields = vec![];
+                for arg in args {
+                    fields.push(self.eval(arg, env, frame, false)?);
+                }
+                Ok(Value::Data(variant.ctor.as_str().into(), fields.into()))
+            }
+            Expr::IsVariant(e, variant) => {
+                let v = self.eval(e, env, frame, false)?;
+                Ok(Value::Bool(matches!(v, Value::Data(ctor, _) if *ctor == *variant.ctor)))
+            }
+            Expr::Field(e, _, i) => match self.eval(e, env, frame, false)? {
+                Value::Data(_, fields) => Ok(fields[*i].clone()),
+                _ => unreachable!("fields are only read after their constructor is matched"),
+            },
+            Expr::MatchFail(e, pos) => {
+                let v = self.eval(e, env, frame, false)?;
+                Err(self.error(ErrorKind::MatchFailure(v.show()), "match", pos))
+            }
+            Expr::Match(..) => unreachable!("matches are compiled before evaluation"),
+            Expr::Sugar(..) => unreachable!("derived forms are desugared before evaluation"),
+            Expr::Call(fname, params, pos) => {
+                let callee = match env.funs.get(fname) {
+                    Some((hops, group, i)) => {
+                        let callee = self.local(fname, *hops, group, *i, frame);
+                        if callee.params.len() != params.len() {
+                            panic!(
+                                "parse error: Function {} expects {} arguments, but actually receive {} arguments",
+                                fname,
+                                callee.params.len(),
+                                params.len()
+                            );
+                        }
+                        callee
+                    }
+                    None => {
+                        let f = self.lookup(fname, params.len());
+                        Callee { name: fname.clone(), params: &f.params, body: &f.body, env: Env::default(), links: vec![] }
+                    }
+                };
+                let mut args = vec![];
+                for param in params {
+                    args.push(self.eval(param, env, frame, false)?);
+                }
+                // a function defined right here needs this frame to stay
+                let keeps_frame = env.funs.get(fname).map_or(false, |(hops, _, _)| *hops == 0);
+                if is_tail && !keeps_frame {
+                    Err(Control::TailCall(Box::new(callee), args))
+                } else {
+                    Ok(self.call(callee, args, format!("{}:{}", self.file, pos))?)
+                }
+            }
+            Expr::LocalFuns(defs, body) => {
+                for Definition::Func(_, args, _) in defs {
+                    let mut params: Vec<&String> = vec![];
+                    for arg in &args[1..] {
+                        if params.contains(&arg) {
+                            panic!("parse error: Duplicate argument name {}", arg);
+                        }
+                        params.push(arg);
+                    }
+                }
+                let group = Rc::new(Group { defs, scope: env.clone() });
+                let mut inner = env.clone();
+                for (i, Definition::Func(_, args, _)) in defs.iter().enumerate() {
+                    inner.funs.insert(args[0].clone(), (0, group.clone(), i));
+                }
+                self.eval(body, &inner, frame, is_tail)
+            }
+        }
+    }
+}
+
+// A fixnum, for the operations that don't take bignums or floats.
+fn num(v: Value) -> Result<i64, ErrorKind> {
+    match v {
+        Value::Num(n) => Ok(n),
+        Value::Big(_) => Err(ErrorKind::TooBig(v.show())),
+        Value::Float(_) => Err(ErrorKind::NotInteger(v.show())),
+        _ => Err(ErrorKind::InvalidArgument(v.show())),
+    }
+}
+
+// An integer of either kind.
+fn big(v: &Value) -> Result<BigInt, ErrorKind> {
+    match v {
+        Value::Num(n) => Ok(BigInt::from_i64(*n)),
+        Value::Big(n) => Ok((**n).clone()),
+        Value::Float(_) => Err(ErrorKind::NotInteger(v.show())),
+        _ => Err(ErrorKind::InvalidArgument(v.show())),
+    }
+}
+
+// Any number, converted to a double.
+fn float(v: &Value) -> Result<f64, ErrorKind> {
+    match v {
+        Value::Num(n) => Ok(*n as f64),
+        Value::Big(n) => Ok(n.to_f64()),
+        Value::Float(x) => Ok(*x),
+        _ => Err(ErrorKind::InvalidArgument(v.show())),
+    }
+}
+
+// Makes an integer result a fixnum when it fits, or else a bignum (an
+// overflow error with `trap_overflow`).
+fn integer(n: BigInt, trap_overflow: bool) -> Result<Value, ErrorKind> {
+    match n.to_i64() {
+        Some(n) if (MIN_NUM..=MAX_NUM).contains(&n) => Ok(Value::Num(n)),
+        _ if trap_overflow => Err(ErrorKind::Overflow),
+        _ => Ok(Value::Big(Arc::new(n))),
+    }
+}
+
+fn string(v: Value) -> Result<Arc<[u8]>, ErrorKind> {
+    match v {
+        Value::Str(s) => Ok(s),
+        _ => Err(ErrorKind::NotString(v.show())),
+    }
+}
+
+fn substring(s: Value, start: Value, end: Value) -> Result<Value, ErrorKind> {
+    let (s, start, end) = (string(s)?, num(start)?, num(end)?);
+    Ok(Value::Str(char_slice(&s, start, end)?.into()))
+}
+
+/// The number of characters in the UTF-8 text `s`; strings are indexed by
+/// character, not byte. Shared with the VM.
+pub fn char_count(s: &[u8]) -> usize {
+    String::from_utf8_lossy(s).chars().count()
+}
+
+/// The characters `start..end` of the UTF-8 text `s`, or a range error.
+/// Shared with the VM.
+pub fn char_slice(s: &[u8], start: i64, end: i64) -> Result<&[u8], ErrorKind> {
+    let len = char_count(s);
+    if start < 0 || start > end || end > len as i64 {
+        return Err(ErrorKind::Range(start, end, len));
+    }
+    let text = std::str::from_utf8(s).expect("strings are UTF-8");
+    let byte = |i: i64| text.char_indices().map(|(b, _)| b).chain([text.len()]).nth(i as usize).unwrap();
+    Ok(&s[byte(start)..byte(end)])
+}
+
+fn check_num(n: i64) -> Result<i64, ErrorKind> {
+    if !(MIN_NUM..=MAX_NUM).contains(&n) {
+        Err(ErrorKind::Overflow)
+    } else {
+        Ok(n)
+    }
+}
+
+fn unop(op: &Op1, v: Value) -> Result<Value, ErrorKind> {
+    match op {
+        Op1::Add1 => arith(&Op2::Plus, &v, &Value::Num(1), false),
+        Op1::Sub1 => arith(&Op2::Minus, &v, &Value::Num(1), false),
+        Op1::IsNum => Ok(Value::Bool(v.is_num())),
+        Op1::IsBool => Ok(Value::Bool(matches!(v, Value::Bool(_)))),
+        Op1::IsStr => Ok(Value::Bool(matches!(v, Value::Str(_)))),
+        Op1::StringLength => Ok(Value::Num(char_count(&string(v)?) as i64)),
+        Op1::Float | Op1::Truncate => convert(op, &v),
+        Op1::Not => match v {
+            Value::Bool(b) => Ok(Value::Bool(!b)),
+            _ => Err(ErrorKind::NotBool(v.show())),
+        },
+    }
+}
+
+/// `float` and `truncate`, shared with the VM. `truncate` rounds towards
+/// zero and leaves integers alone.
+pub fn convert(op: &Op1, v: &Value) -> Result<Value, ErrorKind> {
+    match (op, v) {
+        (Op1::Float, _) => Ok(Value::Float(float(v)?)),
+        (_, Value::Float(x)) if !x.is_finite() => Err(ErrorKind::NotFinite(v.show())),
+        (_, Value::Float(x)) => integer(BigInt::from_f64(*x), false),
+        _ if v.is_num() => Ok(v.clone()),
+        _ => Err(ErrorKind::InvalidArgument(v.show())),
+    }
+}
+
+fn binop(op: &Op2, l: Value, r: Value) -> Result<Value, ErrorKind> {
+    match op {
+        Op2::Equal => equal(&l, &r),
+        Op2::StringAppend => Ok(Value::Str([string(l)?, string(r)?].concat().into())),
+        Op2::StringEqual => Ok(Value::Bool(string(l)? == string(r)?)),
+        Op2::BitAnd | Op2::BitOr | Op2::BitXor | Op2::ShiftLeft | Op2::ShiftRight => {
+            let (a, b) = (num(l)?, num(r)?);
+            Ok(Value::Num(integer_op(op, a, b)?))
+        }
+        _ => arith(op, &l, &r, false),
+    }
+}
+
+/// `=`: operands must be of the same type, and numbers compare by value
+/// whatever their kind.
+pub fn equal(l: &Value, r: &Value) -> Result<Value, ErrorKind> {
+    match (l, r) {
+        (Value::Bool(_), Value::Bool(_)) | (Value::Str(_), Value::Str(_)) => Ok(Value::Bool(l == r)),
+        // fields of different types are just unequal
+        (Value::Data(a, xs), Value::Data(b, ys)) => Ok(Value::Bool(
+            a == b && xs.iter().zip(ys.iter()).all(|(x, y)| equal(x, y) == Ok(Value::Bool(true))),
+        )),
+        (Value::Float(_), _) | (_, Value::Float(_)) if l.is_num() && r.is_num() => {
+            Ok(Value::Bool(float(l)? == float(r)?))
+        }
+        _ if l.is_num() && r.is_num() => Ok(Value::Bool(l == r)),
+        _ => Err(ErrorKind::Mismatch(l.show(), r.show())),
+    }
+}
+
+/// The arithmetic and comparison operators, which dispatch on the kind of
+/// number. Integer results that leave the fixnum range become bignums, or
+/// with `trap_overflow` an overflow error; bignums that come back into
+/// range become fixnums again. A float operand makes `+`, `-`, `*` and `/`
+/// float operations, though `quotient`, `remainder` and `modulo` only take
+/// integers. Shared with the VM.
+pub fn arith(op: &Op2, l: &Value, r: &Value, trap_overflow: bool) -> Result<Value, ErrorKind> {
+    if let (Value::Num(a), Value::Num(b)) = (l, r) {
+        let (a, b) = (*a, *b);
+        let result = match op {
+            Op2::Plus => Some(a + b),
+            Op2::Minus => Some(a - b),
+            Op2::Times => a.checked_mul(b),
+            Op2::Greater => return Ok(Value::Bool(a > b)),
+            Op2::GreaterEqual => return Ok(Value::Bool(a >= b)),
+            Op2::Less => return Ok(Value::Bool(a < b)),
+            Op2::LessEqual => return Ok(Value::Bool(a <= b)),
+            _ => match integer_op(op, a, b) {
+                Err(ErrorKind::Overflow) => None,
+                result => Some(result?),
+            },
+        };
+        if let Some(n) = result.filter(|n| (MIN_NUM..=MAX_NUM).contains(n)) {
+            return Ok(Value::Num(n));
+        }
+    }
+    if let (Value::Float(_), _) | (_, Value::Float(_)) = (l, r) {
+        if let Op2::Quotient | Op2::Remainder | Op2::Modulo = op {
+            big(l)?;
+            big(r)?;
+        }
+        let (a, b) = (float(l)?, float(r)?);
+        return match op {
+            Op2::Plus => Ok(Value::Float(a + b)),
+            Op2::Minus => Ok(Value::Float(a - b)),
+            Op2::Times => Ok(Value::Float(a * b)),
+            // dividing by an exact zero is still an error
+            Op2::Divide if *r == Value::Num(0) => Err(ErrorKind::DivideByZero),
+            Op2::Divide => Ok(Value::Float(a / b)),
+            Op2::Greater => Ok(Value::Bool(a > b)),
+            Op2::GreaterEqual => Ok(Value::Bool(a >= b)),
+            Op2::Less => Ok(Value::Bool(a < b)),
+            _ => Ok(Value::Bool(a <= b)),
+        };
+    }
+    let (a, b) = (big(l)?, big(r)?);
+    let result = match op {
+        Op2::Plus => a.add(&b),
+        Op2::Minus => a.sub(&b),
+        Op2::Times => a.mul(&b),
+        Op2::Greater => return Ok(Value::Bool(a > b)),
+        Op2::GreaterEqual => return Ok(Value::Bool(a >= b)),
+        Op2::Less => return Ok(Value::Bool(a < b)),
+        Op2::LessEqual => return Ok(Value::Bool(a <= b)),
+        _ if b.is_zero() => return Err(ErrorKind::DivideByZero),
+        _ => {
+            let (q, r) = a.div_rem(&b);
+            // as in `integer_op`, `/` and `modulo` round down
+            let round_down = !r.is_zero() && r.is_negative() != b.is_negative();
+            match op {
+                Op2::Quotient => q,
+                Op2::Remainder => r,
+                Op2::Divide if round_down => q.sub(&BigInt::from_i64(1)),
+                Op2::Divide => q,
+                Op2::Modulo if round_down => r.add(&b),
+                _ => r,
+            }
+        }
+    };
+    integer(result, trap_overflow)
+}
+
+/// Division, remainder and the bitwise operators on untagged numbers,
+/// shared with the VM so both agree with the native backend on rounding
+/// and overflow.
+pub fn integer_op(op: &Op2, a: i64, b: i64) -> Result<i64, ErrorKind> {
+    match op {
+        Op2::Divide | Op2::Quotient | Op2::Remainder | Op2::Modulo if b == 0 => Err(ErrorKind::DivideByZero),
+        // `/` rounds towards negative infinity and `quotient` towards zero
+        Op2::Divide => {
+            let (q, r) = (a / b, a % b);
+            check_num(if r != 0 && (r < 0) != (b < 0) { q - 1 } else { q })
+        }
+        Op2::Quotient => check_num(a / b),
+        Op2::Remainder => Ok(a % b),
+        // the result takes the divisor's sign
+        Op2::Modulo => {
+            let r = a % b;
+            Ok(if r != 0 && (r < 0) != (b < 0) { r + b } else { r })
+        }
+        Op2::BitAnd => Ok(a & b),
+        Op2::BitOr => Ok(a | b),
+        Op2::BitXor => Ok(a ^ b),
+        // a negative count shifts the other way
+        Op2::ShiftLeft if b < 0 => integer_op(&Op2::ShiftRight, a, -b),
+        Op2::ShiftRight if b < 0 => integer_op(&Op2::ShiftLeft, a, -b),
+        Op2::ShiftLeft if a == 0 => Ok(0),
+        Op2::ShiftLeft if b >= 63 || (a << b) >> b != a => Err(ErrorKind::Overflow),
+        Op2::ShiftLeft => check_num(a << b),
+        Op2::ShiftRight => Ok(a >> b.min(63)),
+        _ => unreachable!(),
+    }
+}
+
+/// Exit code for a command-line input that is not a valid snek value,
+/// shared with `runtime/start.rs`.
+pub const EXIT_INVALID_INPUT: i32 = 2;
+
+/// Parses a command-line input the same way `runtime/start.rs` does. A
+/// `typed` program only takes numbers.
+pub fn parse_input(input: &str, typed: bool) -> Result<Value, String> {
+    match input {
+        "true" | "false" if typed => Err(format!(
+            "Runtime: invalid input `{}`: expected an integer in -2^62..2^62-1, as the program was type checked",
+            input
+        )),
+        "true" => Ok(Value::Bool(true)),
+        "false" => Ok(Value::Bool(false)),
+        _ => match input.parse::<i64>() {
+            Ok(n) if (MIN_NUM..=MAX_NUM).contains(&n) => Ok(Value::Num(n)),
+            _ => Err(format!(
+                "Runtime: invalid input `{}`: expected true, false or an integer in -2^62..2^62-1",
+                input
+            )),
+        },
+    }
+}
+
+/// Parses every command-line input, exiting with `EXIT_INVALID_INPUT` on
+/// the first bad one.
+pub fn parse_inputs(args: &[String], typed: bool) -> Vec<Value> {
+    let mut inputs = vec![];
+    for arg in args {
+        match parse_input(arg, typed) {
+            Ok(v) => inputs.push(v),
+            Err(msg) => {
+                eprintln!("{}", msg);
+                std::process::exit(EXIT_INVALID_INPUT);
+            }
+        }
+    }
+    inputs
+}
@@ -0,0 +1,601 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::panic::{self, AssertUnwindSafe};
+
+use crate::json::{self, Json};
+use crate::reader::Atom::*;
+use crate::reader::{self, Pos, Sexp};
+use crate::macros::is_macro_define;
+use crate::modules::{self, is_import, is_provide};
+use crate::{compile_program, debug, is_data_define, is_func_define, is_global_define, is_label, RESERVED_WORDS};
+
+// LSP enum values used below
+const SEVERITY_ERROR: i64 = 1;
+const TEXT_SYNC_FULL: i64 = 1;
+const COMPLETION_FUNCTION: i64 = 3;
+const COMPLETION_VARIABLE: i64 = 6;
+const COMPLETION_KEYWORD: i64 = 14;
+const METHOD_NOT_FOUND: i64 = -32601;
+
+/// Serves the Language Server Protocol over stdin/stdout until `exit`.
+/// Documents are synced in full; every change republishes the diagnostics
+/// the reader, the scope checks below and the compiler would report.
+pub fn run() {
+    // parse and check errors surface as panics, just as in the compiler;
+    // they become diagnostics instead of backtraces on stderr
+    panic::set_hook(Box::new(|_| {}));
+
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let mut docs: HashMap<String, String> = HashMap::new();
+    while let Some(msg) = read_message(&mut input) {
+        let method = msg.get("method").and_then(Json::as_str).unwrap_or("");
+        let id = msg.get("id").cloned();
+        let params = msg.get("params").cloned().unwrap_or(Json::Null);
+        let uri = params.at(&["textDocument", "uri"]).and_then(Json::as_str).unwrap_or("").to_string();
+        let result = match method {
+            "initialize" => Some(capabilities()),
+            "shutdown" => Some(Json::Null),
+            "exit" => return,
+            "textDocument/didOpen" => {
+                let text = params.at(&["textDocument", "text"]).and_then(Json::as_str).unwrap_or("");
+                docs.insert(uri.clone(), text.to_string());
+                publish_diagnostics(&uri, &docs[&uri]);
+                None
+            }
+            "textDocument/didChange" => {
+                let changes = params.get("contentChanges");
+                if let Some(Json::Arr(changes)) = changes {
+                    if let Some(text) = changes.last().and_then(|c| c.get("text")).and_then(Json::as_str) {
+                        docs.insert(uri.clone(), text.to_string());
+                    }
+                }
+                publish_diagnostics(&uri, docs.get(&uri).map_or("", |s| s));
+                None
+            }
+            "textDocument/didClose" => {
+                docs.remove(&uri);
+                send(&notification("textDocument/publishDiagnostics", Json::obj(vec![
+                    ("uri", Json::str(&uri)),
+                    ("diagnostics", Json::Arr(vec![])),
+                ])));
+                None
+            }
+            "textDocument/definition" | "textDocument/hover" | "textDocument/completion" => {
+                let text = docs.get(&uri).map_or("", |s| s);
+                let pos = lsp_to_pos(params.get("position").unwrap_or(&Json::Null));
+                let analysis = Analysis::of(text, &uri);
+                Some(match method {
+                    "textDocument/definition" => analysis.definition(&uri, pos),
+                    "textDocument/hover" => analysis.hover(pos),
+                    _ => analysis.completion(pos),
+                })
+            }
+            _ => None,
+        };
+        // requests get an answer even when unsupported; notifications never do
+        if let Some(id) = id {
+            let reply = match result {
+                Some(result) => Json::obj(vec![("jsonrpc", Json::str("2.0")), ("id", id), ("result", result)]),
+                None => Json::obj(vec![
+                    ("jsonrpc", Json::str("2.0")),
+                    ("id", id),
+                    ("error", Json::obj(vec![
+                        ("code", Json::Num(METHOD_NOT_FOUND)),
+                        ("message", Json::Str(format!("unsupported method {}", method))),
+                    ])),
+                ]),
+            };
+            send(&reply);
+        }
+    }
+}
+
+fn capabilities() -> Json {
+    Json::obj(vec![
+        ("capabilities", Json::obj(vec![
+            ("textDocumentSync", Json::Num(TEXT_SYNC_FULL)),
+            ("definitionProvider", Json::Bool(true)),
+            ("hoverProvider", Json::Bool(true)),
+            ("completionProvider", Json::obj(vec![])),
+        ])),
+        ("serverInfo", Json::obj(vec![("name", Json::str("cobra"))])),
+    ])
+}
+
+fn read_message(input: &mut impl BufRead) -> Option<Json> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header).ok()? == 0 {
+            return None;
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(n) = header.strip_prefix("Content-Length:") {
+            length = n.trim().parse::<usize>().ok();
+        }
+    }
+    let mut body = vec![0; length?];
+    input.read_exact(&mut body).ok()?;
+    // a malformed message is skipped rather than ending the session
+    Some(json::parse(&String::from_utf8_lossy(&body)).unwrap_or(Json::Null))
+}
+
+fn send(msg: &Json) {
+    let body = msg.to_string();
+    let mut out = io::stdout().lock();
+    write!(out, "Content-Length: {}\r\n\r\n{}", body.len(), body).ok();
+    out.flush().ok();
+}
+
+fn notification(method: &str, params: Json) -> Json {
+    Json::obj(vec![("jsonrpc", Json::str("2.0")), ("method", Json::str(method)), ("params", params)])
+}
+
+fn publish_diagnostics(uri: &str, text: &str) {
+    let diagnostics = Analysis::of(text, uri)
+        .diagnostics
+        .iter()
+        .map(|d| {
+            Json::obj(vec![
+                ("range", range(d.start, d.end)),
+                ("severity", Json::Num(SEVERITY_ERROR)),
+                ("source", Json::str("cobra")),
+                ("message", Json::str(&d.message)),
+            ])
+        })
+        .collect();
+    send(&notification("textDocument/publishDiagnostics", Json::obj(vec![
+        ("uri", Json::str(uri)),
+        ("diagnostics", Json::Arr(diagnostics)),
+    ])));
+}
+
+// LSP positions are 0-based; the reader's are 1-based.
+fn pos_to_lsp(pos: Pos) -> Json {
+    Json::obj(vec![
+        ("line", Json::Num(pos.line as i64 - 1)),
+        ("character", Json::Num(pos.col as i64 - 1)),
+    ])
+}
+
+fn lsp_to_pos(json: &Json) -> Pos {
+    let field = |k| json.get(k).and_then(Json::as_i64).unwrap_or(0) as u32;
+    Pos { line: field("line") + 1, col: field("character") + 1 }
+}
+
+fn range(start: Pos, end: Pos) -> Json {
+    Json::obj(vec![("start", pos_to_lsp(start)), ("end", pos_to_lsp(end))])
+}
+
+fn after(pos: Pos, name: &str) -> Pos {
+    Pos { line: pos.line, col: pos.col + name.chars().count() as u32 }
+}
+
+struct Diagnostic {
+    start: Pos,
+    end: Pos,
+    message: String,
+}
+
+#[derive(Clone, PartialEq)]
+enum Kind {
+    Function(usize),
+    Argument(String),
+    Variable,
+    Global,
+}
+
+// A definition: a function name, a function argument, a `let` binding or a
+// global.
+#[derive(Clone)]
+struct Symbol {
+    name: String,
+    pos: Pos,
+    kind: Kind,
+}
+
+// An identifier in the source and the symbol it resolves to. Definitions
+// are recorded as references to themselves so hover works on them too.
+struct Ref {
+    pos: Pos,
+    symbol: Symbol,
+}
+
+// A symbol and the region of source where it is in scope.
+struct Visible {
+    symbol: Symbol,
+    from: Pos,
+    to: Pos,
+}
+
+struct Analysis<'a> {
+    text: &'a str,
+    funcs: Vec<Symbol>,
+    // in scope everywhere, under anything a form binds itself
+    globals: Vec<Symbol>,
+    // the names of macros, whose uses are only checked once expanded
+    macros: Vec<String>,
+    refs: Vec<Ref>,
+    visible: Vec<Visible>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'a> Analysis<'a> {
+    // Imports are resolved relative to the document's file, if it has one.
+    fn of(text: &'a str, uri: &str) -> Analysis<'a> {
+        let mut a = Analysis { text, funcs: vec![], globals: vec![], macros: vec![], refs: vec![], visible: vec![], diagnostics: vec![] };
+        let forms = match reader::parse_all(text) {
+            Ok(forms) => forms,
+            Err(e) => {
+                a.error(e.pos, e.pos, format!("Invalid sexp {}", e));
+                return a;
+            }
+        };
+        let path = uri.strip_prefix("file://").unwrap_or(uri);
+        // imported functions are defined, for navigation, at their `import`
+        if let Some(import) = forms.iter().find(|f| is_import(f)) {
+            if let Ok(imported) = panic::catch_unwind(|| modules::visible(path, &forms)) {
+                for (name, arity) in imported {
+                    a.funcs.push(Symbol { name, pos: import.pos(), kind: Kind::Function(arity) });
+                }
+            }
+        }
+        for form in &forms {
+            if let Some((name, pos, args)) = fun_header(form) {
+                a.funcs.push(Symbol { name: name.to_string(), pos, kind: Kind::Function(args.len()) });
+            }
+            if let Sexp::List(vec, _) = form {
+                if let (true, Some(Sexp::Atom(S(name), _))) = (is_macro_define(form), vec.get(1)) {
+                    a.macros.push(name.clone());
+                }
+                if let (true, Some(Sexp::Atom(S(name), pos))) = (is_global_define(form), vec.get(1)) {
+                    a.globals.push(Symbol { name: name.clone(), pos: *pos, kind: Kind::Global });
+                }
+                // a data type's constructors are functions
+                if is_data_define(form) {
+                    for ctor in vec.iter().skip(2) {
+                        if let Sexp::List(parts, _) = ctor {
+                            if let Some(Sexp::Atom(S(name), pos)) = parts.first() {
+                                a.funcs.push(Symbol { name: name.clone(), pos: *pos, kind: Kind::Function(parts.len() - 1) });
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        let file_end = forms.last().map_or(Pos { line: 1, col: 1 }, |f| a.end_of(f));
+        for global in a.globals.clone() {
+            a.bind(&global, Pos { line: 1, col: 1 }, file_end);
+        }
+        for form in &forms {
+            match fun_header(form) {
+                Some((name, pos, args)) => {
+                    a.refs.push(Ref { pos, symbol: a.funcs.iter().find(|f| f.pos == pos).unwrap().clone() });
+                    let mut scope = a.globals.clone();
+                    let end = a.end_of(form);
+                    for (arg, arg_pos) in args {
+                        let symbol = Symbol { name: arg, pos: arg_pos, kind: Kind::Argument(name.to_string()) };
+                        a.bind(&symbol, form.pos(), end);
+                        scope.push(symbol);
+                    }
+                    if let Sexp::List(vec, _) = form {
+                        a.walk(vec.last().unwrap(), &mut scope);
+                    }
+                }
+                None if is_macro_define(form) || is_import(form) || is_provide(form) => {}
+                None if is_data_define(form) => {
+                    if let Sexp::List(vec, _) = form {
+                        for ctor in vec.iter().skip(2) {
+                            if let Some(symbol) = a.funcs.iter().find(|f| f.pos == ctor_pos(ctor)).cloned() {
+                                a.refs.push(Ref { pos: symbol.pos, symbol });
+                            }
+                        }
+                    }
+                }
+                None if is_global_define(form) => {
+                    if let Sexp::List(vec, _) = form {
+                        for e in vec.iter().skip(2) {
+                            a.walk(e, &mut a.globals.clone());
+                        }
+                    }
+                }
+                None => a.walk(form, &mut a.globals.clone()),
+            }
+        }
+        // anything else the compiler rejects, reported at the top of the
+        // file since its messages carry no position
+        if a.diagnostics.is_empty() {
+            let program = Sexp::List(forms, Pos { line: 1, col: 1 });
+            let checked = panic::catch_unwind(AssertUnwindSafe(|| {
+                let prog = modules::load(path, &program);
+                compile_program(&prog, &[], false, &mut vec![], &mut vec![], &mut vec![], &mut debug::DebugInfo::new(false));
+            }));
+            if let Err(payload) = checked {
+                let message = payload
+                    .downcast_ref::<&str>()
+                    .map(|s| s.to_string())
+                    .or_else(|| payload.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "unknown error".to_string());
+                let start = Pos { line: 1, col: 1 };
+                a.error(start, start, message);
+            }
+        }
+        a
+    }
+
+    fn error(&mut self, start: Pos, end: Pos, message: String) {
+        self.diagnostics.push(Diagnostic { start, end, message });
+    }
+
+    fn end_of(&self, form: &Sexp) -> Pos {
+        reader::form_end(self.text, form.pos()).unwrap_or(form.pos())
+    }
+
+    fn bind(&mut self, symbol: &Symbol, from: Pos, to: Pos) {
+        self.refs.push(Ref { pos: symbol.pos, symbol: symbol.clone() });
+        self.visible.push(Visible { symbol: symbol.clone(), from, to });
+    }
+
+    // Resolves the identifiers in `s` against `scope`, innermost last.
+    fn walk(&mut self, s: &Sexp, scope: &mut Vec<Symbol>) {
+        match s {
+            Sexp::Atom(S(name), pos) => {
+                if RESERVED_WORDS.contains(&name.as_str()) || is_label(name) {
+                    return;
+                }
+                match scope.iter().rev().find(|sym| sym.name == *name && !matches!(sym.kind, Kind::Function(_))) {
+                    Some(symbol) => self.refs.push(Ref { pos: *pos, symbol: symbol.clone() }),
+                    None => self.error(*pos, after(*pos, name), format!("Unbound variable identifier {}", name)),
+                }
+            }
+            Sexp::Atom(_, _) => {}
+            Sexp::List(vec, _) => match &vec[..] {
+                [Sexp::Atom(S(op), _), Sexp::List(binds, _), body] if op == "let" => {
+                    let base = scope.len();
+                    let end = self.end_of(s);
+                    for bind in binds {
+                        match bind {
+                            Sexp::List(pair, _) => match &pair[..] {
+                                [Sexp::Atom(S(name), pos), e] => {
+                                    self.walk(e, scope);
+                                    let symbol = Symbol { name: name.clone(), pos: *pos, kind: Kind::Variable };
+                                    self.bind(&symbol, self.end_of(bind), end);
+                                    scope.push(symbol);
+                                }
+                                _ => self.walk(bind, scope),
+                            },
+                            _ => self.walk(bind, scope),
+                        }
+                    }
+                    self.walk(body, scope);
+                    scope.truncate(base);
+                }
+                [Sexp::Atom(S(op), _), rest @ ..] if op == "for" => {
+                    let rest = match rest {
+                        [Sexp::Atom(S(label), _), more @ ..] if is_label(label) => more,
+                        _ => rest,
+                    };
+                    match rest {
+                        [range @ Sexp::List(parts, _), body @ ..] if parts.len() == 3 => {
+                            self.walk(&parts[1], scope);
+                            self.walk(&parts[2], scope);
+                            if let Sexp::Atom(S(name), pos) = &parts[0] {
+                                let symbol = Symbol { name: name.clone(), pos: *pos, kind: Kind::Variable };
+                                self.bind(&symbol, self.end_of(range), self.end_of(s));
+                                scope.push(symbol);
+                                self.walk_body(body, s, scope);
+                                scope.pop();
+                            }
+                        }
+                        _ => {
+                            for e in rest {
+                                self.walk(e, scope);
+                            }
+                        }
+                    }
+                }
+                [Sexp::Atom(S(op), _), scrutinee, clauses @ ..] if op == "match" => {
+                    self.walk(scrutinee, scope);
+                    for clause in clauses {
+                        match clause {
+                            Sexp::List(parts, _) if !parts.is_empty() => {
+                                let base = scope.len();
+                                self.bind_pattern(&parts[0], clause, scope);
+                                self.walk_body(&parts[1..], clause, scope);
+                                scope.truncate(base);
+                            }
+                            _ => self.walk(clause, scope),
+                        }
+                    }
+                }
+                [Sexp::Atom(S(name), _), ..] if self.macros.contains(name) => {}
+                [Sexp::Atom(S(op), _), rest @ ..] if RESERVED_WORDS.contains(&op.as_str()) => self.walk_body(rest, s, scope),
+                [Sexp::Atom(S(fname), pos), args @ ..] => {
+                    let local = scope.iter().rev().find(|sym| sym.name == *fname && matches!(sym.kind, Kind::Function(_)));
+                    match local.or_else(|| self.funcs.iter().find(|f| f.name == *fname)).cloned() {
+                        Some(func) => {
+                            if func.kind != Kind::Function(args.len()) {
+                                let Kind::Function(arity) = func.kind else { unreachable!() };
+                                self.error(*pos, after(*pos, fname), format!(
+                                    "Function {} expects {} arguments, but actually receive {} arguments",
+                                    fname,
+                                    arity,
+                                    args.len()
+                                ));
+                            }
+                            self.refs.push(Ref { pos: *pos, symbol: func });
+                        }
+                        None => self.error(*pos, after(*pos, fname), format!("Invalid Function {} is not defined", fname)),
+                    }
+                    for e in args {
+                        self.walk(e, scope);
+                    }
+                }
+                items => {
+                    for e in items {
+                        self.walk(e, scope);
+                    }
+                }
+            },
+        }
+    }
+
+    // Binds the variables of a `match` pattern to the end of its `clause`,
+    // resolving the constructors it names.
+    fn bind_pattern(&mut self, pattern: &Sexp, clause: &Sexp, scope: &mut Vec<Symbol>) {
+        match pattern {
+            Sexp::Atom(S(name), pos) if name != "_" && name != "true" && name != "false" => {
+                let symbol = Symbol { name: name.clone(), pos: *pos, kind: Kind::Variable };
+                self.bind(&symbol, self.end_of(pattern), self.end_of(clause));
+                scope.push(symbol);
+            }
+            Sexp::List(parts, _) => {
+                if let Some(Sexp::Atom(S(ctor), pos)) = parts.first() {
+                    if let Some(func) = self.funcs.iter().find(|f| f.name == *ctor).cloned() {
+                        self.refs.push(Ref { pos: *pos, symbol: func });
+                    }
+                }
+                for part in parts.iter().skip(1) {
+                    self.bind_pattern(part, clause, scope);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Walks the expressions of `form`, where the functions defined among
+    // them are in scope throughout.
+    fn walk_body(&mut self, items: &[Sexp], form: &Sexp, scope: &mut Vec<Symbol>) {
+        let base = scope.len();
+        let end = self.end_of(form);
+        for item in items {
+            if let Some((name, pos, args)) = fun_header(item) {
+                let symbol = Symbol { name: name.to_string(), pos, kind: Kind::Function(args.len()) };
+                self.bind(&symbol, form.pos(), end);
+                scope.push(symbol);
+            }
+        }
+        for item in items {
+            match fun_header(item) {
+                Some((name, _, args)) => {
+                    let inner = scope.len();
+                    let item_end = self.end_of(item);
+                    for (arg, arg_pos) in args {
+                        let symbol = Symbol { name: arg, pos: arg_pos, kind: Kind::Argument(name.to_string()) };
+                        self.bind(&symbol, item.pos(), item_end);
+                        scope.push(symbol);
+                    }
+                    if let Sexp::List(vec, _) = item {
+                        self.walk(vec.last().unwrap(), scope);
+                    }
+                    scope.truncate(inner);
+                }
+                None => self.walk(item, scope),
+            }
+        }
+        scope.truncate(base);
+    }
+
+    fn ref_at(&self, pos: Pos) -> Option<&Ref> {
+        self.refs.iter().find(|r| r.pos.line == pos.line && r.pos <= pos && pos < after(r.pos, &r.symbol.name))
+    }
+
+    fn definition(&self, uri: &str, pos: Pos) -> Json {
+        match self.ref_at(pos) {
+            Some(r) => Json::obj(vec![
+                ("uri", Json::str(uri)),
+                ("range", range(r.symbol.pos, after(r.symbol.pos, &r.symbol.name))),
+            ]),
+            None => Json::Null,
+        }
+    }
+
+    fn hover(&self, pos: Pos) -> Json {
+        let r = match self.ref_at(pos) {
+            Some(r) => r,
+            None => return Json::Null,
+        };
+        let sym = &r.symbol;
+        let text = match &sym.kind {
+            Kind::Function(arity) => format!("function `{}` of {} argument(s)", sym.name, arity),
+            Kind::Argument(func) => format!("argument `{}` of `{}`", sym.name, func),
+            Kind::Variable => format!("`let`-bound variable `{}`", sym.name),
+            Kind::Global => format!("global `{}`", sym.name),
+        };
+        Json::obj(vec![
+            ("contents", Json::obj(vec![("kind", Json::str("markdown")), ("value", Json::Str(text))])),
+            ("range", range(r.pos, after(r.pos, &sym.name))),
+        ])
+    }
+
+    fn completion(&self, pos: Pos) -> Json {
+        let mut items = vec![];
+        let mut seen = vec![];
+        let mut item = |label: &str, kind: i64, detail: String| {
+            if !seen.contains(&label.to_string()) {
+                seen.push(label.to_string());
+                items.push(Json::obj(vec![
+                    ("label", Json::str(label)),
+                    ("kind", Json::Num(kind)),
+                    ("detail", Json::Str(detail)),
+                ]));
+            }
+        };
+        // innermost bindings first, so shadowed names describe the right one
+        for v in self.visible.iter().rev().filter(|v| v.from <= pos && pos < v.to) {
+            let (kind, detail) = match &v.symbol.kind {
+                Kind::Function(arity) => (COMPLETION_FUNCTION, format!("function of {} argument(s)", arity)),
+                Kind::Argument(func) => (COMPLETION_VARIABLE, format!("argument of {}", func)),
+                Kind::Global => (COMPLETION_VARIABLE, "global".to_string()),
+                _ => (COMPLETION_VARIABLE, "let binding".to_string()),
+            };
+            item(&v.symbol.name, kind, detail);
+        }
+        for f in &self.funcs {
+            if let Kind::Function(arity) = f.kind {
+                item(&f.name, COMPLETION_FUNCTION, format!("function of {} argument(s)", arity));
+            }
+        }
+        for word in RESERVED_WORDS {
+            item(word, COMPLETION_KEYWORD, "keyword".to_string());
+        }
+        Json::Arr(items)
+    }
+}
+
+// The position of a constructor's name in a data declaration.
+fn ctor_pos(ctor: &Sexp) -> Pos {
+    match ctor {
+        Sexp::List(parts, pos) => parts.first().map_or(*pos, |name| name.pos()),
+        _ => ctor.pos(),
+    }
+}
+
+// The name, name position and arguments of a `(fun (name args...) body)`.
+type Header<'s> = (&'s str, Pos, Vec<(String, Pos)>);
+
+fn fun_header(form: &Sexp) -> Option<Header<'_>> {
+    if !is_func_define(form) {
+        return None;
+    }
+    let Sexp::List(vec, _) = form else { return None };
+    let Sexp::List(header, _) = &vec[1] else { return None };
+    let (Sexp::Atom(S(name), pos), rest) = header.split_first()? else { return None };
+    let args = rest
+        .iter()
+        .filter_map(|a| match a {
+            Sexp::Atom(S(arg), pos) => Some((arg.clone(), *pos)),
+            // an annotated parameter, `(x : Num)`
+            Sexp::List(param, _) => match param.first() {
+                Some(Sexp::Atom(S(arg), pos)) => Some((arg.clone(), *pos)),
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect();
+    Some((name, *pos, args))
+}
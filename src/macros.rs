@@ -0,0 +1,473 @@
+// Pattern macros, declared at the top level next to functions:
+//
+//   (define-syntax name
+//     (syntax-rules (literal ...)
+//       (pattern template) ...))
+//
+// Each use `(name ...)` is rewritten by the first rule whose pattern
+// matches, before `parse_program` sees the program. In patterns, a symbol
+// binds whatever it lines up with, except `_`, which matches anything, and
+// the literals, which match only themselves; `p ...` matches any number of
+// `p`, and the same `...` after a template part repeats it for each match.
+// Variables that a template binds with `let` or a `match` pattern are
+// renamed at every use, so they can't capture the names in the code a use
+// passes in.
+
+use std::collections::{HashMap, HashSet};
+use std::panic::{self, AssertUnwindSafe};
+
+use crate::reader::Atom::*;
+use crate::reader::{Pos, Sexp};
+use crate::{is_func_define, parse_expr, RESERVED_WORDS};
+
+// How many expansions may be nested inside each other, to stop macros that
+// expand to themselves.
+const MAX_DEPTH: usize = 200;
+
+const ELLIPSIS: &str = "...";
+
+struct Rule {
+    pattern: Sexp,
+    template: Sexp,
+    // the template's own `let` variables
+    binders: Vec<String>,
+}
+
+struct Macro {
+    literals: Vec<String>,
+    rules: Vec<Rule>,
+}
+
+// What a pattern variable matched: one form, or one match per repetition
+// when it sits under a `...`.
+#[derive(Clone)]
+enum Binding {
+    One(Sexp),
+    Many(Vec<Binding>),
+}
+
+type Bindings = HashMap<String, Binding>;
+
+#[derive(Default)]
+pub struct Macros {
+    macros: HashMap<String, Macro>,
+    // numbers the renamed template variables
+    uses: usize,
+}
+
+pub fn is_macro_define(s: &Sexp) -> bool {
+    match s {
+        Sexp::List(vec, _) => matches!(vec.first(), Some(Sexp::Atom(S(op), _)) if op == "define-syntax"),
+        _ => false,
+    }
+}
+
+/// Collects the program's macros, drops their definitions and expands
+/// every use in the other top-level forms.
+pub fn expand_program(s: &Sexp) -> Sexp {
+    let (forms, pos) = match s {
+        Sexp::List(forms, pos) => (forms, pos),
+        _ => return s.clone(),
+    };
+    let mut macros = Macros::default();
+    for form in forms.iter().filter(|f| is_macro_define(f)) {
+        macros.define(form);
+    }
+    for form in forms.iter().filter(|f| is_func_define(f)) {
+        if let Sexp::List(vec, _) = form {
+            if let Sexp::List(header, _) = &vec[1] {
+                if let Some(Sexp::Atom(S(name), _)) = header.first() {
+                    if macros.is_macro(name) {
+                        panic!("parse error: Invalid function {} has the name of a macro", name);
+                    }
+                }
+            }
+        }
+    }
+    let forms = forms.iter().filter(|f| !is_macro_define(f)).map(|f| macros.expand_top(f)).collect();
+    Sexp::List(forms, *pos)
+}
+
+impl Macros {
+    pub fn is_macro(&self, name: &str) -> bool {
+        self.macros.contains_key(name)
+    }
+
+    /// Adds the macro of a `define-syntax` form, replacing any macro of the
+    /// same name, as the REPL does when a macro is defined again.
+    pub fn redefine(&mut self, s: &Sexp) {
+        if let Sexp::List(vec, _) = s {
+            if let Some(Sexp::Atom(S(name), _)) = vec.get(1) {
+                self.macros.remove(name);
+            }
+        }
+        self.define(s);
+    }
+
+    /// Adds the macro of a `define-syntax` form.
+    pub fn define(&mut self, s: &Sexp) {
+        let vec = match s {
+            Sexp::List(vec, _) => vec,
+            _ => panic!("parse error: Invalid macro definition \"{}\"", s),
+        };
+        let (name, lits, rules) = match &vec[..] {
+            [_, Sexp::Atom(S(name), _), Sexp::List(rules, _)] => match &rules[..] {
+                [Sexp::Atom(S(kw), _), Sexp::List(lits, _), rules @ ..] if kw == "syntax-rules" && !rules.is_empty() => {
+                    (name, lits, rules)
+                }
+                _ => panic!("parse error: Invalid macro definition \"{}\", expected syntax-rules", s),
+            },
+            _ => panic!("parse error: Invalid macro definition \"{}\"", s),
+        };
+        if RESERVED_WORDS.contains(&name.as_str()) {
+            panic!("parse error: Invalid keyword \"{:?}\" matches reserved word", name);
+        }
+        if self.macros.contains_key(name) {
+            panic!("parse error: Duplicate macro {}", name);
+        }
+        let literals: Vec<String> = lits
+            .iter()
+            .map(|lit| match lit {
+                Sexp::Atom(S(lit), _) => lit.clone(),
+                _ => panic!("parse error: Invalid macro literal \"{}\"", lit),
+            })
+            .collect();
+        let rules = rules
+            .iter()
+            .map(|rule| match rule {
+                Sexp::List(pair, _) => match &pair[..] {
+                    [pattern @ Sexp::List(head, _), template] if matches!(head.first(), Some(Sexp::Atom(S(_), _))) => {
+                        let mut vars = HashSet::new();
+                        pattern_vars(&head[1..], &literals, &mut vars, rule);
+                        let mut binders = vec![];
+                        template_binders(template, &vars, &mut binders);
+                        Rule { pattern: pattern.clone(), template: template.clone(), binders }
+                    }
+                    _ => panic!("parse error: Invalid macro rule \"{}\"", rule),
+                },
+                _ => panic!("parse error: Invalid macro rule \"{}\"", rule),
+            })
+            .collect();
+        self.macros.insert(name.clone(), Macro { literals, rules });
+    }
+
+    /// Expands a top-level form: a function's body, or any other form.
+    pub fn expand_top(&mut self, s: &Sexp) -> Sexp {
+        match s {
+            Sexp::List(vec, pos) if is_func_define(s) => {
+                // only the body is an expression, after the header and any
+                // result annotation
+                let (body, header) = vec.split_last().unwrap();
+                let mut items = header.to_vec();
+                items.push(self.expand(body, 0));
+                Sexp::List(items, *pos)
+            }
+            _ => self.expand(s, 0),
+        }
+    }
+
+    // Expands every use in `s`, at `depth` expansions deep. The forms that
+    // hold lists that aren't expressions only expand their expressions.
+    fn expand(&mut self, s: &Sexp, depth: usize) -> Sexp {
+        let (vec, pos) = match s {
+            Sexp::Atom(..) => return s.clone(),
+            Sexp::List(vec, pos) => (vec, *pos),
+        };
+        let op = match vec.first() {
+            Some(Sexp::Atom(S(op), _)) => op.as_str(),
+            _ => "",
+        };
+        if self.is_macro(op) {
+            return self.expand_use(op, s, pos, depth);
+        }
+        let each = |items: &[Sexp], this: &mut Macros| -> Vec<Sexp> { items.iter().map(|e| this.expand(e, depth)).collect() };
+        let items = match (op, &vec[..]) {
+            ("let", [head, Sexp::List(binds, bpos), rest @ ..]) => {
+                let binds = binds
+                    .iter()
+                    .map(|bind| match bind {
+                        Sexp::List(pair, p) if pair.len() == 2 => {
+                            Sexp::List(vec![pair[0].clone(), self.expand(&pair[1], depth)], *p)
+                        }
+                        _ => bind.clone(),
+                    })
+                    .collect();
+                let mut items = vec![head.clone(), Sexp::List(binds, *bpos)];
+                items.extend(each(rest, self));
+                items
+            }
+            // a local function's header isn't an expression
+            ("fun", [head, header, rest @ ..]) => {
+                let mut items = vec![head.clone(), header.clone()];
+                items.extend(each(rest, self));
+                items
+            }
+            // the range starts with the variable, after an optional label
+            ("for", [head, rest @ ..]) => {
+                let mut items = vec![head.clone()];
+                let mut rest = rest;
+                if let [label @ Sexp::Atom(..), more @ ..] = rest {
+                    items.push(label.clone());
+                    rest = more;
+                }
+                if let [Sexp::List(range, p), body @ ..] = rest {
+                    let mut range_out = range.iter().take(1).cloned().collect::<Vec<_>>();
+                    range_out.extend(each(range.get(1..).unwrap_or(&[]), self));
+                    items.push(Sexp::List(range_out, *p));
+                    rest = body;
+                }
+                items.extend(each(rest, self));
+                items
+            }
+            // clauses start with a test, with literals for `case` or with a
+            // pattern for `match`
+            ("cond", [head, clauses @ ..]) | ("case" | "match", [head, _, clauses @ ..]) => {
+                let mut items = vec![head.clone()];
+                if op != "cond" {
+                    items.push(self.expand(&vec[1], depth));
+                }
+                for clause in clauses {
+                    items.push(match clause {
+                        Sexp::List(parts, p) if op != "cond" && !parts.is_empty() => {
+                            let mut parts_out = vec![parts[0].clone()];
+                            parts_out.extend(each(&parts[1..], self));
+                            Sexp::List(parts_out, *p)
+                        }
+                        Sexp::List(parts, p) => Sexp::List(each(parts, self), *p),
+                        _ => clause.clone(),
+                    });
+                }
+                items
+            }
+            _ => each(vec, self),
+        };
+        Sexp::List(items, pos)
+    }
+
+    fn expand_use(&mut self, name: &str, s: &Sexp, pos: Pos, depth: usize) -> Sexp {
+        if depth >= MAX_DEPTH {
+            panic!(
+                "parse error: Invalid expansion of macro `{}` at {}, more than {} expansions deep",
+                name, pos, MAX_DEPTH
+            );
+        }
+        let m = &self.macros[name];
+        let mut found = None;
+        for rule in &m.rules {
+            let mut b = Bindings::new();
+            if let (Sexp::List(ps, _), Sexp::List(fs, _)) = (&rule.pattern, s) {
+                if match_list(&ps[1..], &fs[1..], &m.literals, &mut b) {
+                    found = Some((rule, b));
+                    break;
+                }
+            }
+        }
+        let (rule, b) = match found {
+            Some(found) => found,
+            None => panic!("parse error: Invalid use of macro `{}` at {}, no rule matches \"{}\"", name, pos, s),
+        };
+        self.uses += 1;
+        let renames: HashMap<String, String> =
+            rule.binders.iter().map(|x| (x.clone(), format!("{} {}", x, self.uses))).collect();
+        let out = instantiate(&rule.template, &b, &renames, pos);
+        let out = self.expand(&out, depth + 1);
+        // only the outermost use checks, once everything inside is expanded
+        if depth == 0 {
+            check_form(name, pos, &out);
+        }
+        out
+    }
+}
+
+// Reports a use that expanded to something the parser rejects as an error
+// in that use, rather than in code the user never wrote.
+fn check_form(name: &str, pos: Pos, out: &Sexp) {
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    let parsed = panic::catch_unwind(AssertUnwindSafe(|| {
+        parse_expr(out);
+    }));
+    panic::set_hook(hook);
+    if let Err(payload) = parsed {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown error".to_string());
+        let message = message.strip_prefix("parse error: ").unwrap_or(&message);
+        panic!("parse error: Invalid expansion of macro `{}` at {} to \"{}\": {}", name, pos, out, message);
+    }
+}
+
+fn is_ellipsis(s: &Sexp) -> bool {
+    matches!(s, Sexp::Atom(S(e), _) if e == ELLIPSIS)
+}
+
+fn pattern_vars(ps: &[Sexp], literals: &[String], vars: &mut HashSet<String>, rule: &Sexp) {
+    if ps.iter().filter(|p| is_ellipsis(p)).count() > 1 || ps.first().map_or(false, is_ellipsis) {
+        panic!("parse error: Invalid macro rule \"{}\", misplaced {}", rule, ELLIPSIS);
+    }
+    for p in ps {
+        match p {
+            Sexp::Atom(S(v), _) if v == "_" || v == ELLIPSIS || literals.contains(v) => {}
+            Sexp::Atom(S(v), _) => {
+                if !vars.insert(v.clone()) {
+                    panic!("parse error: Invalid macro rule \"{}\", {} appears twice", rule, v);
+                }
+            }
+            Sexp::Atom(..) => {}
+            Sexp::List(items, _) => pattern_vars(items, literals, vars, rule),
+        }
+    }
+}
+
+// The variables a `match` pattern binds: its symbols, less constructor
+// names, `_` and the boolean literals.
+fn pattern_binders(pattern: &Sexp, vars: &HashSet<String>, binders: &mut Vec<String>) {
+    match pattern {
+        Sexp::Atom(S(x), _) if x == "_" || x == "true" || x == "false" || vars.contains(x) || binders.contains(x) => {}
+        Sexp::Atom(S(x), _) => binders.push(x.clone()),
+        Sexp::List(items, _) => {
+            for item in items.iter().skip(1) {
+                pattern_binders(item, vars, binders);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn template_binders(t: &Sexp, vars: &HashSet<String>, binders: &mut Vec<String>) {
+    if let Sexp::List(items, _) = t {
+        let mut bound = vec![];
+        match &items[..] {
+            [Sexp::Atom(S(op), _), Sexp::List(binds, _), ..] if op == "let" => bound.extend(binds),
+            [Sexp::Atom(S(op), _), range @ Sexp::List(..), ..] if op == "for" => bound.push(range),
+            [Sexp::Atom(S(op), _), Sexp::Atom(..), range @ Sexp::List(..), ..] if op == "for" => bound.push(range),
+            [Sexp::Atom(S(op), _), _, clauses @ ..] if op == "match" => {
+                for clause in clauses {
+                    if let Sexp::List(parts, _) = clause {
+                        if let Some(pattern) = parts.first() {
+                            pattern_binders(pattern, vars, binders);
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+        for bind in bound {
+            if let Sexp::List(pair, _) = bind {
+                if let Some(Sexp::Atom(S(x), _)) = pair.first() {
+                    if !vars.contains(x) && !binders.contains(x) {
+                        binders.push(x.clone());
+                    }
+                }
+            }
+        }
+        for item in items {
+            template_binders(item, vars, binders);
+        }
+    }
+}
+
+fn match_pattern(p: &Sexp, f: &Sexp, literals: &[String], b: &mut Bindings) -> bool {
+    match (p, f) {
+        (Sexp::Atom(S(v), _), _) if v == "_" => true,
+        (Sexp::Atom(S(lit), _), Sexp::Atom(S(x), _)) if literals.contains(lit) => lit == x,
+        (Sexp::Atom(S(lit), _), _) if literals.contains(lit) => false,
+        (Sexp::Atom(S(v), _), _) => {
+            b.insert(v.clone(), Binding::One(f.clone()));
+            true
+        }
+        (Sexp::Atom(a, _), Sexp::Atom(x, _)) => a == x,
+        (Sexp::List(ps, _), Sexp::List(fs, _)) => match_list(ps, fs, literals, b),
+        _ => false,
+    }
+}
+
+fn match_list(ps: &[Sexp], fs: &[Sexp], literals: &[String], b: &mut Bindings) -> bool {
+    let at = match ps.iter().position(is_ellipsis) {
+        Some(at) => at,
+        None => return ps.len() == fs.len() && ps.iter().zip(fs).all(|(p, f)| match_pattern(p, f, literals, b)),
+    };
+    let (before, rep, after) = (&ps[..at - 1], &ps[at - 1], &ps[at + 1..]);
+    if fs.len() < before.len() + after.len() {
+        return false;
+    }
+    let (fs_before, rest) = fs.split_at(before.len());
+    let (fs_rep, fs_after) = rest.split_at(rest.len() - after.len());
+    if !match_list(before, fs_before, literals, b) || !match_list(after, fs_after, literals, b) {
+        return false;
+    }
+    let mut matches = vec![];
+    for f in fs_rep {
+        let mut one = Bindings::new();
+        if !match_pattern(rep, f, literals, &mut one) {
+            return false;
+        }
+        matches.push(one);
+    }
+    let mut vars = HashSet::new();
+    pattern_vars(std::slice::from_ref(rep), literals, &mut vars, rep);
+    for v in vars {
+        let each = matches.iter().map(|one| one[&v].clone()).collect();
+        b.insert(v, Binding::Many(each));
+    }
+    true
+}
+
+// The template with the matches put in. What comes from the template takes
+// the position of the use.
+fn instantiate(t: &Sexp, b: &Bindings, renames: &HashMap<String, String>, pos: Pos) -> Sexp {
+    match t {
+        Sexp::Atom(S(v), _) => match b.get(v) {
+            Some(Binding::One(s)) => s.clone(),
+            Some(Binding::Many(_)) => panic!("parse error: Invalid macro template, {} is missing its {}", v, ELLIPSIS),
+            None => Sexp::Atom(S(renames.get(v).unwrap_or(v).clone()), pos),
+        },
+        Sexp::Atom(a, _) => Sexp::Atom(a.clone(), pos),
+        Sexp::List(items, _) => {
+            let mut out = vec![];
+            let mut i = 0;
+            while i < items.len() {
+                let item = &items[i];
+                if items.get(i + 1).map_or(false, is_ellipsis) {
+                    let vars: Vec<&String> = b
+                        .iter()
+                        .filter(|(v, binding)| matches!(binding, Binding::Many(_)) && mentions(item, v))
+                        .map(|(v, _)| v)
+                        .collect();
+                    if vars.is_empty() {
+                        panic!("parse error: Invalid macro template, nothing to repeat before {} in \"{}\"", ELLIPSIS, t);
+                    }
+                    let counts: Vec<usize> =
+                        vars.iter().map(|v| if let Binding::Many(each) = &b[*v] { each.len() } else { 0 }).collect();
+                    if counts.iter().any(|n| *n != counts[0]) {
+                        panic!("parse error: Invalid macro use, the parts repeated by {} in \"{}\" differ in length", ELLIPSIS, t);
+                    }
+                    for k in 0..counts[0] {
+                        let mut one = b.clone();
+                        for v in &vars {
+                            if let Binding::Many(each) = &b[*v] {
+                                one.insert((*v).clone(), each[k].clone());
+                            }
+                        }
+                        out.push(instantiate(item, &one, renames, pos));
+                    }
+                    i += 2;
+                } else {
+                    out.push(instantiate(item, b, renames, pos));
+                    i += 1;
+                }
+            }
+            Sexp::List(out, pos)
+        }
+    }
+}
+
+fn mentions(t: &Sexp, v: &str) -> bool {
+    match t {
+        Sexp::Atom(S(x), _) => x == v,
+        Sexp::Atom(..) => false,
+        Sexp::List(items, _) => items.iter().any(|item| mentions(item, v)),
+    }
+}
@@ -2,18 +2,38 @@ use std::env;
 use std::fs::File;
 use std::io::prelude::*;
 
-use sexp::Atom::*;
-use sexp::*;
+use reader::Atom::*;
+use reader::{Pos, Sexp};
 
 use im::HashMap;
 use std::collections::HashSet;
 
-#[derive(Debug)]
+mod bignum;
+mod bytecode;
+mod debug;
+mod desugar;
+mod fmt;
+mod interp;
+mod json;
+mod lsp;
+mod macros;
+mod matching;
+mod modules;
+mod reader;
+mod repl;
+mod types;
+mod vm;
+
+#[derive(Debug, Clone)]
 enum Val {
     Reg(Reg),
     Imm(i64),
     Const(i64),
     RegOffset(Reg, i64),
+    // the address of a label, which only a register can be loaded with
+    Label(String),
+    // the word of the global with this index, in the data section
+    Global(usize),
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -23,6 +43,12 @@ enum Reg {
     RCX,
     RSP,
     RDI,
+    RSI,
+    RDX,
+    R8,
+    R9,
+    // the low byte of rcx, for shift counts
+    CL,
 }
 
 #[derive(Debug)]
@@ -37,6 +63,8 @@ enum Instr {
     Je(String),
     Jge(String),
     Jle(String),
+    Jl(String),
+    Jg(String),
     And(Val, Val),
     CMOV(Val, Val),
     Label(String),
@@ -44,19 +72,36 @@ enum Instr {
     Jo(String),
     Call(String),
     Return(),
-    ICMovo(Val, Val),
     ICMovne(Val, Val),
+    ICMovl(Val, Val),
+    ICMovge(Val, Val),
+    // loads the address of a label in the data section
+    Lea(Val, String),
+    Or(Val, Val),
+    Xor(Val, Val),
+    Shl(Val, Val),
+    Neg(Val),
+    // sign-extends rax into rdx, ahead of an `IDiv`
+    Cqo(),
+    // divides rdx:rax, leaving the quotient in rax and the remainder in rdx
+    IDiv(Val),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 enum Op1 {
     Add1,
     Sub1,
     IsNum,
     IsBool,
+    StringLength,
+    Float,
+    Truncate,
+    Not,
+    // only in the tests `match` compiles to
+    IsStr,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 enum Op2 {
     Plus,
     Minus,
@@ -66,47 +111,189 @@ enum Op2 {
     GreaterEqual,
     Less,
     LessEqual,
+    StringAppend,
+    StringEqual,
+    Divide,
+    Quotient,
+    Remainder,
+    Modulo,
+    BitAnd,
+    BitOr,
+    BitXor,
+    ShiftLeft,
+    ShiftRight,
 }
 
 #[derive(Debug)]
 struct Program {
   defs: Vec<Definition>,
+  // the top-level `define`s, evaluated in order before main
+  globals: Vec<(String, Expr)>,
   main: Expr,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 enum Definition {
     Func(String, Vec<String>, Expr),
 }
 
+// A place in the source where generated code may call `snek_error`. Each
+// site's index is passed along to the runtime, which looks up the operator
+// and location in the `snek_sites` table to build its message.
+//
+// Sites double as the frame map used for stack traces: `func` and
+// `frame_size` describe the frame the site's code runs in (a frame size of 0
+// marks main), and call sites also record the size of the arguments they
+// push, so the runtime can step from a return address to the caller's frame.
 #[derive(Debug)]
+struct ErrorSite {
+    op: String,
+    pos: Pos,
+    func: String,
+    frame_size: i64,
+    call_args: Option<i64>,
+}
+
+#[derive(Debug, Clone)]
 enum Expr {
     Number(i64),
     Boolean(bool),
+    Float(f64),
+    Str(String),
     Input(),
+    InputAt(Box<Expr>, Pos),
+    InputCount(),
     Id(String),
-    Let(Vec<(String, Expr)>, Box<Expr>),
-    UnOp(Op1, Box<Expr>),
-    BinOp(Op2, Box<Expr>, Box<Expr>),
-    Set(String, Box<Expr>),
-    If(Box<Expr>, Box<Expr>, Box<Expr>),
+    Let(Vec<(String, Expr)>, Box<Expr>, Pos),
+    UnOp(Op1, Box<Expr>, Pos),
+    BinOp(Op2, Box<Expr>, Box<Expr>, Pos),
+    Substring(Box<Expr>, Box<Expr>, Box<Expr>, Pos),
+    Set(String, Box<Expr>, Pos),
+    If(Box<Expr>, Box<Expr>, Box<Expr>, Pos),
     Block(Vec<Expr>),
-    Loop(Box<Expr>),
-    Break(Box<Expr>),
-    Call(String, Vec<Expr>),
-    Print(Box<Expr>),
+    // short-circuiting; both give back the value that decided them
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+    // loops and the `break`s and `continue`s out of them may name a label
+    Loop(Option<String>, Box<Expr>),
+    Break(Option<String>, Box<Expr>, Pos),
+    Continue(Option<String>, Pos),
+    Call(String, Vec<Expr>, Pos),
+    Print(Box<Expr>, Pos),
+    // functions defined in a block, which see each other, the variables
+    // around them and the whole block, its other expressions as the body
+    LocalFuns(Vec<Definition>, Box<Expr>),
+    // the value of the expression, which has to have the annotated type
+    Check(Box<Expr>, Annotation),
+    // a value of a `data` type, built by its constructor function
+    Construct(Variant, Vec<Expr>),
+    // the scrutinee, then each clause's pattern and body; only until
+    // `matching::lower` compiles it to the forms below
+    Match(Box<Expr>, Vec<(Pattern, Expr)>, Pos),
+    // whether the value is of the variant, whatever it is
+    IsVariant(Box<Expr>, Variant),
+    // a field of a value known to be of the variant
+    Field(Box<Expr>, Variant, usize),
+    // the error for a value no clause of the match at `pos` matches
+    MatchFail(Box<Expr>, Pos),
+    // only until `desugar::expr` expands it
+    Sugar(Box<Sugar>, Pos),
+}
+
+// The types a function's parameters and result can be annotated with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Ty {
+    Num,
+    Bool,
+    Str,
+}
+
+// The annotation of a parameter of `func`, or with no `param`, of its
+// result, where it is written.
+#[derive(Debug, Clone)]
+struct Annotation {
+    ty: Ty,
+    func: String,
+    param: Option<String>,
+    pos: Pos,
+}
+
+// A `(data Name (Ctor field ...) ...)` declaration: the type's name and
+// each constructor with its fields.
+#[derive(Debug, Clone)]
+struct Data {
+    name: String,
+    ctors: Vec<(String, Vec<String>)>,
+}
+
+// One constructor of a `data` type.
+#[derive(Debug, Clone, PartialEq)]
+struct Variant {
+    data: String,
+    ctor: String,
+}
+
+#[derive(Debug, Clone)]
+enum Pattern {
+    // `_`
+    Wild,
+    Var(String),
+    // a number, boolean or string literal
+    Lit(Expr),
+    Ctor(String, Vec<Pattern>, Pos),
+}
+
+// Derived forms. Clause bodies with several expressions are already
+// wrapped in a `Block`, and a missing `else` body gives false.
+#[derive(Debug, Clone)]
+enum Sugar {
+    // each clause's test and body, then the `else` body
+    Cond(Vec<(Expr, Expr)>, Option<Expr>),
+    When(Expr, Expr),
+    Unless(Expr, Expr),
+    // the key, then each clause's literals and body, then the `else` body
+    Case(Expr, Vec<(Vec<Expr>, Expr)>, Option<Expr>),
+    // the loop's label, condition and body
+    While(Option<String>, Expr, Expr),
+    // the loop's label, the variable with its start and (exclusive) end,
+    // and the body
+    For(Option<String>, String, Expr, Expr, Expr),
 }
 
 const TRUE_CONST: i64 = 3;
 const FALSE_CONST: i64 = 1;
+// Strings live on the heap as their length in bytes followed by the bytes,
+// 8-byte aligned; a string value is the address with these low bits set.
+const STRING_TAG: i64 = 5;
+// Numbers that don't fit in a fixnum are bignums, which only the runtime
+// allocates: the address of their signed limb count followed by the limbs,
+// least significant first, with the low bits set to 7.
+// Floats are boxed doubles, the address with these low bits set. The
+// address is never 0, so no float is the word for true.
+const FLOAT_TAG: i64 = 3;
+// Data values are the address of their constructor's name, as a string
+// value, then their field count and fields, with the low bits set to 1.
+// The address is never 0, so no data value is the word for false.
+const DATA_TAG: i64 = 1;
+// What `type_code` gives for data values.
+const DATA_CODE: i64 = 2;
 
 const ERRCODE_INVALID_ARG: i64 = 1;
 const ERRCODE_OVERFLOW: i64 = 2;
+const ERRCODE_INPUT_INDEX: i64 = 3;
+const ERRCODE_MISMATCH: i64 = 4;
+const ERRCODE_NOT_STRING: i64 = 5;
+const ERRCODE_SUBSTRING_RANGE: i64 = 6;
+const ERRCODE_DIVIDE_BY_ZERO: i64 = 7;
+const ERRCODE_NOT_BOOL: i64 = 9;
+const ERRCODE_ANNOTATION: i64 = 10;
+const ERRCODE_MATCH: i64 = 11;
 
-const RESERVED_WORDS: [&'static str; 23] = [
+const RESERVED_WORDS: [&'static str; 57] = [
   "true", 
   "false", 
   "input", 
+  "input-count",
   "let", 
   "set!", 
   "if", 
@@ -127,12 +314,61 @@ const RESERVED_WORDS: [&'static str; 23] = [
   ">=",
   "<=",
   "=",
+  "string-length",
+  "string-append",
+  "substring",
+  "string=?",
+  "/",
+  "quotient",
+  "remainder",
+  "modulo",
+  "bitand",
+  "bitor",
+  "bitxor",
+  "shl",
+  "shr",
+  "float",
+  "truncate",
+  "and",
+  "or",
+  "not",
+  "cond",
+  "when",
+  "unless",
+  "case",
+  "else",
+  "define-syntax",
+  "syntax-rules",
+  "while",
+  "for",
+  "continue",
+  "import",
+  "provide",
+  "define",
+  "data",
+  "match",
 ];
 
-const RESERVED_LABELS: [&'static str; 4] = [
+const REPL_STACK_SIZE: usize = 256 * 1024 * 1024;
+
+const RESERVED_LABELS: [&'static str; 18] = [
   "throw_error",
   "snek_print",
+  "snek_string_append",
+  "snek_substring",
+  "snek_string_length",
+  "snek_string_equal",
+  "snek_arith",
+  "snek_trap_overflow",
+  "snek_typed",
+  "snek_float",
+  "snek_truncate",
+  "snek_construct",
+  "snek_equal",
   "snek_error",
+  "snek_sites",
+  "snek_site_count",
+  "snek_site_tables",
   "our_code_starts_here",
 ];
 
@@ -142,101 +378,600 @@ fn new_label(l: &mut i64, s: &str) -> String {
     format!("{}{}", s, current)
 }
 
+// The runtime is handed the address of a site's entry, so that each object
+// can carry its own table.
+fn site_addr(site: i64) -> Val {
+    Val::Label(format!("snek_site{site}"))
+}
+
+fn new_site(sites: &mut Vec<ErrorSite>, op: &str, pos: Pos) -> i64 {
+    sites.push(ErrorSite { op: op.to_string(), pos, func: "main".to_string(), frame_size: 0, call_args: None });
+    (sites.len() - 1) as i64
+}
+
+fn op1_to_str(op: &Op1) -> &'static str {
+  match op {
+    Op1::Add1 => "add1",
+    Op1::Sub1 => "sub1",
+    Op1::IsNum => "isnum",
+    Op1::IsBool => "isbool",
+    Op1::StringLength => "string-length",
+    Op1::Float => "float",
+    Op1::Truncate => "truncate",
+    Op1::Not => "not",
+    Op1::IsStr => "isstr",
+  }
+}
+
+fn ty_to_str(ty: Ty) -> &'static str {
+  match ty {
+    Ty::Num => "Num",
+    Ty::Bool => "Bool",
+    Ty::Str => "Str",
+  }
+}
+
+fn op2_to_str(op: &Op2) -> &'static str {
+  match op {
+    Op2::Plus => "+",
+    Op2::Minus => "-",
+    Op2::Times => "*",
+    Op2::Equal => "=",
+    Op2::Greater => ">",
+    Op2::GreaterEqual => ">=",
+    Op2::Less => "<",
+    Op2::LessEqual => "<=",
+    Op2::StringAppend => "string-append",
+    Op2::StringEqual => "string=?",
+    Op2::Divide => "/",
+    Op2::Quotient => "quotient",
+    Op2::Remainder => "remainder",
+    Op2::Modulo => "modulo",
+    Op2::BitAnd => "bitand",
+    Op2::BitOr => "bitor",
+    Op2::BitXor => "bitxor",
+    Op2::ShiftLeft => "shl",
+    Op2::ShiftRight => "shr",
+  }
+}
+
 fn parse_bind(s: &Sexp) -> (String, Expr) {
     match s {
-        Sexp::List(vec) => match &vec[..] {
-            [Sexp::Atom(S(s)), e] => {
+        Sexp::List(vec, _) => match &vec[..] {
+            [Sexp::Atom(S(s), _), e] => {
                 if RESERVED_WORDS.contains(&s.as_str()) {
                     panic!("parse error: Invalid keyword \"{:?}\" matches reserved word", s);
                 }
                 (s.clone(), parse_expr(e))
             }
-            _ => panic!("parse error: Invalid bind \"{:?}\"", s),
+            _ => panic!("parse error: Invalid bind \"{}\"", s),
         },
-        _ => panic!("parse error: Invalid bind \"{:?}\"", s),
+        _ => panic!("parse error: Invalid bind \"{}\"", s),
+    }
+}
+
+// The expressions of a body, as a `Block` when there are several.
+fn parse_body(body: &[Sexp], form: &Sexp) -> Expr {
+    match body {
+        [] => panic!("parse error: Invalid empty body in \"{}\"", form),
+        [e] => parse_expr(e),
+        _ => parse_block(body, form),
+    }
+}
+
+// The expressions of a block, with the functions defined among them
+// hoisted around the rest.
+fn parse_block(items: &[Sexp], form: &Sexp) -> Expr {
+    if items.last().map_or(false, is_func_define) {
+        panic!("parse error: Invalid block \"{}\" ending in a function definition", form);
+    }
+    let mut funs = vec![];
+    let mut exprs = vec![];
+    for item in items {
+        if is_func_define(item) {
+            let def = parse_definition(item);
+            let Definition::Func(_, args, _) = &def;
+            if funs.iter().any(|Definition::Func(_, other, _)| other[0] == args[0]) {
+                panic!("parse error: Duplicate function definition for function name {}", args[0]);
+            }
+            funs.push(def);
+        } else {
+            exprs.push(parse_expr(item));
+        }
+    }
+    if funs.is_empty() {
+        Expr::Block(exprs)
+    } else {
+        Expr::LocalFuns(funs, Box::new(Expr::Block(exprs)))
     }
 }
 
+// Splits the clauses of `cond` and `case` into each clause's head, parsed
+// with `head`, and body, plus the body of a final `else` clause.
+fn parse_clauses<T>(clauses: &[Sexp], form: &Sexp, head: impl Fn(&Sexp) -> T) -> (Vec<(T, Expr)>, Option<Expr>) {
+    let mut parsed = vec![];
+    for (i, clause) in clauses.iter().enumerate() {
+        match clause {
+            Sexp::List(vec, _) => match &vec[..] {
+                [Sexp::Atom(S(e), _), body @ ..] if e == "else" => {
+                    if i != clauses.len() - 1 {
+                        panic!("parse error: Invalid else clause before the end of \"{}\"", form);
+                    }
+                    return (parsed, Some(parse_body(body, clause)));
+                }
+                [h, body @ ..] => parsed.push((head(h), parse_body(body, clause))),
+                _ => panic!("parse error: Invalid clause \"{}\"", clause),
+            },
+            _ => panic!("parse error: Invalid clause \"{}\"", clause),
+        }
+    }
+    (parsed, None)
+}
+
+// Loop labels are symbols like `:outer`.
+fn is_label(s: &str) -> bool {
+    s.len() > 1 && s.starts_with(':')
+}
+
+// Finds the loop a `break` or `continue` (the `what`) leaves, innermost
+// first: the innermost loop of all without a label, or else the innermost
+// with that label. `labels` lists the enclosing loops' labels, outermost
+// first.
+fn find_loop<'a>(labels: impl DoubleEndedIterator<Item = &'a Option<String>> + ExactSizeIterator, label: &Option<String>, what: &str) -> usize {
+    let n = labels.len();
+    if n == 0 {
+        panic!("Unexpected {what} outside loop");
+    }
+    match label {
+        None => n - 1,
+        Some(name) => match labels.rev().position(|l| l.as_ref() == Some(name)) {
+            Some(i) => n - 1 - i,
+            None => panic!("parse error: Invalid {what} to unknown label {name}"),
+        },
+    }
+}
+
+// The literals a `case` clause matches.
+fn parse_case_datums(s: &Sexp) -> Vec<Expr> {
+    let datums = match s {
+        Sexp::List(datums, _) => datums,
+        _ => panic!("parse error: Invalid case datums \"{}\"", s),
+    };
+    datums
+        .iter()
+        .map(|d| match parse_expr(d) {
+            e @ (Expr::Number(_) | Expr::Float(_) | Expr::Boolean(_)) => e,
+            _ => panic!("parse error: Invalid case datum \"{}\", expected a number or boolean", d),
+        })
+        .collect()
+}
+
+// A `match` pattern: `_`, a variable, a literal, or a constructor applied
+// to patterns, like `(Rect w _)` or `(Red)`. `bound` has the variables of
+// the rest of the pattern, since each can only be bound once.
+fn parse_pattern(s: &Sexp, bound: &mut Vec<String>) -> Pattern {
+    match s {
+        Sexp::Atom(S(name), _) if name == "_" => Pattern::Wild,
+        Sexp::Atom(S(name), _) if name == "true" || name == "false" => Pattern::Lit(parse_expr(s)),
+        Sexp::Atom(I(_) | F(_) | Str(_), _) => Pattern::Lit(parse_expr(s)),
+        Sexp::Atom(S(name), _) => {
+            if RESERVED_WORDS.contains(&name.as_str()) {
+                panic!("parse error: Invalid keyword \"{:?}\" matches reserved word", name);
+            }
+            if bound.contains(name) {
+                panic!("parse error: Invalid pattern, {} is bound twice", name);
+            }
+            bound.push(name.clone());
+            Pattern::Var(name.clone())
+        }
+        Sexp::List(vec, pos) => match &vec[..] {
+            [Sexp::Atom(S(ctor), _), fields @ ..] if !RESERVED_WORDS.contains(&ctor.as_str()) && ctor != "_" => {
+                Pattern::Ctor(ctor.clone(), fields.iter().map(|f| parse_pattern(f, bound)).collect(), *pos)
+            }
+            _ => panic!("parse error: Invalid pattern \"{}\"", s),
+        },
+    }
+}
+
+fn is_data_define(s: &Sexp) -> bool {
+    match s {
+        Sexp::List(vec, _) => matches!(vec.first(), Some(Sexp::Atom(S(op), _)) if op == "data"),
+        _ => false,
+    }
+}
+
+// A `(data Name (Ctor field ...) ...)` declaration.
+fn parse_data(s: &Sexp) -> Data {
+    let name = |n: &Sexp| match n {
+        Sexp::Atom(S(n), _) if RESERVED_WORDS.contains(&n.as_str()) => {
+            panic!("parse error: Invalid keyword \"{:?}\" matches reserved word", n)
+        }
+        Sexp::Atom(S(n), _) if n != "_" => n.clone(),
+        _ => panic!("parse error: Invalid data declaration \"{}\"", s),
+    };
+    match s {
+        Sexp::List(vec, _) if vec.len() >= 3 => {
+            let ctors = vec[2..]
+                .iter()
+                .map(|ctor| match ctor {
+                    Sexp::List(parts, _) if !parts.is_empty() => {
+                        let ctor = name(&parts[0]);
+                        if RESERVED_LABELS.contains(&ctor.as_str()) {
+                            panic!("parse error: Invalid function definition with reserved label function name");
+                        }
+                        (ctor, parts[1..].iter().map(name).collect())
+                    }
+                    _ => panic!("parse error: Invalid constructor \"{}\"", ctor),
+                })
+                .collect();
+            Data { name: name(&vec[1]), ctors }
+        }
+        _ => panic!("parse error: Invalid data declaration \"{}\"", s),
+    }
+}
+
+// The constructor functions of a `data` type, which take its fields in
+// order.
+fn data_constructors(data: &Data) -> Vec<Definition> {
+    data.ctors
+        .iter()
+        .map(|(ctor, fields)| {
+            let mut args = vec![ctor.clone()];
+            args.extend(fields.iter().cloned());
+            let variant = Variant { data: data.name.clone(), ctor: ctor.clone() };
+            let construct = Expr::Construct(variant, fields.iter().map(|f| Expr::Id(f.clone())).collect());
+            Definition::Func("fun".to_string(), args, construct)
+        })
+        .collect()
+}
+
 fn is_func_define(s: &Sexp) -> bool {
     match s {
-        Sexp::List(vec) => match &vec[..] {
-            [Sexp::Atom(S(s)), Sexp::List(_), _] if s == "fun" => true,
+        Sexp::List(vec, _) => match &vec[..] {
+            [Sexp::Atom(S(s), _), Sexp::List(_, _), _] if s == "fun" => true,
+            [Sexp::Atom(S(s), _), Sexp::List(_, _), Sexp::Atom(S(colon), _), _, _] if s == "fun" && colon == ":" => true,
             _ => false,
         },
         _ => false,
     }
 }
 
-fn parse_program(s: &Sexp) -> Program {
+// Any form headed by `define`, so that malformed ones are reported as such.
+fn is_global_define(s: &Sexp) -> bool {
+    match s {
+        Sexp::List(vec, _) => matches!(vec.first(), Some(Sexp::Atom(S(op), _)) if op == "define"),
+        _ => false,
+    }
+}
+
+// The name and initializer of a `(define name expr)`.
+fn parse_global(s: &Sexp) -> (String, Expr) {
+    match s {
+        Sexp::List(vec, _) => match &vec[..] {
+            [_, Sexp::Atom(S(name), _), e] => {
+                if RESERVED_WORDS.contains(&name.as_str()) {
+                    panic!("parse error: Invalid keyword \"{:?}\" matches reserved word", name);
+                }
+                (name.clone(), desugar::expr(parse_expr(e)))
+            }
+            _ => panic!("parse error: Invalid define \"{}\"", s),
+        },
+        _ => panic!("parse error: Invalid define \"{}\"", s),
+    }
+}
+
+// Parses the program `s`, read from `file`, and compiles its matches.
+fn parse_program(s: &Sexp, file: &str) -> Program {
   match s {
-    Sexp::List(vec) => {
+    Sexp::List(vec, _) => {
       let mut defs: Vec<Definition> = vec![];
+      let mut globals = vec![];
+      let mut datas: Vec<Data> = vec![];
       for (i, sub_expr) in vec.iter().enumerate() {
         if is_func_define(sub_expr) {
           defs.push(parse_definition(sub_expr));
         }
+        else if is_global_define(sub_expr) {
+          globals.push(parse_global(sub_expr));
+        }
+        else if is_data_define(sub_expr) {
+          let data = parse_data(sub_expr);
+          if datas.iter().any(|d| d.name == data.name) {
+            panic!("parse error: Duplicate data type {}", data.name);
+          }
+          defs.extend(data_constructors(&data));
+          datas.push(data);
+        }
         else {
           if i != vec.len() - 1 {
             panic!("parse error: Invalid program, main is not the last element")
           }
-          let main = parse_expr(sub_expr);
-          return Program { defs, main };
+          let main = desugar::expr(parse_expr(sub_expr));
+          let mut program = Program { defs, globals, main };
+          for Definition::Func(_, _, body) in &mut program.defs {
+            matching::lower(body, &datas, file);
+          }
+          for (_, init) in &mut program.globals {
+            matching::lower(init, &datas, file);
+          }
+          matching::lower(&mut program.main, &datas, file);
+          check_globals(&program);
+          return program;
         }
       }
-      panic!("parse error: Invalid program, find not main: \"{:?}\"", s)
+      panic!("parse error: Invalid program, find not main: \"{}\"", s)
     }
-    _ => panic!("parse error: Invalid program, program is not a list: \"{:?}\"", s),
+    _ => panic!("parse error: Invalid program, program is not a list: \"{}\"", s),
   }
 }
 
 fn parse_definition(s: &Sexp) -> Definition {
-    match s {
-        Sexp::List(vec) => match &vec[..] {
-            [Sexp::Atom(S(name)), Sexp::List(arg_names), e] => {
-                let mut args = vec![];
-                for arg_name in arg_names {
-                    match arg_name {
-                        Sexp::Atom(S(s)) => {
-                            if RESERVED_WORDS.contains(&s.as_str()) {
-                                panic!("parse error: Invalid keyword \"{:?}\" matches reserved word", s);
-                            }
-                            args.push(s.clone());
-                        }
-                        _ => panic!("parse error: Invalid arg \"{:?}\"", arg_name),
-                    }
-                }
-                if args.len() == 0 {
-                    panic!("parse error: Invalid function definition without function name");
-                }
-                if RESERVED_LABELS.contains(&args[0].as_str()) {
-                    panic!("parse error: Invalid function definition with reserved label function name");
-                }
-                println!("Fn name: {name}");
-                Definition::Func(name.clone(), args, parse_expr(e))
+    let (name, header, result, e) = match s {
+        Sexp::List(vec, _) => match &vec[..] {
+            [Sexp::Atom(S(name), _), Sexp::List(header, _), e] => (name, header, None, e),
+            [Sexp::Atom(S(name), _), Sexp::List(header, _), Sexp::Atom(S(colon), _), ty, e] if colon == ":" => {
+                (name, header, Some(parse_type(ty)), e)
             }
-            _ => panic!("parse error: Invalid definition \"{:?}\"", s),
+            _ => panic!("parse error: Invalid definition \"{}\"", s),
         },
-        _ => panic!("parse error: Invalid definition \"{:?}\"", s),
+        _ => panic!("parse error: Invalid definition \"{}\"", s),
+    };
+    let mut args = vec![];
+    let mut annotated = vec![];
+    for arg_name in header {
+        let (arg, ty) = match arg_name {
+            Sexp::Atom(S(s), _) => (s, None),
+            // the function name can't be annotated
+            Sexp::List(vec, _) if !args.is_empty() => match &vec[..] {
+                [Sexp::Atom(S(s), _), Sexp::Atom(S(colon), _), ty] if colon == ":" => (s, Some(parse_type(ty))),
+                _ => panic!("parse error: Invalid arg \"{}\"", arg_name),
+            },
+            _ => panic!("parse error: Invalid arg \"{}\"", arg_name),
+        };
+        if RESERVED_WORDS.contains(&arg.as_str()) {
+            panic!("parse error: Invalid keyword \"{:?}\" matches reserved word", arg);
+        }
+        if let Some(ty) = ty {
+            annotated.push((arg.clone(), ty));
+        }
+        args.push(arg.clone());
     }
+    if args.len() == 0 {
+        panic!("parse error: Invalid function definition without function name");
+    }
+    if RESERVED_LABELS.contains(&args[0].as_str()) {
+        panic!("parse error: Invalid function definition with reserved label function name");
+    }
+    let mut body = desugar::expr(parse_expr(e));
+    if let Some((ty, pos)) = result {
+        body = check_result(body, &Annotation { ty, func: args[0].clone(), param: None, pos }, true);
+    }
+    // each annotated parameter is checked once on entry
+    if !annotated.is_empty() {
+        let mut exprs: Vec<Expr> = annotated
+            .into_iter()
+            .map(|(arg, (ty, pos))| {
+                let annotation = Annotation { ty, func: args[0].clone(), param: Some(arg.clone()), pos };
+                Expr::Check(Box::new(Expr::Id(arg)), annotation)
+            })
+            .collect();
+        exprs.push(body);
+        body = Expr::Block(exprs);
+    }
+    Definition::Func(name.clone(), args, body)
+}
+
+fn parse_type(s: &Sexp) -> (Ty, Pos) {
+    match s {
+        Sexp::Atom(S(ty), pos) => match ty.as_str() {
+            "Num" => (Ty::Num, *pos),
+            "Bool" => (Ty::Bool, *pos),
+            "Str" => (Ty::Str, *pos),
+            _ => panic!("parse error: Invalid type annotation \"{}\", expected Num, Bool or Str", s),
+        },
+        _ => panic!("parse error: Invalid type annotation \"{}\", expected Num, Bool or Str", s),
+    }
+}
+
+// Checks the result of a function's body against its annotation where the
+// body produces it, so that tail calls to the function itself stay tail
+// calls: their result is checked when they return it. Tail calls to other
+// functions are checked like any other value. `own` is false where a local
+// function hides the annotated one.
+fn check_result(e: Expr, annotation: &Annotation, own: bool) -> Expr {
+    match e {
+        Expr::If(cond, thn, els, pos) => Expr::If(
+            cond,
+            Box::new(check_result(*thn, annotation, own)),
+            Box::new(check_result(*els, annotation, own)),
+            pos,
+        ),
+        Expr::Let(bindings, body, pos) => Expr::Let(bindings, Box::new(check_result(*body, annotation, own)), pos),
+        Expr::Block(mut exprs) if !exprs.is_empty() => {
+            let last = exprs.pop().unwrap();
+            exprs.push(check_result(last, annotation, own));
+            Expr::Block(exprs)
+        }
+        Expr::LocalFuns(defs, body) => {
+            let own = own && defs.iter().all(|Definition::Func(_, args, _)| args[0] != annotation.func);
+            Expr::LocalFuns(defs, Box::new(check_result(*body, annotation, own)))
+        }
+        Expr::Match(scrutinee, clauses, pos) => {
+            let clauses = clauses.into_iter().map(|(pattern, body)| (pattern, check_result(body, annotation, own))).collect();
+            Expr::Match(scrutinee, clauses, pos)
+        }
+        Expr::Call(name, args, pos) if own && name == annotation.func => Expr::Call(name, args, pos),
+        e => Expr::Check(Box::new(e), annotation.clone()),
+    }
+}
+
+// Rejects globals defined twice, and globals used before their `define`
+// has run: by the initializer of an earlier global, either directly or in
+// a function it calls. Functions and main run after every initializer.
+fn check_globals(p: &Program) {
+  let mut seen = HashSet::new();
+  for (name, _) in &p.globals {
+    if !seen.insert(name) {
+      panic!("parse error: Duplicate global definition for {}", name);
+    }
+  }
+  // the names each function uses and the functions it calls
+  let mut funcs = std::collections::HashMap::new();
+  for Definition::Func(_, args, body) in &p.defs {
+    let (mut uses, mut calls) = (vec![], vec![]);
+    global_uses(body, &mut args[1..].to_vec(), &mut uses, &mut calls);
+    funcs.insert(args[0].as_str(), (uses, calls));
+  }
+  for (i, (name, init)) in p.globals.iter().enumerate() {
+    let later = |used: &String| p.globals[i..].iter().any(|(g, _)| g == used);
+    let (mut uses, mut calls) = (vec![], vec![]);
+    global_uses(init, &mut vec![], &mut uses, &mut calls);
+    if let Some(used) = uses.iter().find(|u| later(u)) {
+      panic!("parse error: Invalid use of global {} before its definition, in the definition of {}", used, name);
+    }
+    for callee in &calls {
+      // every function the call may reach
+      let mut reached: Vec<&str> = vec![callee];
+      let mut next = 0;
+      while next < reached.len() {
+        if let Some((uses, calls)) = funcs.get(reached[next]) {
+          if let Some(used) = uses.iter().find(|u| later(u)) {
+            panic!("parse error: Invalid use of global {} before its definition, in the definition of {} through a call to {}", used, name, callee);
+          }
+          for c in calls {
+            if !reached.contains(&c.as_str()) {
+              reached.push(c);
+            }
+          }
+        }
+        next += 1;
+      }
+    }
+  }
+}
+
+// Collects the free names `e` reads or assigns, other than those `bound`
+// by enclosing `let`s and parameters, and the functions it calls.
+fn global_uses(e: &Expr, bound: &mut Vec<String>, uses: &mut Vec<String>, calls: &mut Vec<String>) {
+  match e {
+    Expr::Number(_) | Expr::Boolean(_) | Expr::Float(_) | Expr::Str(_) => {}
+    Expr::Input() | Expr::InputCount() | Expr::Continue(..) => {}
+    Expr::Id(name) => {
+      if !bound.contains(name) {
+        uses.push(name.clone());
+      }
+    }
+    Expr::Set(name, e, _) => {
+      if !bound.contains(name) {
+        uses.push(name.clone());
+      }
+      global_uses(e, bound, uses, calls);
+    }
+    Expr::InputAt(e, _) | Expr::UnOp(_, e, _) | Expr::Loop(_, e) | Expr::Break(_, e, _) | Expr::Print(e, _) | Expr::Check(e, _) => {
+      global_uses(e, bound, uses, calls)
+    }
+    Expr::IsVariant(e, _) | Expr::Field(e, _, _) | Expr::MatchFail(e, _) => global_uses(e, bound, uses, calls),
+    Expr::BinOp(_, lhs, rhs, _) => {
+      global_uses(lhs, bound, uses, calls);
+      global_uses(rhs, bound, uses, calls);
+    }
+    Expr::Substring(a, b, c, _) | Expr::If(a, b, c, _) => {
+      global_uses(a, bound, uses, calls);
+      global_uses(b, bound, uses, calls);
+      global_uses(c, bound, uses, calls);
+    }
+    Expr::Let(bindings, body, _) => {
+      let base = bound.len();
+      for (name, e) in bindings {
+        global_uses(e, bound, uses, calls);
+        bound.push(name.clone());
+      }
+      global_uses(body, bound, uses, calls);
+      bound.truncate(base);
+    }
+    Expr::Block(exprs) | Expr::And(exprs) | Expr::Or(exprs) | Expr::Construct(_, exprs) => {
+      for e in exprs {
+        global_uses(e, bound, uses, calls);
+      }
+    }
+    Expr::Call(fname, args, _) => {
+      calls.push(fname.clone());
+      for e in args {
+        global_uses(e, bound, uses, calls);
+      }
+    }
+    // what the functions use counts as used here, and calls to them don't
+    // reach the program's functions of the same name
+    Expr::LocalFuns(defs, body) => {
+      let first_call = calls.len();
+      for Definition::Func(_, args, e) in defs {
+        let base = bound.len();
+        bound.extend(args[1..].iter().cloned());
+        global_uses(e, bound, uses, calls);
+        bound.truncate(base);
+      }
+      global_uses(body, bound, uses, calls);
+      let inner = calls.split_off(first_call);
+      calls.extend(inner.into_iter().filter(|c| defs.iter().all(|Definition::Func(_, args, _)| &args[0] != c)));
+    }
+    Expr::Match(..) => unreachable!("matches are compiled to decision trees before checking"),
+    Expr::Sugar(..) => unreachable!("derived forms are desugared before checking"),
+  }
+}
+
+// Whether `e` may `set!` the variable `name`, taking no account of
+// shadowing.
+fn assigns(e: &Expr, name: &str) -> bool {
+  match e {
+    Expr::Number(_) | Expr::Boolean(_) | Expr::Float(_) | Expr::Str(_) => false,
+    Expr::Input() | Expr::InputCount() | Expr::Id(_) | Expr::Continue(..) => false,
+    Expr::Set(var, e, _) => var == name || assigns(e, name),
+    Expr::InputAt(e, _) | Expr::UnOp(_, e, _) | Expr::Loop(_, e) | Expr::Break(_, e, _) | Expr::Print(e, _) | Expr::Check(e, _) => {
+      assigns(e, name)
+    }
+    Expr::IsVariant(e, _) | Expr::Field(e, _, _) | Expr::MatchFail(e, _) => assigns(e, name),
+    Expr::BinOp(_, lhs, rhs, _) => assigns(lhs, name) || assigns(rhs, name),
+    Expr::Substring(a, b, c, _) | Expr::If(a, b, c, _) => assigns(a, name) || assigns(b, name) || assigns(c, name),
+    Expr::Let(bindings, body, _) => bindings.iter().any(|(_, e)| assigns(e, name)) || assigns(body, name),
+    Expr::Block(exprs) | Expr::And(exprs) | Expr::Or(exprs) | Expr::Call(_, exprs, _) | Expr::Construct(_, exprs) => {
+      exprs.iter().any(|e| assigns(e, name))
+    }
+    Expr::LocalFuns(defs, body) => {
+      defs.iter().any(|Definition::Func(_, _, e)| assigns(e, name)) || assigns(body, name)
+    }
+    Expr::Match(..) => unreachable!("matches are compiled to decision trees before codegen"),
+    Expr::Sugar(..) => unreachable!("derived forms are desugared before codegen"),
+  }
 }
 
 fn depth(e: &Expr) -> i32 {
   match e {
     Expr::Number(_) => 0,
     Expr::Boolean(_) => 0,
+    Expr::Float(_) => 0,
+    Expr::Str(_) => 0,
     Expr::Input() => 0,
+    Expr::InputAt(expr, _) => depth(expr),
+    Expr::InputCount() => 0,
     Expr::Id(_) => 0,
-    Expr::Let(bindings, body) => {
+    Expr::Let(bindings, body, _) => {
       let mut d = bindings.len() as i32;  // bindings depth
       for (i, (_, expr)) in bindings.iter().enumerate() {
         d = d.max(depth(expr) + i as i32); // binding expr depth
       }
       d + depth(body)
     },
-    Expr::UnOp(_, expr) => depth(expr),
-    Expr::BinOp(_, lhs, rhs) => depth(rhs).max(1 + depth(lhs)),
-    Expr::Set(_, expr) => depth(expr),
-    Expr::If(cond, thn, els) => depth(cond).max(depth(thn)).max(depth(els)),
+    // arithmetic keeps its operands and rdi around the bignum fallback
+    Expr::UnOp(Op1::Add1 | Op1::Sub1, expr, _) => depth(expr).max(3),
+    Expr::UnOp(Op1::Float | Op1::Truncate, expr, _) => depth(expr).max(1),
+    Expr::UnOp(_, expr, _) => depth(expr),
+    // the string operations also save rdi around their runtime call
+    Expr::BinOp(Op2::StringAppend | Op2::StringEqual | Op2::Equal, lhs, rhs, _) => depth(rhs).max(1 + depth(lhs)).max(2),
+    Expr::BinOp(Op2::BitAnd | Op2::BitOr | Op2::BitXor | Op2::ShiftLeft | Op2::ShiftRight, lhs, rhs, _) => depth(rhs).max(1 + depth(lhs)),
+    Expr::BinOp(_, lhs, rhs, _) => depth(rhs).max(1 + depth(lhs)).max(3),
+    Expr::Substring(s, start, end, _) => depth(s).max(1 + depth(start)).max(2 + depth(end)).max(4),
+    Expr::Set(_, expr, _) => depth(expr),
+    Expr::If(cond, thn, els, _) => depth(cond).max(depth(thn)).max(depth(els)),
     Expr::Block(exprs) => {
       let mut d = 0;
       for expr in exprs {
@@ -244,118 +979,243 @@ fn depth(e: &Expr) -> i32 {
       }
       d
     },
-    Expr::Loop(expr) => depth(expr),
-    Expr::Break(expr) => depth(expr),
-    Expr::Call(_, exprs) => {
+    Expr::And(exprs) | Expr::Or(exprs) => exprs.iter().map(depth).max().unwrap_or(0),
+    Expr::Loop(_, expr) => depth(expr),
+    Expr::Break(_, expr, _) => depth(expr),
+    Expr::Continue(..) => 0,
+    Expr::Call(_, exprs, _) => {
       let mut d = exprs.len() as i32;
       for (i, expr) in exprs.iter().enumerate() {
         d = d.max(depth(expr) + i as i32); // binding expr depth
       }
       d
     },
-    Expr::Print(expr) => depth(expr) + 2,
+    Expr::Print(expr, _) => depth(expr) + 2,
+    // the functions have frames of their own
+    Expr::LocalFuns(_, body) => depth(body),
+    Expr::Check(e, _) => depth(e),
+    // the fields are laid out in a row, and rdi is kept above them
+    Expr::Construct(_, exprs) => {
+      let mut d = exprs.len() as i32 + 1;
+      for (i, expr) in exprs.iter().enumerate() {
+        d = d.max(depth(expr) + i as i32);
+      }
+      d
+    },
+    Expr::IsVariant(e, _) | Expr::Field(e, _, _) | Expr::MatchFail(e, _) => depth(e),
+    Expr::Match(..) => unreachable!("matches are compiled to decision trees before codegen"),
+    Expr::Sugar(..) => unreachable!("derived forms are desugared before codegen"),
   }
 }
 
 fn parse_expr(s: &Sexp) -> Expr {
   match s {
     // number
-    Sexp::Atom(I(n)) => {
+    Sexp::Atom(I(n), _) => {
         let i = i64::try_from(*n);
         match i {
             Err(e) => panic!("Invalid operand {s}, error {e}"),
             Ok(f) => Expr::Number(f),
         }
     }
+    Sexp::Atom(F(x), _) => Expr::Float(*x),
     // boolean
-    Sexp::Atom(S(s)) if s == "true" => Expr::Boolean(true),
-    Sexp::Atom(S(s)) if s == "false" => Expr::Boolean(false),
-    Sexp::Atom(S(s)) if s == "input" => Expr::Input(),
+    // string
+    Sexp::Atom(Str(s), _) => Expr::Str(s.clone()),
+    Sexp::Atom(S(s), _) if s == "true" => Expr::Boolean(true),
+    Sexp::Atom(S(s), _) if s == "false" => Expr::Boolean(false),
+    Sexp::Atom(S(s), _) if s == "input" => Expr::Input(),
+    Sexp::Atom(S(s), _) if s == "input-count" => Expr::InputCount(),
     // identifier
-    Sexp::Atom(S(s)) => Expr::Id(s.clone()),
+    Sexp::Atom(S(s), _) => Expr::Id(s.clone()),
     // let
-    Sexp::List(vec) => {
+    Sexp::List(vec, pos) => {
       match &vec[..] {
         // let
-        [Sexp::Atom(S(op)), Sexp::List(vec), e] if op == "let" => {
+        [Sexp::Atom(S(op), _), Sexp::List(vec, _), e] if op == "let" => {
           let mut binds = vec![];
           for bind in vec {
-            binds.push(parse_bind(&bind));
+            binds.push(parse_bind(bind));
           }
-          Expr::Let(binds, Box::new(parse_expr(&e)))
+          Expr::Let(binds, Box::new(parse_expr(e)), *pos)
         },
+        // indexed input
+        [Sexp::Atom(S(op), _), e] if op == "input" => Expr::InputAt(Box::new(parse_expr(e)), *pos),
         // op1
-        [Sexp::Atom(S(op)), e] if op == "add1" => Expr::UnOp(Op1::Add1, Box::new(parse_expr(&e))),
-        [Sexp::Atom(S(op)), e] if op == "sub1" => Expr::UnOp(Op1::Sub1, Box::new(parse_expr(&e))),
-        [Sexp::Atom(S(op)), e] if op == "isnum" => Expr::UnOp(Op1::IsNum, Box::new(parse_expr(&e))),
-        [Sexp::Atom(S(op)), e] if op == "isbool" => Expr::UnOp(Op1::IsBool, Box::new(parse_expr(&e))),
+        [Sexp::Atom(S(op), _), e] if op == "add1" => Expr::UnOp(Op1::Add1, Box::new(parse_expr(e)), *pos),
+        [Sexp::Atom(S(op), _), e] if op == "sub1" => Expr::UnOp(Op1::Sub1, Box::new(parse_expr(e)), *pos),
+        [Sexp::Atom(S(op), _), e] if op == "isnum" => Expr::UnOp(Op1::IsNum, Box::new(parse_expr(e)), *pos),
+        [Sexp::Atom(S(op), _), e] if op == "isbool" => Expr::UnOp(Op1::IsBool, Box::new(parse_expr(e)), *pos),
+        [Sexp::Atom(S(op), _), e] if op == "string-length" => Expr::UnOp(Op1::StringLength, Box::new(parse_expr(e)), *pos),
+        [Sexp::Atom(S(op), _), e] if op == "float" => Expr::UnOp(Op1::Float, Box::new(parse_expr(e)), *pos),
+        [Sexp::Atom(S(op), _), e] if op == "truncate" => Expr::UnOp(Op1::Truncate, Box::new(parse_expr(e)), *pos),
+        [Sexp::Atom(S(op), _), e] if op == "not" => Expr::UnOp(Op1::Not, Box::new(parse_expr(e)), *pos),
+        [Sexp::Atom(S(op), _), exprs @ ..] if op == "and" => Expr::And(exprs.iter().map(parse_expr).collect()),
+        [Sexp::Atom(S(op), _), exprs @ ..] if op == "or" => Expr::Or(exprs.iter().map(parse_expr).collect()),
         // op2
-        [Sexp::Atom(S(op)), e1, e2] if op == "+" => Expr::BinOp(Op2::Plus, Box::new(parse_expr(&e1)), Box::new(parse_expr(&e2))),
-        [Sexp::Atom(S(op)), e1, e2] if op == "-" => Expr::BinOp(Op2::Minus, Box::new(parse_expr(&e1)), Box::new(parse_expr(&e2))),
-        [Sexp::Atom(S(op)), e1, e2] if op == "*" => Expr::BinOp(Op2::Times, Box::new(parse_expr(&e1)), Box::new(parse_expr(&e2))),
-        [Sexp::Atom(S(op)), e1, e2] if op == "<" => Expr::BinOp(Op2::Less, Box::new(parse_expr(&e1)), Box::new(parse_expr(&e2))),
-        [Sexp::Atom(S(op)), e1, e2] if op == ">" => Expr::BinOp(Op2::Greater, Box::new(parse_expr(&e1)), Box::new(parse_expr(&e2))),
-        [Sexp::Atom(S(op)), e1, e2] if op == ">=" => Expr::BinOp(Op2::GreaterEqual, Box::new(parse_expr(&e1)), Box::new(parse_expr(&e2))),
-        [Sexp::Atom(S(op)), e1, e2] if op == "<=" => Expr::BinOp(Op2::LessEqual, Box::new(parse_expr(&e1)), Box::new(parse_expr(&e2))),
-        [Sexp::Atom(S(op)), e1, e2] if op == "=" => Expr::BinOp(Op2::Equal, Box::new(parse_expr(&e1)), Box::new(parse_expr(&e2))),
+        [Sexp::Atom(S(op), _), e1, e2] if op == "+" => Expr::BinOp(Op2::Plus, Box::new(parse_expr(e1)), Box::new(parse_expr(e2)), *pos),
+        [Sexp::Atom(S(op), _), e1, e2] if op == "-" => Expr::BinOp(Op2::Minus, Box::new(parse_expr(e1)), Box::new(parse_expr(e2)), *pos),
+        [Sexp::Atom(S(op), _), e1, e2] if op == "*" => Expr::BinOp(Op2::Times, Box::new(parse_expr(e1)), Box::new(parse_expr(e2)), *pos),
+        [Sexp::Atom(S(op), _), e1, e2] if op == "<" => Expr::BinOp(Op2::Less, Box::new(parse_expr(e1)), Box::new(parse_expr(e2)), *pos),
+        [Sexp::Atom(S(op), _), e1, e2] if op == ">" => Expr::BinOp(Op2::Greater, Box::new(parse_expr(e1)), Box::new(parse_expr(e2)), *pos),
+        [Sexp::Atom(S(op), _), e1, e2] if op == ">=" => Expr::BinOp(Op2::GreaterEqual, Box::new(parse_expr(e1)), Box::new(parse_expr(e2)), *pos),
+        [Sexp::Atom(S(op), _), e1, e2] if op == "<=" => Expr::BinOp(Op2::LessEqual, Box::new(parse_expr(e1)), Box::new(parse_expr(e2)), *pos),
+        [Sexp::Atom(S(op), _), e1, e2] if op == "=" => Expr::BinOp(Op2::Equal, Box::new(parse_expr(e1)), Box::new(parse_expr(e2)), *pos),
+        [Sexp::Atom(S(op), _), e1, e2] if op == "string-append" => Expr::BinOp(Op2::StringAppend, Box::new(parse_expr(e1)), Box::new(parse_expr(e2)), *pos),
+        [Sexp::Atom(S(op), _), e1, e2] if op == "string=?" => Expr::BinOp(Op2::StringEqual, Box::new(parse_expr(e1)), Box::new(parse_expr(e2)), *pos),
+        [Sexp::Atom(S(op), _), e1, e2] if op == "/" => Expr::BinOp(Op2::Divide, Box::new(parse_expr(e1)), Box::new(parse_expr(e2)), *pos),
+        [Sexp::Atom(S(op), _), e1, e2] if op == "quotient" => Expr::BinOp(Op2::Quotient, Box::new(parse_expr(e1)), Box::new(parse_expr(e2)), *pos),
+        [Sexp::Atom(S(op), _), e1, e2] if op == "remainder" => Expr::BinOp(Op2::Remainder, Box::new(parse_expr(e1)), Box::new(parse_expr(e2)), *pos),
+        [Sexp::Atom(S(op), _), e1, e2] if op == "modulo" => Expr::BinOp(Op2::Modulo, Box::new(parse_expr(e1)), Box::new(parse_expr(e2)), *pos),
+        [Sexp::Atom(S(op), _), e1, e2] if op == "bitand" => Expr::BinOp(Op2::BitAnd, Box::new(parse_expr(e1)), Box::new(parse_expr(e2)), *pos),
+        [Sexp::Atom(S(op), _), e1, e2] if op == "bitor" => Expr::BinOp(Op2::BitOr, Box::new(parse_expr(e1)), Box::new(parse_expr(e2)), *pos),
+        [Sexp::Atom(S(op), _), e1, e2] if op == "bitxor" => Expr::BinOp(Op2::BitXor, Box::new(parse_expr(e1)), Box::new(parse_expr(e2)), *pos),
+        [Sexp::Atom(S(op), _), e1, e2] if op == "shl" => Expr::BinOp(Op2::ShiftLeft, Box::new(parse_expr(e1)), Box::new(parse_expr(e2)), *pos),
+        [Sexp::Atom(S(op), _), e1, e2] if op == "shr" => Expr::BinOp(Op2::ShiftRight, Box::new(parse_expr(e1)), Box::new(parse_expr(e2)), *pos),
+        // substring
+        [Sexp::Atom(S(op), _), e1, e2, e3] if op == "substring" => Expr::Substring(Box::new(parse_expr(e1)), Box::new(parse_expr(e2)), Box::new(parse_expr(e3)), *pos),
         // print
-        [Sexp::Atom(S(op)), e] if op == "print" => Expr::Print(Box::new(parse_expr(&e))),
+        [Sexp::Atom(S(op), _), e] if op == "print" => Expr::Print(Box::new(parse_expr(e)), *pos),
         // if
-        [Sexp::Atom(S(op)), e1, e2, e3] if op == "if" => Expr::If(Box::new(parse_expr(&e1)), Box::new(parse_expr(&e2)), Box::new(parse_expr(&e3))),
-        // loop / break
-        [Sexp::Atom(S(op)), e] if op == "loop" => Expr::Loop(Box::new(parse_expr(&e))),
-        [Sexp::Atom(S(op)), e] if op == "break" => Expr::Break(Box::new(parse_expr(&e))),
+        [Sexp::Atom(S(op), _), e1, e2, e3] if op == "if" => Expr::If(Box::new(parse_expr(e1)), Box::new(parse_expr(e2)), Box::new(parse_expr(e3)), *pos),
+        // derived forms
+        [Sexp::Atom(S(op), _), clauses @ ..] if op == "cond" => {
+          let (clauses, els) = parse_clauses(clauses, s, parse_expr);
+          Expr::Sugar(Box::new(Sugar::Cond(clauses, els)), *pos)
+        }
+        [Sexp::Atom(S(op), _), cond, body @ ..] if op == "when" => Expr::Sugar(Box::new(Sugar::When(parse_expr(cond), parse_body(body, s))), *pos),
+        [Sexp::Atom(S(op), _), cond, body @ ..] if op == "unless" => Expr::Sugar(Box::new(Sugar::Unless(parse_expr(cond), parse_body(body, s))), *pos),
+        [Sexp::Atom(S(op), _), key, clauses @ ..] if op == "case" => {
+          let (clauses, els) = parse_clauses(clauses, s, parse_case_datums);
+          Expr::Sugar(Box::new(Sugar::Case(parse_expr(key), clauses, els)), *pos)
+        }
+        [Sexp::Atom(S(op), _), scrutinee, clauses @ ..] if op == "match" => {
+          if clauses.is_empty() {
+            panic!("parse error: Invalid match without clauses \"{}\"", s);
+          }
+          let clauses = clauses
+            .iter()
+            .map(|clause| match clause {
+              Sexp::List(vec, _) if vec.len() >= 2 => (parse_pattern(&vec[0], &mut vec![]), parse_body(&vec[1..], clause)),
+              _ => panic!("parse error: Invalid clause \"{}\"", clause),
+            })
+            .collect();
+          Expr::Match(Box::new(parse_expr(scrutinee)), clauses, *pos)
+        }
+        [Sexp::Atom(S(op), _), Sexp::Atom(S(label), _), cond, body @ ..] if op == "while" && is_label(label) => {
+          Expr::Sugar(Box::new(Sugar::While(Some(label.clone()), parse_expr(cond), parse_body(body, s))), *pos)
+        }
+        [Sexp::Atom(S(op), _), cond, body @ ..] if op == "while" => {
+          Expr::Sugar(Box::new(Sugar::While(None, parse_expr(cond), parse_body(body, s))), *pos)
+        }
+        [Sexp::Atom(S(op), _), rest @ ..] if op == "for" => {
+          let (label, rest) = match rest {
+            [Sexp::Atom(S(label), _), rest @ ..] if is_label(label) => (Some(label.clone()), rest),
+            _ => (None, rest),
+          };
+          match rest {
+            [Sexp::List(range, _), body @ ..] => match &range[..] {
+              [Sexp::Atom(S(var), _), start, end] => {
+                if RESERVED_WORDS.contains(&var.as_str()) {
+                  panic!("parse error: Invalid keyword \"{:?}\" matches reserved word", var);
+                }
+                let sugar = Sugar::For(label, var.clone(), parse_expr(start), parse_expr(end), parse_body(body, s));
+                Expr::Sugar(Box::new(sugar), *pos)
+              }
+              _ => panic!("parse error: Invalid for range \"{}\"", rest[0]),
+            },
+            _ => panic!("parse error: Invalid for \"{}\"", s),
+          }
+        }
+        // loop / break / continue
+        [Sexp::Atom(S(op), _), Sexp::Atom(S(label), _), e] if op == "loop" && is_label(label) => Expr::Loop(Some(label.clone()), Box::new(parse_expr(e))),
+        [Sexp::Atom(S(op), _), e] if op == "loop" => Expr::Loop(None, Box::new(parse_expr(e))),
+        [Sexp::Atom(S(op), _), Sexp::Atom(S(label), _), e] if op == "break" && is_label(label) => Expr::Break(Some(label.clone()), Box::new(parse_expr(e)), *pos),
+        [Sexp::Atom(S(op), _), e] if op == "break" => Expr::Break(None, Box::new(parse_expr(e)), *pos),
+        [Sexp::Atom(S(op), _)] if op == "continue" => Expr::Continue(None, *pos),
+        [Sexp::Atom(S(op), _), Sexp::Atom(S(label), _)] if op == "continue" && is_label(label) => Expr::Continue(Some(label.clone()), *pos),
         // set
-        [Sexp::Atom(S(op)), Sexp::Atom(S(s)), e] if op == "set!" => Expr::Set(s.clone(), Box::new(parse_expr(&e))),
+        [Sexp::Atom(S(op), _), Sexp::Atom(S(s), _), e] if op == "set!" => Expr::Set(s.clone(), Box::new(parse_expr(e)), *pos),
         // block
-        [Sexp::Atom(S(op)), ..] if op == "block" => {
+        [Sexp::Atom(S(op), _), ..] if op == "block" => {
           let subexpr = &vec[1..];
           if subexpr.len() == 0 {
             panic!("parse error: Invalid Block with 0 subexpr");
           }
           else {
-            let mut binds = vec![];
-            for bind in subexpr {
-              binds.push(parse_expr(&bind));
-            }
-            Expr::Block(binds)  
+            parse_block(subexpr, s)
           }
         },
-        [Sexp::Atom(S(fname)), ..] if ! RESERVED_WORDS.contains(&fname.as_str()) => {
+        [Sexp::Atom(S(fname), _), ..] if ! RESERVED_WORDS.contains(&fname.as_str()) => {
           let subexpr = &vec[1..];
           let mut params = vec![];
           for param in subexpr {
-            params.push(parse_expr(&param));
+            params.push(parse_expr(param));
           }
-          Expr::Call(fname.clone(), params)
+          Expr::Call(fname.clone(), params, *pos)
         },
-        _ => panic!("parse error: Invalid op {:?}", vec),
+        _ => panic!("parse error: Invalid op {}", s),
       }
     }
-    _ => panic!("parse error: Invalid Sexp \"{:?}\"", s),
   }
 }
 
-fn compile_program(p: &Program) -> String {
-  let mut instr: Vec<Instr> = vec![];
-  let mut label_id: i64 = 0;
-  let mut loop_stack: Vec<String> = vec![];
-  let mut func_dic: im::HashMap<String, i32> = im::HashMap::new();
-  // register the function definitions
-  for def in &p.defs {
-    register_definition(def, &mut func_dic);
-  }
-  // compile the function definitions
-  for def in &p.defs {
-    instr.extend(compile_definition(def, &mut label_id, &mut func_dic));
+// What compiling a program threads through every function and expression:
+// the error sites, string and float literals and debug info it collects,
+// the label counter, the arities of the functions it can call, the
+// program's globals, and whether it is `typed`, having passed
+// `types::check`.
+struct CodegenCtx<'a> {
+  sites: &'a mut Vec<ErrorSite>,
+  strings: &'a mut Vec<String>,
+  floats: &'a mut Vec<f64>,
+  label_id: i64,
+  func_dic: im::HashMap<String, i32>,
+  globals: &'a [String],
+  typed: bool,
+  dbg: &'a mut debug::DebugInfo,
+}
+
+// Where the expression being compiled sits: in the main function or not,
+// in tail position of a function taking `tail_param_num` arguments or not,
+// and the size of the enclosing frame.
+#[derive(Clone, Copy)]
+struct Frame {
+  is_main: bool,
+  is_tail: bool,
+  tail_param_num: i32,
+  frame_size: i32,
+}
+
+impl Frame {
+  // The same frame, out of tail position.
+  fn non_tail(self) -> Frame {
+    Frame { is_tail: false, tail_param_num: 0, ..self }
   }
-  // compile the main function
-  let main_depth = (depth(&p.main).max(0) / 2) * 2 + 1;
+}
+
+// `externs` are the functions of other objects, with their arities, when
+// compiled with --separate.
+fn compile_program(p: &Program, externs: &[(String, usize)], typed: bool, sites: &mut Vec<ErrorSite>, strings: &mut Vec<String>, floats: &mut Vec<f64>, dbg: &mut debug::DebugInfo) -> String {
+  let mut loop_stack: Vec<LoopTarget> = vec![];
+  let globals: Vec<String> = p.globals.iter().map(|(name, _)| name.clone()).collect();
+  let mut ctx = CodegenCtx { sites, strings, floats, label_id: 0, func_dic: im::HashMap::new(), globals: &globals, typed, dbg };
+  let main_frame = Frame { is_main: true, is_tail: false, tail_param_num: 0, frame_size: 0 };
+  let mut instr = compile_functions(&p.defs, externs, &mut ctx);
+  // compile the main function, which starts by initializing the globals
+  let main_depth = p.globals.iter().map(|(_, e)| depth(e)).fold(depth(&p.main), i32::max);
+  let main_depth = (main_depth.max(0) / 2) * 2 + 1;
   instr.push(Instr::Label("our_code_starts_here".to_string()));
+  instr.extend(ctx.dbg.begin_function("our_code_starts_here", vec![]));
   instr.push(Instr::ISub(Val::Reg(Reg::RSP), Val::Const((main_depth * 8) as i64)));
-  instr.extend(compile_to_instrs(&p.main, 0, &HashMap::new(), &mut label_id, &mut loop_stack, &mut func_dic, true, false, 0, 0));
+  for (i, (_, init)) in p.globals.iter().enumerate() {
+    instr.extend(compile_to_instrs(init, 0, &Env::default(), &mut loop_stack, &mut ctx, main_frame));
+    instr.push(Instr::IMov(Val::Global(i), Val::Reg(Reg::RAX)));
+  }
+  instr.extend(compile_to_instrs(&p.main, 0, &Env::default(), &mut loop_stack, &mut ctx, main_frame));
   instr.push(Instr::IAdd(Val::Reg(Reg::RSP), Val::Const((main_depth * 8) as i64)));
   instr.push(Instr::Return());
+  instr.extend(ctx.dbg.end_scope());
 
   let mut program = String::new();
   for i in instr {
@@ -364,6 +1224,25 @@ fn compile_program(p: &Program) -> String {
   return program;
 }
 
+// Compiles the function definitions of a program or module, which can use
+// the program's globals.
+fn compile_functions(defs: &[Definition], externs: &[(String, usize)], ctx: &mut CodegenCtx) -> Vec<Instr> {
+  let mut instr = vec![];
+  // register the function definitions
+  for (name, arity) in externs {
+    ctx.func_dic.insert(name.clone(), *arity as i32);
+  }
+  for def in defs {
+    register_definition(def, &mut ctx.func_dic);
+  }
+  // compile the function definitions
+  for def in defs {
+    let Definition::Func(_, args, _) = def;
+    instr.extend(compile_definition(def, &args[0], None, ctx));
+  }
+  instr
+}
+
 fn register_definition(d: &Definition, func_dic :&mut im::HashMap<String, i32>) -> () {
   match d {
     Definition::Func(_, args, _) => {
@@ -379,19 +1258,32 @@ fn register_definition(d: &Definition, func_dic :&mut im::HashMap<String, i32>)
   }
 }
 
-fn compile_definition(d: &Definition, l :&mut i64, func_dic :&mut im::HashMap<String, i32>) -> Vec<Instr> {
+// Compiles the function at `label`. A local function sees the `outer`
+// scope it is defined in, through a static link passed before its
+// arguments. The error sites in its code get its name and frame size.
+fn compile_definition(d: &Definition, label: &str, outer: Option<&Env>, ctx: &mut CodegenCtx) -> Vec<Instr> {
     match d {
         Definition::Func(_, args, body) => {
-          let mut env = HashMap::new();
+          let first_site = ctx.sites.len();
+          let mut env = outer.map_or(Env::default(), Env::nested);
           if args.len() == 0 {
               panic!("parse error: Invalid function definition without function name");
           }
-          let name = args[0].clone();
-          let fn_depth = (depth(body).max(0) / 2) * 2 + 1;
+          let name = label.to_string();
+          // one slot above the temporaries counts the tail calls this frame
+          // has replaced, for stack traces
+          let fn_depth = (depth(body).max(0) / 2) * 2 + 3;
+          let first = if outer.is_some() {
+            env.link = Some(fn_depth as i64 + 1);
+            1
+          } else {
+            0
+          };
           // iterate through the args but skip the first element (which is name)
           let mut arg_names = vec![];
           for (i, arg) in args.iter().enumerate().skip(1) {
-            env.insert(arg.clone(), i as i64 + fn_depth as i64);
+            env.vars.insert(arg.clone(), (0, (i + first) as i64 + fn_depth as i64));
+            env.known.remove(arg);
             if arg_names.contains(&arg.clone()) {
               panic!("parse error: Duplicate argument name {}", arg);
             }
@@ -399,17 +1291,201 @@ fn compile_definition(d: &Definition, l :&mut i64, func_dic :&mut im::HashMap<St
           }
           let mut instrs = vec![];
           instrs.push(Instr::Label(name.clone()));
+          let params = arg_names.iter().map(|arg| (arg.clone(), env.vars[arg].1)).collect();
+          instrs.extend(ctx.dbg.begin_function(&name, params));
+          instrs.push(Instr::IMov(Val::Reg(Reg::RBX), Val::Const(0)));
+          // tail calls enter here with their own count in rbx
+          instrs.push(Instr::Label(tail_entry(&name)));
           instrs.push(Instr::ISub(Val::Reg(Reg::RSP), Val::Const((fn_depth * 8) as i64)));
-          instrs.append(&mut compile_to_instrs(body, 0, &env, l, &mut vec![], func_dic, false, true, (args.len() - 1) as i32, fn_depth * 8));
+          instrs.push(Instr::IMov(Val::RegOffset(Reg::RSP, (fn_depth - 1) as i64), Val::Reg(Reg::RBX)));
+          instrs.append(&mut compile_to_instrs(body, 0, &env, &mut vec![], ctx, Frame { is_main: false, is_tail: true, tail_param_num: (args.len() - 1 + first) as i32, frame_size: fn_depth * 8 }));
           instrs.push(Instr::IAdd(Val::Reg(Reg::RSP), Val::Const((fn_depth * 8) as i64)));
           instrs.push(Instr::Return());
+          instrs.extend(ctx.dbg.end_scope());
+          // the sites of local functions defined inside are already claimed
+          for site in ctx.sites[first_site..].iter_mut().filter(|site| site.frame_size == 0) {
+            site.func = args[0].clone();
+            site.frame_size = (fn_depth * 8) as i64;
+          }
           return instrs;
         }
     }
 }
 
+fn global_label(i: usize) -> String {
+  format!("snek_global{i}")
+}
+
+fn tail_entry(name: &str) -> String {
+  format!("{name}$tail")
+}
+
+// One object's worth of compiled code: a program, which starts at
+// `our_code_starts_here` and lists the site tables of the modules linked
+// with it, or, with an interface, a module, which exports the functions it
+// provides and its site table.
+struct Object<'a> {
+  code: String,
+  interface: Option<&'a modules::Interface>,
+  // functions defined in other objects, with their arities
+  externs: &'a [(String, usize)],
+  // the modules whose site tables a program lists
+  links: &'a [String],
+  // how many globals a program defines
+  globals: usize,
+  // whether a program was type checked, which makes it take only numbers
+  // as inputs
+  typed: bool,
+}
+
+impl Object<'_> {
+  fn to_asm(&self, sites: &[ErrorSite], strings: &[String], floats: &[f64], dbg: &debug::DebugInfo, file: &str, trap_overflow: bool) -> String {
+    let mut header = String::new();
+    for (name, _) in self.externs {
+      header.push_str(&format!("extern {}\nextern {}\n", name, tail_entry(name)));
+    }
+    let mut data = String::new();
+    match self.interface {
+      None => {
+        for link in self.links {
+          header.push_str(&format!("extern {}\n", site_table(link)));
+        }
+        header.push_str("global our_code_starts_here\nglobal snek_site_tables\nglobal snek_trap_overflow\nglobal snek_typed\n");
+        let tables: Vec<String> = std::iter::once("snek_site_count".to_string()).chain(self.links.iter().map(|l| site_table(l))).collect();
+        data.push_str(&format!("snek_trap_overflow:\n  dq {}\nsnek_typed:\n  dq {}\n", trap_overflow as i64, self.typed as i64));
+        data.push_str(&format!("snek_site_tables:\n  dq {}, {}\n", tables.len(), tables.join(", ")));
+        for i in 0..self.globals {
+          data.push_str(&format!("{}:\n  dq 0\n", global_label(i)));
+        }
+      }
+      Some(interface) => {
+        let own = format!("{}.", interface.prefix);
+        for (_, name, _) in interface.provides.iter().filter(|(_, name, _)| name.starts_with(&own)) {
+          header.push_str(&format!("global {}\nglobal {}\n", name, tail_entry(name)));
+        }
+        header.push_str(&format!("global {}\n", site_table(&interface.prefix)));
+        data.push_str(&format!("{}:\n", site_table(&interface.prefix)));
+      }
+    }
+    format!(
+      "
+section .text
+extern snek_error
+extern snek_print
+extern snek_string_append
+extern snek_substring
+extern snek_string_length
+extern snek_string_equal
+extern snek_arith
+extern snek_float
+extern snek_truncate
+extern snek_construct
+extern snek_equal
+{}throw_error:
+  mov r8, rsp
+  call snek_error
+{}{}
+section .data
+{}{}{}{}
+{}",
+      header,
+      self.code,
+      if dbg.enabled() { format!("{}:\n", debug::TEXT_END) } else { String::new() },
+      data,
+      sites_to_data(sites, file),
+      strings_to_data(strings),
+      floats_to_data(floats),
+      dbg.to_sections(file)
+    )
+  }
+}
+
+// The label of a module's site table.
+fn site_table(prefix: &str) -> String {
+  format!("{prefix}$sites")
+}
+
+// Combines separately compiled objects with the runtime into an executable,
+// the way the Makefile links a single one.
+fn link(out: &str, objects: &[String]) -> std::io::Result<()> {
+  let out = std::path::Path::new(out);
+  let dir = match out.parent() {
+    Some(dir) if dir != std::path::Path::new("") => dir,
+    _ => std::path::Path::new("."),
+  };
+  let name = out.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+  let lib = dir.join(format!("lib{name}.a"));
+  // `ar` would add to an old archive rather than replace it
+  std::fs::remove_file(&lib).ok();
+  let runtime = concat!(env!("CARGO_MANIFEST_DIR"), "/runtime/start.rs");
+  let steps = [
+    std::process::Command::new("ar").arg("rcs").arg(&lib).args(objects).status()?,
+    std::process::Command::new("rustc").arg("-L").arg(dir).arg(format!("-lour_code:{name}")).arg(runtime).arg("-o").arg(out).status()?,
+  ];
+  for status in steps {
+    if !status.success() {
+      eprintln!("Linking {} failed", out.display());
+      std::process::exit(1);
+    }
+  }
+  Ok(())
+}
+
+// Lays out the error site table read by `snek_error`: a count, then per site
+// the operator, location and function name as C string pointers, the frame
+// size, and for call sites the return address and argument bytes (0 otherwise).
+fn sites_to_data(sites: &[ErrorSite], file: &str) -> String {
+  let mut data = format!("snek_site_count:\n  dq {}\nsnek_sites:\n", sites.len());
+  for (i, site) in sites.iter().enumerate() {
+    let (ret, args) = match site.call_args {
+      Some(args) => (format!("snek_ret{i}"), args),
+      None => ("0".to_string(), 0),
+    };
+    data.push_str(&format!(
+      "snek_site{i}:\n  dq snek_site_op{i}, snek_site_loc{i}, snek_site_func{i}, {}, {ret}, {args}\n",
+      site.frame_size
+    ));
+  }
+  for (i, site) in sites.iter().enumerate() {
+    let loc = format!("{}:{}", file, site.pos);
+    data.push_str(&format!("snek_site_op{i}:\n  db {}\n", c_string_bytes(&site.op)));
+    data.push_str(&format!("snek_site_loc{i}:\n  db {}\n", c_string_bytes(&loc)));
+    data.push_str(&format!("snek_site_func{i}:\n  db {}\n", c_string_bytes(&site.func)));
+  }
+  data
+}
+
+// Lays out each string literal as its length followed by its bytes, at an
+// 8-byte aligned address so the low bits are free for the tag.
+fn strings_to_data(strings: &[String]) -> String {
+  let mut data = String::new();
+  for (i, s) in strings.iter().enumerate() {
+    data.push_str(&format!("align 8\nsnek_str{i}:\n  dq {}\n", s.len()));
+    if !s.is_empty() {
+      let bytes: Vec<String> = s.bytes().map(|b| b.to_string()).collect();
+      data.push_str(&format!("  db {}\n", bytes.join(", ")));
+    }
+  }
+  data
+}
+
+fn floats_to_data(floats: &[f64]) -> String {
+  let mut data = String::new();
+  for (i, x) in floats.iter().enumerate() {
+    data.push_str(&format!("align 8\nsnek_float{i}:\n  dq {}\n", x.to_bits() as i64));
+  }
+  data
+}
+
+fn c_string_bytes(s: &str) -> String {
+  let mut bytes: Vec<String> = s.bytes().map(|b| b.to_string()).collect();
+  bytes.push("0".to_string());
+  bytes.join(", ")
+}
+
 fn instr_to_str(i: &Instr) -> String {
   match i {
+      Instr::IMov(dst, Val::Label(label)) => format!("  lea {}, [rel {}]\n", val_to_str(dst), label),
       Instr::IMov(dst, src) => format!("  mov {}, {}\n", val_to_str(dst), val_to_str(src)),
       Instr::IAdd(dst, src) => format!("  add {}, {}\n", val_to_str(dst), val_to_str(src)),
       Instr::ISub(dst, src) => format!("  sub {}, {}\n", val_to_str(dst), val_to_str(src)),
@@ -422,13 +1498,23 @@ fn instr_to_str(i: &Instr) -> String {
       Instr::Je(s)  => format!("  je {s}\n"),
       Instr::Jge(s) => format!("  jge {s}\n"),
       Instr::Jle(s) => format!("  jle {s}\n"),
+      Instr::Jl(s) => format!("  jl {s}\n"),
+      Instr::Jg(s) => format!("  jg {s}\n"),
       Instr::Label(s) => format!("{s}:\n"),
       Instr::Sar(dst, cnt) => format!("  sar {}, {}\n", val_to_str(dst), val_to_str(cnt)),
       Instr::Jo(s) => format!("  jo {s}\n"),
       Instr::Call(s) => format!("  call {s}\n"),
       Instr::Return() => format!("  ret\n"),
-      Instr::ICMovo(dst, src) => format!("  cmovo {}, {}\n", val_to_str(dst), val_to_str(src)),
       Instr::ICMovne(dst, src) => format!("  cmovne {}, {}\n", val_to_str(dst), val_to_str(src)),
+      Instr::ICMovl(dst, src) => format!("  cmovl {}, {}\n", val_to_str(dst), val_to_str(src)),
+      Instr::ICMovge(dst, src) => format!("  cmovge {}, {}\n", val_to_str(dst), val_to_str(src)),
+      Instr::Lea(dst, label) => format!("  lea {}, [rel {}]\n", val_to_str(dst), label),
+      Instr::Or(dst, src) => format!("  or {}, {}\n", val_to_str(dst), val_to_str(src)),
+      Instr::Xor(dst, src) => format!("  xor {}, {}\n", val_to_str(dst), val_to_str(src)),
+      Instr::Shl(dst, cnt) => format!("  shl {}, {}\n", val_to_str(dst), val_to_str(cnt)),
+      Instr::Neg(dst) => format!("  neg {}\n", val_to_str(dst)),
+      Instr::Cqo() => "  cqo\n".to_string(),
+      Instr::IDiv(src) => format!("  idiv {}\n", val_to_str(src)),
   }
 }
 
@@ -440,6 +1526,11 @@ fn val_to_str(v: &Val) -> String {
           Reg::RCX => "rcx".to_string(),
           Reg::RSP => "rsp".to_string(),
           Reg::RDI => "rdi".to_string(),
+          Reg::RSI => "rsi".to_string(),
+          Reg::RDX => "rdx".to_string(),
+          Reg::R8 => "r8".to_string(),
+          Reg::R9 => "r9".to_string(),
+          Reg::CL => "cl".to_string(),
       },
       Val::Imm(n) => {
         let max_bound = 4611686018427387903 as i64;
@@ -458,30 +1549,422 @@ fn val_to_str(v: &Val) -> String {
           format!("[{}-{}]", val_to_str(&Val::Reg(r.clone())), (-n * 8).to_string())
         }
       }
+      Val::Label(label) => panic!("the address of {label} can only be loaded into a register"),
+      Val::Global(i) => format!("[rel {}]", global_label(*i)),
   }
 }
 
-fn check_not_bool(val :Val) -> Vec<Instr> {
+// On failure these load snek_error's arguments: rdi = error code,
+// rsi = offending value, rdx = error site id. This one accepts fixnums
+// only, so it stays in typed programs, where floats and bignums are
+// numbers too.
+fn check_not_bool(val :Val, site: i64) -> Vec<Instr> {
   vec![
-    Instr::IMov(Val::Reg(Reg::RBX), val),
+    Instr::IMov(Val::Reg(Reg::RBX), val.clone()),
     Instr::And(Val::Reg(Reg::RBX), Val::Const(1)),
     Instr::Cmp(Val::Reg(Reg::RBX), Val::Const(0)),
     Instr::IMov(Val::Reg(Reg::RBX), Val::Const(ERRCODE_INVALID_ARG)),
     Instr::ICMovne(Val::Reg(Reg::RDI), Val::Reg(Reg::RBX)),
+    Instr::IMov(Val::Reg(Reg::RBX), site_addr(site)),
+    Instr::ICMovne(Val::Reg(Reg::RDX), Val::Reg(Reg::RBX)),
+    Instr::ICMovne(Val::Reg(Reg::RSI), val),
     Instr::Jne("throw_error".to_string()),
   ]
 }
 
-fn check_not_overflow() -> Vec<Instr> {
+fn check_string(val :Val, site: i64) -> Vec<Instr> {
   vec![
-    Instr::IMov(Val::Reg(Reg::RBX), Val::Const(ERRCODE_OVERFLOW)),
-    Instr::ICMovo(Val::Reg(Reg::RDI), Val::Reg(Reg::RBX)),
-    Instr::Jo("throw_error".to_string()),
+    Instr::IMov(Val::Reg(Reg::RBX), val.clone()),
+    Instr::And(Val::Reg(Reg::RBX), Val::Const(7)),
+    Instr::Cmp(Val::Reg(Reg::RBX), Val::Const(STRING_TAG)),
+    Instr::IMov(Val::Reg(Reg::RBX), Val::Const(ERRCODE_NOT_STRING)),
+    Instr::ICMovne(Val::Reg(Reg::RDI), Val::Reg(Reg::RBX)),
+    Instr::IMov(Val::Reg(Reg::RBX), site_addr(site)),
+    Instr::ICMovne(Val::Reg(Reg::RDX), Val::Reg(Reg::RBX)),
+    Instr::ICMovne(Val::Reg(Reg::RSI), val),
+    Instr::Jne("throw_error".to_string()),
   ]
 }
 
-fn compile_to_instrs(e: &Expr, si: i64, env: &HashMap<String, i64>, l :&mut i64, loop_stack :&mut Vec<String>, func_dic :&mut im::HashMap<String, i32>, is_main :bool, is_tail :bool, tail_param_num: i32, frame_size: i32) -> Vec<Instr> {
+// Checks the operands of a string operator, the left one in rax and the
+// right one in slot `si`, unless they are `known` to be strings.
+fn check_strings(known: [bool; 2], si: i64, site: i64) -> Vec<Instr> {
+  let mut instrs = vec![];
+  if !known[0] {
+    instrs.extend(check_string(Val::Reg(Reg::RAX), site));
+  }
+  if !known[1] {
+    instrs.extend(check_string(Val::RegOffset(Reg::RSP, si), site));
+  }
+  instrs
+}
+
+// Checks rax against a parameter or result annotation.
+fn check_annotation(ty: Ty, site: i64, l: &mut i64) -> Vec<Instr> {
+  let ok_label = new_label(l, "annotation_ok");
+  let mut instrs = match ty {
+    Ty::Num => type_code(Val::Reg(Reg::RAX), Reg::RBX),
+    // booleans are exactly 1 and 3
+    Ty::Bool => vec![
+      Instr::IMov(Val::Reg(Reg::RBX), Val::Reg(Reg::RAX)),
+      Instr::Or(Val::Reg(Reg::RBX), Val::Const(2)),
+      Instr::ISub(Val::Reg(Reg::RBX), Val::Const(TRUE_CONST)),
+    ],
+    Ty::Str => vec![
+      Instr::IMov(Val::Reg(Reg::RBX), Val::Reg(Reg::RAX)),
+      Instr::And(Val::Reg(Reg::RBX), Val::Const(7)),
+      Instr::ISub(Val::Reg(Reg::RBX), Val::Const(STRING_TAG)),
+    ],
+  };
+  instrs.extend([
+    Instr::Cmp(Val::Reg(Reg::RBX), Val::Const(0)),
+    Instr::Je(ok_label.clone()),
+    Instr::IMov(Val::Reg(Reg::RDI), Val::Const(ERRCODE_ANNOTATION)),
+    Instr::IMov(Val::Reg(Reg::RDX), site_addr(site)),
+    Instr::IMov(Val::Reg(Reg::RSI), Val::Reg(Reg::RAX)),
+    Instr::Jmp("throw_error".to_string()),
+    Instr::Label(ok_label),
+  ]);
+  instrs
+}
+
+// What an annotation says a value must be, for its error messages.
+fn annotation_to_str(a: &Annotation) -> String {
+  format!("{} expected {}", annotated_to_str(a), ty_to_str(a.ty))
+}
+
+// The value an annotation is on.
+fn annotated_to_str(a: &Annotation) -> String {
+  match &a.param {
+    Some(param) => format!("parameter `{}` of `{}`", param, a.func),
+    None => format!("result of `{}`", a.func),
+  }
+}
+
+// The type an expression's value is known to have from an annotation: a
+// variable known to be checked, or a checked value.
+fn known_type(e: &Expr, env: &Env) -> Option<Ty> {
   match e {
+    Expr::Id(name) => env.known.get(name).copied(),
+    Expr::Check(_, annotation) => Some(annotation.ty),
+    _ => None,
+  }
+}
+
+// Leaves `val`'s type in `code`: 0 for numbers of any kind, 1 for
+// booleans (exactly the words 1 and 3), `DATA_CODE` for data values and 5
+// for strings. Clobbers rsi.
+fn type_code(val :Val, code: Reg) -> Vec<Instr> {
+  vec![
+    Instr::IMov(Val::Reg(code), Val::Const(0)),
+    Instr::IMov(Val::Reg(Reg::RSI), val.clone()),
+    Instr::And(Val::Reg(Reg::RSI), Val::Const(7)),
+    Instr::Cmp(Val::Reg(Reg::RSI), Val::Const(STRING_TAG)),
+    Instr::IMov(Val::Reg(Reg::RSI), Val::Const(STRING_TAG)),
+    Instr::CMOV(Val::Reg(code), Val::Reg(Reg::RSI)),
+    // false has the data tag too, and is put right below
+    Instr::IMov(Val::Reg(Reg::RSI), val.clone()),
+    Instr::And(Val::Reg(Reg::RSI), Val::Const(7)),
+    Instr::Cmp(Val::Reg(Reg::RSI), Val::Const(DATA_TAG)),
+    Instr::IMov(Val::Reg(Reg::RSI), Val::Const(DATA_CODE)),
+    Instr::CMOV(Val::Reg(code), Val::Reg(Reg::RSI)),
+    Instr::IMov(Val::Reg(Reg::RSI), val),
+    Instr::Or(Val::Reg(Reg::RSI), Val::Const(2)),
+    Instr::Cmp(Val::Reg(Reg::RSI), Val::Const(TRUE_CONST)),
+    Instr::IMov(Val::Reg(Reg::RSI), Val::Const(1)),
+    Instr::CMOV(Val::Reg(code), Val::Reg(Reg::RSI)),
+  ]
+}
+
+// Accepts numbers of any kind.
+fn check_number(val :Val, site: i64, l: &mut i64) -> Vec<Instr> {
+  let ok_label = new_label(l, "is_number");
+  let mut instrs = type_code(val.clone(), Reg::RBX);
+  instrs.extend([
+    Instr::Cmp(Val::Reg(Reg::RBX), Val::Const(0)),
+    Instr::Je(ok_label.clone()),
+    Instr::IMov(Val::Reg(Reg::RDI), Val::Const(ERRCODE_INVALID_ARG)),
+    Instr::IMov(Val::Reg(Reg::RDX), site_addr(site)),
+    Instr::IMov(Val::Reg(Reg::RSI), val),
+    Instr::Jmp("throw_error".to_string()),
+    Instr::Label(ok_label),
+  ]);
+  instrs
+}
+
+// Calls a runtime helper with up to five arguments, keeping rdi in stack
+// slot `slot` across the call. The result is left in rax.
+fn call_runtime(name: &str, args: &[Val], slot: i64) -> Vec<Instr> {
+  let regs = [Reg::RDI, Reg::RSI, Reg::RDX, Reg::RCX, Reg::R8];
+  let mut instrs = vec![Instr::IMov(Val::RegOffset(Reg::RSP, slot), Val::Reg(Reg::RDI))];
+  for (arg, reg) in args.iter().zip(regs.iter()) {
+    instrs.push(Instr::IMov(Val::Reg(*reg), arg.clone()));
+  }
+  instrs.push(Instr::Call(name.to_string()));
+  instrs.push(Instr::IMov(Val::Reg(Reg::RDI), Val::RegOffset(Reg::RSP, slot)));
+  instrs
+}
+
+// Finishes a division of rax by rcx, with both operands tagged, once `idiv`
+// has left the truncated quotient in rax and the remainder in rdx. `/` and
+// `modulo` round towards negative infinity, `quotient` and `remainder`
+// towards zero.
+fn compile_division(op: &Op2, slow_label: &str, l: &mut i64) -> Vec<Instr> {
+  let mut instrs = vec![];
+  let end_label = new_label(l, "div_end");
+  match op {
+    Op2::Quotient | Op2::Divide => {
+      if let Op2::Divide = op {
+        // a remainder with the opposite sign to the divisor means the
+        // quotient was rounded up
+        instrs.push(Instr::Cmp(Val::Reg(Reg::RDX), Val::Const(0)));
+        instrs.push(Instr::Je(end_label.clone()));
+        instrs.push(Instr::Xor(Val::Reg(Reg::RDX), Val::Reg(Reg::RCX)));
+        instrs.push(Instr::Jge(end_label.clone()));
+        instrs.push(Instr::ISub(Val::Reg(Reg::RAX), Val::Const(1)));
+        instrs.push(Instr::Label(end_label));
+      }
+      // only (/ min -1) overflows here
+      instrs.push(Instr::IAdd(Val::Reg(Reg::RAX), Val::Reg(Reg::RAX)));
+      instrs.push(Instr::Jo(slow_label.to_string()));
+    },
+    Op2::Remainder => {
+      instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Reg(Reg::RDX)));
+    },
+    Op2::Modulo => {
+      instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Reg(Reg::RDX)));
+      instrs.push(Instr::Cmp(Val::Reg(Reg::RDX), Val::Const(0)));
+      instrs.push(Instr::Je(end_label.clone()));
+      instrs.push(Instr::Xor(Val::Reg(Reg::RDX), Val::Reg(Reg::RCX)));
+      instrs.push(Instr::Jge(end_label.clone()));
+      instrs.push(Instr::IAdd(Val::Reg(Reg::RAX), Val::Reg(Reg::RCX)));
+      instrs.push(Instr::Label(end_label));
+    },
+    _ => unreachable!(),
+  }
+  instrs
+}
+
+// Shifts the tagged number in rax by the untagged count in rcx. A negative
+// count shifts the other way; `shl` overflows like `*` would, and `shr`
+// rounds towards negative infinity.
+fn compile_shift(left: bool, site: i64, l: &mut i64) -> Vec<Instr> {
+  let shl_label = new_label(l, "shl");
+  let shl_in_range = new_label(l, "shl_in_range");
+  let shr_label = new_label(l, "shr");
+  let shr_in_range = new_label(l, "shr_in_range");
+  let overflow_label = new_label(l, "shl_overflow");
+  let end_label = new_label(l, "shift_end");
+  let (this, other) = if left { (&shl_label, &shr_label) } else { (&shr_label, &shl_label) };
+  vec![
+    Instr::Cmp(Val::Reg(Reg::RCX), Val::Const(0)),
+    Instr::Jge(this.clone()),
+    Instr::Neg(Val::Reg(Reg::RCX)),
+    Instr::Jmp(other.clone()),
+    Instr::Label(shl_label),
+    Instr::Cmp(Val::Reg(Reg::RCX), Val::Const(63)),
+    Instr::Jl(shl_in_range.clone()),
+    // everything but 0 shifts out of range
+    Instr::Cmp(Val::Reg(Reg::RAX), Val::Const(0)),
+    Instr::Je(end_label.clone()),
+    Instr::Jmp(overflow_label.clone()),
+    Instr::Label(shl_in_range),
+    // the shift overflowed if shifting back loses bits
+    Instr::IMov(Val::Reg(Reg::RBX), Val::Reg(Reg::RAX)),
+    Instr::Shl(Val::Reg(Reg::RBX), Val::Reg(Reg::CL)),
+    Instr::IMov(Val::Reg(Reg::RDX), Val::Reg(Reg::RBX)),
+    Instr::Sar(Val::Reg(Reg::RDX), Val::Reg(Reg::CL)),
+    Instr::Cmp(Val::Reg(Reg::RDX), Val::Reg(Reg::RAX)),
+    Instr::Jne(overflow_label.clone()),
+    Instr::IMov(Val::Reg(Reg::RAX), Val::Reg(Reg::RBX)),
+    Instr::Jmp(end_label.clone()),
+    Instr::Label(overflow_label),
+    Instr::IMov(Val::Reg(Reg::RDI), Val::Const(ERRCODE_OVERFLOW)),
+    Instr::IMov(Val::Reg(Reg::RDX), site_addr(site)),
+    Instr::Jmp("throw_error".to_string()),
+    Instr::Label(shr_label),
+    Instr::Cmp(Val::Reg(Reg::RCX), Val::Const(63)),
+    Instr::Jl(shr_in_range.clone()),
+    Instr::IMov(Val::Reg(Reg::RCX), Val::Const(63)),
+    Instr::Label(shr_in_range),
+    Instr::Sar(Val::Reg(Reg::RAX), Val::Reg(Reg::CL)),
+    // clear the tag bit the shift may have brought down
+    Instr::And(Val::Reg(Reg::RAX), Val::Const(-2)),
+    Instr::Label(end_label),
+  ]
+}
+
+// The operator codes `snek_arith` in the runtime takes.
+fn arith_code(op: &Op2) -> i64 {
+  match op {
+    Op2::Plus => 0,
+    Op2::Minus => 1,
+    Op2::Times => 2,
+    Op2::Less => 3,
+    Op2::LessEqual => 4,
+    Op2::Greater => 5,
+    Op2::GreaterEqual => 6,
+    Op2::Equal => 7,
+    Op2::Divide => 8,
+    Op2::Quotient => 9,
+    Op2::Remainder => 10,
+    Op2::Modulo => 11,
+    _ => unreachable!(),
+  }
+}
+
+// Arithmetic and comparisons on the numbers in rax (the left operand) and
+// stack slot `si`. Two fixnums take the inline path; bignum operands, and
+// fixnum results that overflow, go to `snek_arith`, which computes on
+// bignums, or raises the overflow error under `--trap-overflow`. Uses
+// slots `si + 1` and `si + 2`. Operands `known` to be numbers aren't
+// checked again.
+fn compile_arith(op: &Op2, si: i64, site: i64, l: &mut i64, known: [bool; 2]) -> Vec<Instr> {
+  let slow_label = new_label(l, "arith_slow");
+  let end_label = new_label(l, "arith_end");
+  let rhs = Val::RegOffset(Reg::RSP, si);
+  let mut instrs = vec![];
+  if !known[0] {
+    instrs.extend(check_number(Val::Reg(Reg::RAX), site, l));
+  }
+  if !known[1] {
+    instrs.extend(check_number(rhs.clone(), site, l));
+  }
+  if let Op2::Divide | Op2::Quotient | Op2::Remainder | Op2::Modulo = op {
+    // a bignum is never zero
+    instrs.push(Instr::IMov(Val::Reg(Reg::RCX), rhs.clone()));
+    instrs.push(Instr::Cmp(Val::Reg(Reg::RCX), Val::Const(0)));
+    instrs.push(Instr::IMov(Val::Reg(Reg::RBX), Val::Const(ERRCODE_DIVIDE_BY_ZERO)));
+    instrs.push(Instr::CMOV(Val::Reg(Reg::RDI), Val::Reg(Reg::RBX)));
+    instrs.push(Instr::IMov(Val::Reg(Reg::RBX), site_addr(site)));
+    instrs.push(Instr::CMOV(Val::Reg(Reg::RDX), Val::Reg(Reg::RBX)));
+    instrs.push(Instr::Je("throw_error".to_string()));
+  }
+  instrs.push(Instr::IMov(Val::RegOffset(Reg::RSP, si + 1), Val::Reg(Reg::RAX)));
+  instrs.push(Instr::IMov(Val::Reg(Reg::RBX), Val::Reg(Reg::RAX)));
+  instrs.push(Instr::Or(Val::Reg(Reg::RBX), rhs.clone()));
+  instrs.push(Instr::And(Val::Reg(Reg::RBX), Val::Const(1)));
+  instrs.push(Instr::Cmp(Val::Reg(Reg::RBX), Val::Const(0)));
+  instrs.push(Instr::Jne(slow_label.clone()));
+  match op {
+    Op2::Plus => {
+      instrs.push(Instr::IAdd(Val::Reg(Reg::RAX), rhs.clone()));
+      instrs.push(Instr::Jo(slow_label.clone()));
+    },
+    Op2::Minus => {
+      instrs.push(Instr::ISub(Val::Reg(Reg::RAX), rhs.clone()));
+      instrs.push(Instr::Jo(slow_label.clone()));
+    },
+    Op2::Times => {
+      instrs.push(Instr::Sar(Val::Reg(Reg::RAX), Val::Const(1)));
+      instrs.push(Instr::IMul(Val::Reg(Reg::RAX), rhs.clone()));
+      instrs.push(Instr::Jo(slow_label.clone()));
+    },
+    Op2::Greater | Op2::GreaterEqual | Op2::Less | Op2::LessEqual => {
+      // the jump is taken when the comparison is false
+      instrs.push(Instr::Cmp(Val::Reg(Reg::RAX), rhs.clone()));
+      instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Const(FALSE_CONST)));
+      instrs.push(match op {
+        Op2::Greater => Instr::Jle(end_label.clone()),
+        Op2::GreaterEqual => Instr::Jl(end_label.clone()),
+        Op2::Less => Instr::Jge(end_label.clone()),
+        _ => Instr::Jg(end_label.clone()),
+      });
+      instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Const(TRUE_CONST)));
+    },
+    _ => {
+      // both operands are tagged, so the quotient comes out untagged
+      // and the remainder tagged
+      instrs.push(Instr::Cqo());
+      instrs.push(Instr::IDiv(Val::Reg(Reg::RCX)));
+      instrs.extend(compile_division(op, &slow_label, l));
+    },
+  }
+  instrs.push(Instr::Jmp(end_label.clone()));
+  instrs.push(Instr::Label(slow_label));
+  instrs.extend(call_runtime(
+    "snek_arith",
+    &[Val::Const(arith_code(op)), Val::RegOffset(Reg::RSP, si + 1), rhs, site_addr(site), Val::Reg(Reg::RSP)],
+    si + 2,
+  ));
+  instrs.push(Instr::Label(end_label));
+  instrs
+}
+
+// An enclosing loop: its label, if it has one, and where `continue` and
+// `break` jump to.
+struct LoopTarget {
+  name: Option<String>,
+  start: String,
+  end: String,
+}
+
+// What code sees around it: each variable's stack slot, `hops` static links
+// out, and the local functions in scope.
+#[derive(Clone, Default)]
+struct Env {
+  vars: HashMap<String, (usize, i64)>,
+  funs: HashMap<String, LocalFun>,
+  // the slot of the static link, in a local function
+  link: Option<i64>,
+  // the variables an annotation has been checked on, and that nothing
+  // assigns afterwards
+  known: HashMap<String, Ty>,
+}
+
+// A local function defined `hops` functions out from the code calling it.
+#[derive(Clone)]
+struct LocalFun {
+  label: String,
+  arity: usize,
+  hops: usize,
+}
+
+impl Env {
+  // What a function defined here sees of it, before its parameters. The
+  // link it is passed points at this frame's own link, or at the frame
+  // itself outside local functions, so following links is one load per
+  // hop and this frame's slots are taken relative to it.
+  fn nested(&self) -> Env {
+    let base = self.link.unwrap_or(0);
+    let vars = self.vars.iter().map(|(name, &(hops, slot))| {
+      let var = if hops == 0 { (1, slot - base) } else { (hops + 1, slot) };
+      (name.clone(), var)
+    }).collect();
+    let funs = self.funs.iter().map(|(name, f)| (name.clone(), LocalFun { hops: f.hops + 1, ..f.clone() })).collect();
+    Env { vars, funs, link: None, known: self.known.clone() }
+  }
+}
+
+// Loads into rax the static link to pass a function defined `hops`
+// functions out.
+fn static_link(env: &Env, hops: usize) -> Vec<Instr> {
+  if hops == 0 {
+    vec![
+      Instr::IMov(Val::Reg(Reg::RAX), Val::Reg(Reg::RSP)),
+      Instr::IAdd(Val::Reg(Reg::RAX), Val::Const(env.link.unwrap_or(0) * 8)),
+    ]
+  } else {
+    follow_links(env, hops, Reg::RAX)
+  }
+}
+
+// Loads into `reg` where the static link `hops` ≥ 1 functions out points.
+fn follow_links(env: &Env, hops: usize, reg: Reg) -> Vec<Instr> {
+  let link = env.link.expect("only local functions see other frames");
+  let mut instrs = vec![Instr::IMov(Val::Reg(reg), Val::RegOffset(Reg::RSP, link))];
+  for _ in 1..hops {
+    instrs.push(Instr::IMov(Val::Reg(reg), Val::RegOffset(reg, 0)));
+  }
+  instrs
+}
+
+fn compile_to_instrs(e: &Expr, si: i64, env: &Env, loop_stack :&mut Vec<LoopTarget>, ctx: &mut CodegenCtx, frame: Frame) -> Vec<Instr> {
+  let mut line = match expr_pos(e) {
+    Some(pos) => ctx.dbg.line(pos),
+    None => vec![],
+  };
+  let instrs = match e {
       Expr::Number(n) => vec![Instr::IMov(Val::Reg(Reg::RAX), Val::Imm(*n))],
       Expr::Boolean(b) => {
         match b {
@@ -489,25 +1972,68 @@ fn compile_to_instrs(e: &Expr, si: i64, env: &HashMap<String, i64>, l :&mut i64,
           false => vec![Instr::IMov(Val::Reg(Reg::RAX), Val::Const(FALSE_CONST))],
         }
       }
+      Expr::Float(x) => {
+        // by bits, so that 0.0 and -0.0 stay apart
+        let i = match ctx.floats.iter().position(|f| f.to_bits() == x.to_bits()) {
+          Some(i) => i,
+          None => {
+            ctx.floats.push(*x);
+            ctx.floats.len() - 1
+          }
+        };
+        vec![
+          Instr::Lea(Val::Reg(Reg::RAX), format!("snek_float{i}")),
+          Instr::IAdd(Val::Reg(Reg::RAX), Val::Const(FLOAT_TAG)),
+        ]
+      }
+      Expr::Str(text) => string_literal(text, Reg::RAX, ctx.strings),
+      // rdi points at the inputs laid out by the runtime: the tagged input
+      // count followed by one tagged word per input
       Expr::Input() => {
-        if is_main {
-          vec![Instr::IMov(Val::Reg(Reg::RAX), Val::Reg(Reg::RDI))]
+        if frame.is_main {
+          vec![Instr::IMov(Val::Reg(Reg::RAX), Val::RegOffset(Reg::RDI, 1))]
+        }
+        else {
+          panic!("parse error: Not expected to use input in non-main function")
+        }
+      },
+      Expr::InputCount() => {
+        if frame.is_main {
+          vec![Instr::IMov(Val::Reg(Reg::RAX), Val::RegOffset(Reg::RDI, 0))]
         }
         else {
           panic!("parse error: Not expected to use input in non-main function")
         }
       },
+      Expr::InputAt(expr, pos) => {
+        if !frame.is_main {
+          panic!("parse error: Not expected to use input in non-main function")
+        }
+        let site = new_site(ctx.sites, "input", *pos);
+        let mut instrs = compile_to_instrs(expr, si, env, loop_stack, ctx, frame.non_tail());
+        instrs.extend(check_not_bool(Val::Reg(Reg::RAX), site));
+        // pass the bad index along so the runtime can report it
+        instrs.push(Instr::IMov(Val::Reg(Reg::RSI), Val::Reg(Reg::RAX)));
+        instrs.push(Instr::IMov(Val::Reg(Reg::RDX), site_addr(site)));
+        instrs.push(Instr::IMov(Val::Reg(Reg::RBX), Val::Const(ERRCODE_INPUT_INDEX)));
+        instrs.push(Instr::Cmp(Val::Reg(Reg::RAX), Val::Const(0)));
+        instrs.push(Instr::ICMovl(Val::Reg(Reg::RDI), Val::Reg(Reg::RBX)));
+        instrs.push(Instr::Jl("throw_error".to_string()));
+        instrs.push(Instr::Cmp(Val::Reg(Reg::RAX), Val::RegOffset(Reg::RDI, 0)));
+        instrs.push(Instr::ICMovge(Val::Reg(Reg::RDI), Val::Reg(Reg::RBX)));
+        instrs.push(Instr::Jge("throw_error".to_string()));
+        // a tagged index is 2 * i, and each input is 8 bytes
+        instrs.push(Instr::IMul(Val::Reg(Reg::RAX), Val::Const(4)));
+        instrs.push(Instr::IAdd(Val::Reg(Reg::RAX), Val::Reg(Reg::RDI)));
+        instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::RegOffset(Reg::RAX, 1)));
+        instrs
+      },
       Expr::Id(s) => {
-          let offset = env.get(s);
-          if offset.is_none() {
-              panic!("Unbound variable identifier {s}");
-          }
-          vec![Instr::IMov(
-              Val::Reg(Reg::RAX),
-              Val::RegOffset(Reg::RSP, *offset.unwrap()),
-          )]
-      }
-      Expr::Let(bindings, body) => {
+        let (mut instrs, var) = variable(s, env, ctx.globals);
+        instrs.push(Instr::IMov(Val::Reg(Reg::RAX), var));
+        instrs
+      },
+      Expr::Let(bindings, body, _) => {
           let mut instrs = vec![];
           let mut env_new = env.clone();
           let mut curr_names = HashSet::<String>::new();
@@ -518,19 +2044,26 @@ fn compile_to_instrs(e: &Expr, si: i64, env: &HashMap<String, i64>, l :&mut i64,
               if curr_names.contains(&name.clone()) {
                   panic!("parse error: Duplicate binding {name} Invalid");
               }
-              instrs.extend(compile_to_instrs(expr, i as i64 + si, &env_new, l, loop_stack, func_dic, is_main, false ,0, frame_size));
+              instrs.extend(compile_to_instrs(expr, i as i64 + si, &env_new, loop_stack, ctx, frame.non_tail()));
               instrs.push(Instr::IMov(
                   Val::RegOffset(Reg::RSP, i as i64 + si),
                   Val::Reg(Reg::RAX),
               ));
               curr_names.insert(name.clone());
-              env_new = env_new.update(name.clone(), i as i64 + si);
+              env_new.vars.insert(name.clone(), (0, i as i64 + si));
+              env_new.known.remove(name);
+              instrs.extend(ctx.dbg.begin_scope(name, i as i64 + si));
+          }
+          instrs.extend(compile_to_instrs(body, si + bindings.len() as i64, &env_new, loop_stack, ctx, frame));
+          for _ in bindings {
+              instrs.extend(ctx.dbg.end_scope());
           }
-          instrs.extend(compile_to_instrs(body, si + bindings.len() as i64, &env_new, l, loop_stack, func_dic, is_main, is_tail, tail_param_num, frame_size));
           instrs
       }
-      Expr::Print(expr) => {
-          let mut instrs = compile_to_instrs(expr, si, env, l, loop_stack, func_dic, is_main, false ,0, frame_size);
+      Expr::Print(expr, pos) => {
+          let mut instrs = compile_to_instrs(expr, si, env, loop_stack, ctx, frame.non_tail());
+          // back on this form's line once the operands are done
+          instrs.extend(ctx.dbg.line(pos));
           instrs.push(Instr::IMov(Val::RegOffset(Reg::RSP, si), Val::Reg(Reg::RAX)));
           instrs.push(Instr::IMov(Val::RegOffset(Reg::RSP, si + 1), Val::Reg(Reg::RDI)));
           instrs.push(Instr::IMov(Val::Reg(Reg::RDI), Val::Reg(Reg::RAX)));
@@ -539,133 +2072,234 @@ fn compile_to_instrs(e: &Expr, si: i64, env: &HashMap<String, i64>, l :&mut i64,
           instrs.push(Instr::IMov(Val::Reg(Reg::RDI), Val::RegOffset(Reg::RSP, si + 1)));
           instrs
       }
-      Expr::UnOp(op, expr) => {
-          let mut instrs = compile_to_instrs(expr, si, env, l, loop_stack, func_dic, is_main, false ,0, frame_size);
+      Expr::UnOp(op, expr, pos) => {
+          let site = new_site(ctx.sites, op1_to_str(op), *pos);
+          let known = known_type(expr, env);
+          let mut instrs = compile_to_instrs(expr, si, env, loop_stack, ctx, frame.non_tail());
+          instrs.extend(ctx.dbg.line(pos));
           match op {
-              Op1::Add1 => {
-                instrs.extend(check_not_bool(Val::Reg(Reg::RAX)));
-                instrs.push(Instr::IAdd(Val::Reg(Reg::RAX), Val::Const(2)));
-                instrs.extend(check_not_overflow());
-              },
-              Op1::Sub1 => {
-                instrs.extend(check_not_bool(Val::Reg(Reg::RAX)));
-                instrs.push(Instr::ISub(Val::Reg(Reg::RAX), Val::Const(2)));
-                instrs.extend(check_not_overflow());
+              Op1::Add1 | Op1::Sub1 => {
+                instrs.push(Instr::IMov(Val::Reg(Reg::RBX), Val::Imm(1)));
+                instrs.push(Instr::IMov(Val::RegOffset(Reg::RSP, si), Val::Reg(Reg::RBX)));
+                let op2 = if let Op1::Add1 = op { Op2::Plus } else { Op2::Minus };
+                instrs.extend(compile_arith(&op2, si, site, &mut ctx.label_id, [ctx.typed || known == Some(Ty::Num), true]));
               },
               Op1::IsNum => {
-                instrs.push(Instr::And(Val::Reg(Reg::RAX), Val::Const(1)));
-                instrs.push(Instr::Cmp(Val::Reg(Reg::RAX), Val::Const(0)));
+                instrs.extend(type_code(Val::Reg(Reg::RAX), Reg::RBX));
+                instrs.push(Instr::Cmp(Val::Reg(Reg::RBX), Val::Const(0)));
+                instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Const(FALSE_CONST)));
+                instrs.push(Instr::IMov(Val::Reg(Reg::RBX), Val::Const(TRUE_CONST)));
+                instrs.push(Instr::CMOV(Val::Reg(Reg::RAX), Val::Reg(Reg::RBX)));
+              },
+              Op1::IsStr => {
+                instrs.push(Instr::And(Val::Reg(Reg::RAX), Val::Const(7)));
+                instrs.push(Instr::Cmp(Val::Reg(Reg::RAX), Val::Const(STRING_TAG)));
                 instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Const(FALSE_CONST)));
                 instrs.push(Instr::IMov(Val::Reg(Reg::RBX), Val::Const(TRUE_CONST)));
                 instrs.push(Instr::CMOV(Val::Reg(Reg::RAX), Val::Reg(Reg::RBX)));
               },
               Op1::IsBool => {
-                instrs.push(Instr::And(Val::Reg(Reg::RAX), Val::Const(1)));
-                instrs.push(Instr::Cmp(Val::Reg(Reg::RAX), Val::Const(0)));
-                instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Const(TRUE_CONST)));
-                instrs.push(Instr::IMov(Val::Reg(Reg::RBX), Val::Const(FALSE_CONST)));
+                // booleans are exactly 1 and 3
+                instrs.push(Instr::Or(Val::Reg(Reg::RAX), Val::Const(2)));
+                instrs.push(Instr::Cmp(Val::Reg(Reg::RAX), Val::Const(TRUE_CONST)));
+                instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Const(FALSE_CONST)));
+                instrs.push(Instr::IMov(Val::Reg(Reg::RBX), Val::Const(TRUE_CONST)));
                 instrs.push(Instr::CMOV(Val::Reg(Reg::RAX), Val::Reg(Reg::RBX)));
               },
+              Op1::StringLength => {
+                if !ctx.typed && known != Some(Ty::Str) {
+                  instrs.extend(check_string(Val::Reg(Reg::RAX), site));
+                }
+                instrs.extend(call_runtime("snek_string_length", &[Val::Reg(Reg::RAX)], si));
+              },
+              Op1::Float => {
+                if !ctx.typed && known != Some(Ty::Num) {
+                  instrs.extend(check_number(Val::Reg(Reg::RAX), site, &mut ctx.label_id));
+                }
+                instrs.extend(call_runtime("snek_float", &[Val::Reg(Reg::RAX)], si));
+              },
+              Op1::Truncate => {
+                if !ctx.typed && known != Some(Ty::Num) {
+                  instrs.extend(check_number(Val::Reg(Reg::RAX), site, &mut ctx.label_id));
+                }
+                instrs.extend(call_runtime("snek_truncate", &[Val::Reg(Reg::RAX), site_addr(site), Val::Reg(Reg::RSP)], si));
+              },
+              // true and false differ in one bit
+              Op1::Not if ctx.typed || known == Some(Ty::Bool) => {
+                instrs.push(Instr::Xor(Val::Reg(Reg::RAX), Val::Const(TRUE_CONST ^ FALSE_CONST)));
+              },
+              Op1::Not => {
+                let true_label = new_label(&mut ctx.label_id, "not_true");
+                let end_label = new_label(&mut ctx.label_id, "not_end");
+                instrs.push(Instr::Cmp(Val::Reg(Reg::RAX), Val::Const(TRUE_CONST)));
+                instrs.push(Instr::Je(true_label.clone()));
+                instrs.push(Instr::Cmp(Val::Reg(Reg::RAX), Val::Const(FALSE_CONST)));
+                instrs.push(Instr::IMov(Val::Reg(Reg::RDI), Val::Const(ERRCODE_NOT_BOOL)));
+                instrs.push(Instr::IMov(Val::Reg(Reg::RDX), site_addr(site)));
+                instrs.push(Instr::IMov(Val::Reg(Reg::RSI), Val::Reg(Reg::RAX)));
+                instrs.push(Instr::Jne("throw_error".to_string()));
+                instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Const(TRUE_CONST)));
+                instrs.push(Instr::Jmp(end_label.clone()));
+                instrs.push(Instr::Label(true_label));
+                instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Const(FALSE_CONST)));
+                instrs.push(Instr::Label(end_label));
+              },
           }
           instrs
       }
-      Expr::BinOp(op, lhs, rhs) => {
-          let mut instrs = compile_to_instrs(rhs, si, env, l, loop_stack, func_dic, is_main, false ,0, frame_size);
+      Expr::BinOp(op, lhs, rhs, pos) => {
+          let site = new_site(ctx.sites, op2_to_str(op), *pos);
+          let known = [known_type(lhs, env), known_type(rhs, env)];
+          let mut instrs = compile_to_instrs(rhs, si, env, loop_stack, ctx, frame.non_tail());
           instrs.push(Instr::IMov(
               Val::RegOffset(Reg::RSP, si),
               Val::Reg(Reg::RAX),
           ));
-          instrs.extend(compile_to_instrs(lhs, si + 1, env, l, loop_stack, func_dic, is_main, false ,0, frame_size));
+          instrs.extend(compile_to_instrs(lhs, si + 1, env, loop_stack, ctx, frame.non_tail()));
+          instrs.extend(ctx.dbg.line(pos));
           match op {
-              Op2::Plus => {
-                instrs.extend(check_not_bool(Val::Reg(Reg::RAX)));
-                instrs.extend(check_not_bool(Val::RegOffset(Reg::RSP, si)));
-
-                instrs.push(Instr::IAdd(Val::Reg(Reg::RAX), Val::RegOffset(Reg::RSP, si)));
-                instrs.extend(check_not_overflow());
-              }
-              Op2::Minus => {
-                instrs.extend(check_not_bool(Val::Reg(Reg::RAX)));
-                instrs.extend(check_not_bool(Val::RegOffset(Reg::RSP, si)));
-                
-                instrs.push(Instr::ISub(Val::Reg(Reg::RAX), Val::RegOffset(Reg::RSP, si)));
-                instrs.extend(check_not_overflow());
-              }
-              Op2::Times => {
-                instrs.extend(check_not_bool(Val::Reg(Reg::RAX)));
-                instrs.extend(check_not_bool(Val::RegOffset(Reg::RSP, si)));
-                instrs.push(Instr::Sar(Val::Reg(Reg::RAX), Val::Const(1)));
-                instrs.push(Instr::IMul(Val::Reg(Reg::RAX), Val::RegOffset(Reg::RSP, si)));
-                instrs.extend(check_not_overflow());
+              Op2::Plus | Op2::Minus | Op2::Times | Op2::Greater | Op2::GreaterEqual | Op2::Less | Op2::LessEqual
+              | Op2::Divide | Op2::Quotient | Op2::Remainder | Op2::Modulo => {
+                instrs.extend(compile_arith(op, si, site, &mut ctx.label_id, known.map(|k| ctx.typed || k == Some(Ty::Num))));
               },
               Op2::Equal => {
-                // check if both have the same type
-                instrs.push(Instr::IMov(Val::Reg(Reg::RBX), Val::Reg(Reg::RAX)));
-                instrs.push(Instr::IMov(Val::Reg(Reg::RCX), Val::RegOffset(Reg::RSP, si)));
+                // check if both have the same type, which a typed program
+                // guarantees
+                if !ctx.typed {
+                  instrs.extend(type_code(Val::Reg(Reg::RAX), Reg::RBX));
+                  instrs.extend(type_code(Val::RegOffset(Reg::RSP, si), Reg::RCX));
+                  instrs.push(Instr::Cmp(Val::Reg(Reg::RBX), Val::Reg(Reg::RCX)));
 
-                instrs.push(Instr::And(Val::Reg(Reg::RBX), Val::Const(1)));
-                instrs.push(Instr::And(Val::Reg(Reg::RCX), Val::Const(1)));
-                instrs.push(Instr::Cmp(Val::Reg(Reg::RBX), Val::Reg(Reg::RCX)));
+                  instrs.push(Instr::IMov(Val::Reg(Reg::RBX), Val::Const(ERRCODE_MISMATCH)));
+                  instrs.push(Instr::ICMovne(Val::Reg(Reg::RDI), Val::Reg(Reg::RBX)));
+                  instrs.push(Instr::IMov(Val::Reg(Reg::RBX), site_addr(site)));
+                  instrs.push(Instr::ICMovne(Val::Reg(Reg::RDX), Val::Reg(Reg::RBX)));
+                  // both operands go to the runtime, in rsi and rcx
+                  instrs.push(Instr::ICMovne(Val::Reg(Reg::RSI), Val::Reg(Reg::RAX)));
+                  instrs.push(Instr::IMov(Val::Reg(Reg::RCX), Val::RegOffset(Reg::RSP, si)));
+                  instrs.push(Instr::Jne("throw_error".to_string()));
+                }
 
-                instrs.push(Instr::IMov(Val::Reg(Reg::RBX), Val::Const(ERRCODE_INVALID_ARG)));
-                instrs.push(Instr::ICMovne(Val::Reg(Reg::RDI), Val::Reg(Reg::RBX)));
-                instrs.push(Instr::Jne("throw_error".to_string()));
+                // strings are equal when their contents are
+                let plain_label = new_label(&mut ctx.label_id, "eq_plain");
+                let not_string_label = new_label(&mut ctx.label_id, "eq_not_string");
+                let end_label = new_label(&mut ctx.label_id, "eq_end");
+                instrs.extend(type_code(Val::Reg(Reg::RAX), Reg::RBX));
+                instrs.push(Instr::Cmp(Val::Reg(Reg::RBX), Val::Const(STRING_TAG)));
+                instrs.push(Instr::Jne(not_string_label.clone()));
+                instrs.extend(call_runtime("snek_string_equal", &[Val::Reg(Reg::RAX), Val::RegOffset(Reg::RSP, si)], si + 1));
+                instrs.push(Instr::Jmp(end_label.clone()));
+                instrs.push(Instr::Label(not_string_label));
+
+                // and data values when their constructors and fields are
+                instrs.push(Instr::Cmp(Val::Reg(Reg::RBX), Val::Const(DATA_CODE)));
+                instrs.push(Instr::Jne(plain_label.clone()));
+                instrs.extend(call_runtime("snek_equal", &[Val::Reg(Reg::RAX), Val::RegOffset(Reg::RSP, si)], si + 1));
+                instrs.push(Instr::Jmp(end_label.clone()));
+                instrs.push(Instr::Label(plain_label));
+
+                // and numbers by value unless both are fixnums
+                let fixnum_label = new_label(&mut ctx.label_id, "eq_fixnum");
+                instrs.push(Instr::Cmp(Val::Reg(Reg::RBX), Val::Const(0)));
+                instrs.push(Instr::Jne(fixnum_label.clone()));
+                instrs.push(Instr::IMov(Val::Reg(Reg::RBX), Val::Reg(Reg::RAX)));
+                instrs.push(Instr::Or(Val::Reg(Reg::RBX), Val::RegOffset(Reg::RSP, si)));
+                instrs.push(Instr::And(Val::Reg(Reg::RBX), Val::Const(1)));
+                instrs.push(Instr::Cmp(Val::Reg(Reg::RBX), Val::Const(0)));
+                instrs.push(Instr::Je(fixnum_label.clone()));
+                instrs.extend(call_runtime(
+                  "snek_arith",
+                  &[Val::Const(arith_code(op)), Val::Reg(Reg::RAX), Val::RegOffset(Reg::RSP, si), site_addr(site), Val::Reg(Reg::RSP)],
+                  si + 1,
+                ));
+                instrs.push(Instr::Jmp(end_label.clone()));
+                instrs.push(Instr::Label(fixnum_label));
                 
                 // compare the equality
                 instrs.push(Instr::Cmp(Val::Reg(Reg::RAX), Val::RegOffset(Reg::RSP, si)));
                 instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Const(FALSE_CONST)));
                 instrs.push(Instr::IMov(Val::Reg(Reg::RBX), Val::Const(TRUE_CONST)));
                 instrs.push(Instr::CMOV(Val::Reg(Reg::RAX), Val::Reg(Reg::RBX)));
+                instrs.push(Instr::Label(end_label));
               },
-              Op2::Greater => {
-                instrs.extend(check_not_bool(Val::Reg(Reg::RAX)));
-                instrs.extend(check_not_bool(Val::RegOffset(Reg::RSP, si)));
-                let cmp_end_label = new_label(l, "cmp_end_label");
-                instrs.push(Instr::Cmp(Val::Reg(Reg::RAX), Val::RegOffset(Reg::RSP, si)));
-                instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Const(FALSE_CONST)));
-                instrs.push(Instr::Jle(cmp_end_label.clone()));
-                instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Const(TRUE_CONST)));
-                instrs.push(Instr::Label(cmp_end_label.clone()));
+              Op2::BitAnd => {
+                instrs.extend(check_not_bool(Val::Reg(Reg::RAX), site));
+                instrs.extend(check_not_bool(Val::RegOffset(Reg::RSP, si), site));
+                instrs.push(Instr::And(Val::Reg(Reg::RAX), Val::RegOffset(Reg::RSP, si)));
               },
-              Op2::GreaterEqual => {
-                instrs.extend(check_not_bool(Val::Reg(Reg::RAX)));
-                instrs.extend(check_not_bool(Val::RegOffset(Reg::RSP, si)));
-                let cmp_end_label = new_label(l, "cmp_end_label");
-                instrs.push(Instr::Cmp(Val::Reg(Reg::RAX), Val::RegOffset(Reg::RSP, si)));
-                instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Const(TRUE_CONST)));
-                instrs.push(Instr::Jge(cmp_end_label.clone()));
-                instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Const(FALSE_CONST)));
-                instrs.push(Instr::Label(cmp_end_label.clone()));
+              Op2::BitOr => {
+                instrs.extend(check_not_bool(Val::Reg(Reg::RAX), site));
+                instrs.extend(check_not_bool(Val::RegOffset(Reg::RSP, si), site));
+                instrs.push(Instr::Or(Val::Reg(Reg::RAX), Val::RegOffset(Reg::RSP, si)));
               },
-              Op2::Less => {
-                instrs.extend(check_not_bool(Val::Reg(Reg::RAX)));
-                instrs.extend(check_not_bool(Val::RegOffset(Reg::RSP, si)));
-                let cmp_end_label = new_label(l, "cmp_end_label");
-                instrs.push(Instr::Cmp(Val::Reg(Reg::RAX), Val::RegOffset(Reg::RSP, si)));
-                instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Const(FALSE_CONST)));
-                instrs.push(Instr::Jge(cmp_end_label.clone()));
-                instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Const(TRUE_CONST)));
-                instrs.push(Instr::Label(cmp_end_label.clone()));
+              Op2::BitXor => {
+                instrs.extend(check_not_bool(Val::Reg(Reg::RAX), site));
+                instrs.extend(check_not_bool(Val::RegOffset(Reg::RSP, si), site));
+                instrs.push(Instr::Xor(Val::Reg(Reg::RAX), Val::RegOffset(Reg::RSP, si)));
               },
-              Op2::LessEqual => {
-                instrs.extend(check_not_bool(Val::Reg(Reg::RAX)));
-                instrs.extend(check_not_bool(Val::RegOffset(Reg::RSP, si)));
-                let cmp_end_label = new_label(l, "cmp_end_label");
-                instrs.push(Instr::Cmp(Val::Reg(Reg::RAX), Val::RegOffset(Reg::RSP, si)));
-                instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Const(TRUE_CONST)));
-                instrs.push(Instr::Jle(cmp_end_label.clone()));
-                instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Const(FALSE_CONST)));
-                instrs.push(Instr::Label(cmp_end_label.clone()));
+              Op2::ShiftLeft | Op2::ShiftRight => {
+                instrs.extend(check_not_bool(Val::Reg(Reg::RAX), site));
+                instrs.extend(check_not_bool(Val::RegOffset(Reg::RSP, si), site));
+                instrs.push(Instr::IMov(Val::Reg(Reg::RCX), Val::RegOffset(Reg::RSP, si)));
+                instrs.push(Instr::Sar(Val::Reg(Reg::RCX), Val::Const(1)));
+                instrs.extend(compile_shift(matches!(op, Op2::ShiftLeft), site, &mut ctx.label_id));
+              },
+              Op2::StringAppend => {
+                instrs.extend(check_strings(known.map(|k| ctx.typed || k == Some(Ty::Str)), si, site));
+                instrs.extend(call_runtime("snek_string_append", &[Val::Reg(Reg::RAX), Val::RegOffset(Reg::RSP, si)], si + 1));
+              },
+              Op2::StringEqual => {
+                instrs.extend(check_strings(known.map(|k| ctx.typed || k == Some(Ty::Str)), si, site));
+                instrs.extend(call_runtime("snek_string_equal", &[Val::Reg(Reg::RAX), Val::RegOffset(Reg::RSP, si)], si + 1));
               },
           }
           instrs
       },
-      Expr::If(cond, thn, els) => {
-        let end_label = new_label(l, "ifend");
-        let els_label = new_label(l, "ifelse");
-        let cond_instrs: Vec<Instr> = compile_to_instrs(cond, si, env, l, loop_stack, func_dic, is_main, false ,0, frame_size);
-        let thn_instrs: Vec<Instr> = compile_to_instrs(thn, si, env, l, loop_stack, func_dic, is_main, is_tail, tail_param_num, frame_size);
-        let els_instrs: Vec<Instr> = compile_to_instrs(els, si, env, l, loop_stack, func_dic, is_main, is_tail, tail_param_num, frame_size);
+      Expr::Substring(s, start, end, pos) => {
+        let site = new_site(ctx.sites, "substring", *pos);
+        let known = known_type(s, env);
+        let mut instrs = compile_to_instrs(s, si, env, loop_stack, ctx, frame.non_tail());
+        instrs.push(Instr::IMov(Val::RegOffset(Reg::RSP, si), Val::Reg(Reg::RAX)));
+        instrs.extend(compile_to_instrs(start, si + 1, env, loop_stack, ctx, frame.non_tail()));
+        instrs.push(Instr::IMov(Val::RegOffset(Reg::RSP, si + 1), Val::Reg(Reg::RAX)));
+        instrs.extend(compile_to_instrs(end, si + 2, env, loop_stack, ctx, frame.non_tail()));
+        instrs.extend(ctx.dbg.line(pos));
+        if !ctx.typed && known != Some(Ty::Str) {
+          instrs.extend(check_string(Val::RegOffset(Reg::RSP, si), site));
+        }
+        instrs.extend(check_not_bool(Val::RegOffset(Reg::RSP, si + 1), site));
+        instrs.extend(check_not_bool(Val::Reg(Reg::RAX), site));
+        // 0 <= start <= end <= length in characters, all compared tagged;
+        // on failure the runtime gets start in rsi, end in rcx and the
+        // length in r9
+        let range_error = new_label(&mut ctx.label_id, "substring_range_error");
+        let range_ok = new_label(&mut ctx.label_id, "substring_range_ok");
+        instrs.push(Instr::IMov(Val::RegOffset(Reg::RSP, si + 2), Val::Reg(Reg::RAX)));
+        instrs.extend(call_runtime("snek_string_length", &[Val::RegOffset(Reg::RSP, si)], si + 3));
+        instrs.push(Instr::IMov(Val::Reg(Reg::R9), Val::Reg(Reg::RAX)));
+        instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::RegOffset(Reg::RSP, si + 2)));
+        instrs.push(Instr::IMov(Val::Reg(Reg::RSI), Val::RegOffset(Reg::RSP, si + 1)));
+        instrs.push(Instr::IMov(Val::Reg(Reg::RCX), Val::Reg(Reg::RAX)));
+        instrs.push(Instr::Cmp(Val::Reg(Reg::RSI), Val::Const(0)));
+        instrs.push(Instr::Jl(range_error.clone()));
+        instrs.push(Instr::Cmp(Val::Reg(Reg::RSI), Val::Reg(Reg::RCX)));
+        instrs.push(Instr::Jg(range_error.clone()));
+        instrs.push(Instr::Cmp(Val::Reg(Reg::RCX), Val::Reg(Reg::R9)));
+        instrs.push(Instr::Jle(range_ok.clone()));
+        instrs.push(Instr::Label(range_error));
+        instrs.push(Instr::IMov(Val::Reg(Reg::RDI), Val::Const(ERRCODE_SUBSTRING_RANGE)));
+        instrs.push(Instr::IMov(Val::Reg(Reg::RDX), site_addr(site)));
+        instrs.push(Instr::Jmp("throw_error".to_string()));
+        instrs.push(Instr::Label(range_ok));
+        instrs.extend(call_runtime("snek_substring", &[Val::RegOffset(Reg::RSP, si), Val::RegOffset(Reg::RSP, si + 1), Val::Reg(Reg::RAX)], si + 2));
+        instrs
+      },
+      Expr::If(cond, thn, els, _) => {
+        let end_label = new_label(&mut ctx.label_id, "ifend");
+        let els_label = new_label(&mut ctx.label_id, "ifelse");
+        let cond_instrs: Vec<Instr> = compile_to_instrs(cond, si, env, loop_stack, ctx, frame.non_tail());
+        let thn_instrs: Vec<Instr> = compile_to_instrs(thn, si, env, loop_stack, ctx, frame);
+        let els_instrs: Vec<Instr> = compile_to_instrs(els, si, env, loop_stack, ctx, frame);
         
         let mut instrs: Vec<Instr> = vec![];
         instrs.extend(cond_instrs);
@@ -678,39 +2312,120 @@ fn compile_to_instrs(e: &Expr, si: i64, env: &HashMap<String, i64>, l :&mut i64,
         instrs.push(Instr::Label(end_label.clone()));
         instrs
       },
-      Expr::Loop(expr) => {
+      Expr::And(exprs) | Expr::Or(exprs) => {
+        // `and` stops at the first false, `or` at the first anything else
+        let (empty, name) = match e {
+          Expr::And(_) => (TRUE_CONST, "and_end"),
+          _ => (FALSE_CONST, "or_end"),
+        };
+        let end_label = new_label(&mut ctx.label_id, name);
         let mut instrs: Vec<Instr> = vec![];
-        let start_label = new_label(l, "loop_start");
-        let end_label = new_label(l, "loop_end");
-        loop_stack.push(end_label.clone());
+        if exprs.is_empty() {
+          instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Const(empty)));
+        }
+        for (i, expr) in exprs.iter().enumerate() {
+          if i == exprs.len() - 1 {
+            instrs.extend(compile_to_instrs(expr, si, env, loop_stack, ctx, frame));
+          } else {
+            instrs.extend(compile_to_instrs(expr, si, env, loop_stack, ctx, frame.non_tail()));
+            instrs.push(Instr::Cmp(Val::Reg(Reg::RAX), Val::Const(FALSE_CONST)));
+            instrs.push(if let Expr::And(_) = e { Instr::Je(end_label.clone()) } else { Instr::Jne(end_label.clone()) });
+          }
+        }
+        instrs.push(Instr::Label(end_label));
+        instrs
+      },
+      Expr::Loop(name, expr) => {
+        let mut instrs: Vec<Instr> = vec![];
+        let start_label = new_label(&mut ctx.label_id, "loop_start");
+        let end_label = new_label(&mut ctx.label_id, "loop_end");
+        loop_stack.push(LoopTarget { name: name.clone(), start: start_label.clone(), end: end_label.clone() });
         instrs.push(Instr::Label(start_label.clone()));
-        println!("{:?}", expr);
-        instrs.extend(compile_to_instrs(expr, si, env, l, loop_stack, func_dic, is_main, false ,0, frame_size));
+        instrs.extend(compile_to_instrs(expr, si, env, loop_stack, ctx, frame.non_tail()));
         loop_stack.pop();
-        if loop_stack.contains(&end_label) {
-          panic!("Loop without break");
-        }
         instrs.push(Instr::Jmp(start_label.clone()));
         instrs.push(Instr::Label(end_label));
         instrs
       },
-      Expr::Break(expr) => {
-        if loop_stack.len() == 0 {
-          panic!("Unexpected break outside loop");
+      // a type checked program has already proven its annotations
+      Expr::Check(e, _) if ctx.typed => {
+        compile_to_instrs(e, si, env, loop_stack, ctx, frame)
+      }
+      Expr::Check(e, annotation) => {
+        let site = new_site(ctx.sites, &annotation_to_str(annotation), annotation.pos);
+        let mut instrs = compile_to_instrs(e, si, env, loop_stack, ctx, frame.non_tail());
+        instrs.extend(check_annotation(annotation.ty, site, &mut ctx.label_id));
+        instrs
+      }
+      Expr::Construct(variant, fields) => {
+        let mut instrs = vec![];
+        for (i, field) in fields.iter().enumerate() {
+          instrs.extend(compile_to_instrs(field, si + i as i64, env, loop_stack, ctx, frame.non_tail()));
+          instrs.push(Instr::IMov(Val::RegOffset(Reg::RSP, si + i as i64), Val::Reg(Reg::RAX)));
         }
-        let break_label = loop_stack[loop_stack.len() - 1].clone();
-        // TODO
-        let mut instrs = compile_to_instrs(expr, si, env, l, loop_stack, func_dic, is_main, false ,0, frame_size);
+        instrs.extend(string_literal(&variant.ctor, Reg::RAX, ctx.strings));
+        instrs.push(Instr::IMov(Val::Reg(Reg::RBX), Val::Reg(Reg::RSP)));
+        instrs.push(Instr::IAdd(Val::Reg(Reg::RBX), Val::Const(si * 8)));
+        let n = fields.len() as i64;
+        instrs.extend(call_runtime("snek_construct", &[Val::Reg(Reg::RAX), Val::Const(n), Val::Reg(Reg::RBX)], si + n));
+        instrs
+      }
+      Expr::IsVariant(e, variant) => {
+        let no_label = new_label(&mut ctx.label_id, "variant_no");
+        let end_label = new_label(&mut ctx.label_id, "variant_end");
+        let mut instrs = compile_to_instrs(e, si, env, loop_stack, ctx, frame.non_tail());
+        instrs.push(Instr::IMov(Val::Reg(Reg::RBX), Val::Reg(Reg::RAX)));
+        instrs.push(Instr::And(Val::Reg(Reg::RBX), Val::Const(7)));
+        instrs.push(Instr::Cmp(Val::Reg(Reg::RBX), Val::Const(DATA_TAG)));
+        instrs.push(Instr::Jne(no_label.clone()));
+        instrs.push(Instr::Cmp(Val::Reg(Reg::RAX), Val::Const(FALSE_CONST)));
+        instrs.push(Instr::Je(no_label.clone()));
+        instrs.push(Instr::IMov(Val::Reg(Reg::RBX), Val::Reg(Reg::RAX)));
+        instrs.push(Instr::ISub(Val::Reg(Reg::RBX), Val::Const(DATA_TAG)));
+        instrs.push(Instr::IMov(Val::Reg(Reg::RBX), Val::RegOffset(Reg::RBX, 0)));
+        instrs.extend(string_literal(&variant.ctor, Reg::RCX, ctx.strings));
+        instrs.push(Instr::Cmp(Val::Reg(Reg::RBX), Val::Reg(Reg::RCX)));
+        instrs.push(Instr::Jne(no_label.clone()));
+        instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Const(TRUE_CONST)));
+        instrs.push(Instr::Jmp(end_label.clone()));
+        instrs.push(Instr::Label(no_label));
+        instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::Const(FALSE_CONST)));
+        instrs.push(Instr::Label(end_label));
+        instrs
+      }
+      Expr::Field(e, _, i) => {
+        let mut instrs = compile_to_instrs(e, si, env, loop_stack, ctx, frame.non_tail());
+        instrs.push(Instr::ISub(Val::Reg(Reg::RAX), Val::Const(DATA_TAG)));
+        instrs.push(Instr::IMov(Val::Reg(Reg::RAX), Val::RegOffset(Reg::RAX, 2 + *i as i64)));
+        instrs
+      }
+      Expr::MatchFail(e, pos) => {
+        let site = new_site(ctx.sites, "match", *pos);
+        let mut instrs = compile_to_instrs(e, si, env, loop_stack, ctx, frame.non_tail());
+        instrs.push(Instr::IMov(Val::Reg(Reg::RSI), Val::Reg(Reg::RAX)));
+        instrs.push(Instr::IMov(Val::Reg(Reg::RDI), Val::Const(ERRCODE_MATCH)));
+        instrs.push(Instr::IMov(Val::Reg(Reg::RDX), site_addr(site)));
+        instrs.push(Instr::Jmp("throw_error".to_string()));
+        instrs
+      }
+      Expr::Match(..) => unreachable!("matches are compiled to decision trees before codegen"),
+      Expr::Sugar(..) => unreachable!("derived forms are desugared before codegen"),
+      Expr::Break(name, expr, _) => {
+        let target = find_loop(loop_stack.iter().map(|t| &t.name), name, "break");
+        let break_label = loop_stack[target].end.clone();
+        let mut instrs = compile_to_instrs(expr, si, env, loop_stack, ctx, frame.non_tail());
         instrs.push(Instr::Jmp(break_label));
         instrs
       },
-      Expr::Set(s, expr) => {
-        let mut instrs = compile_to_instrs(expr, si, env, l, loop_stack, func_dic, is_main, false ,0, frame_size);
-        let offset = env.get(s);
-        if offset.is_none() {
-            panic!("Unbound variable identifier {s}");
-        }
-        instrs.push(Instr::IMov(Val::RegOffset(Reg::RSP, *offset.unwrap()), Val::Reg(Reg::RAX)));
+      Expr::Continue(name, _) => {
+        let target = find_loop(loop_stack.iter().map(|t| &t.name), name, "continue");
+        vec![Instr::Jmp(loop_stack[target].start.clone())]
+      },
+      Expr::Set(s, expr, _) => {
+        let mut instrs = compile_to_instrs(expr, si, env, loop_stack, ctx, frame.non_tail());
+        let (find, var) = variable(s, env, ctx.globals);
+        instrs.extend(find);
+        instrs.push(Instr::IMov(var, Val::Reg(Reg::RAX)));
         instrs
       },
       Expr::Block(exprs) => {
@@ -718,47 +2433,73 @@ fn compile_to_instrs(e: &Expr, si: i64, env: &HashMap<String, i64>, l :&mut i64,
           if exprs.len() == 0 {
               panic!("parse error: Invalid: No instructions in block which is invalid");
           }
+          let mut env = env.clone();
           for (i,  expr) in exprs.iter().enumerate() {
             if i == exprs.len() - 1 {
-              instrs.extend(compile_to_instrs(expr, si, env, l, loop_stack, func_dic, is_main, is_tail ,tail_param_num, frame_size));
+              instrs.extend(compile_to_instrs(expr, si, &env, loop_stack, ctx, frame));
             }
             else {
-              instrs.extend(compile_to_instrs(expr, si, env, l, loop_stack, func_dic, is_main, false ,0, frame_size));
+              instrs.extend(compile_to_instrs(expr, si, &env, loop_stack, ctx, frame.non_tail()));
+            }
+            // a parameter checked on entry stays checked unless assigned
+            if let Expr::Check(checked, annotation) = expr {
+              if let Expr::Id(name) = &**checked {
+                if !exprs[i + 1..].iter().any(|e| assigns(e, name)) {
+                  env.known.insert(name.clone(), annotation.ty);
+                }
+              }
             }
           }
           instrs
       },
-      Expr::Call(fname, params) => {
+      Expr::Call(fname, params, pos) => {
         let mut instrs = vec![];
-        if !(func_dic.contains_key(fname)) {
-          panic!("parse error: Invalid Function {} is not defined", fname);
-        }
-        else if func_dic[fname] != params.len() as i32 {
-          panic!("parse error: Function {} expects {} arguments, but actually receive {} arguments", fname, func_dic[fname], params.len());
+        let local = env.funs.get(fname);
+        let (label, arity) = match local {
+          Some(f) => (f.label.clone(), f.arity as i32),
+          None if ctx.func_dic.contains_key(fname) => (fname.clone(), ctx.func_dic[fname]),
+          None => panic!("parse error: Invalid Function {} is not defined", fname),
+        };
+        if arity != params.len() as i32 {
+          panic!("parse error: Function {} expects {} arguments, but actually receive {} arguments", fname, arity, params.len());
         }
-        let param_offset = (params.len() as i64 + 1) / 2 * 2;
+        // a local function's static link goes before the arguments
+        let first = local.is_some() as usize;
+        let args = params.len() + first;
+        let param_offset = (args as i64 + 1) / 2 * 2;
         for (i,  expr) in params.iter().enumerate() {
-          instrs.extend(compile_to_instrs(expr, si + i as i64, env, l, loop_stack, func_dic, is_main, false, 0, frame_size));
+          instrs.extend(compile_to_instrs(expr, si + i as i64, env, loop_stack, ctx, frame.non_tail()));
           instrs.push(Instr::IMov(
               Val::RegOffset(Reg::RSP, si + i as i64),
               Val::Reg(Reg::RAX),));
         }
+        instrs.extend(ctx.dbg.line(pos));
+        if let Some(f) = local {
+          instrs.extend(static_link(env, f.hops));
+          instrs.push(Instr::IMov(Val::RegOffset(Reg::RSP, -param_offset), Val::Reg(Reg::RAX)));
+        }
+        // first put all the arguments into the right place
+        for (i,  _) in params.iter().enumerate() {
+          instrs.push(Instr::IMov(
+            Val::Reg(Reg::RAX),
+            Val::RegOffset(Reg::RSP, si + i as i64),));
+          instrs.push(Instr::IMov(
+              Val::RegOffset(Reg::RSP, param_offset * -1 + (first + i) as i64),
+              Val::Reg(Reg::RAX),));
+        }
         let enable_tail_call = true;
-        if enable_tail_call && is_tail && params.len() as i32 <= tail_param_num  {
+        // a function defined right here needs this frame to stay
+        let keeps_frame = local.map_or(false, |f| f.hops == 0);
+        if enable_tail_call && frame.is_tail && args as i32 <= frame.tail_param_num && !keeps_frame {
           // proper tail call
-          // first put all the arguments into the right place
-          for (i,  _) in params.iter().enumerate() {
-            instrs.push(Instr::IMov(
-              Val::Reg(Reg::RAX),
-              Val::RegOffset(Reg::RSP, si + i as i64),));
-            instrs.push(Instr::IMov(
-                Val::RegOffset(Reg::RSP, param_offset * -1 + i as i64),
-                Val::Reg(Reg::RAX),));
-          }
           // Then put them back into the previous argument palce
-          let frame_offset = (frame_size / 8) as i64;
-          instrs.push(Instr::IAdd(Val::Reg(Reg::RSP), Val::Const(frame_size as i64)));
-          for (i,  _) in params.iter().enumerate() {
+          let frame_offset = (frame.frame_size / 8) as i64;
+          // the callee's frame replaces ours, so it inherits our count of
+          // elided frames plus one
+          instrs.push(Instr::IMov(Val::Reg(Reg::RBX), Val::RegOffset(Reg::RSP, frame_offset - 1)));
+          instrs.push(Instr::IAdd(Val::Reg(Reg::RBX), Val::Const(1)));
+          instrs.push(Instr::IAdd(Val::Reg(Reg::RSP), Val::Const(frame.frame_size as i64)));
+          for i in 0..args {
             instrs.push(Instr::IMov(
               Val::Reg(Reg::RAX),
               Val::RegOffset(Reg::RSP, -frame_offset + param_offset * -1 + i as i64)));
@@ -766,30 +2507,166 @@ fn compile_to_instrs(e: &Expr, si: i64, env: &HashMap<String, i64>, l :&mut i64,
                 Val::RegOffset(Reg::RSP, (i + 1) as i64),
                 Val::Reg(Reg::RAX),));
           }
-          instrs.push(Instr::Jmp(fname.clone()));
+          instrs.push(Instr::Jmp(tail_entry(&label)));
         }
         else {
           // normal call
-          for (i,  _) in params.iter().enumerate() {
-            instrs.push(Instr::IMov(
-              Val::Reg(Reg::RAX),
-              Val::RegOffset(Reg::RSP, si + i as i64),));
-            instrs.push(Instr::IMov(
-                Val::RegOffset(Reg::RSP, param_offset * -1 + i as i64),
-                Val::Reg(Reg::RAX),));
-          }
+          let site = new_site(ctx.sites, fname, *pos);
+          ctx.sites[site as usize].call_args = Some(param_offset * 8);
           instrs.push(Instr::ISub(Val::Reg(Reg::RSP), Val::Const(param_offset * 8)));
-          instrs.push(Instr::Call(fname.clone()));
+          instrs.push(Instr::Call(label));
+          // the return address the runtime matches against the site table
+          instrs.push(Instr::Label(format!("snek_ret{site}")));
           instrs.push(Instr::IAdd(Val::Reg(Reg::RSP), Val::Const(param_offset * 8)));
         }
         instrs
       },
+      Expr::LocalFuns(defs, body) => {
+        let mut inner = env.clone();
+        let mut labels = vec![];
+        for Definition::Func(_, args, _) in defs {
+          let label = new_label(&mut ctx.label_id, &format!("{}$", args[0]));
+          inner.funs.insert(args[0].clone(), LocalFun { label: label.clone(), arity: args.len() - 1, hops: 0 });
+          labels.push(label);
+        }
+        // the functions' code sits in line, jumped over
+        let end = new_label(&mut ctx.label_id, "local_funs_end");
+        let mut instrs = vec![Instr::Jmp(end.clone())];
+        for (def, label) in defs.iter().zip(&labels) {
+          instrs.extend(compile_definition(def, label, Some(&inner), ctx));
+        }
+        instrs.push(Instr::Label(end));
+        instrs.extend(compile_to_instrs(body, si, &inner, loop_stack, ctx, frame));
+        instrs
+      },
+  };
+  line.extend(instrs);
+  line
+}
+
+// Loads the string literal `text` into `reg`, laying it out once per
+// object. Constructors are told apart by the address of their name.
+fn string_literal(text: &str, reg: Reg, strings: &mut Vec<String>) -> Vec<Instr> {
+  let i = match strings.iter().position(|s| s == text) {
+    Some(i) => i,
+    None => {
+      strings.push(text.to_string());
+      strings.len() - 1
+    }
+  };
+  vec![
+    Instr::Lea(Val::Reg(reg), format!("snek_str{i}")),
+    Instr::IAdd(Val::Reg(reg), Val::Const(STRING_TAG)),
+  ]
+}
+
+// Where a variable lives, after the instructions finding it: its stack slot,
+// through rcx in an enclosing function's frame, or else the global's word.
+fn variable(name: &str, env: &Env, globals: &[String]) -> (Vec<Instr>, Val) {
+  match (env.vars.get(name), globals.iter().position(|g| g == name)) {
+    (Some(&(0, slot)), _) => (vec![], Val::RegOffset(Reg::RSP, slot)),
+    (Some(&(hops, slot)), _) => (follow_links(env, hops, Reg::RCX), Val::RegOffset(Reg::RCX, slot)),
+    (None, Some(i)) => (vec![], Val::Global(i)),
+    (None, None) => panic!("Unbound variable identifier {name}"),
+  }
+}
+
+// The position of `e`'s opening paren, for forms that have one.
+fn expr_pos(e: &Expr) -> Option<&Pos> {
+  match e {
+    Expr::InputAt(_, pos) | Expr::Let(_, _, pos) | Expr::UnOp(_, _, pos) | Expr::BinOp(_, _, _, pos)
+    | Expr::Set(_, _, pos) | Expr::If(_, _, _, pos) | Expr::Break(_, _, pos) | Expr::Continue(_, pos) | Expr::Call(_, _, pos)
+    | Expr::Print(_, pos) | Expr::Substring(_, _, _, pos) => Some(pos),
+    _ => None,
   }
 }
 
 fn main() -> std::io::Result<()> {
     let args: Vec<String> = env::args().collect();
 
+    if args.len() >= 2 && args[1] == "repl" {
+        let inputs = interp::parse_inputs(&args[2..], false);
+        // the interpreter recurses on the Rust stack for non-tail calls
+        let session = std::thread::Builder::new()
+            .stack_size(REPL_STACK_SIZE)
+            .spawn(move || repl::run(inputs))?;
+        session.join().ok();
+        return Ok(());
+    }
+
+    if args.len() >= 2 && args[1] == "fmt" {
+        return fmt::run(&args[2..]);
+    }
+
+    if args.len() >= 2 && args[1] == "lsp" {
+        lsp::run();
+        return Ok(());
+    }
+
+    if args.len() >= 3 && args[1] == "link" {
+        return link(&args[2], &args[3..]);
+    }
+
+    if args.len() >= 3 && args[1] == "vm" {
+        let mut bc_file = File::open(&args[2])?;
+        let mut bytes = vec![];
+        bc_file.read_to_end(&mut bytes)?;
+        let chunk = match bytecode::Chunk::decode(&bytes) {
+            Ok(chunk) => chunk,
+            Err(e) => panic!("Invalid bytecode file {}: {e}", args[2]),
+        };
+        let inputs: Vec<i64> = interp::parse_inputs(&args[3..], chunk.typed).into_iter().map(vm::input_word).collect();
+        match vm::run(&chunk, &inputs) {
+            Ok(v) => println!("{}", v),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+
+    // `--backend vm` emits bytecode for `cobra vm` instead of assembly;
+    // `--debug` adds DWARF line and variable info to the assembly;
+    // `--trap-overflow` makes arithmetic leaving the fixnum range an error
+    // instead of producing a bignum;
+    // `--separate` compiles the file on its own against the interfaces of
+    // the modules it imports, for `cobra link` to combine with theirs;
+    // `--typecheck` infers the program's types first, rejecting it on a
+    // type error, and then leaves out the checks the types make redundant;
+    // the program then only takes numbers as input, and `input` reads 0
+    // without any
+    let mut backend = "native";
+    let mut debug = false;
+    let mut trap_overflow = false;
+    let mut separate = false;
+    let mut typecheck = false;
+    let mut args = args;
+    loop {
+        if args.len() >= 3 && args[1] == "--backend" {
+            backend = match args[2].as_str() {
+                "native" => "native",
+                "vm" => "vm",
+                other => panic!("Unknown backend {other}, expected native or vm"),
+            };
+            args.drain(1..3);
+        } else if args.len() >= 2 && args[1] == "--debug" {
+            debug = true;
+            args.remove(1);
+        } else if args.len() >= 2 && args[1] == "--trap-overflow" {
+            trap_overflow = true;
+            args.remove(1);
+        } else if args.len() >= 2 && args[1] == "--separate" {
+            separate = true;
+            args.remove(1);
+        } else if args.len() >= 2 && args[1] == "--typecheck" {
+            typecheck = true;
+            args.remove(1);
+        } else {
+            break;
+        }
+    }
+
     let in_name = &args[1];
     let out_name = &args[2];
     
@@ -799,32 +2676,72 @@ fn main() -> std::io::Result<()> {
     in_file.read_to_string(&mut in_contents)?;
     
     // parse: string -> sexp
-    let prog  = "(".to_owned() + &in_contents + ")";
-    let parsed_sexp: Result<Sexp, Box<Error>> = parse(&prog);
-    match &parsed_sexp {
+    let parsed_sexp = match reader::parse_all(&in_contents) {
       Err(e) => panic!("Invalid sexp {e}"),
-      Ok(p) => println!("parsed_sexp: {:?}", p),
+      Ok(forms) => Sexp::List(forms, Pos { line: 1, col: 1 }),
+    };
+    println!("parsed_sexp: {}", parsed_sexp);
+
+    if separate {
+        if typecheck {
+            panic!("--typecheck needs the whole program, and is not supported with --separate");
+        }
+        let unit = modules::load_separate(in_name, &parsed_sexp);
+        if backend == "vm" {
+            panic!("--separate is only supported by the native backend");
+        }
+        let mut sites = vec![];
+        let mut strings = vec![];
+        let mut floats = vec![];
+        let mut dbg = debug::DebugInfo::new(debug);
+        let globals = unit.globals.len();
+        let code = match unit.main {
+            Some(main) => {
+                let prog = Program { defs: unit.defs, globals: unit.globals, main };
+                println!("Program: {:?}", prog);
+                compile_program(&prog, &unit.externs, false, &mut sites, &mut strings, &mut floats, &mut dbg)
+            }
+            None => {
+                let mut ctx = CodegenCtx { sites: &mut sites, strings: &mut strings, floats: &mut floats, label_id: 0, func_dic: im::HashMap::new(), globals: &[], typed: false, dbg: &mut dbg };
+                let instrs = compile_functions(&unit.defs, &unit.externs, &mut ctx);
+                instrs.iter().map(instr_to_str).collect()
+            }
+        };
+        let object = Object { code, interface: unit.interface.as_ref(), externs: &unit.externs, links: &unit.links, globals, typed: false };
+        let asm_program = object.to_asm(&sites, &strings, &floats, &dbg, in_name, trap_overflow);
+        if let Some(interface) = &unit.interface {
+            std::fs::write(modules::interface_path(std::path::Path::new(in_name)), interface.to_string())?;
+        }
+        let mut out_file = File::create(out_name)?;
+        out_file.write_all(asm_program.as_bytes())?;
+        return Ok(());
     }
 
-    // parse: sexp -> program
-    let prog = parse_program(&parsed_sexp.unwrap());
+    // load the imported modules, expand macros, then parse: sexp -> program
+    let prog = modules::load(in_name, &parsed_sexp);
     println!("Program: {:?}", prog);
+    if typecheck {
+        types::check(&prog, in_name);
+    }
 
-    // compile: program -> asm
-    let result = compile_program(&prog);
+    if backend == "vm" {
+        if debug {
+            panic!("--debug is only supported by the native backend");
+        }
+        let chunk = bytecode::compile_program(&prog, in_name, trap_overflow, typecheck);
+        let mut out_file = File::create(out_name)?;
+        out_file.write_all(&chunk.encode())?;
+        return Ok(());
+    }
 
-    let asm_program: String = format!(
-        "
-section .text
-extern snek_error
-extern snek_print
-global our_code_starts_here
-throw_error:
-  call snek_error
-{}
-",
-        result
-    );
+    // compile: program -> asm
+    let mut sites = vec![];
+    let mut strings = vec![];
+    let mut floats = vec![];
+    let mut dbg = debug::DebugInfo::new(debug);
+    let code = compile_program(&prog, &[], typecheck, &mut sites, &mut strings, &mut floats, &mut dbg);
+    let object = Object { code, interface: None, externs: &[], links: &[], globals: prog.globals.len(), typed: typecheck };
+    let asm_program = object.to_asm(&sites, &strings, &floats, &dbg, in_name, trap_overflow);
 
     let mut out_file = File::create(out_name)?;
     out_file.write_all(asm_program.as_bytes())?;
@@ -0,0 +1,378 @@
+// Compiles `match` into decision trees, between parsing and the backends,
+// which never see a `Match`. The scrutinee is evaluated once; each test
+// then looks at one occurrence in it, the value itself or a field of a
+// constructor found by an earlier test, so nothing is tested twice. Clause
+// bodies sit in the leaves, copied where several paths reach them. A path
+// no clause covers raises a match failure at runtime, and the compiler
+// warns about it with an example of a value taking it, unless only values
+// of another type than the patterns' can.
+
+use crate::reader::Pos;
+use crate::{Data, Definition, Expr, Op1, Op2, Pattern, Variant};
+
+// A name no program can write, since the reader ends symbols at spaces.
+const MATCH_KEY: &str = "match key";
+
+// Where a test looks in the scrutinee: the fields to follow, outermost
+// first.
+type Occurrence = Vec<(Variant, usize)>;
+
+// What a test at an occurrence checks for.
+#[derive(Clone)]
+enum Head {
+    Ctor(Variant),
+    // a number, boolean or string literal
+    Lit(Expr),
+}
+
+impl Head {
+    fn same(&self, other: &Head) -> bool {
+        match (self, other) {
+            (Head::Ctor(a), Head::Ctor(b)) => a == b,
+            (Head::Lit(a), Head::Lit(b)) => same_lit(a, b),
+            _ => false,
+        }
+    }
+}
+
+// What the tests on the way to a tree say about an occurrence.
+enum Constraint {
+    Is(Head),
+    Not(Vec<Head>),
+}
+
+// A clause still in the running: the tests its pattern has left, with the
+// variables bound so far, and the index of its body.
+#[derive(Clone)]
+struct Row {
+    tests: Vec<(Occurrence, Pattern)>,
+    bindings: Vec<(String, Occurrence)>,
+    body: usize,
+}
+
+impl Row {
+    // Adds the tests for `pattern` at `occ`, at `at` in the row's tests.
+    // Wildcards and variables always match, so only the variables are kept.
+    fn add(&mut self, at: usize, occ: Occurrence, pattern: Pattern) {
+        match pattern {
+            Pattern::Wild => {}
+            Pattern::Var(name) => self.bindings.push((name, occ)),
+            p => self.tests.insert(at, (occ, p)),
+        }
+    }
+
+    fn test_at(&self, occ: &Occurrence) -> Option<&Pattern> {
+        self.tests.iter().find(|(o, _)| o == occ).map(|(_, p)| p)
+    }
+
+    // The row for values with `head` at `occ`, with the tests of the fields
+    // a constructor has in place of the constructor's, or None if its
+    // pattern wants something else there.
+    fn specialize(&self, occ: &Occurrence, head: &Head, m: &Matcher) -> Option<Row> {
+        let Some(at) = self.tests.iter().position(|(o, _)| o == occ) else {
+            return Some(self.clone());
+        };
+        if !m.head(&self.tests[at].1).same(head) {
+            return None;
+        }
+        let mut row = self.clone();
+        let (_, pattern) = row.tests.remove(at);
+        if let (Pattern::Ctor(_, fields, _), Head::Ctor(variant)) = (pattern, head) {
+            let mut at = at;
+            for (i, field) in fields.into_iter().enumerate() {
+                let mut sub = occ.clone();
+                sub.push((variant.clone(), i));
+                let before = row.tests.len();
+                row.add(at, sub, field);
+                at += row.tests.len() - before;
+            }
+        }
+        Some(row)
+    }
+}
+
+struct Matcher<'a> {
+    datas: &'a [Data],
+    bodies: Vec<Expr>,
+    pos: Pos,
+    // a value no clause matches, from the first uncovered path found
+    missing: Option<String>,
+}
+
+/// Replaces every `match` in `e`, read from `file`, with its decision tree.
+/// Constructors are looked up in `datas`.
+pub fn lower(e: &mut Expr, datas: &[Data], file: &str) {
+    match e {
+        Expr::Number(_) | Expr::Boolean(_) | Expr::Float(_) | Expr::Str(_) => {}
+        Expr::Input() | Expr::InputCount() | Expr::Id(_) | Expr::Continue(..) => {}
+        Expr::InputAt(e, _) | Expr::UnOp(_, e, _) | Expr::Set(_, e, _) | Expr::Loop(_, e) | Expr::Break(_, e, _) => {
+            lower(e, datas, file)
+        }
+        Expr::Print(e, _) | Expr::Check(e, _) | Expr::IsVariant(e, _) | Expr::Field(e, _, _) | Expr::MatchFail(e, _) => {
+            lower(e, datas, file)
+        }
+        Expr::BinOp(_, lhs, rhs, _) => {
+            lower(lhs, datas, file);
+            lower(rhs, datas, file);
+        }
+        Expr::Substring(a, b, c, _) | Expr::If(a, b, c, _) => {
+            lower(a, datas, file);
+            lower(b, datas, file);
+            lower(c, datas, file);
+        }
+        Expr::Let(bindings, body, _) => {
+            for (_, e) in bindings {
+                lower(e, datas, file);
+            }
+            lower(body, datas, file);
+        }
+        Expr::Block(exprs) | Expr::And(exprs) | Expr::Or(exprs) | Expr::Call(_, exprs, _) | Expr::Construct(_, exprs) => {
+            for e in exprs {
+                lower(e, datas, file);
+            }
+        }
+        Expr::LocalFuns(defs, body) => {
+            for Definition::Func(_, _, e) in defs {
+                lower(e, datas, file);
+            }
+            lower(body, datas, file);
+        }
+        Expr::Match(..) => {
+            let Expr::Match(mut scrutinee, clauses, pos) = std::mem::replace(e, Expr::Boolean(false)) else {
+                unreachable!()
+            };
+            lower(&mut scrutinee, datas, file);
+            let mut m = Matcher { datas, bodies: vec![], pos, missing: None };
+            let mut rows = vec![];
+            for (i, (pattern, mut body)) in clauses.into_iter().enumerate() {
+                m.check(&pattern, file);
+                lower(&mut body, datas, file);
+                m.bodies.push(body);
+                let mut row = Row { tests: vec![], bindings: vec![], body: i };
+                row.add(0, vec![], pattern);
+                rows.push(row);
+            }
+            let tree = m.tree(rows, &mut vec![], true);
+            if let Some(missing) = &m.missing {
+                eprintln!("warning: non-exhaustive match at {}:{}: {} is not matched", file, pos, missing);
+            }
+            *e = Expr::Let(vec![(MATCH_KEY.to_string(), *scrutinee)], Box::new(tree), pos);
+        }
+        Expr::Sugar(..) => unreachable!("derived forms are desugared before matches are compiled"),
+    }
+}
+
+impl Matcher<'_> {
+    // The constructor `name` and its number of fields.
+    fn lookup(&self, name: &str) -> Option<(Variant, usize)> {
+        self.datas.iter().find_map(|data| {
+            let (ctor, fields) = data.ctors.iter().find(|(ctor, _)| ctor == name)?;
+            Some((Variant { data: data.name.clone(), ctor: ctor.clone() }, fields.len()))
+        })
+    }
+
+    // Rejects patterns with unknown constructors or the wrong number of
+    // fields, and variables that look like nullary constructors.
+    fn check(&self, pattern: &Pattern, file: &str) {
+        match pattern {
+            Pattern::Wild | Pattern::Lit(_) => {}
+            Pattern::Var(name) => {
+                if self.lookup(name).is_some() {
+                    panic!("parse error: Invalid pattern variable {}, which names a constructor; write ({}) to match it", name, name);
+                }
+            }
+            Pattern::Ctor(name, fields, pos) => match self.lookup(name) {
+                None => panic!("parse error: Invalid pattern, {} is not a constructor at {}:{}", name, file, pos),
+                Some((_, n)) if n != fields.len() => panic!(
+                    "parse error: Invalid pattern, constructor {} takes {} field(s), got {} at {}:{}",
+                    name,
+                    n,
+                    fields.len(),
+                    file,
+                    pos
+                ),
+                Some(_) => {
+                    for field in fields {
+                        self.check(field, file);
+                    }
+                }
+            },
+        }
+    }
+
+    // What a refutable pattern tests for; patterns have been checked.
+    fn head(&self, pattern: &Pattern) -> Head {
+        match pattern {
+            Pattern::Ctor(name, _, _) => Head::Ctor(self.lookup(name).unwrap().0),
+            Pattern::Lit(e) => Head::Lit(e.clone()),
+            Pattern::Wild | Pattern::Var(_) => unreachable!("wildcards and variables are never tested"),
+        }
+    }
+
+    // The decision tree for `rows`, at the end of `path`. A tree that is
+    // not `reachable` can only be reached by values of another type than
+    // the patterns', and gets no warning when nothing matches there.
+    fn tree(&mut self, rows: Vec<Row>, path: &mut Vec<(Occurrence, Constraint)>, reachable: bool) -> Expr {
+        let Some(first) = rows.first() else {
+            if reachable && self.missing.is_none() {
+                self.missing = Some(self.witness(&vec![], path));
+            }
+            return Expr::MatchFail(Box::new(occurrence(&[])), self.pos);
+        };
+        // the first row to get here matches once its tests pass
+        let Some((occ, _)) = first.tests.first() else {
+            let body = self.bodies[first.body].clone();
+            if first.bindings.is_empty() {
+                return body;
+            }
+            let bindings = first.bindings.iter().map(|(name, occ)| (name.clone(), occurrence(occ))).collect();
+            return Expr::Let(bindings, Box::new(body), self.pos);
+        };
+        let occ = occ.clone();
+        let mut heads: Vec<Head> = vec![];
+        for pattern in rows.iter().filter_map(|row| row.test_at(&occ)) {
+            let head = self.head(pattern);
+            if !heads.iter().any(|h| h.same(&head)) {
+                heads.push(head);
+            }
+        }
+        let mut branches = vec![];
+        for head in &heads {
+            let specialized = rows.iter().filter_map(|row| row.specialize(&occ, head, self)).collect();
+            path.push((occ.clone(), Constraint::Is(head.clone())));
+            let branch = self.tree(specialized, path, reachable);
+            path.pop();
+            branches.push((self.test(&occ, head), branch));
+        }
+        let complete = self.complete(&heads);
+        let rest = rows.into_iter().filter(|row| row.test_at(&occ).is_none()).collect();
+        path.push((occ, Constraint::Not(heads)));
+        let default = self.tree(rest, path, reachable && !complete);
+        path.pop();
+        branches.into_iter().rev().fold(default, |els, (test, thn)| Expr::If(Box::new(test), Box::new(thn), Box::new(els), self.pos))
+    }
+
+    // Whether a test for `head` at `occ` never fails, whatever is there.
+    fn test(&self, occ: &Occurrence, head: &Head) -> Expr {
+        let at = || Box::new(occurrence(occ));
+        match head {
+            Head::Ctor(variant) => Expr::IsVariant(at(), variant.clone()),
+            Head::Lit(lit) => {
+                let (kind, equal) = match lit {
+                    Expr::Boolean(_) => (Op1::IsBool, Op2::Equal),
+                    Expr::Str(_) => (Op1::IsStr, Op2::StringEqual),
+                    _ => (Op1::IsNum, Op2::Equal),
+                };
+                Expr::And(vec![
+                    Expr::UnOp(kind, at(), self.pos),
+                    Expr::BinOp(equal, at(), Box::new(lit.clone()), self.pos),
+                ])
+            }
+        }
+    }
+
+    // Whether `heads` cover every constructor of a data type, or both
+    // booleans.
+    fn complete(&self, heads: &[Head]) -> bool {
+        let has = |b: bool| heads.iter().any(|h| matches!(h, Head::Lit(Expr::Boolean(x)) if *x == b));
+        if has(true) && has(false) {
+            return true;
+        }
+        match heads.first() {
+            Some(Head::Ctor(variant)) => {
+                let data = self.datas.iter().find(|d| d.name == variant.data).unwrap();
+                data.ctors.iter().all(|(ctor, _)| {
+                    heads.iter().any(|h| matches!(h, Head::Ctor(v) if v.data == data.name && v.ctor == *ctor))
+                })
+            }
+            _ => false,
+        }
+    }
+
+    // A value, written as a pattern, that has what `path` says at `occ`.
+    fn witness(&self, occ: &Occurrence, path: &[(Occurrence, Constraint)]) -> String {
+        let mut excluded: Vec<&Head> = vec![];
+        for (_, constraint) in path.iter().filter(|(o, _)| o == occ) {
+            match constraint {
+                Constraint::Is(Head::Ctor(variant)) => {
+                    let (_, n) = self.lookup(&variant.ctor).unwrap();
+                    let mut parts = vec![variant.ctor.clone()];
+                    for i in 0..n {
+                        let mut sub = occ.clone();
+                        sub.push((variant.clone(), i));
+                        parts.push(self.witness(&sub, path));
+                    }
+                    return format!("({})", parts.join(" "));
+                }
+                Constraint::Is(Head::Lit(lit)) => return lit_to_str(lit),
+                Constraint::Not(heads) => excluded.extend(heads),
+            }
+        }
+        self.missing_value(&excluded)
+    }
+
+    // A value none of `excluded` is, of the same kind as them.
+    fn missing_value(&self, excluded: &[&Head]) -> String {
+        let lit = |h: &&Head| match h {
+            Head::Lit(e) => Some(e.clone()),
+            Head::Ctor(_) => None,
+        };
+        let taken = |e: &Expr| excluded.iter().filter_map(lit).any(|x| same_lit(&x, e));
+        match excluded.first() {
+            Some(Head::Ctor(variant)) => {
+                let data = self.datas.iter().find(|d| d.name == variant.data).unwrap();
+                let missing = data.ctors.iter().find(|(ctor, _)| {
+                    !excluded.iter().any(|h| matches!(h, Head::Ctor(v) if v.data == data.name && v.ctor == *ctor))
+                });
+                match missing {
+                    Some((ctor, fields)) => {
+                        let parts: Vec<&str> = std::iter::once(ctor.as_str()).chain(fields.iter().map(|_| "_")).collect();
+                        format!("({})", parts.join(" "))
+                    }
+                    None => "_".to_string(),
+                }
+            }
+            Some(Head::Lit(Expr::Boolean(_))) => {
+                let missing = [true, false].into_iter().find(|b| !taken(&Expr::Boolean(*b)));
+                missing.map_or("_".to_string(), |b| b.to_string())
+            }
+            Some(Head::Lit(Expr::Str(_))) => {
+                let missing = (0..).map(|n| "a".repeat(n)).find(|s| !taken(&Expr::Str(s.clone()))).unwrap();
+                format!("{:?}", missing)
+            }
+            Some(Head::Lit(_)) => {
+                let missing = (0..).find(|n| !taken(&Expr::Number(*n))).unwrap();
+                missing.to_string()
+            }
+            None => "_".to_string(),
+        }
+    }
+}
+
+// The value at `occ`, following the fields from the scrutinee.
+fn occurrence(occ: &[(Variant, usize)]) -> Expr {
+    occ.iter().fold(Expr::Id(MATCH_KEY.to_string()), |e, (variant, i)| Expr::Field(Box::new(e), variant.clone(), *i))
+}
+
+// Whether two literals are equal under `=`, where numbers of any kind
+// compare by value.
+fn same_lit(a: &Expr, b: &Expr) -> bool {
+    match (a, b) {
+        (Expr::Number(x), Expr::Number(y)) => x == y,
+        (Expr::Number(x), Expr::Float(y)) | (Expr::Float(y), Expr::Number(x)) => *x as f64 == *y,
+        (Expr::Float(x), Expr::Float(y)) => x == y,
+        (Expr::Boolean(x), Expr::Boolean(y)) => x == y,
+        (Expr::Str(x), Expr::Str(y)) => x == y,
+        _ => false,
+    }
+}
+
+fn lit_to_str(lit: &Expr) -> String {
+    match lit {
+        Expr::Number(n) => n.to_string(),
+        Expr::Float(x) => format!("{:?}", x),
+        Expr::Boolean(b) => b.to_string(),
+        Expr::Str(s) => format!("{:?}", s),
+        _ => unreachable!("patterns only have number, boolean and string literals"),
+    }
+}
@@ -0,0 +1,441 @@
+// Loads a program together with the modules it imports. `(import "path")`
+// loads another file, relative to the importing one, and `(provide f g)`
+// lists the functions a module lets its importers call. A module's own
+// functions are renamed `<module>.<name>` so that modules can't collide;
+// calls are then resolved against what each module can see, and anything
+// left unresolved fails later as an undefined function. The program's own
+// functions keep their names.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::reader::{self, Atom::*, Sexp};
+use crate::{is_data_define, is_func_define, macros, matching, parse_definition, parse_program, Definition, Expr, Program};
+
+pub fn is_import(s: &Sexp) -> bool {
+    matches!(s, Sexp::List(vec, _) if matches!(vec.first(), Some(Sexp::Atom(S(op), _)) if op == "import"))
+}
+
+pub fn is_provide(s: &Sexp) -> bool {
+    matches!(s, Sexp::List(vec, _) if matches!(vec.first(), Some(Sexp::Atom(S(op), _)) if op == "provide"))
+}
+
+/// Parses the program in `s`, read from `path`, and the modules it imports.
+pub fn load(path: &str, s: &Sexp) -> Program {
+    let forms = match s {
+        Sexp::List(forms, _) => forms,
+        _ => panic!("parse error: Invalid program, program is not a list: \"{}\"", s),
+    };
+    let mut loader = Loader::default();
+    let root = Path::new(path);
+    loader.loading.push((canonical(root), path.to_string()));
+    let (header, body) = split_header(forms);
+    let mut names = HashMap::new();
+    loader.import_all(root, &header, &mut names);
+    let mut prog = parse_program(&macros::expand_program(&Sexp::List(body, s.pos())), path);
+    for Definition::Func(_, args, _) in &prog.defs {
+        see(&mut names, &args[0], &args[0], path);
+    }
+    for Definition::Func(_, _, body) in &mut prog.defs {
+        resolve(body, &names);
+    }
+    for (_, init) in &mut prog.globals {
+        resolve(init, &names);
+    }
+    resolve(&mut prog.main, &names);
+    loader.defs.append(&mut prog.defs);
+    Program { defs: loader.defs, globals: prog.globals, main: prog.main }
+}
+
+/// The functions the modules imported by `forms`, read from `path`, let it
+/// call, with their arities.
+pub fn visible(path: &str, forms: &[Sexp]) -> Vec<(String, usize)> {
+    let mut loader = Loader::default();
+    let root = Path::new(path);
+    loader.loading.push((canonical(root), path.to_string()));
+    let (header, _) = split_header(forms);
+    let mut names = HashMap::new();
+    loader.import_all(root, &header, &mut names);
+    let arities: HashMap<&String, usize> = loader.defs.iter().map(|Definition::Func(_, args, _)| (&args[0], args.len() - 1)).collect();
+    let mut visible: Vec<(String, usize)> = names.iter().map(|(name, qualified)| (name.clone(), arities[qualified])).collect();
+    visible.sort();
+    visible
+}
+
+/// A file compiled on its own, against the interfaces of the modules it
+/// imports instead of their source: either a program or a module, which
+/// only defines functions and gets an interface of its own.
+pub struct Unit {
+    pub defs: Vec<Definition>,
+    // a module has neither
+    pub globals: Vec<(String, Expr)>,
+    pub main: Option<Expr>,
+    // the functions of other objects it calls, with their arities
+    pub externs: Vec<(String, usize)>,
+    // the modules whose objects it has to be linked with
+    pub links: Vec<String>,
+    pub interface: Option<Interface>,
+}
+
+/// What a separately compiled module lets its importers call. It is written
+/// next to the module's source, with the extension `.snei`.
+pub struct Interface {
+    pub prefix: String,
+    // each provided function's name, qualified name and arity
+    pub provides: Vec<(String, String, usize)>,
+    // the modules whose objects its importers have to be linked with,
+    // starting with its own
+    pub links: Vec<String>,
+}
+
+pub fn interface_path(path: &Path) -> PathBuf {
+    path.with_extension("snei")
+}
+
+impl Interface {
+    fn read(module: &Path) -> Interface {
+        let path = interface_path(module);
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(_) => panic!(
+                "parse error: Invalid import, cannot read interface file \"{}\" (compile \"{}\" with --separate first)",
+                path.display(),
+                module.display()
+            ),
+        };
+        let invalid = || -> ! { panic!("parse error: Invalid interface file \"{}\"", path.display()) };
+        let forms = reader::parse_all(&text).unwrap_or_else(|_| invalid());
+        let items = match &forms[..] {
+            [Sexp::List(items, _)] => items,
+            _ => invalid(),
+        };
+        let names = |list: &Sexp, head: &str| -> Vec<Sexp> {
+            match list {
+                Sexp::List(vec, _) if matches!(vec.first(), Some(Sexp::Atom(S(h), _)) if h == head) => vec[1..].to_vec(),
+                _ => invalid(),
+            }
+        };
+        match &items[..] {
+            [Sexp::Atom(S(head), _), Sexp::Atom(S(prefix), _), provide, link] if head == "interface" => {
+                let provides = names(provide, "provide")
+                    .iter()
+                    .map(|p| match p {
+                        Sexp::List(vec, _) => match &vec[..] {
+                            [Sexp::Atom(S(name), _), Sexp::Atom(S(qualified), _), Sexp::Atom(I(arity), _)] => {
+                                (name.clone(), qualified.clone(), *arity as usize)
+                            }
+                            _ => invalid(),
+                        },
+                        _ => invalid(),
+                    })
+                    .collect();
+                let links = names(link, "link")
+                    .iter()
+                    .map(|l| match l {
+                        Sexp::Atom(S(l), _) => l.clone(),
+                        _ => invalid(),
+                    })
+                    .collect();
+                Interface { prefix: prefix.clone(), provides, links }
+            }
+            _ => invalid(),
+        }
+    }
+}
+
+impl std::fmt::Display for Interface {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let provides: Vec<String> = self.provides.iter().map(|(n, q, a)| format!("({} {} {})", n, q, a)).collect();
+        writeln!(f, "(interface {}", self.prefix)?;
+        writeln!(f, "  (provide {})", provides.join(" "))?;
+        writeln!(f, "  (link {}))", self.links.join(" "))
+    }
+}
+
+/// Parses the program or module in `s`, read from `path`, for compiling on
+/// its own: imported functions are left for the link step, and only
+/// checked against the imported modules' interfaces.
+pub fn load_separate(path: &str, s: &Sexp) -> Unit {
+    let forms = match s {
+        Sexp::List(forms, _) => forms,
+        _ => panic!("parse error: Invalid program, program is not a list: \"{}\"", s),
+    };
+    let (header, body) = split_header(forms);
+    let mut names = HashMap::new();
+    let mut externs = vec![];
+    let mut links: Vec<String> = vec![];
+    for form in header.iter().filter(|f| is_import(f)) {
+        let module = import_path(Path::new(path), form);
+        let interface = Interface::read(&module);
+        for (name, qualified, arity) in interface.provides {
+            see(&mut names, &name, &qualified, &module.display().to_string());
+            if !externs.iter().any(|(q, _)| *q == qualified) {
+                externs.push((qualified, arity));
+            }
+        }
+        for link in interface.links {
+            if !links.contains(&link) {
+                links.push(link);
+            }
+        }
+    }
+    let is_module = body.last().map_or(true, |f| is_func_define(f) || is_data_define(f) || macros::is_macro_define(f));
+    if !is_module {
+        let mut prog = parse_program(&macros::expand_program(&Sexp::List(body, s.pos())), path);
+        for Definition::Func(_, args, _) in &prog.defs {
+            see(&mut names, &args[0], &args[0], path);
+        }
+        for Definition::Func(_, _, body) in &mut prog.defs {
+            resolve(body, &names);
+        }
+        for (_, init) in &mut prog.globals {
+            resolve(init, &names);
+        }
+        resolve(&mut prog.main, &names);
+        return Unit { defs: prog.defs, globals: prog.globals, main: Some(prog.main), externs, links, interface: None };
+    }
+    let prefix = label_base(Path::new(path));
+    if links.contains(&prefix) {
+        panic!("parse error: Invalid module \"{}\", another module it imports is also named {}", path, prefix);
+    }
+    let defs = module_defs(&prefix, body, &mut names, path);
+    let mut provided: Vec<(String, String, usize)> = provides(&header, &names, path)
+        .into_iter()
+        .map(|(name, qualified)| {
+            let own = defs.iter().find_map(|Definition::Func(_, args, _)| (args[0] == qualified).then(|| args.len() - 1));
+            let arity = own.or_else(|| externs.iter().find_map(|(q, a)| (*q == qualified).then_some(*a)));
+            (name, qualified, arity.unwrap())
+        })
+        .collect();
+    provided.sort();
+    let mut interface_links = vec![prefix.clone()];
+    interface_links.extend(links.iter().cloned());
+    let interface = Interface { prefix, provides: provided, links: interface_links };
+    Unit { defs, globals: vec![], main: None, externs, links, interface: Some(interface) }
+}
+
+#[derive(Default)]
+struct Loader {
+    // the functions each module provides, by name, with their qualified
+    // names; keyed by canonical path so a module imported twice loads once
+    provided: HashMap<PathBuf, HashMap<String, String>>,
+    // the chain of imports being loaded, as canonical and displayed paths
+    loading: Vec<(PathBuf, String)>,
+    prefixes: HashSet<String>,
+    defs: Vec<Definition>,
+}
+
+impl Loader {
+    // Loads the modules `header` imports, relative to `from`, adding what
+    // they provide to `names`.
+    fn import_all(&mut self, from: &Path, header: &[Sexp], names: &mut HashMap<String, String>) {
+        for form in header.iter().filter(|f| is_import(f)) {
+            let path = import_path(from, form);
+            let shown = path.display().to_string();
+            for (name, qualified) in self.module(&path) {
+                see(names, &name, &qualified, &shown);
+            }
+        }
+    }
+
+    // The functions the module at `path` provides, loading it if needed.
+    fn module(&mut self, path: &Path) -> HashMap<String, String> {
+        let shown = path.display().to_string();
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(_) => panic!("parse error: Invalid import, cannot read file \"{}\"", shown),
+        };
+        let key = canonical(path);
+        if let Some(i) = self.loading.iter().position(|(p, _)| *p == key) {
+            let chain: Vec<&str> = self.loading[i..].iter().map(|(_, shown)| shown.as_str()).collect();
+            panic!("parse error: Invalid import cycle {} -> {}", chain.join(" -> "), shown);
+        }
+        if let Some(provided) = self.provided.get(&key) {
+            return provided.clone();
+        }
+        let forms = match reader::parse_all(&text) {
+            Ok(forms) => forms,
+            Err(e) => panic!("Invalid sexp in \"{}\": {e}", shown),
+        };
+        self.loading.push((key.clone(), shown.clone()));
+        let (header, body) = split_header(&forms);
+        let mut names = HashMap::new();
+        self.import_all(path, &header, &mut names);
+        let prefix = self.prefix(path);
+        let mut defs = module_defs(&prefix, body, &mut names, &shown);
+        let provided = provides(&header, &names, &shown);
+        self.defs.append(&mut defs);
+        self.loading.pop();
+        self.provided.insert(key, provided.clone());
+        provided
+    }
+
+    // A prefix for the functions of the module at `path`: its file name,
+    // made into a valid label, and numbered if another module has it.
+    fn prefix(&mut self, path: &Path) -> String {
+        let base = label_base(path);
+        let mut prefix = base.clone();
+        let mut n = 1;
+        while self.prefixes.contains(&prefix) {
+            n += 1;
+            prefix = format!("{}{}", base, n);
+        }
+        self.prefixes.insert(prefix.clone());
+        prefix
+    }
+}
+
+// The file an `import` form names, relative to the importing file `from`.
+fn import_path(from: &Path, form: &Sexp) -> PathBuf {
+    match form {
+        Sexp::List(vec, _) => match &vec[..] {
+            [_, Sexp::Atom(Str(target), _)] => from.parent().unwrap_or(Path::new("")).join(target),
+            _ => panic!("parse error: Invalid import \"{}\"", form),
+        },
+        _ => unreachable!(),
+    }
+}
+
+fn label_base(path: &Path) -> String {
+    let stem = path.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+    let base: String = stem.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+    if base.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        base
+    } else {
+        format!("m{}", base)
+    }
+}
+
+// Parses the definitions of a module, which can have nothing else, naming
+// them `<prefix>.<name>` and resolving their calls. `names` gets the
+// module's own functions, on top of what it imports.
+fn module_defs(prefix: &str, body: Vec<Sexp>, names: &mut HashMap<String, String>, shown: &str) -> Vec<Definition> {
+    let start = reader::Pos { line: 1, col: 1 };
+    let body = match macros::expand_program(&Sexp::List(body, start)) {
+        Sexp::List(body, _) => body,
+        _ => unreachable!(),
+    };
+    let mut defs = vec![];
+    for form in &body {
+        // constructors would need qualified names, and the patterns that
+        // name them a way to see another module's types
+        if is_data_define(form) {
+            panic!("parse error: Invalid module \"{}\", which cannot declare data types: \"{}\"", shown, form);
+        }
+        if !is_func_define(form) {
+            panic!("parse error: Invalid module \"{}\", which can only define functions: \"{}\"", shown, form);
+        }
+        let mut def = parse_definition(form);
+        let Definition::Func(_, args, body) = &mut def;
+        // modules declare no data types, so their patterns name no constructors
+        matching::lower(body, &[], shown);
+        see(names, &args[0], &format!("{}.{}", prefix, args[0]), shown);
+        defs.push(def);
+    }
+    for Definition::Func(_, args, body) in &mut defs {
+        resolve(body, names);
+        args[0] = names[&args[0]].clone();
+    }
+    defs
+}
+
+// The functions a module's `provide` forms list, with their qualified names.
+fn provides(header: &[Sexp], names: &HashMap<String, String>, shown: &str) -> HashMap<String, String> {
+    let mut provided = HashMap::new();
+    for form in header.iter().filter(|f| is_provide(f)) {
+        let Sexp::List(vec, _) = form else { unreachable!() };
+        for item in &vec[1..] {
+            match item {
+                Sexp::Atom(S(name), _) => match names.get(name) {
+                    Some(qualified) => provided.insert(name.clone(), qualified.clone()),
+                    None => panic!("parse error: Invalid provide of {} from \"{}\", which doesn't define it", name, shown),
+                },
+                _ => panic!("parse error: Invalid provide \"{}\"", form),
+            };
+        }
+    }
+    provided
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+// Splits a module's `import` and `provide` forms, which have to come
+// first, from the rest.
+fn split_header(forms: &[Sexp]) -> (Vec<Sexp>, Vec<Sexp>) {
+    let n = forms.iter().take_while(|f| is_import(f) || is_provide(f)).count();
+    if let Some(late) = forms[n..].iter().find(|f| is_import(f) || is_provide(f)) {
+        panic!("parse error: Invalid program, \"{}\" has to come before the definitions", late);
+    }
+    (forms[..n].to_vec(), forms[n..].to_vec())
+}
+
+// Makes `name` call `qualified` in a module, unless it already calls
+// another function by that name.
+fn see(names: &mut HashMap<String, String>, name: &str, qualified: &str, from: &str) {
+    match names.get(name) {
+        Some(other) if other != qualified => {
+            panic!("parse error: Invalid function {} from \"{}\", which is already defined or imported", name, from)
+        }
+        _ => {
+            names.insert(name.to_string(), qualified.to_string());
+        }
+    }
+}
+
+fn resolve(e: &mut Expr, names: &HashMap<String, String>) {
+    match e {
+        Expr::Number(_) | Expr::Boolean(_) | Expr::Float(_) | Expr::Str(_) => {}
+        Expr::Input() | Expr::InputCount() | Expr::Id(_) | Expr::Continue(..) => {}
+        Expr::InputAt(e, _) | Expr::UnOp(_, e, _) | Expr::Set(_, e, _) => resolve(e, names),
+        Expr::Loop(_, e) | Expr::Break(_, e, _) | Expr::Print(e, _) | Expr::Check(e, _) => resolve(e, names),
+        Expr::IsVariant(e, _) | Expr::Field(e, _, _) | Expr::MatchFail(e, _) => resolve(e, names),
+        Expr::BinOp(_, lhs, rhs, _) => {
+            resolve(lhs, names);
+            resolve(rhs, names);
+        }
+        Expr::Substring(a, b, c, _) | Expr::If(a, b, c, _) => {
+            resolve(a, names);
+            resolve(b, names);
+            resolve(c, names);
+        }
+        Expr::Let(bindings, body, _) => {
+            for (_, e) in bindings {
+                resolve(e, names);
+            }
+            resolve(body, names);
+        }
+        Expr::Block(exprs) | Expr::And(exprs) | Expr::Or(exprs) | Expr::Construct(_, exprs) => {
+            for e in exprs {
+                resolve(e, names);
+            }
+        }
+        Expr::Call(fname, args, _) => {
+            match names.get(fname) {
+                Some(qualified) => *fname = qualified.clone(),
+                // the qualified names of other modules' functions aren't
+                // visible, even written out
+                None if fname.contains('.') => panic!("parse error: Invalid Function {} is not defined", fname),
+                None => {}
+            }
+            for e in args {
+                resolve(e, names);
+            }
+        }
+        // local functions hide what they are named after
+        Expr::LocalFuns(defs, body) => {
+            let mut inner = names.clone();
+            for Definition::Func(_, args, _) in defs.iter() {
+                inner.remove(&args[0]);
+            }
+            for Definition::Func(_, _, e) in defs {
+                resolve(e, &inner);
+            }
+            resolve(body, &inner);
+        }
+        Expr::Match(..) => unreachable!("matches are compiled before modules are linked"),
+        Expr::Sugar(..) => unreachable!("derived forms are desugared before modules are linked"),
+    }
+}
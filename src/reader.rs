@@ -0,0 +1,326 @@
+use std::collections::HashMap;
+use std::fmt;
+
+/// A 1-based line and column in the source file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Pos {
+    pub line: u32,
+    pub col: u32,
+}
+
+impl fmt::Display for Pos {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Atom {
+    S(String),
+    I(i64),
+    F(f64),
+    // a string literal, with its escapes resolved
+    Str(String),
+}
+
+/// An s-expression that remembers where it starts in the source.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Sexp {
+    Atom(Atom, Pos),
+    List(Vec<Sexp>, Pos),
+}
+
+impl fmt::Display for Atom {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Atom::S(s) => write!(f, "{}", s),
+            Atom::I(i) => write!(f, "{}", i),
+            Atom::F(x) => write!(f, "{:?}", x),
+            Atom::Str(s) => {
+                write!(f, "\"")?;
+                for c in s.chars() {
+                    match c {
+                        '"' => write!(f, "\\\"")?,
+                        '\\' => write!(f, "\\\\")?,
+                        '\n' => write!(f, "\\n")?,
+                        '\t' => write!(f, "\\t")?,
+                        c => write!(f, "{}", c)?,
+                    }
+                }
+                write!(f, "\"")
+            }
+        }
+    }
+}
+
+impl Sexp {
+    pub fn pos(&self) -> Pos {
+        match self {
+            Sexp::Atom(_, pos) | Sexp::List(_, pos) => *pos,
+        }
+    }
+}
+
+impl fmt::Display for Sexp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Sexp::Atom(a, _) => write!(f, "{}", a),
+            Sexp::List(xs, _) => {
+                write!(f, "(")?;
+                for (i, x) in xs.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{}", x)?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+/// A malformed s-expression and where it was detected.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReadError {
+    pub message: String,
+    pub pos: Pos,
+}
+
+impl ReadError {
+    /// Whether more input could complete the text: a form, string or block
+    /// comment was still open when it ran out.
+    pub fn is_unclosed(&self) -> bool {
+        self.message.starts_with("unclosed")
+    }
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {}", self.message, self.pos)
+    }
+}
+
+/// A comment and its source text: a `;` comment running to the end of its
+/// line, a `#| ... |#` block comment (these nest) or a `#;` datum comment
+/// together with the form it comments out. `trailing` comments follow code
+/// on the same line; the others start a line of their own.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Comment {
+    pub text: String,
+    pub pos: Pos,
+    pub trailing: bool,
+}
+
+/// What `parse_all` throws away: the comments, and the position just past
+/// each form, keyed by where the form starts.
+#[derive(Debug, Default)]
+pub struct Layout {
+    pub comments: Vec<Comment>,
+    pub ends: HashMap<Pos, Pos>,
+}
+
+fn error<T>(message: &str, pos: Pos) -> Result<T, ReadError> {
+    Err(ReadError { message: message.to_string(), pos })
+}
+
+struct Reader<'a> {
+    src: &'a str,
+    // the byte offset of `pos` in `src`
+    offset: usize,
+    pos: Pos,
+    // the line of the last paren or atom, to tell trailing comments apart
+    last_token_line: u32,
+    layout: Layout,
+}
+
+impl<'a> Reader<'a> {
+    fn rest(&self) -> &'a str {
+        &self.src[self.offset..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.offset += c.len_utf8();
+        if c == '\n' {
+            self.pos.line += 1;
+            self.pos.col = 1;
+        } else {
+            self.pos.col += 1;
+        }
+        Some(c)
+    }
+
+    // Skips whitespace and comments, recording the comments.
+    fn skip_whitespace(&mut self) -> Result<(), ReadError> {
+        loop {
+            let (pos, begin) = (self.pos, self.offset);
+            let trailing = pos.line == self.last_token_line;
+            if self.rest().starts_with(';') {
+                while !matches!(self.peek(), None | Some('\n')) {
+                    self.next();
+                }
+            } else if self.rest().starts_with("#|") {
+                self.block_comment()?;
+                self.last_token_line = self.pos.line;
+            } else if self.rest().starts_with("#;") {
+                self.next();
+                self.next();
+                let comments = self.layout.comments.len();
+                self.skip_whitespace()?;
+                if matches!(self.peek(), None | Some(')')) {
+                    return error("`#;` is not followed by a form", pos);
+                }
+                self.read()?;
+                // comments inside the skipped form belong to this one
+                self.layout.comments.truncate(comments);
+            } else if self.peek().map_or(false, char::is_whitespace) {
+                self.next();
+                continue;
+            } else {
+                return Ok(());
+            }
+            let text = self.src[begin..self.offset].trim_end().to_string();
+            self.layout.comments.push(Comment { text, pos, trailing });
+        }
+    }
+
+    fn block_comment(&mut self) -> Result<(), ReadError> {
+        let start = self.pos;
+        let mut depth = 0;
+        loop {
+            if self.rest().starts_with("#|") {
+                depth += 1;
+            } else if self.rest().starts_with("|#") {
+                depth -= 1;
+            } else if self.next().is_none() {
+                return error("unclosed `#|` opened", start);
+            } else {
+                continue;
+            }
+            self.next();
+            self.next();
+            if depth == 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    fn finish(&mut self, start: Pos) {
+        self.last_token_line = self.pos.line;
+        self.layout.ends.insert(start, self.pos);
+    }
+
+    fn read(&mut self) -> Result<Sexp, ReadError> {
+        self.skip_whitespace()?;
+        let start = self.pos;
+        match self.peek() {
+            None => error("unexpected end of input", start),
+            Some(')') => error("unexpected `)`", start),
+            Some('(') => {
+                self.next();
+                self.last_token_line = start.line;
+                let mut xs = vec![];
+                loop {
+                    self.skip_whitespace()?;
+                    match self.peek() {
+                        None => return error("unclosed `(` opened", start),
+                        Some(')') => {
+                            self.next();
+                            self.finish(start);
+                            return Ok(Sexp::List(xs, start));
+                        }
+                        Some(_) => xs.push(self.read()?),
+                    }
+                }
+            }
+            Some('"') => {
+                self.next();
+                let mut s = String::new();
+                loop {
+                    let escape = self.pos;
+                    match self.next() {
+                        None => return error("unclosed string opened", start),
+                        Some('"') => break,
+                        Some('\\') => match self.next() {
+                            Some('"') => s.push('"'),
+                            Some('\\') => s.push('\\'),
+                            Some('n') => s.push('\n'),
+                            Some('t') => s.push('\t'),
+                            Some(c) => return error(&format!("unknown escape `\\{}` in string", c), escape),
+                            None => return error("unclosed string opened", start),
+                        },
+                        Some(c) => s.push(c),
+                    }
+                }
+                self.finish(start);
+                Ok(Sexp::Atom(Atom::Str(s), start))
+            }
+            Some(_) => {
+                let mut token = String::new();
+                while let Some(c) = self.peek() {
+                    if c.is_whitespace() || c == '(' || c == ')' || c == ';' || c == '"' {
+                        break;
+                    }
+                    token.push(c);
+                    self.next();
+                }
+                self.finish(start);
+                Ok(Sexp::Atom(atom(token), start))
+            }
+        }
+    }
+}
+
+fn atom(token: String) -> Atom {
+    if let Ok(i) = token.parse::<i64>() {
+        Atom::I(i)
+    } else if token.starts_with(|c: char| c.is_ascii_digit() || c == '-' || c == '.')
+        && token.parse::<f64>().is_ok()
+    {
+        Atom::F(token.parse().unwrap())
+    } else {
+        Atom::S(token)
+    }
+}
+
+fn reader(src: &str) -> Reader<'_> {
+    Reader {
+        src,
+        offset: 0,
+        pos: Pos { line: 1, col: 1 },
+        last_token_line: 0,
+        layout: Layout::default(),
+    }
+}
+
+/// Reads every top-level form in `src`.
+pub fn parse_all(src: &str) -> Result<Vec<Sexp>, ReadError> {
+    parse_with_layout(src).map(|(forms, _)| forms)
+}
+
+/// Reads every top-level form in `src`, keeping the comments and form ends
+/// the formatter needs.
+pub fn parse_with_layout(src: &str) -> Result<(Vec<Sexp>, Layout), ReadError> {
+    let mut reader = reader(src);
+    let mut forms = vec![];
+    loop {
+        reader.skip_whitespace()?;
+        if reader.peek().is_none() {
+            return Ok((forms, reader.layout));
+        }
+        forms.push(reader.read()?);
+    }
+}
+
+/// The position just past the form starting at `start`, e.g. after the
+/// closing paren of a list.
+pub fn form_end(src: &str, start: Pos) -> Result<Pos, ReadError> {
+    let mut reader = reader(src);
+    while reader.pos < start && reader.next().is_some() {}
+    reader.read()?;
+    Ok(reader.pos)
+}
@@ -0,0 +1,136 @@
+use std::io::{self, BufRead, Write};
+use std::panic::{self, AssertUnwindSafe};
+
+use crate::reader::{self, Sexp};
+
+use crate::interp::{Interp, RuntimeError, Value};
+use crate::macros::{self, Macros};
+use crate::{
+    data_constructors, desugar, is_data_define, is_func_define, is_global_define, matching, parse_data, parse_definition,
+    parse_expr, parse_global, Data, Definition, Expr,
+};
+
+const PROMPT: &str = "snek> ";
+const CONTINUE_PROMPT: &str = "  ... ";
+
+enum Entry {
+    Data(Data),
+    Def(Sexp),
+    Define(String, Expr),
+    Expr(Sexp),
+}
+
+/// Runs a read-eval-print loop over stdin. Function definitions, data
+/// declarations and `(define name expr)` bindings persist for the rest of the session; every
+/// other entry is evaluated as a main expression and its value printed.
+/// Prompts go to stderr so that piped sessions only produce values on stdout.
+pub fn run(inputs: Vec<Value>) {
+    // parse and check errors surface as panics, just as in the compiler;
+    // report them as messages instead of letting the default hook print
+    // a backtrace hint
+    panic::set_hook(Box::new(|_| {}));
+
+    let mut interp = Interp::new(inputs, "repl");
+    let mut macros = Macros::default();
+    let mut datas = vec![];
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    let mut pending = String::new();
+    loop {
+        eprint!("{}", if pending.is_empty() { PROMPT } else { CONTINUE_PROMPT });
+        io::stderr().flush().ok();
+        let line = match lines.next() {
+            Some(Ok(line)) => line,
+            _ => break,
+        };
+        pending.push_str(&line);
+        pending.push('\n');
+        if pending.trim().is_empty() {
+            pending.clear();
+            continue;
+        }
+        if is_incomplete(&pending) {
+            continue;
+        }
+        let source = std::mem::take(&mut pending);
+        let result = catch(|| eval_entry(&source, &mut interp, &mut macros, &mut datas));
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => eprintln!("{}", e),
+            Err(msg) => eprintln!("Error: {}", msg),
+        }
+        io::stdout().flush().ok();
+    }
+}
+
+fn eval_entry(source: &str, interp: &mut Interp, macros: &mut Macros, datas: &mut Vec<Data>) -> Result<(), RuntimeError> {
+    // an entry may hold several forms, so read it the way `main` reads a file
+    let parsed = match reader::parse_all(source) {
+        Err(e) => panic!("Invalid sexp {e}"),
+        Ok(forms) => forms,
+    };
+    for form in parsed {
+        if macros::is_macro_define(&form) {
+            macros.redefine(&form);
+            continue;
+        }
+        match classify(macros.expand_top(&form)) {
+            // a type declared again replaces the old one
+            Entry::Data(data) => {
+                for def in data_constructors(&data) {
+                    interp.define(def);
+                }
+                datas.retain(|d| d.name != data.name);
+                datas.push(data);
+            }
+            Entry::Def(s) => {
+                let mut def = parse_definition(&s);
+                let Definition::Func(_, _, body) = &mut def;
+                matching::lower(body, datas, "repl");
+                interp.define(def)
+            }
+            Entry::Define(name, mut init) => {
+                matching::lower(&mut init, datas, "repl");
+                interp.define_global(&name, &init)?
+            }
+            Entry::Expr(s) => {
+                let mut e = desugar::expr(parse_expr(&s));
+                matching::lower(&mut e, datas, "repl");
+                println!("{}", interp.eval_main(&e)?)
+            }
+        }
+    }
+    Ok(())
+}
+
+fn classify(s: Sexp) -> Entry {
+    if is_data_define(&s) {
+        return Entry::Data(parse_data(&s));
+    }
+    if is_func_define(&s) {
+        return Entry::Def(s);
+    }
+    if is_global_define(&s) {
+        let (name, init) = parse_global(&s);
+        return Entry::Define(name, init);
+    }
+    Entry::Expr(s)
+}
+
+// Whether the entry so far stops inside a form, string or block comment;
+// other reader errors are reported when it is evaluated.
+fn is_incomplete(source: &str) -> bool {
+    matches!(reader::parse_all(source), Err(e) if e.is_unclosed())
+}
+
+fn catch<T>(f: impl FnOnce() -> T) -> Result<T, String> {
+    panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| {
+        if let Some(s) = payload.downcast_ref::<&str>() {
+            s.to_string()
+        } else if let Some(s) = payload.downcast_ref::<String>() {
+            s.clone()
+        } else {
+            "unknown error".to_string()
+        }
+    })
+}
@@ -0,0 +1,507 @@
+// The optional static type checker behind `--typecheck`: Hindley–Milner
+// inference over a whole `Program`, before any backend runs. Values are
+// numbers, booleans, strings or values of a data type, whose fields each
+// have one type for the whole program; each function gets a type scheme,
+// generalized over the type variables its group of mutually recursive
+// definitions leaves free, so that `(fun (id x) x)` can be called on
+// numbers and booleans alike. Variables, globals and loops are
+// monomorphic. A program that checks never hands an operator an operand of
+// the wrong type, so codegen drops those checks. `Num` covers floats and
+// bignums as well as fixnums, though, so the checks for an integer, on
+// bitwise operators, shifts and indices, stay.
+//
+// For the same reason a type checked program only takes numbers as input:
+// `input` is a `Num`, a `true` or `false` on the command line is rejected
+// as invalid input (exit code 2), and without any input `input` reads 0
+// rather than false.
+
+use std::collections::HashSet;
+
+use im::HashMap;
+
+use crate::reader::Pos;
+use crate::{annotated_to_str, global_uses, op1_to_str, op2_to_str, Definition, Expr, Op1, Op2, Program, Ty};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Type {
+    Num,
+    Bool,
+    Str,
+    // the data type with this index in `Checker::datas`
+    Data(usize),
+    Var(usize),
+}
+
+// A function's parameter and result types, for every instantiation of the
+// type variables in `vars`.
+#[derive(Debug, Clone)]
+struct Scheme {
+    vars: Vec<usize>,
+    params: Vec<Type>,
+    ret: Type,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Env {
+    vars: HashMap<String, Type>,
+    funs: HashMap<String, Scheme>,
+}
+
+struct Checker<'a> {
+    file: &'a str,
+    // what each type variable has been unified with, if anything
+    subst: Vec<Option<Type>>,
+    // the result type of each enclosing loop, for its `break`s
+    loops: Vec<(Option<String>, Type)>,
+    datas: Vec<String>,
+    // the type of each constructor's fields, by position
+    fields: HashMap<(String, usize), Type>,
+}
+
+/// Infers the types of `p`, panicking with the location of the first
+/// expression whose type doesn't fit where it is used.
+pub fn check(p: &Program, file: &str) {
+    let mut c = Checker { file, subst: vec![], loops: vec![], datas: vec![], fields: HashMap::new() };
+    let mut env = Env::default();
+    for (name, _) in &p.globals {
+        let t = c.fresh();
+        env.vars.insert(name.clone(), t);
+    }
+    // callees are generalized before their callers can instantiate them
+    for group in call_groups(&p.defs) {
+        let defs: Vec<&Definition> = group.iter().map(|&i| &p.defs[i]).collect();
+        for (name, scheme) in c.group(&defs, &env) {
+            env.funs.insert(name, scheme);
+        }
+    }
+    for (name, init) in &p.globals {
+        let t = c.expr(init, &env, Pos { line: 1, col: 1 });
+        let global = env.vars[name];
+        if c.unify(global, t).is_err() {
+            c.mismatch(&format!("global `{}`", name), global, t, tail_pos(init));
+        }
+    }
+    c.expr(&p.main, &env, Pos { line: 1, col: 1 });
+}
+
+// The program's functions grouped into strongly connected components of
+// the call graph, each after the groups it calls (Tarjan's algorithm).
+fn call_groups(defs: &[Definition]) -> Vec<Vec<usize>> {
+    let index: std::collections::HashMap<&str, usize> =
+        defs.iter().enumerate().map(|(i, Definition::Func(_, args, _))| (args[0].as_str(), i)).collect();
+    let edges: Vec<Vec<usize>> = defs
+        .iter()
+        .map(|Definition::Func(_, args, body)| {
+            let (mut uses, mut calls) = (vec![], vec![]);
+            global_uses(body, &mut args[1..].to_vec(), &mut uses, &mut calls);
+            calls.iter().filter_map(|c| index.get(c.as_str()).copied()).collect()
+        })
+        .collect();
+
+    struct Tarjan<'a> {
+        edges: &'a [Vec<usize>],
+        visited: usize,
+        order: Vec<Option<usize>>,
+        low: Vec<usize>,
+        stack: Vec<usize>,
+        on_stack: Vec<bool>,
+        groups: Vec<Vec<usize>>,
+    }
+    impl Tarjan<'_> {
+        fn visit(&mut self, v: usize) {
+            self.order[v] = Some(self.visited);
+            self.low[v] = self.visited;
+            self.visited += 1;
+            self.stack.push(v);
+            self.on_stack[v] = true;
+            for &w in &self.edges[v] {
+                match self.order[w] {
+                    None => {
+                        self.visit(w);
+                        self.low[v] = self.low[v].min(self.low[w]);
+                    }
+                    Some(o) if self.on_stack[w] => self.low[v] = self.low[v].min(o),
+                    Some(_) => {}
+                }
+            }
+            if Some(self.low[v]) == self.order[v] {
+                let mut group = vec![];
+                loop {
+                    let w = self.stack.pop().unwrap();
+                    self.on_stack[w] = false;
+                    group.push(w);
+                    if w == v {
+                        break;
+                    }
+                }
+                group.reverse();
+                self.groups.push(group);
+            }
+        }
+    }
+
+    let n = defs.len();
+    let mut t = Tarjan { edges: &edges, visited: 0, order: vec![None; n], low: vec![0; n], stack: vec![], on_stack: vec![false; n], groups: vec![] };
+    for v in 0..n {
+        if t.order[v].is_none() {
+            t.visit(v);
+        }
+    }
+    t.groups
+}
+
+// The position of the form whose value `e` produces, if it has one.
+fn tail_pos(e: &Expr) -> Option<Pos> {
+    match e {
+        Expr::InputAt(_, pos)
+        | Expr::Let(_, _, pos)
+        | Expr::UnOp(_, _, pos)
+        | Expr::BinOp(_, _, _, pos)
+        | Expr::Substring(_, _, _, pos)
+        | Expr::Set(_, _, pos)
+        | Expr::If(_, _, _, pos)
+        | Expr::Call(_, _, pos)
+        | Expr::Print(_, pos)
+        | Expr::MatchFail(_, pos) => Some(*pos),
+        Expr::Block(exprs) | Expr::And(exprs) | Expr::Or(exprs) => exprs.last().and_then(tail_pos),
+        Expr::LocalFuns(_, body) | Expr::Check(body, _) => tail_pos(body),
+        _ => None,
+    }
+}
+
+impl Checker<'_> {
+    fn fresh(&mut self) -> Type {
+        self.subst.push(None);
+        Type::Var(self.subst.len() - 1)
+    }
+
+    fn data(&mut self, name: &str) -> Type {
+        match self.datas.iter().position(|d| d == name) {
+            Some(i) => Type::Data(i),
+            None => {
+                self.datas.push(name.to_string());
+                Type::Data(self.datas.len() - 1)
+            }
+        }
+    }
+
+    fn field(&mut self, ctor: &str, i: usize) -> Type {
+        match self.fields.get(&(ctor.to_string(), i)) {
+            Some(t) => *t,
+            None => {
+                let t = self.fresh();
+                self.fields.insert((ctor.to_string(), i), t);
+                t
+            }
+        }
+    }
+
+    fn show(&self, t: Type) -> String {
+        match self.resolve(t) {
+            Type::Num => "Num".to_string(),
+            Type::Bool => "Bool".to_string(),
+            Type::Str => "Str".to_string(),
+            Type::Data(i) => self.datas[i].clone(),
+            Type::Var(n) => format!("t{}", n),
+        }
+    }
+
+    fn resolve(&self, t: Type) -> Type {
+        match t {
+            Type::Var(n) => match self.subst[n] {
+                Some(t) => self.resolve(t),
+                None => t,
+            },
+            _ => t,
+        }
+    }
+
+    // Types are atomic, so a variable never needs an occurs check.
+    fn unify(&mut self, a: Type, b: Type) -> Result<(), ()> {
+        match (self.resolve(a), self.resolve(b)) {
+            (a, b) if a == b => Ok(()),
+            (Type::Var(n), t) | (t, Type::Var(n)) => {
+                self.subst[n] = Some(t);
+                Ok(())
+            }
+            _ => Err(()),
+        }
+    }
+
+    fn mismatch(&self, what: &str, expected: Type, got: Type, pos: Option<Pos>) -> ! {
+        let (expected, got) = (self.show(expected), self.show(got));
+        match pos {
+            Some(pos) => panic!("type error: {} expected {}, got {} at {}:{}", what, expected, got, self.file, pos),
+            None => panic!("type error: {} expected {}, got {} in {}", what, expected, got, self.file),
+        }
+    }
+
+    // Unifies the type `got` of an operand of `what` at `pos` with the one
+    // it takes.
+    fn expect(&mut self, what: &str, expected: Type, got: Type, pos: Pos) {
+        if self.unify(expected, got).is_err() {
+            self.mismatch(what, expected, got, Some(pos));
+        }
+    }
+
+    fn free_vars(&self, t: Type, out: &mut HashSet<usize>) {
+        if let Type::Var(n) = self.resolve(t) {
+            out.insert(n);
+        }
+    }
+
+    fn env_vars(&self, env: &Env) -> HashSet<usize> {
+        let mut out = HashSet::new();
+        for t in env.vars.values() {
+            self.free_vars(*t, &mut out);
+        }
+        for scheme in env.funs.values() {
+            let mut own = HashSet::new();
+            for t in scheme.params.iter().chain([&scheme.ret]) {
+                self.free_vars(*t, &mut own);
+            }
+            out.extend(own.into_iter().filter(|n| !scheme.vars.contains(n)));
+        }
+        // fields are never generalized
+        for t in self.fields.values() {
+            self.free_vars(*t, &mut out);
+        }
+        out
+    }
+
+    fn instantiate(&mut self, scheme: &Scheme) -> (Vec<Type>, Type) {
+        let fresh: Vec<(usize, Type)> = scheme.vars.iter().map(|&n| (n, self.fresh())).collect();
+        let inst = |c: &Self, t: Type| match c.resolve(t) {
+            Type::Var(n) => fresh.iter().find(|(m, _)| *m == n).map_or(Type::Var(n), |(_, t)| *t),
+            t => t,
+        };
+        (scheme.params.iter().map(|t| inst(self, *t)).collect(), inst(self, scheme.ret))
+    }
+
+    // Infers a group of functions that may call each other, monomorphic
+    // within the group, and generalizes them over what `env` leaves free.
+    fn group(&mut self, defs: &[&Definition], env: &Env) -> Vec<(String, Scheme)> {
+        let mut inner = env.clone();
+        for Definition::Func(_, args, _) in defs {
+            let params = args[1..].iter().map(|_| self.fresh()).collect();
+            let ret = self.fresh();
+            inner.funs.insert(args[0].clone(), Scheme { vars: vec![], params, ret });
+        }
+        for Definition::Func(_, args, body) in defs {
+            let scheme = inner.funs[&args[0]].clone();
+            let mut body_env = inner.clone();
+            for (arg, t) in args[1..].iter().zip(&scheme.params) {
+                body_env.vars.insert(arg.clone(), *t);
+            }
+            let loops = std::mem::take(&mut self.loops);
+            let t = self.expr(body, &body_env, tail_pos(body).unwrap_or(Pos { line: 1, col: 1 }));
+            self.loops = loops;
+            if self.unify(scheme.ret, t).is_err() {
+                self.mismatch(&format!("the result of `{}`", args[0]), scheme.ret, t, tail_pos(body));
+            }
+        }
+        let outer = self.env_vars(env);
+        defs.iter()
+            .map(|Definition::Func(_, args, _)| {
+                let mut scheme = inner.funs[&args[0]].clone();
+                let mut vars = HashSet::new();
+                for t in scheme.params.iter().chain([&scheme.ret]) {
+                    self.free_vars(*t, &mut vars);
+                }
+                scheme.vars = vars.into_iter().filter(|n| !outer.contains(n)).collect();
+                (args[0].clone(), scheme)
+            })
+            .collect()
+    }
+
+    // The type of `e`; `pos` is that of the innermost enclosing form, for
+    // the forms that have none of their own.
+    fn expr(&mut self, e: &Expr, env: &Env, pos: Pos) -> Type {
+        match e {
+            Expr::Number(_) | Expr::Float(_) => Type::Num,
+            Expr::Boolean(_) => Type::Bool,
+            Expr::Str(_) => Type::Str,
+            // the inputs of a type checked program are all numbers
+            Expr::Input() | Expr::InputCount() => Type::Num,
+            Expr::InputAt(i, pos) => {
+                let t = self.expr(i, env, *pos);
+                self.expect("`input`", Type::Num, t, *pos);
+                Type::Num
+            }
+            // unknown names are left to the backends to report
+            Expr::Id(name) => match env.vars.get(name) {
+                Some(t) => *t,
+                None => self.fresh(),
+            },
+            Expr::Let(bindings, body, pos) => {
+                let mut env = env.clone();
+                for (name, e) in bindings {
+                    let t = self.expr(e, &env, *pos);
+                    env.vars.insert(name.clone(), t);
+                }
+                self.expr(body, &env, *pos)
+            }
+            Expr::UnOp(op, e, pos) => {
+                let t = self.expr(e, env, *pos);
+                let what = format!("`{}`", op1_to_str(op));
+                match op {
+                    Op1::Add1 | Op1::Sub1 | Op1::Float | Op1::Truncate => {
+                        self.expect(&what, Type::Num, t, *pos);
+                        Type::Num
+                    }
+                    Op1::IsNum | Op1::IsBool | Op1::IsStr => Type::Bool,
+                    Op1::StringLength => {
+                        self.expect(&what, Type::Str, t, *pos);
+                        Type::Num
+                    }
+                    Op1::Not => {
+                        self.expect(&what, Type::Bool, t, *pos);
+                        Type::Bool
+                    }
+                }
+            }
+            Expr::BinOp(op, lhs, rhs, pos) => {
+                let l = self.expr(lhs, env, *pos);
+                let r = self.expr(rhs, env, *pos);
+                let what = format!("`{}`", op2_to_str(op));
+                let (operand, result) = match op {
+                    Op2::Equal => {
+                        if self.unify(l, r).is_err() {
+                            let (l, r) = (self.show(l), self.show(r));
+                            panic!("type error: {} expected operands of the same type, got {} and {} at {}:{}", what, l, r, self.file, pos);
+                        }
+                        return Type::Bool;
+                    }
+                    Op2::Greater | Op2::GreaterEqual | Op2::Less | Op2::LessEqual => (Type::Num, Type::Bool),
+                    Op2::StringAppend => (Type::Str, Type::Str),
+                    Op2::StringEqual => (Type::Str, Type::Bool),
+                    _ => (Type::Num, Type::Num),
+                };
+                self.expect(&what, operand, l, *pos);
+                self.expect(&what, operand, r, *pos);
+                result
+            }
+            Expr::Substring(s, start, end, pos) => {
+                let types = [self.expr(s, env, *pos), self.expr(start, env, *pos), self.expr(end, env, *pos)];
+                for (t, expected) in types.into_iter().zip([Type::Str, Type::Num, Type::Num]) {
+                    self.expect("`substring`", expected, t, *pos);
+                }
+                Type::Str
+            }
+            Expr::Set(name, e, pos) => {
+                let t = self.expr(e, env, *pos);
+                if let Some(var) = env.vars.get(name) {
+                    self.expect(&format!("`set!` of `{}`", name), *var, t, *pos);
+                }
+                t
+            }
+            Expr::If(cond, thn, els, pos) => {
+                let c = self.expr(cond, env, *pos);
+                self.expect("the condition of `if`", Type::Bool, c, *pos);
+                let t = self.expr(thn, env, *pos);
+                let f = self.expr(els, env, *pos);
+                if self.unify(t, f).is_err() {
+                    let (t, f) = (self.show(t), self.show(f));
+                    panic!("type error: `if` expected branches of the same type, got {} and {} at {}:{}", t, f, self.file, pos);
+                }
+                t
+            }
+            Expr::Block(exprs) => {
+                let mut t = None;
+                for e in exprs {
+                    t = Some(self.expr(e, env, pos));
+                }
+                t.unwrap_or_else(|| self.fresh())
+            }
+            Expr::And(exprs) | Expr::Or(exprs) => {
+                let what = if let Expr::And(_) = e { "`and`" } else { "`or`" };
+                for e in exprs {
+                    let t = self.expr(e, env, pos);
+                    self.expect(what, Type::Bool, t, tail_pos(e).unwrap_or(pos));
+                }
+                Type::Bool
+            }
+            Expr::Loop(label, body) => {
+                let t = self.fresh();
+                self.loops.push((label.clone(), t));
+                self.expr(body, env, pos);
+                self.loops.pop();
+                t
+            }
+            Expr::Break(label, e, pos) => {
+                let t = self.expr(e, env, *pos);
+                let target = match label {
+                    None => self.loops.last(),
+                    Some(_) => self.loops.iter().rev().find(|(l, _)| l == label),
+                };
+                if let Some(&(_, loop_type)) = target {
+                    self.expect("`break`", loop_type, t, *pos);
+                }
+                // control never comes back
+                self.fresh()
+            }
+            Expr::Continue(..) => self.fresh(),
+            Expr::Call(name, args, pos) => {
+                let types: Vec<Type> = args.iter().map(|e| self.expr(e, env, *pos)).collect();
+                match env.funs.get(name).cloned() {
+                    Some(scheme) if scheme.params.len() == types.len() => {
+                        let (params, ret) = self.instantiate(&scheme);
+                        for (i, (param, t)) in params.into_iter().zip(types).enumerate() {
+                            self.expect(&format!("argument {} of `{}`", i + 1, name), param, t, *pos);
+                        }
+                        ret
+                    }
+                    // the backends report unknown functions and wrong arities
+                    _ => self.fresh(),
+                }
+            }
+            Expr::Print(e, pos) => self.expr(e, env, *pos),
+            Expr::LocalFuns(defs, body) => {
+                let defs: Vec<&Definition> = defs.iter().collect();
+                let mut env = env.clone();
+                for (name, scheme) in self.group(&defs, &env) {
+                    env.funs.insert(name, scheme);
+                }
+                self.expr(body, &env, pos)
+            }
+            // annotations are types the inference has to agree with
+            Expr::Check(e, annotation) => {
+                let t = self.expr(e, env, pos);
+                let ty = match annotation.ty {
+                    Ty::Num => Type::Num,
+                    Ty::Bool => Type::Bool,
+                    Ty::Str => Type::Str,
+                };
+                self.expect(&annotated_to_str(annotation), ty, t, annotation.pos);
+                ty
+            }
+            Expr::Construct(variant, args) => {
+                for (i, e) in args.iter().enumerate() {
+                    let t = self.expr(e, env, pos);
+                    let field = self.field(&variant.ctor, i);
+                    self.expect(&format!("field {} of `{}`", i + 1, variant.ctor), field, t, pos);
+                }
+                self.data(&variant.data)
+            }
+            // the tests and fields a match compiles to
+            Expr::IsVariant(e, variant) => {
+                let t = self.expr(e, env, pos);
+                let data = self.data(&variant.data);
+                self.expect("`match`", data, t, pos);
+                Type::Bool
+            }
+            Expr::Field(e, variant, i) => {
+                let t = self.expr(e, env, pos);
+                let data = self.data(&variant.data);
+                self.expect("`match`", data, t, pos);
+                self.field(&variant.ctor, *i)
+            }
+            Expr::MatchFail(e, pos) => {
+                self.expr(e, env, *pos);
+                self.fresh()
+            }
+            Expr::Match(..) => unreachable!("matches are lowered before checking"),
+            Expr::Sugar(..) => unreachable!("derived forms are desugared before checking"),
+        }
+    }
+}
@@ -0,0 +1,464 @@
+use std::rc::Rc;
+use std::sync::Arc;
+
+use crate::bignum::BigInt;
+use crate::bytecode::{Chunk, Op};
+use crate::interp::{self, ErrorKind, RuntimeError, TraceCall, Value};
+use crate::{Op1, Op2, Ty, FALSE_CONST, TRUE_CONST};
+
+// A suspended caller. `func` and `elided` describe the caller itself (None
+// for main) and `site` the call it is waiting on, for stack traces.
+struct Frame {
+    ret: usize,
+    base: usize,
+    func: Option<u32>,
+    elided: usize,
+    site: u32,
+}
+
+// Strings, bignums and floats live in tables of their own rather than in
+// memory, so their words hold an index into the table in place of an
+// address, with the same tags as the native backend's. The string and float
+// literals come first, in chunk order. Float indices start at 1 so that no
+// float is the word for true. Data values hold the index of their
+// constructor's name among the strings and their fields; their indices
+// start at 1 so that none is the word for false.
+struct Heap {
+    strings: Vec<Rc<[u8]>>,
+    bigs: Vec<Arc<BigInt>>,
+    floats: Vec<f64>,
+    datas: Vec<(u32, Vec<i64>)>,
+}
+
+const STRING_TAG: i64 = 5;
+const BIG_TAG: i64 = 7;
+const FLOAT_TAG: i64 = 3;
+const DATA_TAG: i64 = 1;
+
+// Booleans are exactly the words 1 and 3.
+fn is_bool(v: i64) -> bool {
+    v | 2 == TRUE_CONST
+}
+
+fn is_float(v: i64) -> bool {
+    v & 7 == FLOAT_TAG && !is_bool(v)
+}
+
+fn is_data(v: i64) -> bool {
+    v & 7 == DATA_TAG && !is_bool(v)
+}
+
+impl Heap {
+    fn alloc(&mut self, bytes: Rc<[u8]>) -> i64 {
+        self.strings.push(bytes);
+        ((self.strings.len() - 1) as i64) << 3 | STRING_TAG
+    }
+
+    fn string(&self, v: i64) -> Result<Rc<[u8]>, ErrorKind> {
+        if v & 7 != STRING_TAG {
+            return Err(ErrorKind::NotString(self.show(v)));
+        }
+        Ok(self.strings[(v >> 3) as usize].clone())
+    }
+
+    // A number of either kind, for the operations shared with the
+    // interpreter.
+    fn number(&self, v: i64) -> Result<Value, ErrorKind> {
+        if v & 1 == 0 {
+            Ok(Value::Num(v >> 1))
+        } else if v & 7 == BIG_TAG {
+            Ok(Value::Big(self.bigs[(v >> 3) as usize].clone()))
+        } else if is_float(v) {
+            Ok(Value::Float(self.float(v)))
+        } else {
+            Err(ErrorKind::InvalidArgument(self.show(v)))
+        }
+    }
+
+    fn number_word(&mut self, v: Value) -> i64 {
+        match v {
+            Value::Num(n) => n << 1,
+            Value::Big(n) => {
+                self.bigs.push(n);
+                ((self.bigs.len() - 1) as i64) << 3 | BIG_TAG
+            }
+            Value::Float(x) => {
+                self.floats.push(x);
+                float_word(self.floats.len() - 1)
+            }
+            Value::Bool(b) => bool_word(b),
+            Value::Str(_) | Value::Data(..) => unreachable!("arithmetic never produces strings or data"),
+        }
+    }
+
+    fn float(&self, v: i64) -> f64 {
+        self.floats[(v >> 3) as usize]
+    }
+
+    // A fixnum, for the operations that don't take bignums.
+    fn check_not_bool(&self, v: i64) -> Result<i64, ErrorKind> {
+        if v & 7 == BIG_TAG {
+            Err(ErrorKind::TooBig(self.show(v)))
+        } else if is_float(v) {
+            Err(ErrorKind::NotInteger(self.show(v)))
+        } else if v & 1 != 0 {
+            Err(ErrorKind::InvalidArgument(self.show(v)))
+        } else {
+            Ok(v)
+        }
+    }
+
+    // The interpreter's value for a tagged word.
+    fn value(&self, val: i64) -> Value {
+        match val {
+            TRUE_CONST => Value::Bool(true),
+            FALSE_CONST => Value::Bool(false),
+            n if n & 7 == STRING_TAG => Value::Str(self.strings[(n >> 3) as usize].as_ref().into()),
+            n if is_data(n) => {
+                let (name, fields) = &self.datas[(n >> 3) as usize];
+                let ctor = String::from_utf8_lossy(&self.strings[*name as usize]);
+                Value::Data(ctor.as_ref().into(), fields.iter().map(|f| self.value(*f)).collect())
+            }
+            n => self.number(n).unwrap(),
+        }
+    }
+
+    // Formats a tagged word the way error messages show values.
+    fn show(&self, val: i64) -> String {
+        match val {
+            TRUE_CONST => "true".to_string(),
+            FALSE_CONST => "false".to_string(),
+            n if n & 7 == STRING_TAG => format!("{:?}", String::from_utf8_lossy(&self.strings[(n >> 3) as usize])),
+            n if n & 7 == BIG_TAG => self.bigs[(n >> 3) as usize].to_string(),
+            n if is_float(n) => format!("{:?}", self.float(n)),
+            n if is_data(n) => self.value(n).show(),
+            n => (n >> 1).to_string(),
+        }
+    }
+
+    // Formats a tagged word the way `print_value` in `runtime/start.rs` does.
+    fn display(&self, val: i64) -> String {
+        if val == TRUE_CONST {
+            "true".to_string()
+        } else if val == FALSE_CONST {
+            "false".to_string()
+        } else if val % 2 == 0 {
+            (val >> 1).to_string()
+        } else if val & 7 == STRING_TAG {
+            String::from_utf8_lossy(&self.strings[(val >> 3) as usize]).into_owned()
+        } else if val & 7 == BIG_TAG {
+            self.bigs[(val >> 3) as usize].to_string()
+        } else if is_float(val) {
+            format!("{:?}", self.float(val))
+        } else if is_data(val) {
+            self.value(val).to_string()
+        } else {
+            format!("NaN, with value {}", val)
+        }
+    }
+}
+
+/// Executes `chunk` with the tagged `inputs` and returns the result of main,
+/// formatted the way `print` shows it, or the first runtime error raised.
+pub fn run(chunk: &Chunk, inputs: &[i64]) -> Result<String, RuntimeError> {
+    let mut heap = Heap {
+        strings: chunk.strings.iter().map(|s| s.as_bytes().into()).collect(),
+        bigs: vec![],
+        floats: [0.0].iter().chain(&chunk.floats).copied().collect(),
+        datas: vec![(0, vec![])],
+    };
+    let mut stack: Vec<i64> = vec![];
+    let mut locals: Vec<i64> = vec![0; chunk.main_locals as usize];
+    let mut globals: Vec<i64> = vec![0; chunk.globals as usize];
+    let mut frames: Vec<Frame> = vec![];
+    let mut base = 0;
+    let mut pc = chunk.main_entry as usize;
+    // the running function and how many frames its tail calls replaced
+    let mut func: Option<u32> = None;
+    let mut elided = 0;
+    // attaches the site an operation was compiled from, and the active
+    // calls, to a failure
+    let fail = |kind: ErrorKind, site: u32, func: Option<u32>, elided: usize, frames: &[Frame]| {
+        let mut err = RuntimeError::new(kind, chunk.sites[site as usize].clone());
+        let mut callee = func;
+        let mut callee_elided = elided;
+        for frame in frames.iter().rev() {
+            err.trace.push(TraceCall {
+                func: chunk.funcs[callee.unwrap() as usize].name.clone(),
+                elided: callee_elided,
+                loc: chunk.sites[frame.site as usize].loc.clone(),
+            });
+            callee = frame.func;
+            callee_elided = frame.elided;
+        }
+        err
+    };
+    loop {
+        let op = chunk.code[pc];
+        pc += 1;
+        match op {
+            Op::Const(n) => stack.push(n),
+            // without any input, plain `input` reads false, or 0 when typed
+            Op::Input => stack.push(*inputs.first().unwrap_or(if chunk.typed { &0 } else { &FALSE_CONST })),
+            Op::InputCount => stack.push((inputs.len() as i64) << 1),
+            Op::InputAt(site) => {
+                let i = heap.check_not_bool(stack.pop().unwrap()).map_err(|k| fail(k, site, func, elided, &frames))? >> 1;
+                match usize::try_from(i).ok().and_then(|i| inputs.get(i)) {
+                    Some(v) => stack.push(*v),
+                    None => return Err(fail(ErrorKind::InputIndex(i, inputs.len()), site, func, elided, &frames)),
+                }
+            }
+            Op::Load(s) => stack.push(locals[base + s as usize]),
+            Op::Store(s) => locals[base + s as usize] = stack.pop().unwrap(),
+            Op::LoadGlobal(i) => stack.push(globals[i as usize]),
+            Op::StoreGlobal(i) => globals[i as usize] = stack.pop().unwrap(),
+            Op::LoadOuter(hops, s) => stack.push(locals[outer_base(&locals, base, hops) + s as usize]),
+            Op::StoreOuter(hops, s) => {
+                let at = outer_base(&locals, base, hops) + s as usize;
+                locals[at] = stack.pop().unwrap();
+            }
+            Op::Link(hops) => stack.push(outer_base(&locals, base, hops) as i64),
+            Op::Pop => {
+                stack.pop();
+            }
+            Op::Slide(n) => {
+                let top = stack.pop().unwrap();
+                stack.truncate(stack.len() - n as usize);
+                stack.push(top);
+            }
+            Op::Add1(site) | Op::Sub1(site) => {
+                let v = heap.number(stack.pop().unwrap()).map_err(|k| fail(k, site, func, elided, &frames))?;
+                let op2 = if let Op::Add1(_) = op { Op2::Plus } else { Op2::Minus };
+                let result = interp::arith(&op2, &v, &Value::Num(1), chunk.trap_overflow)
+                    .map_err(|k| fail(k, site, func, elided, &frames))?;
+                let word = heap.number_word(result);
+                stack.push(word);
+            }
+            Op::IsNum => {
+                let v = stack.pop().unwrap();
+                stack.push(bool_word(!is_bool(v) && v & 7 != STRING_TAG && !is_data(v)));
+            }
+            Op::IsBool => {
+                let v = stack.pop().unwrap();
+                stack.push(bool_word(is_bool(v)));
+            }
+            Op::Eq(site) => {
+                let lhs = stack.pop().unwrap();
+                let rhs = stack.pop().unwrap();
+                // 0 for numbers, 1 for booleans, 2 for data and 5 for strings
+                let type_code = |v: i64| {
+                    if is_bool(v) {
+                        1
+                    } else if is_data(v) {
+                        2
+                    } else if v & 7 == STRING_TAG {
+                        5
+                    } else {
+                        0
+                    }
+                };
+                if type_code(lhs) != type_code(rhs) {
+                    return Err(fail(ErrorKind::Mismatch(heap.show(lhs), heap.show(rhs)), site, func, elided, &frames));
+                }
+                if type_code(lhs) == 2 {
+                    let result = interp::equal(&heap.value(lhs), &heap.value(rhs)).unwrap();
+                    stack.push(bool_word(result == Value::Bool(true)));
+                } else if type_code(lhs) == STRING_TAG {
+                    stack.push(bool_word(heap.string(lhs) == heap.string(rhs)));
+                } else if type_code(lhs) == 0 {
+                    let (lhs, rhs) = (heap.number(lhs).unwrap(), heap.number(rhs).unwrap());
+                    let result = interp::equal(&lhs, &rhs).map_err(|k| fail(k, site, func, elided, &frames))?;
+                    let word = heap.number_word(result);
+                    stack.push(word);
+                } else {
+                    stack.push(bool_word(lhs == rhs));
+                }
+            }
+            Op::Str(i) => stack.push((i as i64) << 3 | STRING_TAG),
+            Op::Float(i) => stack.push(float_word(i as usize + 1)),
+            Op::ToFloat(site) | Op::Truncate(site) => {
+                let v = heap.number(stack.pop().unwrap()).map_err(|k| fail(k, site, func, elided, &frames))?;
+                let op1 = if let Op::ToFloat(_) = op { Op1::Float } else { Op1::Truncate };
+                let result = interp::convert(&op1, &v).map_err(|k| fail(k, site, func, elided, &frames))?;
+                let word = heap.number_word(result);
+                stack.push(word);
+            }
+            Op::StrLen(site) => {
+                let s = heap.string(stack.pop().unwrap()).map_err(|k| fail(k, site, func, elided, &frames))?;
+                stack.push((interp::char_count(&s) as i64) << 1);
+            }
+            Op::StrAppend(site) | Op::StrEq(site) => {
+                let lhs = heap.string(stack.pop().unwrap()).map_err(|k| fail(k, site, func, elided, &frames))?;
+                let rhs = heap.string(stack.pop().unwrap()).map_err(|k| fail(k, site, func, elided, &frames))?;
+                if let Op::StrEq(_) = op {
+                    stack.push(bool_word(lhs == rhs));
+                } else {
+                    let s = heap.alloc([lhs, rhs].concat().into());
+                    stack.push(s);
+                }
+            }
+            Op::Substring(site) => {
+                let end = stack.pop().unwrap();
+                let start = stack.pop().unwrap();
+                let s = heap.string(stack.pop().unwrap()).map_err(|k| fail(k, site, func, elided, &frames))?;
+                let start = heap.check_not_bool(start).map_err(|k| fail(k, site, func, elided, &frames))? >> 1;
+                let end = heap.check_not_bool(end).map_err(|k| fail(k, site, func, elided, &frames))? >> 1;
+                let sub = interp::char_slice(&s, start, end).map_err(|k| fail(k, site, func, elided, &frames))?;
+                let sub = heap.alloc(sub.into());
+                stack.push(sub);
+            }
+            Op::Add(site) | Op::Sub(site) | Op::Mul(site) | Op::Lt(site) | Op::Le(site) | Op::Gt(site) | Op::Ge(site)
+            | Op::Div(site) | Op::Quotient(site) | Op::Remainder(site) | Op::Modulo(site) => {
+                let lhs = heap.number(stack.pop().unwrap()).map_err(|k| fail(k, site, func, elided, &frames))?;
+                let rhs = heap.number(stack.pop().unwrap()).map_err(|k| fail(k, site, func, elided, &frames))?;
+                let op2 = match op {
+                    Op::Add(_) => Op2::Plus,
+                    Op::Sub(_) => Op2::Minus,
+                    Op::Mul(_) => Op2::Times,
+                    Op::Lt(_) => Op2::Less,
+                    Op::Le(_) => Op2::LessEqual,
+                    Op::Gt(_) => Op2::Greater,
+                    Op::Ge(_) => Op2::GreaterEqual,
+                    Op::Div(_) => Op2::Divide,
+                    Op::Quotient(_) => Op2::Quotient,
+                    Op::Remainder(_) => Op2::Remainder,
+                    _ => Op2::Modulo,
+                };
+                let result = interp::arith(&op2, &lhs, &rhs, chunk.trap_overflow)
+                    .map_err(|k| fail(k, site, func, elided, &frames))?;
+                let word = heap.number_word(result);
+                stack.push(word);
+            }
+            Op::BitAnd(site) | Op::BitOr(site) | Op::BitXor(site) | Op::Shl(site) | Op::Shr(site) => {
+                let lhs = heap.check_not_bool(stack.pop().unwrap()).map_err(|k| fail(k, site, func, elided, &frames))?;
+                let rhs = heap.check_not_bool(stack.pop().unwrap()).map_err(|k| fail(k, site, func, elided, &frames))?;
+                let op2 = match op {
+                    Op::BitAnd(_) => Op2::BitAnd,
+                    Op::BitOr(_) => Op2::BitOr,
+                    Op::BitXor(_) => Op2::BitXor,
+                    Op::Shl(_) => Op2::ShiftLeft,
+                    _ => Op2::ShiftRight,
+                };
+                let result = interp::integer_op(&op2, lhs >> 1, rhs >> 1).map_err(|k| fail(k, site, func, elided, &frames))?;
+                stack.push(result << 1);
+            }
+            Op::Jmp(t) => pc = t as usize,
+            Op::JmpFalse(t) => {
+                if stack.pop().unwrap() == FALSE_CONST {
+                    pc = t as usize;
+                }
+            }
+            Op::JmpFalseOrPop(t) | Op::JmpTrueOrPop(t) => {
+                let is_false = *stack.last().unwrap() == FALSE_CONST;
+                if is_false == matches!(op, Op::JmpFalseOrPop(_)) {
+                    pc = t as usize;
+                } else {
+                    stack.pop();
+                }
+            }
+            Op::Check(ty, site) => {
+                let v = *stack.last().unwrap();
+                let ok = match ty {
+                    Ty::Num => heap.number(v).is_ok(),
+                    Ty::Bool => is_bool(v),
+                    Ty::Str => v & 7 == STRING_TAG,
+                };
+                if !ok {
+                    return Err(fail(ErrorKind::Annotation(heap.show(v)), site, func, elided, &frames));
+                }
+            }
+            Op::IsStr => {
+                let v = stack.pop().unwrap();
+                stack.push(bool_word(v & 7 == STRING_TAG));
+            }
+            Op::Construct(name, n) => {
+                let fields = stack.split_off(stack.len() - n as usize);
+                heap.datas.push((name, fields));
+                stack.push(((heap.datas.len() - 1) as i64) << 3 | DATA_TAG);
+            }
+            Op::IsVariant(name) => {
+                let v = stack.pop().unwrap();
+                stack.push(bool_word(is_data(v) && heap.datas[(v >> 3) as usize].0 == name));
+            }
+            Op::Field(i) => {
+                let v = stack.pop().unwrap();
+                stack.push(heap.datas[(v >> 3) as usize].1[i as usize]);
+            }
+            Op::MatchFail(site) => {
+                let v = stack.pop().unwrap();
+                return Err(fail(ErrorKind::MatchFailure(heap.show(v)), site, func, elided, &frames));
+            }
+            Op::Not(site) => {
+                let v = stack.pop().unwrap();
+                if !is_bool(v) {
+                    return Err(fail(ErrorKind::NotBool(heap.show(v)), site, func, elided, &frames));
+                }
+                stack.push(bool_word(v == FALSE_CONST));
+            }
+            Op::Call(f, site) => {
+                frames.push(Frame { ret: pc, base, func, elided, site });
+                base = locals.len();
+                func = Some(f);
+                elided = 0;
+                pc = enter(chunk, f, &mut stack, &mut locals, base);
+            }
+            Op::TailCall(f) => {
+                locals.truncate(base);
+                func = Some(f);
+                elided += 1;
+                pc = enter(chunk, f, &mut stack, &mut locals, base);
+            }
+            Op::Ret => {
+                let frame = frames.pop().unwrap();
+                locals.truncate(base);
+                base = frame.base;
+                func = frame.func;
+                elided = frame.elided;
+                pc = frame.ret;
+            }
+            Op::Print => println!("{}", heap.display(*stack.last().unwrap())),
+            Op::Halt => return Ok(heap.display(stack.pop().unwrap())),
+        }
+    }
+}
+
+// The base of the frame `hops` static links out from the frame at `base`;
+// each link is its local function's local 0.
+fn outer_base(locals: &[i64], base: usize, hops: u32) -> usize {
+    let mut at = base;
+    for _ in 0..hops {
+        at = locals[at] as usize;
+    }
+    at
+}
+
+// Moves the arguments of `f` off the operand stack into a fresh frame of
+// locals starting at `base`, returning the function's entry point.
+fn enter(chunk: &Chunk, f: u32, stack: &mut Vec<i64>, locals: &mut Vec<i64>, base: usize) -> usize {
+    let func = &chunk.funcs[f as usize];
+    let args = stack.len() - func.arity as usize;
+    locals.extend(stack.drain(args..));
+    locals.resize(base + func.locals as usize, 0);
+    func.entry as usize
+}
+
+fn float_word(i: usize) -> i64 {
+    (i as i64) << 3 | FLOAT_TAG
+}
+
+fn bool_word(b: bool) -> i64 {
+    if b {
+        TRUE_CONST
+    } else {
+        FALSE_CONST
+    }
+}
+
+/// Tags an input value the way `parse_input` in `runtime/start.rs` does.
+pub fn input_word(v: Value) -> i64 {
+    match v {
+        Value::Num(n) => n << 1,
+        Value::Bool(b) => bool_word(b),
+        Value::Str(_) | Value::Big(_) | Value::Float(_) | Value::Data(..) => unreachable!("inputs are always fixnums or booleans"),
+    }
+}
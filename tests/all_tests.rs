@@ -375,6 +375,189 @@ success_tests! {
         file: "diamondback_recursive_fibonacci.snek",
         expected: "55",
     },
+    {
+        name: diamondback_nested_fun,
+        file: "diamondback_nested_fun.snek",
+        expected: "13",
+    },
+
+    // Multiple inputs
+    {
+        name: multi_input_sum,
+        file: "multi_input_sum.snek",
+        input: "1 2 3 4",
+        expected: "10",
+    },
+    {
+        name: multi_input_index,
+        file: "multi_input_index.snek",
+        input: "5 false true",
+        expected: "5\nfalse\n3",
+    },
+    {
+        name: multi_input_count_none,
+        file: "multi_input_count.snek",
+        expected: "0",
+    },
+    {
+        name: multi_input_count_some,
+        file: "multi_input_count.snek",
+        input: "true -7",
+        expected: "2",
+    },
+
+    // comments
+    {
+        name: comments,
+        file: "comments.snek",
+        expected: "10\n11",
+    },
+
+    // strings
+    {
+        name: strings,
+        file: "strings.snek",
+        expected: "hello, world\n5\nell\ntrue\ntrue\nfalse\nfalse\ntab\tand \"quotes\"\nworld",
+    },
+    {
+        name: string_unicode,
+        file: "string_unicode.snek",
+        expected: "hé\n11\nörld\n7",
+    },
+    {
+        name: string_label,
+        file: "string_label.snek",
+        expected: "second:\nfirst:\n3",
+    },
+
+    // division, remainder and bitwise operators
+    {
+        name: integer_ops,
+        file: "integer_ops.snek",
+        expected: "3\n-4\n-3\n-1\n1\n-1\n1\n21\n8\n14\n6\n6\n48\n-4\n5\n0\n0\ntrue",
+    },
+    // without --trap-overflow, overflowing fixnums become bignums
+    {
+        name: cobra_number_overflow_promotes,
+        file: "cobra_number_overflow_fail0.snek",
+        expected: "4611686018427387904",
+    },
+    {
+        name: cobra_add_promotes,
+        file: "cobra_add.snek",
+        input: "4611686018427387899",
+        expected: "4611686018427387904",
+    },
+    {
+        name: bignum_factorial,
+        file: "bignum_factorial.snek",
+        expected: "2432902008176640000\n51090942171709440000\n265252859812191058636308480000000\n-15511210043330985984000000",
+    },
+    {
+        name: bignum_ops,
+        file: "bignum_ops.snek",
+        expected: "18446744073709551612\ntrue\nfalse\ntrue\nfalse\ntrue\nfalse\n-6148914691236517204\n2635249153387078801\n-5\n2\n-4611686018427387905\n1",
+    },
+    {
+        name: float_ops,
+        file: "float_ops.snek",
+        expected: "1.5\n2.5\n3.0\n0.5\n0.3333333333333333\n3.5\n1.25\n-1.5\ninf\ninf\n1e300\ntrue\nfalse\ntrue\ntrue\ntrue\nfalse\ntrue\n0.30000000000000004",
+    },
+    {
+        name: float_convert,
+        file: "float_convert.snek",
+        expected: "3.0\n2.5\n1.8446744073709552e19\n2\n-2\n7\n100000000000000000000\ntrue\n5000000000000000000",
+    },
+    {
+        name: logic_ops,
+        file: "logic_ops.snek",
+        expected: "true\nfalse\n7\nfalse\n8\nfalse\ntrue\nfalse\nfalse\ntrue\n0\n5",
+    },
+    {
+        name: logic_tail,
+        file: "logic_tail.snek",
+        expected: "true\nfalse",
+    },
+    {
+        name: derived_forms,
+        file: "derived_forms.snek",
+        expected: "-1\n0\n1\nfalse\nsmall\nboolean\ntwo and a half\nother\nother\n14\nfalse\nfalse\ntwice\ntwice",
+    },
+    {
+        name: macro_range,
+        file: "macro_range.snek",
+        expected: "9\n5\n1\n1\nfalse\n0\n1\n10\n11\n5050",
+    },
+    {
+        name: loop_forms,
+        file: "loop_forms.snek",
+        expected: "10\n25\nfalse\n43\n7\nfalse",
+    },
+    {
+        name: import_modules,
+        file: "modules/import_modules.snek",
+        expected: "12\n14\n25\n-7",
+    },
+    {
+        name: globals,
+        file: "globals.snek",
+        expected: "11\n10\nfalse\ntrue",
+    },
+    {
+        name: globals_input,
+        file: "globals_input.snek",
+        input: "7",
+        expected: "35",
+    },
+    {
+        name: local_funs,
+        file: "local_funs.snek",
+        expected: "14\n5000050000\n6\ntrue\n1112\n11",
+    },
+    {
+        name: typecheck,
+        file: "typecheck.snek",
+        input: "3",
+        flags: "--typecheck",
+        expected: "true\n5\n435\ntyped",
+    },
+    {
+        name: typecheck_no_input,
+        file: "typecheck.snek",
+        flags: "--typecheck",
+        expected: "true\n5\n0\ntyped",
+    },
+    {
+        name: typecheck_checks,
+        file: "typecheck_checks.snek",
+        flags: "--typecheck",
+        expected: "2\n4\n4\n3\n2\n-1.0",
+    },
+    {
+        name: annotations,
+        file: "annotations.snek",
+        input: "3",
+        expected: "100003\n1\nhi!\n8",
+    },
+    {
+        name: annotations_typecheck,
+        file: "annotations.snek",
+        input: "7",
+        flags: "--typecheck",
+        expected: "100007\n2\nhi!\n16",
+    },
+    {
+        name: data_match,
+        file: "data_match.snek",
+        expected: "12\n12\n0\n(Rect 3 \"x\")\n15\n(Node (Leaf) 1 (Leaf))\nzero\nyes\ngreeting\nunit square\nsquare\nrect\nsingleton\nother\ntrue\ntrue\nfalse\nfalse\ntrue\nfalse",
+    },
+    {
+        name: match_typecheck,
+        file: "match_typecheck.snek",
+        input: "5",
+        flags: "--typecheck",
+        expected: "(Node (Leaf) 0 (Node (Node (Node (Leaf) 1 (Leaf)) 4 (Leaf)) 7 (Node (Leaf) 8 (Leaf))))\n4",
+    },
 }
 
 runtime_error_tests! {
@@ -382,28 +565,33 @@ runtime_error_tests! {
     {
         name: cobra_number_overflow_fail0,
         file: "cobra_number_overflow_fail0.snek",
+        flags: "--trap-overflow",
         expected: "overflow",
     },
     {
         name: cobra_number_overflow_fail1,
         file: "cobra_number_overflow_fail1.snek",
+        flags: "--trap-overflow",
         expected: "overflow",
     },
     {
         name: cobra_number_overflow_fail2,
         file: "cobra_add.snek",
         input: "4611686018427387899",
+        flags: "--trap-overflow",
         expected: "overflow",
     },
     {
         name: cobra_number_overflow_fail3,
         file: "cobra_nested_arith3.snek",
         input: "4611686018427387890",
+        flags: "--trap-overflow",
         expected: "overflow",
     },
     {
         name: diamondback_eventually_overflows,
         file: "diamondback_eventually_overflows.snek",
+        flags: "--trap-overflow",
         expected: "overflow",
     },
 
@@ -472,6 +660,206 @@ runtime_error_tests! {
         file: "cobra_invalid_argument_fail11.snek",
         expected: "invalid argument",
     },
+
+    // input index out of range
+    {
+        name: multi_input_too_few_fail,
+        file: "multi_input_too_few_fail.snek",
+        input: "1 2",
+        expected: "input index 3 out of range, the program was given 2 input(s)",
+    },
+    {
+        name: multi_input_bad_index_fail,
+        file: "multi_input_bad_index_fail.snek",
+        input: "1",
+        expected: "invalid argument",
+    },
+
+    // error sites
+    {
+        name: error_site_add_fail,
+        file: "error_site_add.snek",
+        expected: "invalid argument: `+` expected a number, got true at tests/error_site_add.snek:4:5",
+    },
+    {
+        name: error_site_eq_fail,
+        file: "error_site_eq.snek",
+        expected: "invalid argument: `=` expected operands of the same type, got 1 and true at tests/error_site_eq.snek:2:7",
+    },
+    {
+        name: error_site_overflow_fail,
+        file: "error_site_overflow.snek",
+        flags: "--trap-overflow",
+        expected: "overflow: `*` result does not fit in 63 bits at tests/error_site_overflow.snek:2:9",
+    },
+    {
+        name: error_site_input_fail,
+        file: "error_site_input.snek",
+        input: "0",
+        expected: "input index 1 out of range, the program was given 1 input(s) at tests/error_site_input.snek:3:3",
+    },
+
+    // stack traces
+    {
+        name: stack_trace_fail,
+        file: "stack_trace.snek",
+        expected: "at tests/stack_trace.snek:2:3
+  at leaf (tests/stack_trace.snek:2:3)
+  ... 1 frame(s) elided by tail calls
+  at mid (tests/stack_trace.snek:8:8)
+  at top (tests/stack_trace.snek:11:8)
+  at main (tests/stack_trace.snek:13:1)",
+    },
+    {
+        name: local_funs_trace_fail,
+        file: "local_funs_trace.snek",
+        expected: "at tests/local_funs_trace.snek:3:32
+  at check (tests/local_funs_trace.snek:3:32)
+  ... 3 frame(s) elided by tail calls
+  at go (tests/local_funs_trace.snek:4:22)
+  at scale (tests/local_funs_trace.snek:5:5)
+  at main (tests/local_funs_trace.snek:7:1)",
+    },
+
+    // strings
+    {
+        name: string_not_string_fail,
+        file: "string_not_string_fail.snek",
+        expected: "`string-length` expected a string, got 5 at tests/string_not_string_fail.snek:1:1",
+    },
+    {
+        name: string_append_bool_fail,
+        file: "string_append_bool_fail.snek",
+        expected: "`string-append` expected a string, got true",
+    },
+    {
+        name: string_add_fail,
+        file: "string_add_fail.snek",
+        expected: "`+` expected a number, got \"one\"",
+    },
+    {
+        name: string_equal_mismatch_fail,
+        file: "string_equal_mismatch_fail.snek",
+        expected: "`=` expected operands of the same type, got \"1\" and 1",
+    },
+    {
+        name: substring_range_fail,
+        file: "substring_range_fail.snek",
+        expected: "`substring` range 2..5 is out of bounds for a string of length 4 at tests/substring_range_fail.snek:2:3",
+    },
+    {
+        name: substring_reversed_fail,
+        file: "substring_reversed_fail.snek",
+        expected: "`substring` range 3..1 is out of bounds",
+    },
+    {
+        name: substring_unicode_fail,
+        file: "substring_unicode_fail.snek",
+        expected: "`substring` range 3..6 is out of bounds for a string of length 5",
+    },
+
+    // division, remainder and bitwise operators
+    {
+        name: divide_by_zero_fail,
+        file: "divide_by_zero_fail.snek",
+        expected: "division by zero: `modulo` at tests/divide_by_zero_fail.snek:2:3",
+    },
+    {
+        name: divide_overflow_fail,
+        file: "divide_overflow_fail.snek",
+        flags: "--trap-overflow",
+        expected: "overflow: `quotient`",
+    },
+    {
+        name: bignum_bitand_fail,
+        file: "bignum_bitand_fail.snek",
+        expected: "invalid argument: `bitand` expected a number that fits in 63 bits, got 18446744073709551612",
+    },
+    {
+        name: float_bitand_fail,
+        file: "float_bitand_fail.snek",
+        expected: "invalid argument: `bitand` expected an integer, got 1.0",
+    },
+    {
+        name: float_quotient_fail,
+        file: "float_quotient_fail.snek",
+        expected: "invalid argument: `quotient` expected an integer, got 7.5",
+    },
+    {
+        name: float_truncate_fail,
+        file: "float_truncate_fail.snek",
+        expected: "invalid argument: `truncate` expected a finite number, got NaN",
+    },
+    {
+        name: not_fail,
+        file: "not_fail.snek",
+        expected: "invalid argument: `not` expected a boolean, got 3",
+    },
+    {
+        name: cond_error_site_fail,
+        file: "cond_error_site_fail.snek",
+        expected: "invalid argument: `+` expected a number, got true at tests/cond_error_site_fail.snek:5:6",
+    },
+    {
+        name: shl_overflow_fail,
+        file: "shl_overflow_fail.snek",
+        expected: "overflow: `shl`",
+    },
+    {
+        name: bitand_bool_fail,
+        file: "bitand_bool_fail.snek",
+        expected: "`bitand` expected a number, got true",
+    },
+
+    // invalid command-line input
+    {
+        name: invalid_input_token_fail,
+        file: "cobra_input0.snek",
+        input: "abc",
+        expected: "invalid input `abc`: expected true, false or an integer in -2^62..2^62-1",
+    },
+    {
+        name: invalid_input_out_of_range_fail,
+        file: "cobra_input0.snek",
+        input: "4611686018427387904",
+        expected: "invalid input `4611686018427387904`",
+    },
+    {
+        name: invalid_input_second_arg_fail,
+        file: "multi_input_count.snek",
+        input: "1 2x",
+        expected: "invalid input `2x`",
+    },
+    {
+        name: typecheck_bool_input_fail,
+        file: "typecheck.snek",
+        input: "true",
+        flags: "--typecheck",
+        expected: "invalid input `true`: expected an integer",
+    },
+    {
+        name: typecheck_bitand_float_fail,
+        file: "typecheck_bitand_float_fail.snek",
+        flags: "--typecheck",
+        expected: "`bitand` expected an integer, got 1.5",
+    },
+    {
+        name: annotation_param_fail,
+        file: "annotation_param_fail.snek",
+        input: "3",
+        expected: "annotation violation: parameter `x` of `half` expected Num, got false at tests/annotation_param_fail.snek:1:17",
+    },
+    {
+        name: annotation_result_fail,
+        file: "annotation_result_fail.snek",
+        input: "3",
+        expected: "annotation violation: result of `sign` expected Num, got true at tests/annotation_result_fail.snek:1:25",
+    },
+    {
+        name: match_fail,
+        file: "match_fail.snek",
+        expected: "match failure: no pattern matches (Rect 2 3) at tests/match_fail.snek:3:17",
+    },
 }
 
 static_error_tests! {
@@ -750,11 +1138,6 @@ static_error_tests! {
         file: "diamondback_no_expr_fail.snek",
         expected: "",
     },
-    {
-        name: diamondback_nested_fun_fail,
-        file: "diamondback_nested_fun_fail.snek",
-        expected: "",
-    },
 
     {
         name: diamondback_fun_scope_fail0,
@@ -787,4 +1170,381 @@ static_error_tests! {
         file: "diamondback_function_arg_is_keyword_fail.snek",
         expected: "",
     },
+
+    {
+        name: multi_input_in_fun_fail,
+        file: "multi_input_in_fun_fail.snek",
+        expected: "input",
+    },
+
+    {
+        name: comment_unclosed_fail,
+        file: "comment_unclosed_fail.snek",
+        expected: "unclosed `#|` opened at 2:3",
+    },
+    {
+        name: comment_datum_fail,
+        file: "comment_datum_fail.snek",
+        expected: "`#;` is not followed by a form at 1:6",
+    },
+    {
+        name: string_unclosed_fail,
+        file: "string_unclosed_fail.snek",
+        expected: "unclosed string opened at 1:8",
+    },
+    {
+        name: string_escape_fail,
+        file: "string_escape_fail.snek",
+        expected: "unknown escape `\\q` in string at 1:13",
+    },
+    {
+        name: cond_else_not_last_fail,
+        file: "cond_else_not_last_fail.snek",
+        expected: "Invalid else clause",
+    },
+    {
+        name: case_bad_datum_fail,
+        file: "case_bad_datum_fail.snek",
+        expected: "Invalid case datum",
+    },
+    {
+        name: macro_no_rule_fail,
+        file: "macro_no_rule_fail.snek",
+        expected: "Invalid use of macro `twice` at 4:1, no rule matches \"(twice 1 2)\"",
+    },
+    {
+        name: macro_bad_expansion_fail,
+        file: "macro_bad_expansion_fail.snek",
+        expected: "Invalid expansion of macro `bind` at 4:1 to \"(let y 5 (+ y 1))\"",
+    },
+    {
+        name: macro_recursion_fail,
+        file: "macro_recursion_fail.snek",
+        expected: "Invalid expansion of macro `forever` at 4:1, more than 200 expansions deep",
+    },
+    {
+        name: break_unknown_label_fail,
+        file: "break_unknown_label_fail.snek",
+        expected: "Invalid break to unknown label :inner",
+    },
+    {
+        name: continue_outside_loop_fail,
+        file: "continue_outside_loop_fail.snek",
+        expected: "Unexpected continue outside loop",
+    },
+    {
+        name: import_missing_fail,
+        file: "modules/import_missing_fail.snek",
+        expected: "Invalid import, cannot read file \"tests/modules/nowhere.snek\"",
+    },
+    {
+        name: import_cycle_fail,
+        file: "modules/import_cycle_fail.snek",
+        expected: "Invalid import cycle tests/modules/cycle_a.snek -> tests/modules/cycle_b.snek -> tests/modules/cycle_a.snek",
+    },
+    {
+        name: import_private_fail,
+        file: "modules/import_private_fail.snek",
+        expected: "Invalid Function helper is not defined",
+    },
+    {
+        name: import_qualified_fail,
+        file: "modules/import_qualified_fail.snek",
+        expected: "Invalid Function util.helper is not defined",
+    },
+    {
+        name: import_conflict_fail,
+        file: "modules/import_conflict_fail.snek",
+        expected: "Invalid function double from \"tests/modules/import_conflict_fail.snek\", which is already defined or imported",
+    },
+    {
+        name: import_data_fail,
+        file: "modules/import_data_fail.snek",
+        expected: "Invalid module \"tests/modules/geometry.snek\", which cannot declare data types",
+    },
+    {
+        name: separate_arity_fail,
+        file: "modules/separate_arity_fail.snek",
+        flags: "--separate",
+        expected: "Function prebuilt.twice expects 1 arguments, but actually receive 2 arguments",
+    },
+    {
+        name: separate_no_interface_fail,
+        file: "modules/separate_no_interface_fail.snek",
+        flags: "--separate",
+        expected: "Invalid import, cannot read interface file \"tests/modules/nowhere.snei\"",
+    },
+    {
+        name: global_before_define_fail,
+        file: "global_before_define_fail.snek",
+        expected: "Invalid use of global b before its definition, in the definition of a",
+    },
+    {
+        name: global_through_call_fail,
+        file: "global_through_call_fail.snek",
+        expected: "Invalid use of global rate before its definition, in the definition of price through a call to price_of",
+    },
+    {
+        name: global_duplicate_fail,
+        file: "global_duplicate_fail.snek",
+        expected: "Duplicate global definition for x",
+    },
+    {
+        name: local_fun_scope_fail,
+        file: "local_fun_scope_fail.snek",
+        expected: "Invalid Function goodbye is not defined",
+    },
+    {
+        name: local_fun_last_fail,
+        file: "local_fun_last_fail.snek",
+        expected: "ending in a function definition",
+    },
+    {
+        name: typecheck_plus_bool_fail,
+        file: "typecheck_plus_bool_fail.snek",
+        flags: "--typecheck",
+        expected: "type error: `+` expected Num, got Bool at tests/typecheck_plus_bool_fail.snek:2:3",
+    },
+    {
+        name: typecheck_fun_fail,
+        file: "typecheck_fun_fail.snek",
+        flags: "--typecheck",
+        expected: "type error: the condition of `if` expected Bool, got Num at tests/typecheck_fun_fail.snek:3:1",
+    },
+    {
+        name: typecheck_break_fail,
+        file: "typecheck_break_fail.snek",
+        flags: "--typecheck",
+        expected: "type error: `break` expected Num, got Bool",
+    },
+    {
+        name: typecheck_set_fail,
+        file: "typecheck_set_fail.snek",
+        flags: "--typecheck",
+        expected: "type error: `set!` of `acc` expected Num, got Bool",
+    },
+    {
+        name: annotation_result_typecheck_fail,
+        file: "annotation_result_fail.snek",
+        flags: "--typecheck",
+        expected: "type error: result of `sign` expected Num, got Bool at tests/annotation_result_fail.snek:1:25",
+    },
+    {
+        name: annotation_type_fail,
+        file: "annotation_type_fail.snek",
+        expected: "Invalid type annotation \"Int\"",
+    },
+    {
+        name: match_arity_fail,
+        file: "match_arity_fail.snek",
+        expected: "Invalid pattern, constructor Rect takes 2 field(s), got 1 at tests/match_arity_fail.snek:5:4",
+    },
+    {
+        name: data_typecheck_fail,
+        file: "data_typecheck_fail.snek",
+        flags: "--typecheck",
+        expected: "type error: argument 1 of `area` expected Shape, got Num at tests/data_typecheck_fail.snek:8:1",
+    },
+}
+
+warning_tests! {
+    {
+        name: match_warning,
+        file: "match_warning.snek",
+        expected: "warning: non-exhaustive match at tests/match_warning.snek:4:3: (Some (Some false)) is not matched",
+    },
+}
+
+repl_tests! {
+    {
+        name: repl_persistent_defs,
+        file: "repl_persistent_defs.snek",
+        expected: "120\n16\n11\n11",
+    },
+    {
+        name: repl_error_recovery,
+        file: "repl_error_recovery.snek",
+        expected: "8",
+    },
+    {
+        name: repl_input,
+        file: "repl_input.snek",
+        input: "41",
+        expected: "42\n41\ntrue",
+    },
+    {
+        name: repl_bignum,
+        file: "repl_bignum.snek",
+        expected: "18446744073709551612\ntrue\n1",
+    },
+    {
+        name: repl_float,
+        file: "repl_float.snek",
+        expected: "1.5\n4",
+    },
+    {
+        name: repl_cond,
+        file: "repl_cond.snek",
+        expected: "positive\nlarge",
+    },
+    {
+        name: repl_macro,
+        file: "repl_macro.snek",
+        expected: "2\n3\n13",
+    },
+    {
+        name: repl_loops,
+        file: "repl_loops.snek",
+        expected: "false\n10\n20\n11\n12\nfalse",
+    },
+    {
+        name: repl_globals,
+        file: "repl_globals.snek",
+        expected: "5\n12\n12\n101",
+    },
+    {
+        name: repl_local_funs,
+        file: "repl_local_funs.snek",
+        expected: "4\n10\n105",
+    },
+    {
+        name: repl_parens,
+        file: "repl_parens.snek",
+        expected: "a (b\na (b\n3\n7\n5\nx)\nx)",
+    },
+    {
+        name: repl_data,
+        file: "repl_data.snek",
+        expected: "4\n9\n(Some \"b\")\ntrue",
+    },
+}
+
+lsp_tests! {
+    {
+        name: lsp_diagnostics,
+        file: "lsp_diagnostics.jsonrpc",
+        expected: r#"{"jsonrpc":"2.0","id":1,"result":{"capabilities":{"textDocumentSync":1,"definitionProvider":true,"hoverProvider":true,"completionProvider":{}},"serverInfo":{"name":"cobra"}}}
+{"jsonrpc":"2.0","method":"textDocument/publishDiagnostics","params":{"uri":"file:///d.snek","diagnostics":[{"range":{"start":{"line":0,"character":16},"end":{"line":0,"character":17}},"severity":1,"source":"cobra","message":"Unbound variable identifier y"},{"range":{"start":{"line":1,"character":14},"end":{"line":1,"character":15}},"severity":1,"source":"cobra","message":"Function f expects 1 arguments, but actually receive 2 arguments"},{"range":{"start":{"line":1,"character":18},"end":{"line":1,"character":19}},"severity":1,"source":"cobra","message":"Unbound variable identifier b"}]}}
+{"jsonrpc":"2.0","method":"textDocument/publishDiagnostics","params":{"uri":"file:///d.snek","diagnostics":[{"range":{"start":{"line":0,"character":5},"end":{"line":0,"character":5}},"severity":1,"source":"cobra","message":"Invalid sexp unclosed `(` opened at 1:6"}]}}
+{"jsonrpc":"2.0","method":"textDocument/publishDiagnostics","params":{"uri":"file:///d.snek","diagnostics":[{"range":{"start":{"line":0,"character":0},"end":{"line":0,"character":0}},"severity":1,"source":"cobra","message":"parse error: Duplicate binding a Invalid"}]}}
+{"jsonrpc":"2.0","method":"textDocument/publishDiagnostics","params":{"uri":"file:///d.snek","diagnostics":[{"range":{"start":{"line":0,"character":0},"end":{"line":0,"character":0}},"severity":1,"source":"cobra","message":"parse error: Invalid program, main is not the last element"}]}}
+{"jsonrpc":"2.0","method":"textDocument/publishDiagnostics","params":{"uri":"file:///d.snek","diagnostics":[]}}
+{"jsonrpc":"2.0","id":2,"result":null}"#,
+    },
+    {
+        name: lsp_navigation,
+        file: "lsp_navigation.jsonrpc",
+        expected: r#"{"jsonrpc":"2.0","id":1,"result":{"capabilities":{"textDocumentSync":1,"definitionProvider":true,"hoverProvider":true,"completionProvider":{}},"serverInfo":{"name":"cobra"}}}
+{"jsonrpc":"2.0","method":"textDocument/publishDiagnostics","params":{"uri":"file:///n.snek","diagnostics":[]}}
+{"jsonrpc":"2.0","id":2,"result":{"uri":"file:///n.snek","range":{"start":{"line":1,"character":17},"end":{"line":1,"character":18}}}}
+{"jsonrpc":"2.0","id":3,"result":{"uri":"file:///n.snek","range":{"start":{"line":0,"character":6},"end":{"line":0,"character":12}}}}
+{"jsonrpc":"2.0","id":4,"result":{"contents":{"kind":"markdown","value":"argument `x` of `double`"},"range":{"start":{"line":0,"character":21},"end":{"line":0,"character":22}}}}
+{"jsonrpc":"2.0","id":5,"result":{"contents":{"kind":"markdown","value":"function `double` of 1 argument(s)"},"range":{"start":{"line":1,"character":20},"end":{"line":1,"character":26}}}}
+{"jsonrpc":"2.0","id":6,"result":null}
+{"jsonrpc":"2.0","id":7,"result":[{"label":"m","kind":6,"detail":"let binding"},{"label":"n","kind":6,"detail":"let binding"},{"label":"double","kind":3,"detail":"function of 1 argument(s)"},{"label":"true","kind":14,"detail":"keyword"},{"label":"false","kind":14,"detail":"keyword"},{"label":"input","kind":14,"detail":"keyword"},{"label":"input-count","kind":14,"detail":"keyword"},{"label":"let","kind":14,"detail":"keyword"},{"label":"set!","kind":14,"detail":"keyword"},{"label":"if","kind":14,"detail":"keyword"},{"label":"block","kind":14,"detail":"keyword"},{"label":"loop","kind":14,"detail":"keyword"},{"label":"break","kind":14,"detail":"keyword"},{"label":"add1","kind":14,"detail":"keyword"},{"label":"sub1","kind":14,"detail":"keyword"},{"label":"isnum","kind":14,"detail":"keyword"},{"label":"isbool","kind":14,"detail":"keyword"},{"label":"print","kind":14,"detail":"keyword"},{"label":"fun","kind":14,"detail":"keyword"},{"label":"+","kind":14,"detail":"keyword"},{"label":"-","kind":14,"detail":"keyword"},{"label":"*","kind":14,"detail":"keyword"},{"label":"<","kind":14,"detail":"keyword"},{"label":">","kind":14,"detail":"keyword"},{"label":">=","kind":14,"detail":"keyword"},{"label":"<=","kind":14,"detail":"keyword"},{"label":"=","kind":14,"detail":"keyword"},{"label":"string-length","kind":14,"detail":"keyword"},{"label":"string-append","kind":14,"detail":"keyword"},{"label":"substring","kind":14,"detail":"keyword"},{"label":"string=?","kind":14,"detail":"keyword"},{"label":"/","kind":14,"detail":"keyword"},{"label":"quotient","kind":14,"detail":"keyword"},{"label":"remainder","kind":14,"detail":"keyword"},{"label":"modulo","kind":14,"detail":"keyword"},{"label":"bitand","kind":14,"detail":"keyword"},{"label":"bitor","kind":14,"detail":"keyword"},{"label":"bitxor","kind":14,"detail":"keyword"},{"label":"shl","kind":14,"detail":"keyword"},{"label":"shr","kind":14,"detail":"keyword"},{"label":"float","kind":14,"detail":"keyword"},{"label":"truncate","kind":14,"detail":"keyword"},{"label":"and","kind":14,"detail":"keyword"},{"label":"or","kind":14,"detail":"keyword"},{"label":"not","kind":14,"detail":"keyword"},{"label":"cond","kind":14,"detail":"keyword"},{"label":"when","kind":14,"detail":"keyword"},{"label":"unless","kind":14,"detail":"keyword"},{"label":"case","kind":14,"detail":"keyword"},{"label":"else","kind":14,"detail":"keyword"},{"label":"define-syntax","kind":14,"detail":"keyword"},{"label":"syntax-rules","kind":14,"detail":"keyword"},{"label":"while","kind":14,"detail":"keyword"},{"label":"for","kind":14,"detail":"keyword"},{"label":"continue","kind":14,"detail":"keyword"},{"label":"import","kind":14,"detail":"keyword"},{"label":"provide","kind":14,"detail":"keyword"},{"label":"define","kind":14,"detail":"keyword"},{"label":"data","kind":14,"detail":"keyword"},{"label":"match","kind":14,"detail":"keyword"}]}
+{"jsonrpc":"2.0","id":8,"error":{"code":-32601,"message":"unsupported method workspace/symbol"}}
+{"jsonrpc":"2.0","id":9,"result":null}"#,
+    },
+}
+
+fmt_tests! {
+    {
+        name: fmt_layout,
+        file: "fmt_layout.snek",
+        expected: "; sums the numbers from 1 to n
+(fun (sum n)
+  (let ((i 1) (acc 0))
+    (loop
+      (if (> i n)
+          (break acc)
+          (block
+            (set! acc (+ acc i)) ; add this one
+            (set! i (add1 i)))))))
+
+(fun (fact n) (if (< n 2) 1 (* n (fact (sub1 n)))))
+(fun (pick a b)
+  ; the larger of the two
+  (if (> a b) a b))
+
+(let ((first-argument-with-long-name (sum input))
+      (second-argument-with-long-name (fact 5)))
+  (block
+    (print (pick first-argument-with-long-name second-argument-with-long-name))
+    (+ first-argument-with-long-name second-argument-with-long-name)))
+; done",
+    },
+}
+
+link_tests! {
+    {
+        name: link_modules,
+        file: "modules/import_modules.link",
+        expected: "12\n14\n25\n-7",
+    },
+}
+
+// the same programs compiled with --debug, which must not change what they do
+debug_tests! {
+    {
+        name: debug_vars,
+        file: "debug_vars.snek",
+        expected: "snek\n42",
+    },
+    {
+        name: debug_fun_many_args,
+        file: "diamondback_fun_many_args.snek",
+        input: "-1",
+        expected: "4294967296",
+    },
+    {
+        name: debug_fun_mutual_recursion,
+        file: "diamondback_fun_mutual_recursion.snek",
+        input: "4",
+        expected: "true",
+    },
+    {
+        name: debug_strings,
+        file: "strings.snek",
+        expected: "hello, world\n5\nell\ntrue\ntrue\nfalse\nfalse\ntab\tand \"quotes\"\nworld",
+    },
+    {
+        name: debug_bignum_factorial,
+        file: "bignum_factorial.snek",
+        expected: "2432902008176640000\n51090942171709440000\n265252859812191058636308480000000\n-15511210043330985984000000",
+    },
+    {
+        name: debug_float_ops,
+        file: "float_ops.snek",
+        expected: "1.5\n2.5\n3.0\n0.5\n0.3333333333333333\n3.5\n1.25\n-1.5\ninf\ninf\n1e300\ntrue\nfalse\ntrue\ntrue\ntrue\nfalse\ntrue\n0.30000000000000004",
+    },
+    {
+        name: debug_local_funs,
+        file: "local_funs.snek",
+        expected: "14\n5000050000\n6\ntrue\n1112\n11",
+    },
+    {
+        name: debug_data_match,
+        file: "data_match.snek",
+        expected: "12\n12\n0\n(Rect 3 \"x\")\n15\n(Node (Leaf) 1 (Leaf))\nzero\nyes\ngreeting\nunit square\nsquare\nrect\nsingleton\nother\ntrue\ntrue\nfalse\nfalse\ntrue\nfalse",
+    },
+}
+
+#[test]
+fn debug_info() {
+    infra::run_debug_info_test();
+}
+
+#[test]
+fn debug_gdb() {
+    infra::run_debug_gdb_test();
+}
+
+#[test]
+fn fmt_round_trip() {
+    infra::run_fmt_round_trip();
+}
+
+#[test]
+fn typecheck_drops_checks() {
+    infra::run_typecheck_drops_checks();
 }
@@ -1,12 +1,19 @@
 use std::{
+    io::Write,
     path::{Path, PathBuf},
-    process::Command,
+    process::{Command, Stdio},
 };
 
 pub(crate) enum TestKind {
     Success,
     RuntimeError,
     StaticError,
+    Warning,
+    Repl,
+    Lsp,
+    Fmt,
+    Link,
+    Debug,
 }
 
 #[macro_export]
@@ -24,6 +31,36 @@ macro_rules! static_error_tests {
     ($($tt:tt)*) => { $crate::tests!(StaticError => $($tt)*); }
 }
 
+#[macro_export]
+macro_rules! warning_tests {
+    ($($tt:tt)*) => { $crate::tests!(Warning => $($tt)*); }
+}
+
+#[macro_export]
+macro_rules! repl_tests {
+    ($($tt:tt)*) => { $crate::tests!(Repl => $($tt)*); }
+}
+
+#[macro_export]
+macro_rules! lsp_tests {
+    ($($tt:tt)*) => { $crate::tests!(Lsp => $($tt)*); }
+}
+
+#[macro_export]
+macro_rules! fmt_tests {
+    ($($tt:tt)*) => { $crate::tests!(Fmt => $($tt)*); }
+}
+
+#[macro_export]
+macro_rules! link_tests {
+    ($($tt:tt)*) => { $crate::tests!(Link => $($tt)*); }
+}
+
+#[macro_export]
+macro_rules! debug_tests {
+    ($($tt:tt)*) => { $crate::tests!(Debug => $($tt)*); }
+}
+
 #[macro_export]
 macro_rules! tests {
     ($kind:ident =>
@@ -32,6 +69,7 @@ macro_rules! tests {
                 name: $name:ident,
                 file: $file:literal,
                 $(input: $input:literal,)?
+                $(flags: $flags:literal,)?
                 expected: $expected:literal $(,)?
                 $(" $(tt:$tt)* ")?
             }
@@ -44,8 +82,11 @@ macro_rules! tests {
                 #[allow(unused_assignments, unused_mut)]
                 let mut input = None;
                 $(input = Some($input);)?
+                #[allow(unused_assignments, unused_mut)]
+                let mut flags = None;
+                $(flags = Some($flags);)?
                 let kind = $crate::infra::TestKind::$kind;
-                $crate::infra::run_test(stringify!($name), $file, input, $expected, kind);
+                $crate::infra::run_test(stringify!($name), $file, input, flags, $expected, kind);
             }
         )*
     };
@@ -55,19 +96,26 @@ pub(crate) fn run_test(
     name: &str,
     file: &str,
     input: Option<&str>,
+    flags: Option<&str>,
     expected: &str,
     kind: TestKind,
 ) {
     let file = Path::new("tests").join(file);
     match kind {
-        TestKind::Success => run_success_test(name, &file, expected, input),
-        TestKind::RuntimeError => run_runtime_error_test(name, &file, expected, input),
-        TestKind::StaticError => run_static_error_test(name, &file, expected),
+        TestKind::Success => run_success_test(name, &file, expected, input, flags),
+        TestKind::RuntimeError => run_runtime_error_test(name, &file, expected, input, flags),
+        TestKind::StaticError => run_static_error_test(name, &file, expected, flags),
+        TestKind::Warning => run_warning_test(name, &file, expected, flags),
+        TestKind::Repl => run_repl_test(&file, expected, input),
+        TestKind::Lsp => run_lsp_test(&file, expected),
+        TestKind::Fmt => run_fmt_test(&file, expected),
+        TestKind::Link => run_link_test(name, &file, expected, input),
+        TestKind::Debug => run_debug_test(name, &file, expected, input),
     }
 }
 
-fn run_success_test(name: &str, file: &Path, expected: &str, input: Option<&str>) {
-    if let Err(err) = compile(name, file) {
+fn run_success_test(name: &str, file: &Path, expected: &str, input: Option<&str>, flags: Option<&str>) {
+    if let Err(err) = compile(name, file, flags) {
         panic!("expected a successful compilation, but got an error: `{err}`");
     }
     match run(name, input) {
@@ -80,8 +128,8 @@ fn run_success_test(name: &str, file: &Path, expected: &str, input: Option<&str>
     }
 }
 
-fn run_runtime_error_test(name: &str, file: &Path, expected: &str, input: Option<&str>) {
-    if let Err(err) = compile(name, file) {
+fn run_runtime_error_test(name: &str, file: &Path, expected: &str, input: Option<&str>, flags: Option<&str>) {
+    if let Err(err) = compile(name, file, flags) {
         panic!("expected a successful compilation, but got an error: `{err}`");
     }
     match run(name, input) {
@@ -92,9 +140,9 @@ fn run_runtime_error_test(name: &str, file: &Path, expected: &str, input: Option
     }
 }
 
-fn run_static_error_test(name: &str, file: &Path, expected: &str) {
-    match compile(name, file) {
-        Ok(()) => {
+fn run_static_error_test(name: &str, file: &Path, expected: &str, flags: Option<&str>) {
+    match compile(name, file, flags) {
+        Ok(_) => {
             panic!(
                 "expected a static error, but compilation succeeded - expected error: `{expected}`"
             )
@@ -103,32 +151,431 @@ fn run_static_error_test(name: &str, file: &Path, expected: &str) {
     }
 }
 
-fn compile(name: &str, file: &Path) -> Result<(), String> {
+// The program compiles, with `expected` among what the compiler reports.
+fn run_warning_test(name: &str, file: &Path, expected: &str, flags: Option<&str>) {
+    match compile(name, file, flags) {
+        Ok(warnings) => check_error_msg(&warnings, expected),
+        Err(err) => panic!("expected a successful compilation, but got an error: `{err}`"),
+    }
+}
+
+// The file lists the sources of a program, one per line and relative to
+// it, in an order where each comes after the modules it imports. Each is
+// compiled with --separate and assembled on its own, and the objects are
+// linked with `cobra link`. The VM has no separate compilation, so there the
+// last source, the program, is compiled whole.
+fn run_link_test(name: &str, file: &Path, expected: &str, input: Option<&str>) {
+    let list = std::fs::read_to_string(file).unwrap();
+    let sources: Vec<PathBuf> = list.lines().filter(|l| !l.trim().is_empty()).map(|l| file.with_file_name(l.trim())).collect();
+    if use_vm() {
+        if let Err(err) = compile(name, sources.last().unwrap(), None) {
+            panic!("expected a successful compilation, but got an error: `{err}`");
+        }
+    } else {
+        let mut objects = vec![];
+        for source in &sources {
+            let stem = source.file_stem().unwrap().to_string_lossy();
+            let asm = Path::new("tests").join(format!("{name}_{stem}.s"));
+            let output = Command::new(compiler_path())
+                .arg("--separate")
+                .arg(source)
+                .arg(&asm)
+                .output()
+                .expect("could not run the compiler");
+            if !output.status.success() {
+                panic!("expected a successful compilation, but got an error: `{}`", String::from_utf8_lossy(&output.stderr));
+            }
+            let object = asm.with_extension("o");
+            let output = Command::new("make").arg(&object).output().expect("could not run make");
+            assert!(output.status.success(), "assembling failed");
+            objects.push(object);
+        }
+        let output = Command::new(compiler_path())
+            .arg("link")
+            .arg(mk_path(name, Ext::Run))
+            .args(&objects)
+            .output()
+            .expect("could not run the linker");
+        assert!(output.status.success(), "linking failed");
+    }
+    match run(name, input) {
+        Err(err) => {
+            panic!("expected a successful execution, but got an error: `{err}`");
+        }
+        Ok(actual_output) => {
+            diff(expected, actual_output);
+        }
+    }
+}
+
+// A program compiled with `--debug` has to assemble and behave exactly as
+// without. Debug info is only emitted by the native backend, so under
+// `SNEK_BACKEND=vm` there is nothing to check.
+fn run_debug_test(name: &str, file: &Path, expected: &str, input: Option<&str>) {
+    if use_vm() {
+        return;
+    }
+    debug_build(name, file);
+    match run(name, input) {
+        Err(err) => {
+            panic!("expected a successful execution, but got an error: `{err}`");
+        }
+        Ok(actual_output) => {
+            diff(expected, actual_output);
+        }
+    }
+}
+
+/// Builds `tests/debug_vars.snek` with `--debug` and checks its DWARF, as
+/// `objdump` decodes it: the line table covers the lines with code on them,
+/// and each function argument and `let` binding is a variable at its stack
+/// slot.
+pub(crate) fn run_debug_info_test() {
+    if use_vm() {
+        return;
+    }
+    let file = Path::new("tests").join("debug_vars.snek");
+    debug_build("debug_info", &file);
+    let object = mk_path("debug_info", Ext::Object);
+
+    let lines = objdump(&object, "--dwarf=decodedline");
+    let covered: Vec<u32> = lines
+        .lines()
+        .filter_map(|l| l.strip_prefix("tests/debug_vars.snek"))
+        .filter_map(|l| l.split_whitespace().next()?.parse().ok())
+        .collect();
+    for line in [2, 3, 5, 7, 8] {
+        assert!(covered.contains(&line), "line {line} is missing from the line table: {covered:?}");
+    }
+
+    let entries = dwarf_entries(&objdump(&object, "--dwarf=info"));
+    let has = |tag: &str, name: &str| entries.iter().any(|(t, n, _)| t == tag && n == name);
+    for func in ["scale", "our_code_starts_here"] {
+        assert!(has("DW_TAG_subprogram", func), "no function {func} in {entries:?}");
+    }
+    for param in ["x", "k"] {
+        assert!(has("DW_TAG_formal_parameter", param), "no argument {param} in {entries:?}");
+    }
+    for var in ["y", "n", "s"] {
+        assert!(has("DW_TAG_variable", var), "no variable {var} in {entries:?}");
+    }
+    for (tag, name, location) in &entries {
+        if tag == "DW_TAG_formal_parameter" || tag == "DW_TAG_variable" {
+            assert!(location.contains("DW_OP_breg7 (rsp)"), "{name} is not on the stack: {location}");
+        }
+    }
+}
+
+/// Debugs `tests/debug_vars.snek` under `gdb -batch`, with
+/// `runtime/snek-gdb.py` loaded, and checks that `print` shows decoded snek
+/// values. Skipped where gdb is not installed.
+pub(crate) fn run_debug_gdb_test() {
+    if use_vm() || Command::new("gdb").arg("--version").output().is_err() {
+        return;
+    }
+    let file = Path::new("tests").join("debug_vars.snek");
+    debug_build("debug_gdb", &file);
+    let output = Command::new("gdb")
+        .args(["-nx", "-batch", "-x", "runtime/snek-gdb.py"])
+        .args(["-ex", "break debug_vars.snek:7", "-ex", "break debug_vars.snek:3", "-ex", "run"])
+        .args(["-ex", "print s", "-ex", "continue", "-ex", "print y", "-ex", "print x"])
+        .arg(mk_path("debug_gdb", Ext::Run))
+        .output()
+        .expect("could not run gdb");
+    let found = String::from_utf8(output.stdout).unwrap();
+    for value in ["$1 = snek", "$2 = 42", "$3 = 21"] {
+        assert!(found.contains(value), "gdb did not print `{value}`:\n{found}");
+    }
+}
+
+// Compiles `file` natively with `--debug` and links it as `tests/<name>.run`,
+// leaving its object in `tests/<name>.o`.
+fn debug_build(name: &str, file: &Path) {
+    let output = Command::new(compiler_path())
+        .arg("--debug")
+        .arg(file)
+        .arg(mk_path(name, Ext::Asm))
+        .output()
+        .expect("could not run the compiler");
+    assert!(output.status.success(), "could not compile {}: {}", file.display(), String::from_utf8_lossy(&output.stderr));
+    let output = Command::new("make").arg(mk_path(name, Ext::Run)).output().expect("could not run make");
+    assert!(output.status.success(), "linking failed");
+}
+
+fn objdump(object: &Path, flag: &str) -> String {
+    let output = Command::new("objdump").arg(flag).arg(object).output().expect("could not run objdump");
+    assert!(output.status.success(), "objdump failed: {}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).unwrap()
+}
+
+// The tag, `DW_AT_name` and `DW_AT_location` of every entry objdump lists.
+fn dwarf_entries(info: &str) -> Vec<(String, String, String)> {
+    let mut entries: Vec<(String, String, String)> = vec![];
+    for line in info.lines() {
+        if let Some(tag) = line.split_once("(DW_TAG_").map(|(_, t)| t.trim_end_matches(')')) {
+            entries.push((format!("DW_TAG_{tag}"), String::new(), String::new()));
+        } else if let (Some(entry), Some((attr, value))) = (entries.last_mut(), line.split_once(':')) {
+            match attr.split_whitespace().last() {
+                Some("DW_AT_name") => entry.1 = value.trim().to_string(),
+                Some("DW_AT_location") => entry.2 = value.trim().to_string(),
+                _ => {}
+            }
+        }
+    }
+    entries
+}
+
+fn run_repl_test(file: &Path, expected: &str, input: Option<&str>) {
+    let session = std::fs::read_to_string(file).unwrap();
+    let mut cmd = Command::new(compiler_path());
+    cmd.arg("repl");
+    if let Some(input) = input {
+        cmd.args(input.split_whitespace());
+    }
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("could not run the repl");
+    child.stdin.take().unwrap().write_all(session.as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "the repl exited with an error");
+    diff(expected, String::from_utf8(output.stdout).unwrap().trim().to_string());
+}
+
+// The session file holds one JSON-RPC message per line. Each is framed and
+// sent to `cobra lsp`; the expected output is the bodies of the messages it
+// sends back, one per line.
+fn run_lsp_test(file: &Path, expected: &str) {
+    let session = std::fs::read_to_string(file).unwrap();
+    let mut framed = String::new();
+    for msg in session.lines().filter(|l| !l.trim().is_empty()) {
+        framed.push_str(&format!("Content-Length: {}\r\n\r\n{}", msg.len(), msg));
+    }
+    let mut child = Command::new(compiler_path())
+        .arg("lsp")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("could not run the language server");
+    child.stdin.take().unwrap().write_all(framed.as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "the language server exited with an error");
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let mut bodies = vec![];
+    let mut rest = stdout.as_str();
+    while let Some(header_end) = rest.find("\r\n\r\n") {
+        let length: usize = rest[..header_end]
+            .strip_prefix("Content-Length: ")
+            .and_then(|n| n.parse().ok())
+            .expect("malformed header");
+        let body_start = header_end + 4;
+        bodies.push(&rest[body_start..body_start + length]);
+        rest = &rest[body_start + length..];
+    }
+    assert!(rest.is_empty(), "trailing output: `{rest}`");
+    diff(expected, bodies.join("\n"));
+}
+
+fn run_fmt_test(file: &Path, expected: &str) {
+    let formatted = format(&std::fs::read_to_string(file).unwrap()).unwrap();
+    diff(expected, formatted.trim().to_string());
+    let mut check = Command::new(compiler_path())
+        .args(["fmt", "--check"])
+        .stdin(Stdio::piped())
+        .spawn()
+        .expect("could not run the formatter");
+    check.stdin.take().unwrap().write_all(formatted.as_bytes()).unwrap();
+    assert!(check.wait().unwrap().success(), "formatting is not idempotent");
+}
+
+fn format(src: &str) -> Result<String, String> {
+    let mut child = Command::new(compiler_path())
+        .arg("fmt")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("could not run the formatter");
+    child.stdin.take().unwrap().write_all(src.as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    if !output.status.success() {
+        return Err(String::from_utf8(output.stderr).unwrap());
+    }
+    Ok(String::from_utf8(output.stdout).unwrap())
+}
+
+/// Formats every `.snek` file under `tests/` and checks that formatting
+/// again changes nothing, and that the compiler parses the result to the
+/// same program, up to source positions. Files that are not programs, such
+/// as those testing parse errors, must still read as the same s-expressions,
+/// and files that don't read at all must be rejected by the formatter too.
+pub(crate) fn run_fmt_round_trip() {
+    let out_dir = Path::new("target").join("fmt");
+    let mut files = vec![];
+    snek_files(Path::new("tests"), &mut files);
+    files.sort();
+    // write every formatted file first, so that imports between them resolve
+    let mut formatted_files = vec![];
+    for file in &files {
+        let src = std::fs::read_to_string(file).unwrap();
+        let formatted_file = out_dir.join(file.strip_prefix("tests").unwrap());
+        std::fs::create_dir_all(formatted_file.parent().unwrap()).unwrap();
+        match format(&src) {
+            Ok(formatted) => {
+                assert_eq!(format(&formatted).unwrap(), formatted, "formatting {} is not idempotent", file.display());
+                std::fs::write(&formatted_file, &formatted).unwrap();
+                formatted_files.push((file, formatted_file));
+            }
+            Err(err) => {
+                assert!(err.contains("Invalid sexp"), "could not format {}: {err}", file.display());
+                assert_eq!(parsed(file), Parsed::Unreadable, "the formatter rejects {}, which reads", file.display());
+            }
+        }
+    }
+    for (file, formatted_file) in formatted_files {
+        assert_eq!(parsed(file), parsed(&formatted_file), "formatting {} changed the program", file.display());
+    }
+}
+
+fn snek_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    for entry in std::fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            snek_files(&path, files);
+        } else if path.extension().map_or(false, |ext| ext == "snek") {
+            files.push(path);
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Parsed {
+    // the compiler's dump of the parsed program, with source positions removed
+    Program(String),
+    // what the reader made of a file that is not a valid program, without
+    // comments or positions
+    Sexp(String),
+    Unreadable,
+}
+
+fn parsed(file: &Path) -> Parsed {
+    let output = Command::new(compiler_path())
+        .args(["--backend", "vm"])
+        .arg(file)
+        .arg(Path::new("target").join("fmt").join("out.bc"))
+        .output()
+        .expect("could not run the compiler");
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    if let Some(program) = stdout.lines().find_map(|l| l.strip_prefix("Program: ")) {
+        let mut program = program.to_string();
+        while let Some(start) = program.find("Pos { line: ") {
+            let end = start + program[start..].find('}').unwrap() + 1;
+            program.replace_range(start..end, "Pos");
+        }
+        return Parsed::Program(program);
+    }
+    match stdout.lines().find_map(|l| l.strip_prefix("parsed_sexp: ")) {
+        Some(sexp) => Parsed::Sexp(sexp.to_string()),
+        None => Parsed::Unreadable,
+    }
+}
+
+/// Compiles `tests/typecheck_checks.snek` with and without `--typecheck`
+/// and checks that the typed code has none of the operand checks that raise
+/// errors through `throw_error`, which the types already rule out.
+pub(crate) fn run_typecheck_drops_checks() {
+    let checks = |flags: &[&str]| {
+        asm("typecheck_checks.snek", flags)
+            .lines()
+            .filter(|l| l.trim_start().starts_with('j') && l.ends_with(" throw_error"))
+            .count()
+    };
+    assert!(checks(&[]) > 0, "the untyped program has no checks to drop");
+    assert_eq!(checks(&["--typecheck"]), 0, "the typed program still has checks");
+}
+
+// The assembly the compiler emits for `file` under `tests/` with `flags`,
+// without assembling it.
+fn asm(file: &str, flags: &[&str]) -> String {
+    let out_dir = Path::new("target").join("asm");
+    std::fs::create_dir_all(&out_dir).unwrap();
+    let out = out_dir.join(format!("{}{}.s", file.trim_end_matches(".snek"), flags.concat()));
+    let output = Command::new(compiler_path())
+        .args(flags)
+        .arg(Path::new("tests").join(file))
+        .arg(&out)
+        .output()
+        .expect("could not run the compiler");
+    assert!(output.status.success(), "could not compile {file}: {}", String::from_utf8_lossy(&output.stderr));
+    std::fs::read_to_string(out).unwrap()
+}
+
+// `SNEK_BACKEND=vm cargo test` runs the suite through `--backend vm` and the
+// bytecode VM instead of nasm and the Rust runtime.
+fn use_vm() -> bool {
+    std::env::var("SNEK_BACKEND").map_or(false, |b| b == "vm")
+}
+
+fn compiler_path() -> PathBuf {
+    ["target", "debug", env!("CARGO_PKG_NAME")].iter().collect()
+}
+
+// `flags` are extra compiler flags, space-separated like inputs. Returns
+// what the compiler wrote to stderr.
+fn compile(name: &str, file: &Path, flags: Option<&str>) -> Result<String, String> {
+    let flags: Vec<&str> = flags.map_or(vec![], |flags| flags.split_whitespace().collect());
+    // separately compiled objects are only native
+    if use_vm() && !flags.contains(&"--separate") {
+        let output = Command::new(compiler_path())
+            .args(["--backend", "vm"])
+            .args(&flags)
+            .arg(file)
+            .arg(mk_path(name, Ext::Bytecode))
+            .output()
+            .expect("could not run the compiler");
+        if !output.status.success() {
+            return Err(String::from_utf8(output.stderr).unwrap());
+        }
+        return Ok(String::from_utf8(output.stderr).unwrap());
+    }
+
     // Run the compiler
-    let compiler: PathBuf = ["target", "debug", env!("CARGO_PKG_NAME")].iter().collect();
+    let compiler = compiler_path();
     let output = Command::new(&compiler)
+        .args(&flags)
         .arg(file)
-        .arg(&mk_path(name, Ext::Asm))
+        .arg(mk_path(name, Ext::Asm))
         .output()
         .expect("could not run the compiler");
     if !output.status.success() {
         return Err(String::from_utf8(output.stderr).unwrap());
     }
+    let warnings = String::from_utf8(output.stderr).unwrap();
 
     // Assemble and link
     let output = Command::new("make")
-        .arg(&mk_path(name, Ext::Run))
+        .arg(mk_path(name, Ext::Run))
         .output()
         .expect("could not run make");
     assert!(output.status.success(), "linking failed");
 
-    Ok(())
+    Ok(warnings)
 }
 
 fn run(name: &str, input: Option<&str>) -> Result<String, String> {
-    let mut cmd = Command::new(&mk_path(name, Ext::Run));
+    let mut cmd = if use_vm() {
+        let mut cmd = Command::new(compiler_path());
+        cmd.arg("vm").arg(mk_path(name, Ext::Bytecode));
+        cmd
+    } else {
+        Command::new(mk_path(name, Ext::Run))
+    };
+    // several inputs are written space-separated, e.g. `input: "1 true 3"`
     if let Some(input) = input {
-        cmd.arg(input);
+        cmd.args(input.split_whitespace());
     }
     let output = cmd.output().unwrap();
     if output.status.success() {
@@ -169,6 +616,8 @@ fn mk_path(name: &str, ext: Ext) -> PathBuf {
 enum Ext {
     Asm,
     Run,
+    Bytecode,
+    Object,
 }
 
 impl std::fmt::Display for Ext {
@@ -176,6 +625,8 @@ impl std::fmt::Display for Ext {
         match self {
             Ext::Asm => write!(f, "s"),
             Ext::Run => write!(f, "run"),
+            Ext::Bytecode => write!(f, "bc"),
+            Ext::Object => write!(f, "o"),
         }
     }
 }